@@ -57,6 +57,16 @@ impl KvsEngine for KvSled {
             None => Ok(None),
         }
     }
+    fn range_first(&self, range: impl RangeBounds<String>) -> Result<Option<(String, String)>> {
+        match self.db.range(range).next() {
+            Some(Ok((k, v))) => Ok(Some((
+                from_utf8(k.to_vec().as_ref()).unwrap().to_string(),
+                from_utf8(v.to_vec().as_ref()).unwrap().to_string(),
+            ))),
+            Some(Err(e)) => Err(KvError::StringError(e.to_string())),
+            None => Ok(None),
+        }
+    }
     fn range_erase(&self, range: impl RangeBounds<String>) -> Result<()> {
         let keys: Vec<String> = self
             .db
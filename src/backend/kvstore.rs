@@ -211,6 +211,17 @@ impl KvsEngine for KvStore {
             None => Ok(None),
         }
     }
+    fn range_first(&self, range: impl RangeBounds<String>) -> Result<Option<(String, String)>> {
+        let index = self.index.read().unwrap();
+        let key = index.range(range).next().map(|(k, _)| k.to_string());
+        match key {
+            Some(key) => {
+                let value = self.get(key.to_string())?.unwrap();
+                Ok(Some((key, value)))
+            }
+            None => Ok(None),
+        }
+    }
     fn range_erase(&self, range: impl RangeBounds<String>) -> Result<()> {
         let index = self.index.read().unwrap();
         let keys: Vec<String> = index
@@ -29,6 +29,10 @@ pub trait KvsEngine: Clone + Send + 'static {
     ///
     ///Return an error if the value is not read successfully.
     fn range_last(&self, range: impl RangeBounds<String>) -> Result<Option<(String, String)>>;
+    ///Get the first value within a given string key range.
+    ///
+    ///Return an error if the value is not read successfully.
+    fn range_first(&self, range: impl RangeBounds<String>) -> Result<Option<(String, String)>>;
     ///Erase a batch of value within a given string key range.
     ///
     ///Return an error if the value is not erase successfully.
@@ -77,6 +81,12 @@ impl KvsEngine for EngineKind {
             EngineKind::sled(store) => store.range_last(range),
         }
     }
+    fn range_first(&self, range: impl RangeBounds<String>) -> Result<Option<(String, String)>> {
+        match self {
+            EngineKind::kvs(store) => store.range_first(range),
+            EngineKind::sled(store) => store.range_first(range),
+        }
+    }
     fn range_erase(&self, range: impl RangeBounds<String>) -> Result<()> {
         match self {
             EngineKind::kvs(store) => store.range_erase(range),
@@ -2,6 +2,7 @@ use kvs::preclude::*;
 use serde::{Deserialize, Serialize};
 use std::{io::BufRead, net::SocketAddr, process::exit};
 use structopt::StructOpt;
+use tokio_stream::StreamExt;
 
 #[macro_use]
 extern crate lazy_static;
@@ -31,6 +32,8 @@ enum Command {
     Get {
         #[structopt(help = "A string key")]
         key: String,
+        #[structopt(long = "at-ts", help = "Read the value the key had at this ts")]
+        at_ts: Option<u64>,
         #[structopt(
             name = "IP-PORT",
             short = "a",
@@ -68,6 +71,160 @@ enum Command {
         )]
         addrs: Vec<SocketAddr>,
     },
+    #[structopt(about = "List the key value pairs within a given key range")]
+    Scan {
+        #[structopt(help = "The first key of the range")]
+        start: String,
+        #[structopt(help = "The key the range ends before, the last key if absent")]
+        end: Option<String>,
+        #[structopt(short = "l", long = "limit", help = "Max number of pairs to list")]
+        limit: Option<u32>,
+        #[structopt(
+            name = "IP-PORT",
+            short = "a",
+            long = "addr",
+            // default_value = DEFAULT_ADDR,
+            // parse(try_from_str = parse_str_to_vec)
+        )]
+        addrs: Vec<SocketAddr>,
+    },
+    #[structopt(about = "Drop the versions no read at or after a given ts can see")]
+    Gc {
+        #[structopt(help = "The GC safe point, reads below it fail afterwards")]
+        safe_point: u64,
+        #[structopt(
+            name = "IP-PORT",
+            short = "a",
+            long = "addr",
+            // default_value = DEFAULT_ADDR,
+            // parse(try_from_str = parse_str_to_vec)
+        )]
+        addrs: Vec<SocketAddr>,
+    },
+    #[structopt(about = "Add a node to the raft group, or promote a learner")]
+    AddNode {
+        #[structopt(help = "The raft id of the node")]
+        id: u64,
+        #[structopt(help = "The address the node serves raft at")]
+        node_addr: SocketAddr,
+        #[structopt(
+            name = "IP-PORT",
+            short = "a",
+            long = "addr",
+            // default_value = DEFAULT_ADDR,
+            // parse(try_from_str = parse_str_to_vec)
+        )]
+        addrs: Vec<SocketAddr>,
+    },
+    #[structopt(about = "Add a non-voting learner to the raft group")]
+    AddLearner {
+        #[structopt(help = "The raft id of the node")]
+        id: u64,
+        #[structopt(help = "The address the node serves raft at")]
+        node_addr: SocketAddr,
+        #[structopt(
+            name = "IP-PORT",
+            short = "a",
+            long = "addr",
+            // default_value = DEFAULT_ADDR,
+            // parse(try_from_str = parse_str_to_vec)
+        )]
+        addrs: Vec<SocketAddr>,
+    },
+    #[structopt(about = "Remove a node from the raft group")]
+    RemoveNode {
+        #[structopt(help = "The raft id of the node")]
+        id: u64,
+        #[structopt(
+            name = "IP-PORT",
+            short = "a",
+            long = "addr",
+            // default_value = DEFAULT_ADDR,
+            // parse(try_from_str = parse_str_to_vec)
+        )]
+        addrs: Vec<SocketAddr>,
+    },
+    #[structopt(about = "Hand the raft leadership over to a voter")]
+    TransferLeader {
+        #[structopt(help = "The raft id of the voter")]
+        id: u64,
+        #[structopt(
+            name = "IP-PORT",
+            short = "a",
+            long = "addr",
+            // default_value = DEFAULT_ADDR,
+            // parse(try_from_str = parse_str_to_vec)
+        )]
+        addrs: Vec<SocketAddr>,
+    },
+    #[structopt(about = "Index the keys by a field of their JSON values")]
+    CreateIndex {
+        #[structopt(help = "A name of the index")]
+        name: String,
+        #[structopt(help = "A JSON pointer to the field, like /user/email")]
+        path: String,
+        #[structopt(
+            name = "IP-PORT",
+            short = "a",
+            long = "addr",
+            // default_value = DEFAULT_ADDR,
+            // parse(try_from_str = parse_str_to_vec)
+        )]
+        addrs: Vec<SocketAddr>,
+    },
+    #[structopt(about = "List the keys whose indexed field has a given value")]
+    Lookup {
+        #[structopt(help = "A name of the index")]
+        index: String,
+        #[structopt(help = "A value of the indexed field")]
+        value: String,
+        #[structopt(
+            name = "IP-PORT",
+            short = "a",
+            long = "addr",
+            // default_value = DEFAULT_ADDR,
+            // parse(try_from_str = parse_str_to_vec)
+        )]
+        addrs: Vec<SocketAddr>,
+    },
+    #[structopt(about = "Follow the changes committed to keys with a given prefix")]
+    Subscribe {
+        #[structopt(help = "The prefix of the keys to follow, every key if absent")]
+        prefix: Option<String>,
+        #[structopt(
+            short = "s",
+            long = "start-ts",
+            help = "Replay the changes committed from this ts on"
+        )]
+        start_ts: Option<u64>,
+        #[structopt(
+            name = "IP-PORT",
+            short = "a",
+            long = "addr",
+            // default_value = DEFAULT_ADDR,
+            // parse(try_from_str = parse_str_to_vec)
+        )]
+        addrs: Vec<SocketAddr>,
+    },
+    #[structopt(about = "Watch the puts and deletes of keys with a given prefix")]
+    Watch {
+        #[structopt(help = "The prefix of the keys to watch")]
+        prefix: String,
+        #[structopt(
+            short = "r",
+            long = "from-revision",
+            help = "Replay the changes from this revision on, only new ones if absent"
+        )]
+        from_revision: Option<u64>,
+        #[structopt(
+            name = "IP-PORT",
+            short = "a",
+            long = "addr",
+            // default_value = DEFAULT_ADDR,
+            // parse(try_from_str = parse_str_to_vec)
+        )]
+        addrs: Vec<SocketAddr>,
+    },
     #[structopt(about = "Start a transaction")]
     Txn {
         #[structopt(
@@ -78,6 +235,11 @@ enum Command {
             // parse(try_from_str = parse_str_to_vec)
         )]
         addrs: Vec<SocketAddr>,
+        #[structopt(
+            long = "txn-size-limit",
+            help = "Max bytes of keys and values written by one transaction"
+        )]
+        txn_size_limit: Option<usize>,
     },
 }
 
@@ -88,12 +250,20 @@ async fn main() -> Result<()> {
     // println!("{:?}", opt);
 
     match opt.cmd {
-        Command::Get { key, mut addrs } => {
+        Command::Get {
+            key,
+            at_ts,
+            mut addrs,
+        } => {
             if addrs.is_empty() {
                 addrs = (*DEFAULT_ADDRS).to_owned();
             }
             let mut client = KvsClient::builder().add_batch_nodes(addrs).build();
-            match client.get(key).await {
+            let value = match at_ts {
+                Some(ts) => client.get_at(key, ts).await,
+                None => client.get(key).await,
+            };
+            match value {
                 Ok(value) => println!("{}", value),
                 Err(KvError::KeyNotFound) => println!("Key not found"),
                 Err(e) => {
@@ -132,8 +302,232 @@ async fn main() -> Result<()> {
                 }
             }
         }
-        Command::Txn { addrs } => {
+        Command::Scan {
+            start,
+            end,
+            limit,
+            mut addrs,
+        } => {
+            if addrs.is_empty() {
+                addrs = (*DEFAULT_ADDRS).to_owned();
+            }
+            let mut client = KvsClient::builder().add_batch_nodes(addrs).build();
+            match client
+                .scan(start, end.unwrap_or_default(), limit.unwrap_or(0))
+                .await
+            {
+                Ok(pairs) => pairs
+                    .into_iter()
+                    .for_each(|(key, value)| println!("{} {}", key, value)),
+                Err(e) => {
+                    eprintln!("{}", e);
+                    exit(1);
+                }
+            }
+        }
+        Command::Gc {
+            safe_point,
+            mut addrs,
+        } => {
+            if addrs.is_empty() {
+                addrs = (*DEFAULT_ADDRS).to_owned();
+            }
+            let mut client = KvsClient::builder().add_batch_nodes(addrs).build();
+            match client.gc(safe_point).await {
+                Ok(_removed) => {}
+                Err(e) => {
+                    eprintln!("{}", e);
+                    exit(1);
+                }
+            }
+        }
+        Command::AddNode {
+            id,
+            node_addr,
+            mut addrs,
+        } => {
+            if addrs.is_empty() {
+                addrs = (*DEFAULT_ADDRS).to_owned();
+            }
             let mut client = KvsClient::builder().add_batch_nodes(addrs).build();
+            match client.add_node(id, node_addr.to_string()).await {
+                Ok(nodes) => {
+                    for node in nodes {
+                        println!("{} {}", node.id, node.addr);
+                    }
+                }
+                Err(e) => {
+                    eprintln!("{}", e);
+                    exit(1);
+                }
+            }
+        }
+        Command::AddLearner {
+            id,
+            node_addr,
+            mut addrs,
+        } => {
+            if addrs.is_empty() {
+                addrs = (*DEFAULT_ADDRS).to_owned();
+            }
+            let mut client = KvsClient::builder().add_batch_nodes(addrs).build();
+            match client.add_learner(id, node_addr.to_string()).await {
+                Ok(learners) => {
+                    for learner in learners {
+                        println!("{} {}", learner.id, learner.addr);
+                    }
+                }
+                Err(e) => {
+                    eprintln!("{}", e);
+                    exit(1);
+                }
+            }
+        }
+        Command::RemoveNode { id, mut addrs } => {
+            if addrs.is_empty() {
+                addrs = (*DEFAULT_ADDRS).to_owned();
+            }
+            let mut client = KvsClient::builder().add_batch_nodes(addrs).build();
+            match client.remove_node(id).await {
+                Ok(nodes) => {
+                    for node in nodes {
+                        println!("{} {}", node.id, node.addr);
+                    }
+                }
+                Err(e) => {
+                    eprintln!("{}", e);
+                    exit(1);
+                }
+            }
+        }
+        Command::TransferLeader { id, mut addrs } => {
+            if addrs.is_empty() {
+                addrs = (*DEFAULT_ADDRS).to_owned();
+            }
+            let mut client = KvsClient::builder().add_batch_nodes(addrs).build();
+            if let Err(e) = client.transfer_leader(id).await {
+                eprintln!("{}", e);
+                exit(1);
+            }
+        }
+        Command::CreateIndex {
+            name,
+            path,
+            mut addrs,
+        } => {
+            if addrs.is_empty() {
+                addrs = (*DEFAULT_ADDRS).to_owned();
+            }
+            let mut client = KvsClient::builder().add_batch_nodes(addrs).build();
+            if let Err(e) = client.create_index(name, path).await {
+                eprintln!("{}", e);
+                exit(1);
+            }
+        }
+        Command::Lookup {
+            index,
+            value,
+            mut addrs,
+        } => {
+            if addrs.is_empty() {
+                addrs = (*DEFAULT_ADDRS).to_owned();
+            }
+            let mut client = KvsClient::builder().add_batch_nodes(addrs).build();
+            match client.index_lookup(index, value).await {
+                Ok(keys) => {
+                    for key in keys {
+                        println!("{}", key);
+                    }
+                }
+                Err(e) => {
+                    eprintln!("{}", e);
+                    exit(1);
+                }
+            }
+        }
+        Command::Subscribe {
+            prefix,
+            start_ts,
+            mut addrs,
+        } => {
+            if addrs.is_empty() {
+                addrs = (*DEFAULT_ADDRS).to_owned();
+            }
+            let mut client = KvsClient::builder().add_batch_nodes(addrs).build();
+            let mut stream = match client
+                .subscribe(start_ts.unwrap_or(0), prefix.unwrap_or_default())
+                .await
+            {
+                Ok(stream) => stream,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    exit(1);
+                }
+            };
+            loop {
+                match stream.message().await {
+                    Ok(Some(reply)) => {
+                        for event in reply.events {
+                            match WriteOp::from_i32(event.op) {
+                                Some(WriteOp::Delete) => {
+                                    println!("{} delete {}", event.commit_ts, event.key)
+                                }
+                                _ => println!(
+                                    "{} put {} {}",
+                                    event.commit_ts, event.key, event.value
+                                ),
+                            }
+                        }
+                    }
+                    Ok(None) => break,
+                    Err(e) => {
+                        eprintln!("{}", e);
+                        exit(1);
+                    }
+                }
+            }
+        }
+        Command::Watch {
+            prefix,
+            from_revision,
+            mut addrs,
+        } => {
+            if addrs.is_empty() {
+                addrs = (*DEFAULT_ADDRS).to_owned();
+            }
+            let mut client = KvsClient::builder().add_batch_nodes(addrs).build();
+            let stream = match client.watch(prefix, true, from_revision.unwrap_or(0)).await {
+                Ok(stream) => stream,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    exit(1);
+                }
+            };
+            tokio::pin!(stream);
+            while let Some(event) = stream.next().await {
+                match event {
+                    Ok(event) => match WriteOp::from_i32(event.op) {
+                        Some(WriteOp::Delete) => {
+                            println!("{} delete {}", event.commit_ts, event.key)
+                        }
+                        _ => println!("{} put {} {}", event.commit_ts, event.key, event.value),
+                    },
+                    Err(e) => {
+                        eprintln!("{}", e);
+                        exit(1);
+                    }
+                }
+            }
+        }
+        Command::Txn {
+            addrs,
+            txn_size_limit,
+        } => {
+            let mut builder = KvsClient::builder().add_batch_nodes(addrs);
+            if let Some(txn_size_limit) = txn_size_limit {
+                builder = builder.set_txn_size_limit(txn_size_limit);
+            }
+            let mut client = builder.build();
 
             let stdin = std::io::stdin();
             let mut handle = stdin.lock();
@@ -145,7 +539,7 @@ async fn main() -> Result<()> {
                 }
                 let args: Vec<&str> = input.split_ascii_whitespace().collect();
                 match parse_txn_args(args) {
-                    TxnArgs::Begin => match client.txn_start().await {
+                    TxnArgs::Begin(isolation) => match client.txn_start_with(isolation).await {
                         Ok(_) => println!("Transaction Started"),
                         Err(_) => println!("Error in starting transaction! Please try again"),
                     },
@@ -159,19 +553,35 @@ async fn main() -> Result<()> {
                             Err(e) => println!("Error: {}", e),
                         }
                     }
+                    TxnArgs::Scan(start, end, limit) => {
+                        if !client.txn_is_started() {
+                            println!("No active transaction detected! Use `begin` first");
+                            continue;
+                        }
+                        match client.txn_scan(start, end, limit).await {
+                            Ok(pairs) => pairs
+                                .into_iter()
+                                .for_each(|(key, value)| println!("{} {}", key, value)),
+                            Err(e) => println!("Error: {}", e),
+                        }
+                    }
                     TxnArgs::Remove(k) => {
                         if !client.txn_is_started() {
                             println!("No active transaction detected! Use `begin` first");
                             continue;
                         }
-                        client.txn_delete(k)?;
+                        if let Err(e) = client.txn_delete(k) {
+                            println!("Error: {}", e);
+                        }
                     }
                     TxnArgs::Set(k, v) => {
                         if !client.txn_is_started() {
                             println!("No active transaction detected! Use `begin` first");
                             continue;
                         }
-                        client.txn_set(k, v)?;
+                        if let Err(e) = client.txn_set(k, v) {
+                            println!("Error: {}", e);
+                        }
                     }
                     TxnArgs::Commit => {
                         if !client.txn_is_started() {
@@ -183,6 +593,16 @@ async fn main() -> Result<()> {
                             Err(e) => println!("Transaction Failed. Error: {}", e),
                         }
                     }
+                    TxnArgs::Abort => {
+                        if !client.txn_is_started() {
+                            println!("No active transaction detected! Use `begin` first!");
+                            continue;
+                        }
+                        match client.txn_rollback().await {
+                            Ok(()) => println!("Transaction Aborted"),
+                            Err(e) => println!("Transaction Abort Failed. Error: {}", e),
+                        }
+                    }
                     TxnArgs::Exit => {
                         exit(0);
                     }
@@ -197,11 +617,13 @@ async fn main() -> Result<()> {
 }
 
 enum TxnArgs {
-    Begin,
+    Begin(IsolationLevel),
     Get(String),
+    Scan(String, String, u32),
     Remove(String),
     Set(String, String),
     Commit,
+    Abort,
     Exit,
     Unknown,
 }
@@ -209,22 +631,38 @@ enum TxnArgs {
 fn parse_txn_args(args: Vec<&str>) -> TxnArgs {
     if args.len() == 2 && args[0] == "get" {
         TxnArgs::Get(args[1].to_string())
+    } else if args.len() == 2 && args[0] == "scan" {
+        TxnArgs::Scan(args[1].to_string(), String::new(), 0)
+    } else if args.len() == 3 && args[0] == "scan" {
+        TxnArgs::Scan(args[1].to_string(), args[2].to_string(), 0)
+    } else if args.len() == 4 && args[0] == "scan" && args[3].parse::<u32>().is_ok() {
+        TxnArgs::Scan(
+            args[1].to_string(),
+            args[2].to_string(),
+            args[3].parse().unwrap(),
+        )
     } else if args.len() == 2 && args[0] == "remove" {
         TxnArgs::Remove(args[1].to_string())
     } else if args.len() == 3 && args[0] == "set" {
         TxnArgs::Set(args[1].to_string(), args[2].to_string())
     } else if args.len() == 1 && args[0] == "commit" {
         TxnArgs::Commit
+    } else if args.len() == 1 && args[0] == "abort" {
+        TxnArgs::Abort
     } else if args.len() == 1 && args[0] == "begin" {
-        TxnArgs::Begin
+        TxnArgs::Begin(IsolationLevel::default())
+    } else if args.len() == 2 && args[0] == "begin" && args[1].parse::<IsolationLevel>().is_ok() {
+        TxnArgs::Begin(args[1].parse().unwrap())
     } else if args.len() == 1 && args[0] == "exit" {
         TxnArgs::Exit
     } else {
-        eprintln!("Unknown args! Avaliale: begin get set commit exit");
-        eprintln!("    begin");
+        eprintln!("Unknown args! Avaliale: begin get scan set commit abort exit");
+        eprintln!("    begin [snapshot|serializable|read-committed]");
         eprintln!("    get <key>");
+        eprintln!("    scan <start> [end] [limit]");
         eprintln!("    set <key> <value>");
         eprintln!("    commit");
+        eprintln!("    abort");
         eprintln!("    exit");
         TxnArgs::Unknown
     }
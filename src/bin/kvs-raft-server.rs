@@ -7,6 +7,7 @@ use std::{
     fs,
     io::Write,
     net::SocketAddr,
+    time::Duration,
 };
 use structopt::StructOpt;
 
@@ -47,6 +48,26 @@ struct Opt {
         parse(try_from_str = parse_str_to_pool)
     )]
     thread_pool: String,
+    #[structopt(
+        long = "snapshot-log-bytes",
+        help = "Snapshot once the persisted raft state reaches this many bytes"
+    )]
+    snapshot_log_bytes: Option<u64>,
+    #[structopt(
+        long = "snapshot-log-entries",
+        help = "Snapshot once this many entries were applied since the last snapshot"
+    )]
+    snapshot_log_entries: Option<u64>,
+    #[structopt(
+        long = "snapshot-interval",
+        help = "Snapshot once this many seconds passed since the last snapshot"
+    )]
+    snapshot_interval: Option<u64>,
+    #[structopt(
+        long = "snapshot-followers-caught-up",
+        help = "Snapshot on the leader only what every follower replicated"
+    )]
+    snapshot_followers_caught_up: bool,
 }
 
 fn parse_str_to_engine(src: &str) -> Result<String> {
@@ -96,11 +117,19 @@ async fn main() -> Result<()> {
     info!("Key Value Store Raft Server");
     info!("  Version : {}", env!("CARGO_PKG_VERSION"));
 
+    let policy = SnapshotPolicy {
+        max_log_bytes: opt.snapshot_log_bytes,
+        max_log_entries: opt.snapshot_log_entries,
+        interval: opt.snapshot_interval.map(Duration::from_secs),
+        followers_caught_up: opt.snapshot_followers_caught_up,
+    };
     let servers = KvsServer::builder()
+        .set_server(String::from("raft"))
         .set_engine(opt.engine.clone())
+        .set_snapshot_policy(policy)
         .add_node("127.0.0.1:5001".parse().unwrap(), root_path.join("1"))
         .add_node("127.0.0.1:5002".parse().unwrap(), root_path.join("2"))
         .add_node("127.0.0.1:5003".parse().unwrap(), root_path.join("3"))
-        .build();
+        .build()?;
     servers.start()
 }
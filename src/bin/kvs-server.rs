@@ -2,7 +2,7 @@
 extern crate log;
 
 // use serde::{Deserialize, Serialize};
-use std::{env::current_dir, fs, io::Write, net::SocketAddr};
+use std::{env::current_dir, fs, io::Write, net::SocketAddr, time::Duration};
 use structopt::StructOpt;
 
 const DEFAULT_ADDR: &str = "127.0.0.1:4000";
@@ -41,6 +41,43 @@ struct Opt {
         default_value = DEFAULT_ADDR
     )]
     addrs: Vec<SocketAddr>,
+    #[structopt(
+        name = "NODE-ID",
+        long = "first-id",
+        default_value = "0",
+        help = "The raft id of the first node, the others count up from it"
+    )]
+    first_id: usize,
+    #[structopt(
+        long = "join",
+        help = "Wait to be added to a running raft group instead of forming one"
+    )]
+    join: bool,
+    #[structopt(
+        long = "lease-read",
+        help = "Serve reads on the raft leader alone while it holds a lease"
+    )]
+    lease_read: bool,
+    #[structopt(
+        long = "snapshot-log-bytes",
+        help = "Snapshot once the persisted raft state reaches this many bytes"
+    )]
+    snapshot_log_bytes: Option<u64>,
+    #[structopt(
+        long = "snapshot-log-entries",
+        help = "Snapshot once this many entries were applied since the last snapshot"
+    )]
+    snapshot_log_entries: Option<u64>,
+    #[structopt(
+        long = "snapshot-interval",
+        help = "Snapshot once this many seconds passed since the last snapshot"
+    )]
+    snapshot_interval: Option<u64>,
+    #[structopt(
+        long = "snapshot-followers-caught-up",
+        help = "Snapshot on the leader only what every follower replicated"
+    )]
+    snapshot_followers_caught_up: bool,
 }
 
 fn parse_str_to_engine(src: &str) -> Result<String> {
@@ -76,12 +113,22 @@ async fn main() -> Result<()> {
     info!("  IP-PORT : {:?}", opt.addrs);
     info!("  Engine  : {}", opt.engine);
 
+    let policy = SnapshotPolicy {
+        max_log_bytes: opt.snapshot_log_bytes,
+        max_log_entries: opt.snapshot_log_entries,
+        interval: opt.snapshot_interval.map(Duration::from_secs),
+        followers_caught_up: opt.snapshot_followers_caught_up,
+    };
     let server = KvsServer::builder()
         .set_server(opt.server)
         .set_engine(opt.engine)
         .set_root_path(current_dir().unwrap())
+        .set_first_id(opt.first_id)
+        .set_join(opt.join)
+        .set_lease_read(opt.lease_read)
+        .set_snapshot_policy(policy)
         .add_batch_nodes(opt.addrs);
 
-    let server = server.build();
+    let server = server.build()?;
     server.start()
 }
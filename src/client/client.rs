@@ -1,8 +1,29 @@
-use std::{net::SocketAddr, time::Duration};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    future::Future,
+    net::SocketAddr,
+    ops::Range,
+    str::FromStr,
+    time::{Duration, Instant},
+};
 
-use tonic::{transport::Channel, Code, Request};
+use tokio::{runtime::Handle, task::JoinHandle};
+use tokio_stream::Stream;
+use tonic::{transport::Channel, Code, Request, Response, Status, Streaming};
 
-use crate::preclude::*;
+use crate::{
+    percolator::{is_index_key, Index, DEFAULT_LOCK_TTL},
+    preclude::*,
+};
+
+/// First delay of a reader waiting on the lock of a running transaction
+const BACKOFF_BASE_MS: u64 = 10;
+/// Longest single delay of a waiting reader
+const BACKOFF_MAX_MS: u64 = 1000;
+/// How long a reader waits on one key before giving up
+const MAX_BACKOFF_WAIT_MS: u64 = 20_000;
+/// Total bytes of keys and values one transaction may write by default
+const DEFAULT_TXN_SIZE_LIMIT: usize = 256 * 1024 * 1024;
 
 #[derive(Debug, Clone)]
 struct WriteInfo {
@@ -11,16 +32,61 @@ struct WriteInfo {
     op: WriteOp,
 }
 
+/// Isolation level of a transaction, chosen when it starts
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum IsolationLevel {
+    /// Every read sees the snapshot at start ts, write skew is allowed
+    #[default]
+    Snapshot,
+    /// Keys read are locked at commit, so a conflicting writer aborts
+    Serializable,
+    /// Every read sees the latest committed value
+    ReadCommitted,
+}
+
+impl FromStr for IsolationLevel {
+    type Err = KvError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "snapshot" => Ok(IsolationLevel::Snapshot),
+            "serializable" => Ok(IsolationLevel::Serializable),
+            "read-committed" => Ok(IsolationLevel::ReadCommitted),
+            _ => Err(KvError::ParserError(s.to_string())),
+        }
+    }
+}
+
 /// A KvsClient that support communicate with KvsServer
 pub struct KvsClient {
     name: String,
     ts: Option<u64>,
+    // when `ts` was fetched, lock ttls are counted from the start ts
+    started: Instant,
     seq: u64,
     num_servers: usize,
     servers: Vec<KvRpcClient<Channel>>,
+    // the server the next read is sent to first, so reads spread over them
+    next_reader: usize,
     retries: usize,
     timeout: Duration,
-    write_infos: Vec<WriteInfo>,
+    batch_size: usize,
+    lock_ttl: Duration,
+    isolation: IsolationLevel,
+    read_keys: HashSet<String>,
+    // keys written by a serializable txn, which need no read lock
+    written_keys: HashSet<String>,
+    // the latest unflushed write of each key, read before going to servers
+    write_buffer: BTreeMap<String, WriteInfo>,
+    // the first write of this txn, kept after its batch is flushed
+    primary: Option<WriteInfo>,
+    txn_size: usize,
+    txn_size_limit: usize,
+    // whether batches were flushed before commit, whose keys only servers know
+    streamed: bool,
+    // prewrites of batches flushed before commit, run one after another
+    flushing: Option<JoinHandle<Result<()>>>,
+    heart_beat: Option<JoinHandle<()>>,
 }
 
 impl KvsClient {
@@ -48,10 +114,15 @@ impl KvsClient {
     pub fn txn_is_started(&self) -> bool {
         self.ts.is_some()
     }
-    /// Send a request to server and get the max seq number of this client's name
+    /// Send a request to server and get a new timestamp
     pub async fn get_timestamp(&mut self) -> Result<u64> {
+        self.get_timestamps(1).await.map(|range| range.start)
+    }
+    /// Send a request to server and allocate `count` consecutive timestamps at once
+    pub async fn get_timestamps(&mut self, count: u32) -> Result<Range<u64>> {
         let req = TsRequest {
             name: self.name.clone(),
+            count,
         };
         for _retries in 0..self.retries {
             for client in self.servers.iter_mut() {
@@ -59,9 +130,8 @@ impl KvsClient {
                 match tokio::time::timeout(self.timeout, res).await {
                     Ok(Ok(res)) => {
                         let res = res.into_inner();
-                        info!("get timestamp: {:?}", res.ts);
-                        // self.ts = Some(res.ts);
-                        return Ok(res.ts);
+                        info!("get timestamp: {:?}, count: {}", res.ts, res.count);
+                        return Ok(res.ts..res.ts + res.count as u64);
                     }
                     Ok(Err(_e)) => continue,
                     Err(_e) => continue,
@@ -86,6 +156,274 @@ impl KvsClient {
         self.txn_get(key.clone()).await
     }
 
+    /// Read the value `key` had at `ts`, for audits of past versions.
+    /// Fails with `VersionCompacted` if `ts` is below the GC safe point, and
+    /// if `ts` is ahead of the TSO, whose versions may still be committed
+    pub async fn get_at(&mut self, key: String, ts: u64) -> Result<String> {
+        // a fresh txn identifies this reader when it resolves locks
+        self.txn_start().await?;
+        let current_ts = self.ts.unwrap();
+        if ts > current_ts {
+            return Err(KvError::StringError(format!(
+                "ts {} is ahead of the TSO at {}",
+                ts, current_ts
+            )));
+        }
+        let mut backoff = Backoff::new();
+        loop {
+            let mut reply = self.txn_send_batch_get(vec![key.clone()], ts).await?;
+            if let Some(pair) = reply.pairs.pop() {
+                return Ok(pair.value);
+            }
+            let lock = match reply.locks.pop() {
+                Some(lock) => lock,
+                None => return Err(KvError::KeyNotFound),
+            };
+            if !self.txn_resolve_lock(lock).await? && !backoff.wait().await {
+                return Err(KvError::StringError(format!("Key {} is locked", key)));
+            }
+        }
+    }
+    /// Move the GC safe point up to `safe_point`, dropping the versions no
+    /// read at or after it can see. Returns how many versions were dropped
+    pub async fn gc(&mut self, safe_point: u64) -> Result<u64> {
+        self.seq += 1;
+        let req = GcRequest {
+            safe_point,
+            ts: self.ts.unwrap_or(safe_point),
+            seq: self.seq,
+        };
+        for _retries in 0..self.retries {
+            for client in self.servers.iter_mut() {
+                let res = client.txn_gc(Request::new(req.clone()));
+                match tokio::time::timeout(self.timeout, res).await {
+                    Ok(Ok(res)) => return Ok(res.into_inner().removed),
+                    Ok(Err(e)) if e.code() == Code::PermissionDenied => continue,
+                    Ok(Err(e)) => return Err(KvError::StringError(e.to_string())),
+                    Err(e) => {
+                        info!("{}", e);
+                        continue;
+                    }
+                }
+            }
+        }
+        Err(KvError::Unknown)
+    }
+
+    /// Send batch get command to server, and process the response.
+    pub async fn batch_get(&mut self, keys: Vec<String>) -> Result<Vec<(String, String)>> {
+        self.txn_start().await?;
+        self.txn_batch_get(keys).await
+    }
+    /// Send scan command to server, and process the response.
+    pub async fn scan(
+        &mut self,
+        start: String,
+        end: String,
+        limit: u32,
+    ) -> Result<Vec<(String, String)>> {
+        self.txn_start().await?;
+        self.txn_scan(start, end, limit).await
+    }
+    /// Defines the index `name` over the field at the JSON pointer `path` of
+    /// values. The definition is committed first, so later writes keep their
+    /// own entries, then the keys already written are indexed `batch_size`
+    /// keys per transaction.
+    pub async fn create_index(&mut self, name: String, path: String) -> Result<()> {
+        let index = Index::new(name, path);
+        self.txn_start().await?;
+        self.txn_set(index.definition_key(), index.path().to_string())?;
+        self.txn_commit().await?;
+        let page = self.batch_size as u32;
+        let mut cursor = String::new();
+        loop {
+            let mut retries = self.retries;
+            let pairs = loop {
+                match self.create_index_page(&index, cursor.clone(), page).await {
+                    Ok(pairs) => break pairs,
+                    // a write racing the page conflicts with its entries
+                    Err(e) if retries == 0 => return Err(e),
+                    Err(_) => retries -= 1,
+                }
+            };
+            match pairs.last() {
+                Some(last) if pairs.len() == page as usize => cursor = format!("{}\0", last),
+                _ => return Ok(()),
+            }
+        }
+    }
+    /// Indexes up to `limit` keys from `start` on in one transaction,
+    /// replying the keys read
+    async fn create_index_page(
+        &mut self,
+        index: &Index,
+        start: String,
+        limit: u32,
+    ) -> Result<Vec<String>> {
+        self.txn_start().await?;
+        let pairs = self.txn_scan(start, String::new(), limit).await?;
+        let mut keys = Vec::new();
+        for (key, value) in pairs {
+            if !is_index_key(&key) {
+                if let Some(entry) = index.entry_key(&key, &value) {
+                    self.txn_set(entry, key.clone())?;
+                }
+            }
+            keys.push(key);
+        }
+        self.txn_commit().await?;
+        Ok(keys)
+    }
+    /// The keys whose field indexed by `index` is `value`
+    pub async fn index_lookup(&mut self, index: String, value: String) -> Result<Vec<String>> {
+        let start = Index::new(index, String::new()).entry_prefix(&value);
+        // the prefix ends with '/', which '0' follows
+        let end = format!("{}0", &start[..start.len() - 1]);
+        let pairs = self.scan(start, end, 0).await?;
+        Ok(pairs.into_iter().map(|(_, key)| key).collect())
+    }
+    /// Adds the raft node `id` serving at `addr` to the voters, replying
+    /// the voters once the change is committed.
+    pub async fn add_node(&mut self, id: u64, addr: String) -> Result<Vec<NodeInfo>> {
+        let req = AddNodeRequest { id, addr };
+        let reply = self
+            .retry_on_leader(|mut client| {
+                let req = req.clone();
+                async move { client.add_node(Request::new(req)).await }
+            })
+            .await?;
+        Ok(reply.nodes)
+    }
+    /// Hands the leadership of the raft group over to the voter `id`,
+    /// replying once the leader stepped down.
+    pub async fn transfer_leader(&mut self, id: u64) -> Result<()> {
+        let req = TransferLeaderRequest { id };
+        self.retry_on_leader(|mut client| {
+            let req = req.clone();
+            async move { client.transfer_leader(Request::new(req)).await }
+        })
+        .await?;
+        Ok(())
+    }
+    /// Removes the raft node `id` from the voters or learners, replying the
+    /// voters once the change is committed.
+    pub async fn remove_node(&mut self, id: u64) -> Result<Vec<NodeInfo>> {
+        let req = RemoveNodeRequest { id };
+        let reply = self
+            .retry_on_leader(|mut client| {
+                let req = req.clone();
+                async move { client.remove_node(Request::new(req)).await }
+            })
+            .await?;
+        Ok(reply.nodes)
+    }
+    /// Adds the raft node `id` serving at `addr` as a learner, which is
+    /// replicated to without voting, replying the learners once the change
+    /// is committed. `add_node` promotes it to a voter.
+    pub async fn add_learner(&mut self, id: u64, addr: String) -> Result<Vec<NodeInfo>> {
+        let req = AddNodeRequest { id, addr };
+        let reply = self
+            .retry_on_leader(|mut client| {
+                let req = req.clone();
+                async move { client.add_learner(Request::new(req)).await }
+            })
+            .await?;
+        Ok(reply.learners)
+    }
+    /// Sends the request `call` makes to every server in turn until the
+    /// leader takes it, as followers turn down what only a leader serves
+    async fn retry_on_leader<T, F, Fut>(&self, mut call: F) -> Result<T>
+    where
+        F: FnMut(KvRpcClient<Channel>) -> Fut,
+        Fut: Future<Output = std::result::Result<Response<T>, Status>>,
+    {
+        for _retries in 0..self.retries {
+            for client in self.servers.iter() {
+                match tokio::time::timeout(self.timeout, call(client.clone())).await {
+                    Ok(Ok(res)) => return Ok(res.into_inner()),
+                    Ok(Err(e)) if e.code() == Code::PermissionDenied => continue,
+                    Ok(Err(e)) => return Err(KvError::StringError(e.message().to_string())),
+                    Err(e) => {
+                        info!("{}", e);
+                        continue;
+                    }
+                }
+            }
+        }
+        Err(KvError::Unknown)
+    }
+    /// Subscribe to the changes committed to keys with `key_prefix`, replaying
+    /// those committed from `start_ts` on. Each reply carries the resolved ts
+    /// up to which every change has been sent.
+    pub async fn subscribe(
+        &mut self,
+        start_ts: u64,
+        key_prefix: String,
+    ) -> Result<Streaming<SubscribeReply>> {
+        let req = SubscribeRequest {
+            start_ts,
+            key_prefix,
+        };
+        for client in self.servers.iter_mut() {
+            let res = client.subscribe(Request::new(req.clone()));
+            match tokio::time::timeout(self.timeout, res).await {
+                Ok(Ok(res)) => return Ok(res.into_inner()),
+                Ok(Err(e)) if e.code() == Code::PermissionDenied => continue,
+                Ok(Err(e)) => return Err(KvError::StringError(e.to_string())),
+                Err(e) => {
+                    info!("{}", e);
+                    continue;
+                }
+            }
+        }
+        Err(KvError::Unknown)
+    }
+
+    /// Watch the puts and deletes of `key`, or of every key starting with it
+    /// if `prefix` is set, from revision `from_revision` on. A zero revision
+    /// only watches the changes committed from now on.
+    pub async fn watch(
+        &mut self,
+        key: String,
+        prefix: bool,
+        from_revision: u64,
+    ) -> Result<impl Stream<Item = Result<ChangeEvent>>> {
+        let req = WatchRequest {
+            key,
+            prefix,
+            from_revision,
+        };
+        for client in self.servers.iter_mut() {
+            let res = client.watch(Request::new(req.clone()));
+            let mut replies = match tokio::time::timeout(self.timeout, res).await {
+                Ok(Ok(res)) => res.into_inner(),
+                Ok(Err(e)) if e.code() == Code::PermissionDenied => continue,
+                Ok(Err(e)) => return Err(KvError::StringError(e.to_string())),
+                Err(e) => {
+                    info!("{}", e);
+                    continue;
+                }
+            };
+            return Ok(async_stream::stream! {
+                loop {
+                    match replies.message().await {
+                        Ok(Some(reply)) => {
+                            for event in reply.events {
+                                yield Ok(event);
+                            }
+                        }
+                        Ok(None) => break,
+                        Err(e) => {
+                            yield Err(KvError::Rpc(e));
+                            break;
+                        }
+                    }
+                }
+            });
+        }
+        Err(KvError::Unknown)
+    }
+
     /// Send remove command to server, and process the response.
     pub async fn remove(&mut self, key: String) -> Result<()> {
         self.txn_start().await?;
@@ -96,11 +434,19 @@ impl KvsClient {
 }
 
 impl KvsClient {
-    /// Start a transaction
+    /// Start a transaction with snapshot isolation
     pub async fn txn_start(&mut self) -> Result<()> {
-        let ts = self.get_timestamp().await.unwrap();
+        self.txn_start_with(IsolationLevel::default()).await
+    }
+    /// Start a transaction with the given isolation level
+    pub async fn txn_start_with(&mut self, isolation: IsolationLevel) -> Result<()> {
+        // nothing of a transaction left unfinished carries over
+        self.txn_clear();
+        let ts = self.get_timestamp().await?;
         self.ts = Some(ts);
+        self.started = Instant::now();
         self.seq = 0;
+        self.isolation = isolation;
         Ok(())
     }
     /// Set a value
@@ -110,7 +456,9 @@ impl KvsClient {
             value,
             op: WriteOp::Put,
         };
-        self.write_infos.push(info);
+        if !self.txn_buffer(info) {
+            return Err(KvError::TxnTooLarge(self.txn_size_limit));
+        }
         Ok(())
     }
     /// Delete a value
@@ -120,25 +468,250 @@ impl KvsClient {
             value: String::new(),
             op: WriteOp::Delete,
         };
-        self.write_infos.push(info);
+        if !self.txn_buffer(info) {
+            return Err(KvError::TxnTooLarge(self.txn_size_limit));
+        }
         Ok(())
     }
-    /// Get a value
+    /// Buffer a write, flushing a full batch in the background so large
+    /// transactions never hold all of their writes in memory. Returns
+    /// false without buffering if the write exceeds the size limit
+    fn txn_buffer(&mut self, info: WriteInfo) -> bool {
+        let size = info.key.len() + info.value.len();
+        if self.txn_size + size > self.txn_size_limit {
+            return false;
+        }
+        self.txn_size += size;
+        if self.isolation == IsolationLevel::Serializable {
+            self.written_keys.insert(info.key.clone());
+        }
+        match &self.primary {
+            Some(primary) if primary.key != info.key => {}
+            _ => self.primary = Some(info.clone()),
+        }
+        // a later write of the same key replaces the earlier one
+        if let Some(old) = self.write_buffer.insert(info.key.clone(), info) {
+            self.txn_size -= old.key.len() + old.value.len();
+        }
+        // flushing needs a runtime to run on
+        if self.write_buffer.len() >= self.batch_size && Handle::try_current().is_ok() {
+            self.txn_flush();
+        }
+        true
+    }
+    /// Prewrite the buffered writes after the batches flushed before
+    fn txn_flush(&mut self) {
+        let primary = self.primary.as_ref().unwrap().key.clone();
+        let start_ts = self.ts.unwrap();
+        self.seq += 1;
+        let req = BatchPrewriteRequest {
+            mutations: std::mem::take(&mut self.write_buffer)
+                .into_values()
+                .map(Mutation::from)
+                .collect(),
+            primary: primary.clone(),
+            ts: start_ts,
+            seq: self.seq,
+            lock_ttl: (self.started.elapsed() + self.lock_ttl).as_millis() as u64,
+        };
+        self.streamed = true;
+        let previous = self.flushing.take();
+        let (servers, retries, timeout) = (self.servers.clone(), self.retries, self.timeout);
+        self.flushing = Some(tokio::spawn(async move {
+            if let Some(previous) = previous {
+                previous.await.unwrap_or(Err(KvError::Unknown))?;
+            }
+            // index entries are left to the resolve lock after the primary
+            txn_batch_prewrite(servers, req, retries, timeout)
+                .await
+                .map(|_| ())
+        }));
+        // keep the primary lock alive while writes are still being added
+        if self.heart_beat.is_none() {
+            self.heart_beat = Some(tokio::spawn(txn_heart_beat(
+                self.servers.clone(),
+                primary,
+                start_ts,
+                self.started,
+                self.lock_ttl,
+                self.timeout,
+            )));
+        }
+    }
+    /// Get a value, reading the writes of this transaction first, resolving
+    /// locks of finished transactions and backing off exponentially on locks
+    /// of running ones
     pub async fn txn_get(&mut self, key: String) -> Result<String> {
+        match self.write_buffer.get(&key) {
+            Some(info) if info.op == WriteOp::Put => return Ok(info.value.clone()),
+            Some(info) if info.op == WriteOp::Delete => return Err(KvError::KeyNotFound),
+            _ => {}
+        }
+        self.txn_wait_flushed().await?;
+        let start_ts = self.ts.unwrap();
+        let ts = self.txn_read_ts().await?;
+        if self.isolation == IsolationLevel::Serializable {
+            self.read_keys.insert(key.clone());
+        }
+        let mut backoff = Backoff::new();
+        loop {
+            let reply = self.txn_get_at(key.clone(), ts).await?;
+            let lock = match reply.lock {
+                Some(lock) => lock,
+                None => return Ok(reply.message),
+            };
+            // flushed by this transaction, servers read it at the start ts
+            if lock.lock_ts == start_ts {
+                let reply = self.txn_get_at(key, start_ts).await?;
+                return Ok(reply.message);
+            }
+            if !self.txn_resolve_lock(lock).await? && !backoff.wait().await {
+                return Err(KvError::StringError(format!("Key {} is locked", key)));
+            }
+        }
+    }
+    /// Get the values of `keys` in one request, reading the writes of this
+    /// transaction first. Missing keys are left out of the result
+    pub async fn txn_batch_get(&mut self, keys: Vec<String>) -> Result<Vec<(String, String)>> {
+        let mut found = HashMap::new();
+        let mut remote = Vec::new();
+        for key in keys.iter() {
+            match self.write_buffer.get(key) {
+                Some(info) if info.op == WriteOp::Put => {
+                    found.insert(key.clone(), info.value.clone());
+                }
+                Some(info) if info.op == WriteOp::Delete => {}
+                _ => remote.push(key.clone()),
+            }
+        }
+        self.txn_wait_flushed().await?;
+        let ts = self.txn_read_ts().await?;
+        if self.isolation == IsolationLevel::Serializable {
+            self.read_keys.extend(remote.iter().cloned());
+        }
+        let mut backoff = Backoff::new();
+        while !remote.is_empty() {
+            let reply = self.txn_send_batch_get(remote, ts).await?;
+            found.extend(reply.pairs.into_iter().map(|pair| (pair.key, pair.value)));
+            // retry only the locked keys, resolving each txn once
+            remote = reply.locks.iter().map(|lock| lock.key.clone()).collect();
+            let mut gone: HashMap<u64, bool> = HashMap::new();
+            let mut blocked = false;
+            for lock in reply.locks {
+                let lock_ts = lock.lock_ts;
+                let resolved = match gone.get(&lock_ts) {
+                    Some(resolved) => *resolved,
+                    None => self.txn_resolve_lock(lock).await?,
+                };
+                gone.insert(lock_ts, resolved);
+                blocked |= !resolved;
+            }
+            if blocked && !backoff.wait().await {
+                return Err(KvError::StringError(format!("Key {} is locked", remote[0])));
+            }
+        }
+        Ok(keys
+            .into_iter()
+            .filter_map(|key| found.get(&key).cloned().map(|value| (key, value)))
+            .collect())
+    }
+    /// Scan up to `limit` pairs with keys in `[start, end)` in key order,
+    /// reading the writes of this transaction first. An empty `end` scans
+    /// to the last key and a zero `limit` returns every pair
+    pub async fn txn_scan(
+        &mut self,
+        start: String,
+        end: String,
+        limit: u32,
+    ) -> Result<Vec<(String, String)>> {
+        self.txn_wait_flushed().await?;
+        let ts = self.txn_read_ts().await?;
+        let buffered: Vec<WriteInfo> = self
+            .write_buffer
+            .range(start.clone()..)
+            .map(|(_, info)| info)
+            .filter(|info| end.is_empty() || info.key < end)
+            .filter(|info| info.op != WriteOp::Lock)
+            .cloned()
+            .collect();
+        // each buffered write hides at most one pair from servers
+        let remote_limit = match limit {
+            0 => 0,
+            limit => limit + buffered.len() as u32,
+        };
+        let mut pairs = BTreeMap::new();
+        let mut cursor = start;
+        let mut backoff = Backoff::new();
+        loop {
+            let want = remote_limit.saturating_sub(pairs.len() as u32);
+            let reply = self.txn_send_scan(cursor, end.clone(), want, ts).await?;
+            pairs.extend(reply.pairs.into_iter().map(|pair| (pair.key, pair.value)));
+            let lock = match reply.lock {
+                Some(lock) => lock,
+                None => break,
+            };
+            // go on from the locked key once it is resolved
+            cursor = lock.key.clone();
+            if !self.txn_resolve_lock(lock).await? && !backoff.wait().await {
+                return Err(KvError::StringError(format!("Key {} is locked", cursor)));
+            }
+        }
+        for info in buffered {
+            match info.op {
+                WriteOp::Delete => pairs.remove(&info.key),
+                _ => pairs.insert(info.key, info.value),
+            };
+        }
+        let mut pairs: Vec<(String, String)> = pairs.into_iter().collect();
+        if limit != 0 {
+            pairs.truncate(limit as usize);
+        }
+        if self.isolation == IsolationLevel::Serializable {
+            self.read_keys
+                .extend(pairs.iter().map(|(key, _)| key.clone()));
+        }
+        Ok(pairs)
+    }
+    /// Wait until the batches flushed before are prewritten, as they may
+    /// hold the keys about to be read
+    async fn txn_wait_flushed(&mut self) -> Result<()> {
+        if let Some(flushing) = self.flushing.take() {
+            if let Err(e) = flushing.await.unwrap_or(Err(KvError::Unknown)) {
+                // keep the failure for commit, which must roll back
+                let msg = e.to_string();
+                self.flushing = Some(tokio::spawn(async { Err(KvError::StringError(msg)) }));
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+    /// The ts the next read of this transaction is made at
+    async fn txn_read_ts(&mut self) -> Result<u64> {
+        match self.isolation {
+            IsolationLevel::ReadCommitted => self.get_timestamp().await,
+            _ => Ok(self.ts.unwrap()),
+        }
+    }
+    /// The servers to send a read to in turn, starting one further on than
+    /// the read before, as any of them serves reads
+    fn read_order(&mut self) -> impl Iterator<Item = usize> {
+        let len = self.servers.len();
+        let first = self.next_reader;
+        self.next_reader = (first + 1) % len.max(1);
+        (0..len).map(move |i| (first + i) % len)
+    }
+    async fn txn_get_at(&mut self, key: String, ts: u64) -> Result<GetReply> {
         self.seq += 1;
         let req = GetRequest {
             key,
-            ts: self.ts.unwrap(),
+            ts,
             seq: self.seq,
         };
-        for client in self.servers.iter_mut() {
-            // let call = |req| client.txn_get(Request::new(req.clone()));
-            let res = client.txn_get(Request::new(req.clone()));
+        for server in self.read_order() {
+            let res = self.servers[server].txn_get(Request::new(req.clone()));
             match tokio::time::timeout(self.timeout, res).await {
                 Ok(Ok(res)) => {
-                    let res = res.into_inner();
-                    // println!("{}", res.message);
-                    return Ok(res.message);
+                    return Ok(res.into_inner());
                 }
                 Ok(Err(e)) if e.code() == Code::PermissionDenied => {
                     continue;
@@ -146,6 +719,9 @@ impl KvsClient {
                 Ok(Err(e)) if e.code() == Code::NotFound => {
                     return Err(KvError::KeyNotFound);
                 }
+                Ok(Err(e)) if e.code() == Code::OutOfRange => {
+                    return Err(KvError::VersionCompacted(ts));
+                }
                 Ok(Err(e)) => return Err(KvError::StringError(e.to_string())),
                 Err(e) => {
                     info!("{}", e.to_string());
@@ -156,40 +732,186 @@ impl KvsClient {
 
         Err(KvError::Unknown)
     }
-    /// prewrite
-    async fn txn_prewrite(&mut self, info: WriteInfo, primary: String) -> Result<()> {
+    async fn txn_send_batch_get(&mut self, keys: Vec<String>, ts: u64) -> Result<BatchGetReply> {
         self.seq += 1;
-        let req = PrewriteRequest {
-            key: info.key,
-            value: info.value,
-            op: info.op.into(),
-            primary,
+        let req = BatchGetRequest {
+            keys,
+            ts,
+            seq: self.seq,
+            start_ts: self.ts.unwrap(),
+        };
+        for server in self.read_order() {
+            let res = self.servers[server].txn_batch_get(Request::new(req.clone()));
+            match tokio::time::timeout(self.timeout, res).await {
+                Ok(Ok(res)) => return Ok(res.into_inner()),
+                Ok(Err(e)) if e.code() == Code::PermissionDenied => continue,
+                Ok(Err(e)) if e.code() == Code::OutOfRange => {
+                    return Err(KvError::VersionCompacted(ts));
+                }
+                Ok(Err(e)) => return Err(KvError::StringError(e.to_string())),
+                Err(e) => {
+                    info!("{}", e);
+                    continue;
+                }
+            }
+        }
+        Err(KvError::Unknown)
+    }
+    async fn txn_send_scan(
+        &mut self,
+        start: String,
+        end: String,
+        limit: u32,
+        ts: u64,
+    ) -> Result<ScanReply> {
+        self.seq += 1;
+        let req = ScanRequest {
+            start,
+            end,
+            limit,
+            ts,
+            seq: self.seq,
+            start_ts: self.ts.unwrap(),
+        };
+        for server in self.read_order() {
+            let res = self.servers[server].txn_scan(Request::new(req.clone()));
+            match tokio::time::timeout(self.timeout, res).await {
+                Ok(Ok(res)) => return Ok(res.into_inner()),
+                Ok(Err(e)) if e.code() == Code::PermissionDenied => continue,
+                Ok(Err(e)) if e.code() == Code::OutOfRange => {
+                    return Err(KvError::VersionCompacted(ts));
+                }
+                Ok(Err(e)) => return Err(KvError::StringError(e.to_string())),
+                Err(e) => {
+                    info!("{}", e);
+                    continue;
+                }
+            }
+        }
+        Err(KvError::Unknown)
+    }
+    /// Check the txn owning `lock` through its primary and, once it is
+    /// finished, commit or roll back all of its locks. Returns whether the
+    /// lock is gone.
+    async fn txn_resolve_lock(&mut self, lock: LockInfo) -> Result<bool> {
+        self.seq += 1;
+        let req = CheckTxnStatusRequest {
+            primary: lock.primary,
+            lock_ts: lock.lock_ts,
+            lock_ttl: lock.lock_ttl,
+            current_ts: 0,
             ts: self.ts.unwrap(),
             seq: self.seq,
         };
-        info!(
-            "try to prewrite {} : {} , primary: {}, ts: {}, seq: {}",
-            req.key, req.value, req.primary, req.ts, req.seq
-        );
+        let status = self.txn_check_txn_status(req).await?;
+        let commit_ts = match TxnStatus::from_i32(status.status) {
+            Some(TxnStatus::Committed) => status.commit_ts,
+            Some(TxnStatus::RolledBack) => 0,
+            _ => return Ok(false),
+        };
+        self.txn_send_resolve_lock(lock.lock_ts, commit_ts).await?;
+        Ok(true)
+    }
+    /// Commit at `commit_ts`, or roll back if 0, every lock of the txn
+    /// started at `start_ts`, returns how many locks were resolved
+    async fn txn_send_resolve_lock(&mut self, start_ts: u64, commit_ts: u64) -> Result<u64> {
+        self.seq += 1;
+        let req = ResolveLockRequest {
+            start_ts,
+            commit_ts,
+            ts: self.ts.unwrap_or(start_ts),
+            seq: self.seq,
+        };
         for _retries in 0..self.retries {
             for client in self.servers.iter_mut() {
-                let res = client.txn_prewrite(Request::new(req.clone()));
+                let res = client.txn_resolve_lock(Request::new(req.clone()));
                 match tokio::time::timeout(self.timeout, res).await {
                     Ok(Ok(res)) => {
-                        let res = res.into_inner();
-                        if res.ok {
-                            info!("Prewrite ok");
-                            return Ok(());
-                        } else {
-                            return Err(KvError::Unknown);
-                        }
+                        let resolved = res.into_inner().resolved;
+                        info!("resolve {} locks", resolved);
+                        return Ok(resolved);
+                    }
+                    Ok(Err(e)) if e.code() == Code::PermissionDenied => continue,
+                    Ok(Err(e)) => return Err(KvError::StringError(e.to_string())),
+                    Err(e) => {
+                        info!("{}", e);
+                        continue;
+                    }
+                }
+            }
+        }
+        Err(KvError::Unknown)
+    }
+    async fn txn_check_txn_status(
+        &mut self,
+        req: CheckTxnStatusRequest,
+    ) -> Result<CheckTxnStatusReply> {
+        for _retries in 0..self.retries {
+            for client in self.servers.iter_mut() {
+                let res = client.txn_check_txn_status(Request::new(req.clone()));
+                match tokio::time::timeout(self.timeout, res).await {
+                    Ok(Ok(res)) => return Ok(res.into_inner()),
+                    Ok(Err(e)) if e.code() == Code::PermissionDenied => continue,
+                    Ok(Err(e)) => return Err(KvError::StringError(e.to_string())),
+                    Err(e) => {
+                        info!("{}", e);
+                        continue;
+                    }
+                }
+            }
+        }
+        Err(KvError::Unknown)
+    }
+    /// Abandon this transaction, removing every lock it left on servers
+    pub async fn txn_rollback(&mut self) -> Result<()> {
+        let start_ts = match self.ts {
+            Some(ts) => ts,
+            None => return Ok(()),
+        };
+        let streamed = self.streamed;
+        // wait for the flushed batches, so none of their locks land after the rollback
+        if let Some(flushing) = self.flushing.take() {
+            flushing.await.ok();
+        }
+        let mut keys: Vec<String> = self.write_buffer.keys().cloned().collect();
+        let primary = self.primary.as_ref().map(|info| info.key.clone());
+        match primary {
+            Some(primary) if streamed && !self.write_buffer.contains_key(&primary) => {
+                keys.push(primary)
+            }
+            _ => {}
+        }
+        self.seq += 1;
+        let req = RollbackRequest {
+            keys,
+            start_ts,
+            seq: self.seq,
+        };
+        self.txn_clear();
+        if req.keys.is_empty() {
+            return Ok(());
+        }
+        self.txn_send_rollback(req).await?;
+        // index entries locked by servers and the keys of flushed batches
+        // are only known to servers
+        self.txn_send_resolve_lock(start_ts, 0).await?;
+        Ok(())
+    }
+    async fn txn_send_rollback(&mut self, req: RollbackRequest) -> Result<()> {
+        for _retries in 0..self.retries {
+            for client in self.servers.iter_mut() {
+                let res = client.txn_rollback(Request::new(req.clone()));
+                match tokio::time::timeout(self.timeout, res).await {
+                    Ok(Ok(_)) => {
+                        info!("Rollback ok");
+                        return Ok(());
                     }
                     Ok(Err(e)) if e.code() == Code::PermissionDenied => {
                         continue;
                     }
                     Ok(Err(e)) => return Err(KvError::StringError(e.to_string())),
                     Err(e) => {
-                        info!("{}", e.to_string());
+                        info!("{}", e);
                         continue;
                     }
                 }
@@ -197,33 +919,177 @@ impl KvsClient {
         }
         Err(KvError::Unknown)
     }
+    /// Forget the buffered writes and the timestamp of this transaction
+    fn txn_clear(&mut self) {
+        self.write_buffer.clear();
+        self.read_keys.clear();
+        self.written_keys.clear();
+        self.primary = None;
+        self.txn_size = 0;
+        self.streamed = false;
+        if let Some(flushing) = self.flushing.take() {
+            flushing.abort();
+        }
+        if let Some(heart_beat) = self.heart_beat.take() {
+            heart_beat.abort();
+        }
+        self.ts = None;
+    }
+    /// The buffered writes, the primary first
+    fn txn_writes(&self) -> Vec<WriteInfo> {
+        let primary = self.primary.as_ref().unwrap();
+        let others = self
+            .write_buffer
+            .values()
+            .filter(|info| info.key != primary.key);
+        std::iter::once(primary).chain(others).cloned().collect()
+    }
     /// Commit this transaction
     pub async fn txn_commit(&mut self) -> Result<()> {
-        let primary_write = self.write_infos.first().unwrap().to_owned();
+        // lock the keys only read, so a txn writing them concurrently conflicts with this one
+        for key in self.read_keys.difference(&self.written_keys) {
+            let info = WriteInfo {
+                key: key.clone(),
+                value: String::new(),
+                op: WriteOp::Lock,
+            };
+            self.primary.get_or_insert_with(|| info.clone());
+            self.write_buffer.insert(key.clone(), info);
+        }
+        if self.write_buffer.is_empty() && !self.streamed {
+            self.txn_clear();
+            return Ok(());
+        }
+        let primary_write = self.primary.clone().unwrap();
         let primary = primary_write.key.clone();
-        for info in self.write_infos.clone().into_iter() {
-            self.txn_prewrite(info, primary.clone()).await?;
+        let start_ts = self.ts.unwrap();
+        let mut index_mutations = Vec::new();
+        let prewritten = if self.streamed {
+            // the rest goes after the batches flushed before
+            if !self.write_buffer.is_empty() {
+                self.txn_flush();
+            }
+            match self.flushing.take() {
+                Some(flushing) => flushing.await.unwrap_or(Err(KvError::Unknown)),
+                None => Ok(()),
+            }
+        } else {
+            let lock_ttl = (self.started.elapsed() + self.lock_ttl).as_millis() as u64;
+            let mut seq = self.seq;
+            let requests: Vec<BatchPrewriteRequest> = self
+                .txn_writes()
+                .chunks(self.batch_size)
+                .map(|infos| {
+                    seq += 1;
+                    BatchPrewriteRequest {
+                        mutations: infos.iter().cloned().map(Mutation::from).collect(),
+                        primary: primary.clone(),
+                        ts: start_ts,
+                        seq,
+                        lock_ttl,
+                    }
+                })
+                .collect();
+            self.seq = seq;
+            let prewrites = requests.into_iter().map(|req| {
+                txn_batch_prewrite(self.servers.clone(), req, self.retries, self.timeout)
+            });
+            futures::future::try_join_all(prewrites)
+                .await
+                .map(|replies| index_mutations = replies.into_iter().flatten().collect())
+        };
+        if let Err(e) = prewritten {
+            // clean up the locks of the batches that did succeed
+            self.txn_rollback().await.unwrap_or(());
+            return Err(e);
+        }
+        // keep the primary lock alive until the primary is committed
+        let heart_beat = match self.heart_beat.take() {
+            Some(heart_beat) => heart_beat,
+            None => tokio::spawn(txn_heart_beat(
+                self.servers.clone(),
+                primary.clone(),
+                start_ts,
+                self.started,
+                self.lock_ttl,
+                self.timeout,
+            )),
+        };
+        let committed = self.txn_commit_primary(primary_write, start_ts).await;
+        heart_beat.abort();
+        let commit_ts = match committed {
+            Ok(commit_ts) => commit_ts,
+            // the primary lock is gone, so the whole transaction is dead
+            Err(KvError::Rpc(e)) if e.code() == Code::Aborted => {
+                self.txn_rollback().await.unwrap_or(());
+                return Err(KvError::Rpc(e));
+            }
+            // the primary may or may not be committed, leave it to readers
+            Err(e) => {
+                self.txn_clear();
+                return Err(e);
+            }
+        };
+        // secondaries can be resolved by readers once the primary is committed
+        if self.streamed {
+            self.txn_send_resolve_lock(start_ts, commit_ts)
+                .await
+                .unwrap_or(0);
+            self.txn_clear();
+            return Ok(());
         }
+        let mut seq = self.seq;
+        // the index entries prewritten by the servers are secondaries too
+        let secondaries: Vec<Mutation> = self.txn_writes()[1..]
+            .iter()
+            .cloned()
+            .map(Mutation::from)
+            .chain(index_mutations)
+            .collect();
+        let requests: Vec<BatchCommitRequest> = secondaries
+            .chunks(self.batch_size)
+            .map(|mutations| {
+                seq += 1;
+                BatchCommitRequest {
+                    mutations: mutations.to_vec(),
+                    primary: primary.clone(),
+                    start_ts,
+                    commit_ts,
+                    seq,
+                }
+            })
+            .collect();
+        self.seq = seq;
+        let commits = requests
+            .into_iter()
+            .map(|req| txn_batch_commit(self.servers.clone(), req, self.timeout));
+        futures::future::join_all(commits).await;
+        self.txn_clear();
+        Ok(())
+    }
+}
+
+impl KvsClient {
+    /// commit the primary key and return the commit ts
+    async fn txn_commit_primary(&mut self, primary_write: WriteInfo, start_ts: u64) -> Result<u64> {
         let commit_ts = self.get_timestamp().await?;
         self.seq = 1;
         let primary_request = CommitRequest {
             is_primary: true,
-            primary: primary.clone(),
-            key: primary.clone(),
+            primary: primary_write.key.clone(),
+            key: primary_write.key,
             op: primary_write.op.into(),
-            start_ts: self.ts.unwrap(),
+            start_ts,
             commit_ts,
             seq: self.seq,
         };
-        let mut ok = false;
         for client in self.servers.iter_mut() {
             let res = client.txn_commit(Request::new(primary_request.clone()));
             match tokio::time::timeout(self.timeout, res).await {
                 Ok(Ok(res)) => {
                     let res = res.into_inner();
                     if res.ok {
-                        ok = true;
-                        break;
+                        return Ok(commit_ts);
                     } else {
                         return Err(KvError::Unknown);
                     }
@@ -231,6 +1097,7 @@ impl KvsClient {
                 Ok(Err(e)) if e.code() == Code::PermissionDenied || e.code() == Code::DataLoss => {
                     continue;
                 }
+                Ok(Err(e)) if e.code() == Code::Aborted => return Err(KvError::Rpc(e)),
                 Ok(Err(e)) => return Err(KvError::StringError(e.to_string())),
                 Err(e) => {
                     info!("{}", e.to_string());
@@ -238,33 +1105,133 @@ impl KvsClient {
                 }
             }
         }
+        Err(KvError::Unknown)
+    }
+}
+
+/// Exponential backoff of a reader waiting on the locks of running transactions
+struct Backoff {
+    delay: Duration,
+    waited: Duration,
+}
+
+impl Backoff {
+    fn new() -> Self {
+        Backoff {
+            delay: Duration::from_millis(BACKOFF_BASE_MS),
+            waited: Duration::from_millis(0),
+        }
+    }
+    /// Sleep for the next delay, or return false once the reader waited too long
+    async fn wait(&mut self) -> bool {
+        if self.waited >= Duration::from_millis(MAX_BACKOFF_WAIT_MS) {
+            return false;
+        }
+        tokio::time::sleep(self.delay).await;
+        self.waited += self.delay;
+        self.delay = (self.delay * 2).min(Duration::from_millis(BACKOFF_MAX_MS));
+        true
+    }
+}
 
-        if !ok {
-            return Err(KvError::Unknown);
-        }
-        for info in self.write_infos.clone().into_iter().skip(1) {
-            self.seq += 1;
-            let request = CommitRequest {
-                is_primary: false,
-                primary: primary.clone(),
-                key: info.key.clone(),
-                op: info.op.into(),
-                start_ts: self.ts.unwrap(),
-                commit_ts,
-                seq: self.seq,
-            };
-            for client in self.servers.iter_mut() {
-                let res = client.txn_commit(Request::new(request.clone()));
-                match tokio::time::timeout(self.timeout, res).await {
-                    Ok(Ok(_)) => {
-                        break;
+impl From<WriteInfo> for Mutation {
+    fn from(info: WriteInfo) -> Self {
+        Mutation {
+            key: info.key,
+            value: info.value,
+            op: info.op.into(),
+        }
+    }
+}
+
+/// prewrite a batch of mutations, trying every server until one accepts it
+async fn txn_batch_prewrite(
+    mut servers: Vec<KvRpcClient<Channel>>,
+    req: BatchPrewriteRequest,
+    retries: usize,
+    timeout: Duration,
+) -> Result<Vec<Mutation>> {
+    info!(
+        "try to prewrite {} keys, primary: {}, ts: {}, seq: {}",
+        req.mutations.len(),
+        req.primary,
+        req.ts,
+        req.seq
+    );
+    for _retries in 0..retries {
+        for client in servers.iter_mut() {
+            let res = client.txn_batch_prewrite(Request::new(req.clone()));
+            match tokio::time::timeout(timeout, res).await {
+                Ok(Ok(res)) => {
+                    let res = res.into_inner();
+                    if res.ok {
+                        info!("Prewrite ok");
+                        return Ok(res.index_mutations);
+                    } else {
+                        return Err(KvError::Unknown);
                     }
-                    Ok(Err(_e)) => continue,
-                    Err(_e) => continue,
+                }
+                Ok(Err(e)) if e.code() == Code::PermissionDenied => {
+                    continue;
+                }
+                Ok(Err(e)) => return Err(KvError::StringError(e.to_string())),
+                Err(e) => {
+                    info!("{}", e.to_string());
+                    continue;
                 }
             }
         }
-        Ok(())
+    }
+    Err(KvError::Unknown)
+}
+
+/// commit a batch of secondaries, trying every server until one accepts it
+async fn txn_batch_commit(
+    mut servers: Vec<KvRpcClient<Channel>>,
+    req: BatchCommitRequest,
+    timeout: Duration,
+) {
+    for client in servers.iter_mut() {
+        let res = client.txn_batch_commit(Request::new(req.clone()));
+        match tokio::time::timeout(timeout, res).await {
+            Ok(Ok(_)) => {
+                break;
+            }
+            Ok(Err(_e)) => continue,
+            Err(_e) => continue,
+        }
+    }
+}
+
+/// extend the primary lock every half ttl until the lock is gone
+async fn txn_heart_beat(
+    mut servers: Vec<KvRpcClient<Channel>>,
+    primary: String,
+    start_ts: u64,
+    started: Instant,
+    lock_ttl: Duration,
+    timeout: Duration,
+) {
+    // heartbeats have seqs of their own, servers never check them for duplicates
+    let mut seq = 0;
+    loop {
+        tokio::time::sleep(lock_ttl / 2).await;
+        seq += 1;
+        let req = TxnHeartBeatRequest {
+            primary: primary.clone(),
+            start_ts,
+            advise_lock_ttl: (started.elapsed() + lock_ttl).as_millis() as u64,
+            seq,
+        };
+        for client in servers.iter_mut() {
+            let res = client.txn_heart_beat(Request::new(req.clone()));
+            match tokio::time::timeout(timeout, res).await {
+                Ok(Ok(_)) => break,
+                Ok(Err(e)) if e.code() == Code::Aborted => return,
+                Ok(Err(_e)) => continue,
+                Err(_e) => continue,
+            }
+        }
     }
 }
 
@@ -275,6 +1242,9 @@ pub struct KvsClientBuilder {
     info: Vec<SocketAddr>,
     retries: usize,
     timeout: Duration,
+    batch_size: usize,
+    lock_ttl: Duration,
+    txn_size_limit: usize,
 }
 
 impl Default for KvsClientBuilder {
@@ -285,6 +1255,9 @@ impl Default for KvsClientBuilder {
             info: Vec::new(),
             retries: 3,
             timeout: Duration::from_secs(3),
+            batch_size: 64,
+            lock_ttl: Duration::from_millis(DEFAULT_LOCK_TTL),
+            txn_size_limit: DEFAULT_TXN_SIZE_LIMIT,
         }
     }
 }
@@ -320,6 +1293,22 @@ impl KvsClientBuilder {
         self.timeout = timeout;
         self
     }
+    /// set how many mutations are sent in one prewrite or commit request,
+    /// at least one
+    pub fn set_batch_size(mut self, batch_size: usize) -> KvsClientBuilder {
+        self.batch_size = batch_size.max(1);
+        self
+    }
+    /// set how long locks of this client live without a heartbeat
+    pub fn set_lock_ttl(mut self, lock_ttl: Duration) -> KvsClientBuilder {
+        self.lock_ttl = lock_ttl;
+        self
+    }
+    /// set how many bytes of keys and values one transaction may write
+    pub fn set_txn_size_limit(mut self, txn_size_limit: usize) -> KvsClientBuilder {
+        self.txn_size_limit = txn_size_limit;
+        self
+    }
     /// build the client
     pub fn build(self) -> KvsClient {
         let servers: Vec<KvRpcClient<Channel>> = self
@@ -333,12 +1322,25 @@ impl KvsClientBuilder {
         KvsClient {
             name: self.name,
             ts: None,
+            started: Instant::now(),
             seq: self.seq,
             num_servers: servers.len(),
+            next_reader: rand::random::<usize>() % servers.len().max(1),
             servers,
             retries: self.retries,
             timeout: self.timeout,
-            write_infos: Vec::new(),
+            batch_size: self.batch_size,
+            lock_ttl: self.lock_ttl,
+            isolation: IsolationLevel::default(),
+            read_keys: HashSet::new(),
+            written_keys: HashSet::new(),
+            write_buffer: BTreeMap::new(),
+            primary: None,
+            txn_size: 0,
+            txn_size_limit: self.txn_size_limit,
+            streamed: false,
+            flushing: None,
+            heart_beat: None,
         }
     }
 }
@@ -1,3 +1,3 @@
 mod client;
 
-pub use client::{KvsClient, KvsClientBuilder};
+pub use client::{IsolationLevel, KvsClient, KvsClientBuilder};
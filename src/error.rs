@@ -27,6 +27,12 @@ pub enum KvError {
     /// Unknown Error
     #[error("Not Leader")]
     NotLeader,
+    /// Transaction writes more than the size limit
+    #[error("Transaction too large: exceeds the limit of {0} bytes")]
+    TxnTooLarge(usize),
+    /// The versions read at this ts are compacted by GC
+    #[error("Version compacted: ts {0} is below the GC safe point")]
+    VersionCompacted(u64),
     /// Unknown Error
     #[error("Unknown Error")]
     Unknown,
@@ -45,6 +51,8 @@ impl From<KvError> for Status {
             KvError::ParserError(e) => Status::internal(e.to_string()),
             KvError::StringError(e) => Status::internal(e.to_string()),
             KvError::NotLeader => Status::permission_denied("Not Leader"),
+            KvError::TxnTooLarge(_) => Status::resource_exhausted(err.to_string()),
+            KvError::VersionCompacted(_) => Status::out_of_range(err.to_string()),
             KvError::Unknown => Status::unknown("Unknown Error"),
         }
     }
@@ -71,6 +79,18 @@ pub enum KvRpcError {
     /// Unknown Error
     #[error("Abort: {0}")]
     Abort(String),
+    /// The versions read at this ts are compacted by GC
+    #[error("Version compacted: ts {0} is below the GC safe point {1}")]
+    VersionCompacted(u64, u64),
+    /// The raft membership cannot be changed as asked
+    #[error("Membership change: {0}")]
+    ConfChange(String),
+    /// The leadership cannot be transferred as asked
+    #[error("Leader transfer: {0}")]
+    LeaderTransfer(String),
+    /// The request carries a field this server does not understand
+    #[error("Invalid argument: {0}")]
+    InvalidArgument(String),
     /// Unknown Error
     #[error("Error: {0}")]
     Unknown(String),
@@ -85,6 +105,10 @@ impl From<KvRpcError> for Status {
             KvRpcError::Timeout => Status::deadline_exceeded("Timeout"),
             KvRpcError::Recv => Status::cancelled("Recv Error"),
             KvRpcError::Abort(e) => Status::aborted(e),
+            KvRpcError::VersionCompacted(..) => Status::out_of_range(err.to_string()),
+            KvRpcError::ConfChange(e) => Status::failed_precondition(e),
+            KvRpcError::LeaderTransfer(e) => Status::failed_precondition(e),
+            KvRpcError::InvalidArgument(e) => Status::invalid_argument(e),
             KvRpcError::Unknown(e) => Status::unknown(e),
         }
     }
@@ -20,25 +20,29 @@ mod server;
 pub mod thread_pool;
 
 pub use backend::{EngineKind, KvSled, KvStore, KvsEngine};
-pub use client::{KvsClient, KvsClientBuilder};
+pub use client::{IsolationLevel, KvsClient, KvsClientBuilder};
 pub use error::{KvError, KvRpcError, Result};
-pub use raft::{FilePersister, KvRaftNode, Persister, RaftNode};
+pub use raft::{FilePersister, KvRaftNode, Persister, RaftNode, SnapshotPolicy, Staging};
 // #[allow(missing_docs)]
 // pub(crate) use rpc::kvs_service::*;
 // #[allow(missing_docs)]
 // pub(crate) use rpc::raft_service::*;
-pub use percolator::{DataValue, Key, LockValue, MultiStore, TimestampOracle, WriteValue};
+pub use percolator::{
+    DataValue, Key, LockValue, MultiStore, TimestampOracle, TxnState, WriteValue,
+};
 pub use server::{KvsServer, KvsServerBuilder};
 
 /// preclude
 pub mod preclude {
     pub use crate::backend::{EngineKind, KvSled, KvStore, KvsEngine};
-    pub use crate::client::{KvsClient, KvsClientBuilder};
+    pub use crate::client::{IsolationLevel, KvsClient, KvsClientBuilder};
     pub use crate::error::{KvError, Result};
     pub use crate::percolator::{
-        DataValue, Key, LockValue, MultiStore, TimestampOracle, WriteValue,
+        DataValue, Key, LockValue, MultiStore, TimestampOracle, TxnState, WriteValue,
+    };
+    pub use crate::raft::{
+        FilePersister, KvRaftNode, Persister, RaftNode, SnapshotPolicy, Staging,
     };
-    pub use crate::raft::{FilePersister, KvRaftNode, Persister, RaftNode};
     #[allow(missing_docs)]
     pub use crate::rpc::kvs_service::*;
     #[allow(missing_docs)]
@@ -0,0 +1,321 @@
+use std::{
+    collections::BTreeMap,
+    ops::Bound::*,
+    sync::{Arc, Mutex},
+};
+
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+
+use super::*;
+use crate::preclude::*;
+
+/// How many times a replay is scanned without the feed locked before it is
+/// scanned holding back new changes
+const REPLAY_TRIES: usize = 3;
+
+/// Publishes the changes committed to a MultiStore in commit ts order.
+///
+/// A change is held back until the resolved ts passes its commit ts, that
+/// is once no lock left could still commit at or before it.
+#[derive(Clone, Default)]
+pub struct ChangeFeed {
+    inner: Arc<Mutex<FeedInner>>,
+}
+
+#[derive(Default)]
+struct FeedInner {
+    // changes committed after resolved_ts, keyed by commit ts and key
+    pending: BTreeMap<(u64, String), ChangeEvent>,
+    resolved_ts: u64,
+    max_commit_ts: u64,
+    // changes recorded so far, for a replay scanned without the feed locked
+    // to tell whether it missed one
+    recorded: u64,
+    subscribers: Vec<Subscriber>,
+}
+
+struct Subscriber {
+    start_ts: u64,
+    key: String,
+    // follow every key starting with key instead of key alone
+    prefix: bool,
+    sender: UnboundedSender<SubscribeReply>,
+}
+
+impl Subscriber {
+    fn wants(&self, event: &ChangeEvent) -> bool {
+        let key_matched = match self.prefix {
+            true => event.key.starts_with(&self.key),
+            false => event.key == self.key,
+        };
+        event.commit_ts >= self.start_ts && key_matched
+    }
+    /// Returns false once the subscriber is gone
+    fn send(&self, events: &[ChangeEvent], resolved_ts: u64) -> bool {
+        let events = events
+            .iter()
+            .filter(|event| self.wants(event))
+            .cloned()
+            .collect();
+        self.sender
+            .send(SubscribeReply {
+                events,
+                resolved_ts,
+            })
+            .is_ok()
+    }
+}
+
+impl FeedInner {
+    /// Moves resolved_ts up to what `min_lock_ts` allows and sends the
+    /// changes it passed to every subscriber.
+    fn advance(&mut self, min_lock_ts: Option<u64>) {
+        let bound = match min_lock_ts {
+            Some(lock_ts) => self.max_commit_ts.min(lock_ts.saturating_sub(1)),
+            None => self.max_commit_ts,
+        };
+        if bound <= self.resolved_ts {
+            return;
+        }
+        self.resolved_ts = bound;
+        let pending = self.pending.split_off(&(bound + 1, String::new()));
+        let events: Vec<ChangeEvent> = std::mem::replace(&mut self.pending, pending)
+            .into_values()
+            .collect();
+        self.subscribers
+            .retain(|subscriber| subscriber.send(&events, bound));
+    }
+}
+
+impl MultiStore {
+    /// Subscribes to the changes of keys with `key_prefix`, replaying those
+    /// committed from `start_ts` on before following new ones.
+    pub fn subscribe(
+        &self,
+        start_ts: u64,
+        key_prefix: String,
+    ) -> UnboundedReceiver<SubscribeReply> {
+        self.follow(key_prefix, true, Some(start_ts))
+    }
+    /// Watches the changes of `key`, or of every key starting with it if
+    /// `prefix` is set, from revision `from_revision` on. A zero revision
+    /// only watches the changes committed from now on.
+    pub fn watch(
+        &self,
+        key: String,
+        prefix: bool,
+        from_revision: u64,
+    ) -> UnboundedReceiver<SubscribeReply> {
+        self.follow(key, prefix, Some(from_revision).filter(|ts| *ts != 0))
+    }
+    /// Adds a subscriber replaying the changes from `start_ts` on, or from
+    /// the resolved ts on if `None`.
+    fn follow(
+        &self,
+        key: String,
+        prefix: bool,
+        start_ts: Option<u64>,
+    ) -> UnboundedReceiver<SubscribeReply> {
+        let (mut recorded, resolved_ts) = {
+            let feed = self.feed.inner.lock().unwrap();
+            (feed.recorded, feed.resolved_ts)
+        };
+        // the resolved ts only moves up, so this covers the changes to replay
+        let from_ts = start_ts.unwrap_or(resolved_ts + 1);
+        let mut tries = 0;
+        let (mut feed, committed) = loop {
+            let committed = self.committed_changes(&key, prefix, from_ts);
+            let feed = self.feed.inner.lock().unwrap();
+            if feed.recorded == recorded {
+                break (feed, committed);
+            }
+            tries += 1;
+            if tries == REPLAY_TRIES {
+                // changes keep coming, hold them back until the replay is built
+                let committed = self.committed_changes(&key, prefix, from_ts);
+                break (feed, committed);
+            }
+            recorded = feed.recorded;
+        };
+        if let Some(event) = committed.last() {
+            feed.max_commit_ts = feed.max_commit_ts.max(event.commit_ts);
+        }
+        feed.advance(self.min_lock_ts());
+
+        let resolved_ts = feed.resolved_ts;
+        let (sender, receiver) = unbounded_channel();
+        let subscriber = Subscriber {
+            start_ts: start_ts.unwrap_or(resolved_ts + 1),
+            key,
+            prefix,
+            sender,
+        };
+        let (replay, later): (Vec<ChangeEvent>, Vec<ChangeEvent>) = committed
+            .into_iter()
+            .partition(|event| event.commit_ts <= resolved_ts);
+        // changes recorded before anyone subscribed are not pending yet
+        for event in later {
+            feed.pending
+                .insert((event.commit_ts, event.key.clone()), event);
+        }
+        subscriber.send(&replay, resolved_ts);
+        feed.subscribers.push(subscriber);
+        receiver
+    }
+    /// Sends the changes the resolved ts passed to the subscribers, called
+    /// after locks are committed or rolled back.
+    pub fn publish_changes(&self) {
+        let mut feed = self.feed.inner.lock().unwrap();
+        if feed.subscribers.is_empty() {
+            return;
+        }
+        feed.advance(self.min_lock_ts());
+    }
+    /// Records a change written to the Write column while its lock is still
+    /// held, so that the resolved ts cannot pass it before it is recorded.
+    pub(super) fn record_change(&self, key: String, commit_ts: u64, start_ts: u64, op: WriteOp) {
+        if op != WriteOp::Put && op != WriteOp::Delete {
+            return;
+        }
+        let mut feed = self.feed.inner.lock().unwrap();
+        feed.recorded += 1;
+        feed.max_commit_ts = feed.max_commit_ts.max(commit_ts);
+        if feed.subscribers.is_empty() {
+            return;
+        }
+        let event = self.change_event(key, commit_ts, start_ts, op);
+        feed.pending.insert((commit_ts, event.key.clone()), event);
+    }
+    /// The changes of `key`, or of every key starting with it if `prefix` is
+    /// set, committed from `from_ts` on, in commit ts order
+    fn committed_changes(&self, key: &str, prefix: bool, from_ts: u64) -> Vec<ChangeEvent> {
+        let mut events = Vec::new();
+        let mut next = self.next_key(Included(key.to_string()));
+        while let Some(next_key) = next {
+            let key_matched = match prefix {
+                true => next_key.starts_with(key),
+                false => next_key == key,
+            };
+            if !key_matched {
+                break;
+            }
+            let mut ts_end = None;
+            while let Some((write_key, write_value)) =
+                self.read_write(next_key.clone(), Some(from_ts), ts_end)
+            {
+                let op = write_value.op();
+                if op == WriteOp::Put || op == WriteOp::Delete {
+                    let event =
+                        self.change_event(next_key.clone(), write_key.ts(), write_value.ts(), op);
+                    events.push(event);
+                }
+                ts_end = match write_key.ts().checked_sub(1) {
+                    Some(ts) if ts >= from_ts => Some(ts),
+                    _ => break,
+                };
+            }
+            next = self.next_key(Excluded(next_key));
+        }
+        events.sort_by(|a, b| (a.commit_ts, &a.key).cmp(&(b.commit_ts, &b.key)));
+        events
+    }
+    fn change_event(&self, key: String, commit_ts: u64, start_ts: u64, op: WriteOp) -> ChangeEvent {
+        let value = match op {
+            WriteOp::Put => self
+                .read_data(key.clone(), Some(start_ts), Some(start_ts))
+                .map(|(_, value)| value.value())
+                .unwrap_or_default(),
+            _ => String::new(),
+        };
+        ChangeEvent {
+            key,
+            value,
+            op: op.into(),
+            commit_ts,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn commit(store: &MultiStore, key: &str, start_ts: u64, commit_ts: u64) {
+        store.write_write(key.to_string(), commit_ts, start_ts, WriteOp::Put);
+        store.erase_lock(key.to_string(), commit_ts);
+    }
+
+    #[test]
+    fn test_subscribe() {
+        let temp_dir = TempDir::new().unwrap();
+        let store = MultiStore::new(temp_dir.path(), String::from("kvs"));
+        store.write_data(String::from("a"), 10, String::from("a10"));
+        commit(&store, "a", 10, 11);
+
+        let mut receiver = store.subscribe(0, String::new());
+        let reply = receiver.try_recv().unwrap();
+        assert_eq!(reply.resolved_ts, 11);
+        assert_eq!(reply.events[0].value, "a10");
+
+        // the secondary of the txn at 20 holds back the txn at 30
+        for (key, start_ts) in vec![("b", 20), ("c", 20), ("d", 30)] {
+            store.write_data(key.to_string(), start_ts, format!("{}{}", key, start_ts));
+            store.write_lock(
+                key.to_string(),
+                start_ts,
+                key.to_string(),
+                WriteOp::Put,
+                100,
+            );
+        }
+        commit(&store, "d", 30, 31);
+        commit(&store, "b", 20, 32);
+        store.publish_changes();
+        let reply = receiver.try_recv().unwrap();
+        assert_eq!(reply.resolved_ts, 19);
+        assert!(reply.events.is_empty());
+
+        commit(&store, "c", 20, 32);
+        store.publish_changes();
+        let reply = receiver.try_recv().unwrap();
+        assert_eq!(reply.resolved_ts, 32);
+        let keys: Vec<&str> = reply.events.iter().map(|e| e.key.as_ref()).collect();
+        assert_eq!(keys, vec!["d", "b", "c"]);
+
+        // a late subscriber replays from start_ts on, filtered by prefix
+        let mut receiver = store.subscribe(31, String::from("c"));
+        let reply = receiver.try_recv().unwrap();
+        assert_eq!(reply.events.len(), 1);
+        assert_eq!(reply.events[0].commit_ts, 32);
+    }
+
+    #[test]
+    fn test_watch() {
+        let temp_dir = TempDir::new().unwrap();
+        let store = MultiStore::new(temp_dir.path(), String::from("kvs"));
+        for (key, start_ts) in vec![("a", 10), ("ab", 20)] {
+            store.write_data(key.to_string(), start_ts, format!("{}{}", key, start_ts));
+            commit(&store, key, start_ts, start_ts + 1);
+        }
+
+        // a zero revision skips the changes committed before
+        let mut key_watcher = store.watch(String::from("a"), false, 0);
+        let mut prefix_watcher = store.watch(String::from("a"), true, 0);
+        assert!(key_watcher.try_recv().unwrap().events.is_empty());
+        assert!(prefix_watcher.try_recv().unwrap().events.is_empty());
+        let mut replay_watcher = store.watch(String::from("a"), true, 11);
+        assert_eq!(replay_watcher.try_recv().unwrap().events.len(), 2);
+
+        for (key, start_ts) in vec![("a", 30), ("ab", 40)] {
+            store.write_data(key.to_string(), start_ts, format!("{}{}", key, start_ts));
+            commit(&store, key, start_ts, start_ts + 1);
+            store.publish_changes();
+        }
+        let reply = key_watcher.try_recv().unwrap();
+        assert_eq!(reply.events[0].value, "a30");
+        assert!(key_watcher.try_recv().unwrap().events.is_empty());
+        assert_eq!(prefix_watcher.try_recv().unwrap().events.len(), 1);
+        assert_eq!(prefix_watcher.try_recv().unwrap().events[0].value, "ab40");
+    }
+}
@@ -0,0 +1,158 @@
+use std::{
+    fs::{self, File},
+    io::{self, Write},
+    ops::Bound::*,
+    sync::atomic::Ordering,
+};
+
+use super::*;
+use crate::preclude::*;
+use crate::KvRpcError;
+
+impl MultiStore {
+    /// The ts below which old versions may be compacted away.
+    pub fn gc_safe_point(&self) -> u64 {
+        self.safe_point.load(Ordering::SeqCst)
+    }
+    /// Fails a read at `ts` below the GC safe point, whose versions may be gone.
+    pub fn check_safe_point(&self, ts: u64) -> std::result::Result<(), KvRpcError> {
+        let safe_point = self.gc_safe_point();
+        if ts < safe_point {
+            return Err(KvRpcError::VersionCompacted(ts, safe_point));
+        }
+        Ok(())
+    }
+    /// Moves the GC safe point up to `safe_point` and drops every version a
+    /// read at or after it can no longer see, returns how many were dropped.
+    /// Fails without dropping any if the safe point cannot be saved.
+    ///
+    /// The latest put of a key committed at or before `safe_point` is kept,
+    /// older versions, deletes, rollbacks and read locks before it are not.
+    ///
+    /// The safe point stays below the oldest lock, whose txn may still commit
+    /// a version the reads after the safe point have to see.
+    pub fn gc(&self, safe_point: u64) -> io::Result<usize> {
+        let safe_point = match self.min_lock_ts() {
+            Some(min_lock_ts) => safe_point.min(min_lock_ts.saturating_sub(1)),
+            None => safe_point,
+        };
+        if safe_point <= self.gc_safe_point() {
+            return Ok(0);
+        }
+        self.set_safe_point(safe_point)?;
+
+        let mut dropped = 0;
+        let mut next = self.next_key(Unbounded);
+        while let Some(key) = next {
+            dropped += self.gc_key(&key, safe_point);
+            next = self.next_key(Excluded(key));
+        }
+        Ok(dropped)
+    }
+    /// Moves the GC safe point up to `safe_point` without dropping anything,
+    /// for versions compacted already.
+    pub fn set_safe_point(&self, safe_point: u64) -> io::Result<()> {
+        if safe_point <= self.gc_safe_point() {
+            return Ok(());
+        }
+        self.save_safe_point(safe_point)?;
+        self.safe_point.store(safe_point, Ordering::SeqCst);
+        Ok(())
+    }
+    /// Drops the versions of `key` the GC at `safe_point` can drop, walking
+    /// its writes at or before it newest first, returns how many were dropped.
+    fn gc_key(&self, key: &str, safe_point: u64) -> usize {
+        let mut dropped = 0;
+        let mut latest_seen = false;
+        let mut ts_end = safe_point;
+        while let Some((write_key, write_value)) =
+            self.read_write(key.to_string(), None, Some(ts_end))
+        {
+            let op = write_value.op();
+            let keep = !latest_seen && op == WriteOp::Put;
+            // the latest version is a put to keep or a delete, nothing is
+            // left to see past either
+            latest_seen |= op == WriteOp::Put || op == WriteOp::Delete;
+            if !keep {
+                if op == WriteOp::Put {
+                    self.erase_data_at(key.to_string(), write_value.ts());
+                }
+                self.erase_write_at(key.to_string(), write_key.ts());
+                dropped += 1;
+            }
+            ts_end = match write_key.ts().checked_sub(1) {
+                Some(ts) => ts,
+                None => break,
+            };
+        }
+        dropped
+    }
+    /// Saves the safe point crash-atomically: a temporary file is synced,
+    /// then renamed over the old one
+    fn save_safe_point(&self, safe_point: u64) -> io::Result<()> {
+        let tmp = self.safe_point_path.with_extension("tmp");
+        let mut file = File::create(&tmp)?;
+        file.write_all(safe_point.to_string().as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&tmp, &self.safe_point_path)?;
+        if let Some(dir) = self.safe_point_path.parent() {
+            File::open(dir)?.sync_all()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn commit(store: &MultiStore, key: &str, start_ts: u64, commit_ts: u64, op: WriteOp) {
+        store.write_data(key.to_string(), start_ts, format!("{}{}", key, start_ts));
+        store.write_write(key.to_string(), commit_ts, start_ts, op);
+    }
+
+    #[test]
+    fn test_gc() {
+        let temp_dir = TempDir::new().unwrap();
+        let store = MultiStore::new(temp_dir.path(), String::from("kvs"));
+        commit(&store, "a", 10, 11, WriteOp::Put);
+        commit(&store, "a", 20, 21, WriteOp::Put);
+        commit(&store, "a", 30, 31, WriteOp::Put);
+        commit(&store, "b", 10, 11, WriteOp::Put);
+        commit(&store, "b", 20, 21, WriteOp::Delete);
+        store.write_write(String::from("c"), 15, 15, WriteOp::Rollback);
+
+        assert_eq!(store.gc(25).unwrap(), 4);
+        assert_eq!(store.gc(25).unwrap(), 0);
+        assert!(store.check_safe_point(24).is_err());
+        assert!(store.check_safe_point(25).is_ok());
+        assert_eq!(
+            store.read_at(String::from("a"), 25, 25),
+            Ok(Some(String::from("a20")))
+        );
+        assert_eq!(
+            store.read_at(String::from("a"), 35, 35),
+            Ok(Some(String::from("a30")))
+        );
+        assert_eq!(store.read_at(String::from("b"), 25, 25), Ok(None));
+        assert!(store
+            .read_data(String::from("a"), Some(10), Some(10))
+            .is_none());
+
+        // the safe point survives a restart
+        drop(store);
+        let store = MultiStore::new(temp_dir.path(), String::from("kvs"));
+        assert_eq!(store.gc_safe_point(), 25);
+
+        // the versions a locked txn started after may still be overwritten
+        store.write_lock(String::from("a"), 40, String::from("a"), WriteOp::Put, 100);
+        commit(&store, "b", 50, 51, WriteOp::Put);
+        assert_eq!(store.gc(60).unwrap(), 1);
+        assert_eq!(store.gc_safe_point(), 39);
+        assert_eq!(
+            store.read_at(String::from("a"), 39, 39),
+            Ok(Some(String::from("a30")))
+        );
+    }
+}
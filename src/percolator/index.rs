@@ -0,0 +1,221 @@
+use std::ops::Bound::*;
+
+use serde_json::Value;
+
+use super::*;
+use crate::preclude::*;
+
+/// Keys of index definitions, holding the JSON pointer of the indexed field
+const INDEX_DEF_PREFIX: &str = "!index/";
+/// Keys of index entries, holding the key of the indexed record
+const INDEX_ENTRY_PREFIX: &str = "!idx/";
+
+/// A secondary index mapping a field of JSON values to the keys holding them
+#[derive(Debug, Clone, PartialEq)]
+pub struct Index {
+    name: String,
+    /// A JSON pointer like `/user/email`, or empty to index the whole value
+    path: String,
+}
+
+impl Index {
+    /// Create an index definition named `name` over the field at `path`
+    pub fn new(name: String, path: String) -> Self {
+        Index { name, path }
+    }
+    /// The key the definition is stored at
+    pub fn definition_key(&self) -> String {
+        format!("{}{}", INDEX_DEF_PREFIX, self.name)
+    }
+    /// The JSON pointer of the indexed field
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+    /// The field of `value` indexed, `None` if it has no such scalar field
+    pub fn field(&self, value: &str) -> Option<String> {
+        if self.path.is_empty() {
+            return Some(value.to_string());
+        }
+        let value: Value = serde_json::from_str(value).ok()?;
+        match value.pointer(&self.path)? {
+            Value::String(field) => Some(field.clone()),
+            Value::Number(field) => Some(field.to_string()),
+            Value::Bool(field) => Some(field.to_string()),
+            _ => None,
+        }
+    }
+    /// The prefix of the entries of every key whose field is `field`, the
+    /// length keeps a field from matching the prefix of a longer one
+    pub fn entry_prefix(&self, field: &str) -> String {
+        format!(
+            "{}{}/{}:{}/",
+            INDEX_ENTRY_PREFIX,
+            self.name,
+            field.len(),
+            field
+        )
+    }
+    /// The entry indexing `key` holding `value`, if it has the field
+    pub fn entry_key(&self, key: &str, value: &str) -> Option<String> {
+        self.field(value)
+            .map(|field| format!("{}{}", self.entry_prefix(&field), key))
+    }
+}
+
+/// Whether `key` belongs to index definitions or entries, which are never indexed
+pub fn is_index_key(key: &str) -> bool {
+    key.starts_with(INDEX_DEF_PREFIX) || key.starts_with(INDEX_ENTRY_PREFIX)
+}
+
+impl MultiStore {
+    /// The index definitions committed so far
+    pub fn index_definitions(&self) -> Vec<Index> {
+        let mut indexes = Vec::new();
+        let mut next = self.next_key(Included(INDEX_DEF_PREFIX.to_string()));
+        while let Some(key) = next {
+            if !key.starts_with(INDEX_DEF_PREFIX) {
+                break;
+            }
+            if let Some(path) = self.read_latest(key.clone(), u64::MAX) {
+                let name = key[INDEX_DEF_PREFIX.len()..].to_string();
+                indexes.push(Index::new(name, path));
+            }
+            next = self.next_key(Excluded(key));
+        }
+        indexes
+    }
+    /// The index entries to write along with `mutations` of the transaction
+    /// started at `start_ts`, moving each key from the entry of its last
+    /// value to the entry of its new one. The last value is the one an
+    /// earlier batch of the same transaction wrote, else the last committed.
+    pub fn index_mutations(&self, mutations: &[Mutation], start_ts: u64) -> Vec<Mutation> {
+        let indexes = self.index_definitions();
+        if indexes.is_empty() {
+            return Vec::new();
+        }
+        let mut index_mutations = Vec::new();
+        for mutation in mutations.iter().filter(|m| !is_index_key(&m.key)) {
+            let new_value = match WriteOp::from_i32(mutation.op) {
+                Some(WriteOp::Put) => Some(mutation.value.as_str()),
+                Some(WriteOp::Delete) => None,
+                _ => continue,
+            };
+            let old_value = self.value_before(&mutation.key, new_value, start_ts);
+            for index in indexes.iter() {
+                let old_entry = old_value
+                    .as_ref()
+                    .and_then(|value| index.entry_key(&mutation.key, value));
+                let new_entry = new_value.and_then(|value| index.entry_key(&mutation.key, value));
+                if old_entry == new_entry {
+                    continue;
+                }
+                if let Some(old_entry) = old_entry {
+                    index_mutations.push(Mutation {
+                        key: old_entry,
+                        value: String::new(),
+                        op: WriteOp::Delete.into(),
+                    });
+                }
+                if let Some(new_entry) = new_entry {
+                    index_mutations.push(Mutation {
+                        key: new_entry,
+                        value: mutation.key.clone(),
+                        op: WriteOp::Put.into(),
+                    });
+                }
+            }
+        }
+        index_mutations
+    }
+    /// The value `key` held before the transaction started at `start_ts`
+    /// writes `new_value` to it
+    fn value_before(&self, key: &str, new_value: Option<&str>, start_ts: u64) -> Option<String> {
+        if let Some((_, lock)) = self.read_lock(key.to_string(), Some(start_ts), Some(start_ts)) {
+            let own_value = match lock.op() {
+                WriteOp::Put => self
+                    .read_data(key.to_string(), Some(start_ts), Some(start_ts))
+                    .map(|(_, value)| value.value()),
+                WriteOp::Delete => None,
+                _ => return self.read_latest(key.to_string(), start_ts),
+            };
+            // a retried batch finds its own value, and is indexed as the first time
+            if own_value.as_deref() != new_value {
+                return own_value;
+            }
+        }
+        self.read_latest(key.to_string(), start_ts)
+    }
+    /// The value of the latest put of `key` committed at or before `ts`,
+    /// ignoring locks
+    fn read_latest(&self, key: String, ts: u64) -> Option<String> {
+        let (_write_key, write_value) = self
+            .read_committed_write(key.clone(), None, Some(ts))
+            .filter(|(_, write_value)| write_value.op() == WriteOp::Put)?;
+        self.read_data(key, Some(write_value.ts()), Some(write_value.ts()))
+            .map(|(_, value)| value.value())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn commit(store: &MultiStore, key: &str, value: &str, start_ts: u64, commit_ts: u64) {
+        store.write_data(key.to_string(), start_ts, value.to_string());
+        store.write_write(key.to_string(), commit_ts, start_ts, WriteOp::Put);
+    }
+
+    #[test]
+    fn test_index_mutations() {
+        let temp_dir = TempDir::new().unwrap();
+        let store = MultiStore::new(temp_dir.path(), String::from("kvs"));
+        let index = Index::new(String::from("email"), String::from("/user/email"));
+        commit(&store, &index.definition_key(), index.path(), 10, 11);
+        assert_eq!(store.index_definitions(), vec![index.clone()]);
+        commit(&store, "u1", r#"{"user":{"email":"a@x"}}"#, 20, 21);
+
+        let put = |key: &str, value: &str| Mutation {
+            key: key.to_string(),
+            value: value.to_string(),
+            op: WriteOp::Put.into(),
+        };
+        let mutations = vec![
+            put("u1", r#"{"user":{"email":"b@x"}}"#),
+            put("u2", r#"{"user":{"email":"b@x"}}"#),
+            put("u3", r#"{"user":{}}"#),
+        ];
+        let entries = store.index_mutations(&mutations, 30);
+        let keys: Vec<&str> = entries.iter().map(|m| m.key.as_ref()).collect();
+        assert_eq!(
+            keys,
+            vec![
+                "!idx/email/3:a@x/u1",
+                "!idx/email/3:b@x/u1",
+                "!idx/email/3:b@x/u2"
+            ]
+        );
+        assert_eq!(entries[0].op, WriteOp::Delete as i32);
+        assert_eq!(entries[2].value, "u2");
+
+        // index entries are never indexed themselves
+        assert!(store.index_mutations(&entries, 30).is_empty());
+
+        // a key written again after its batch was flushed moves off the
+        // entry that batch wrote
+        for mutation in mutations.iter().chain(entries.iter()) {
+            let op = WriteOp::from_i32(mutation.op).unwrap();
+            store.write_data(mutation.key.clone(), 30, mutation.value.clone());
+            store.write_lock(mutation.key.clone(), 30, String::from("u1"), op, 100);
+        }
+        let entries = store.index_mutations(&[put("u1", r#"{"user":{"email":"c@x"}}"#)], 30);
+        let keys: Vec<&str> = entries.iter().map(|m| m.key.as_ref()).collect();
+        assert_eq!(keys, vec!["!idx/email/3:b@x/u1", "!idx/email/3:c@x/u1"]);
+        assert_eq!(entries[0].op, WriteOp::Delete as i32);
+
+        // while a retried batch is indexed as the first time
+        let retried = store.index_mutations(&mutations[..1], 30);
+        let keys: Vec<&str> = retried.iter().map(|m| m.key.as_ref()).collect();
+        assert_eq!(keys, vec!["!idx/email/3:a@x/u1", "!idx/email/3:b@x/u1"]);
+    }
+}
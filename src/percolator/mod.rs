@@ -1,7 +1,15 @@
+mod cdc;
+mod gc;
+mod index;
 mod multi_store;
+mod reader;
+mod resolver;
 mod tso;
 mod types;
 
+pub use cdc::ChangeFeed;
+pub use index::{is_index_key, Index};
 pub use multi_store::MultiStore;
+pub use resolver::TxnState;
 pub use tso::TimestampOracle;
-pub use types::{Column, DataValue, Key, LockValue, WriteValue};
+pub use types::{mutation_op, Column, DataValue, Key, LockValue, WriteValue, DEFAULT_LOCK_TTL};
@@ -1,4 +1,10 @@
-use std::{ops::Bound::*, ops::RangeBounds, path::PathBuf, str::FromStr};
+use std::{
+    collections::BTreeSet,
+    ops::{Bound, Bound::*, RangeBounds},
+    path::PathBuf,
+    str::FromStr,
+    sync::{atomic::AtomicU64, Mutex},
+};
 
 use super::*;
 use crate::preclude::*;
@@ -6,7 +12,12 @@ use crate::preclude::*;
 pub struct MultiStore {
     data: EngineKind,
     lock: EngineKind,
+    // the locks of the Lock column by start ts, so finding them loads no column
+    lock_index: Mutex<BTreeSet<(u64, String)>>,
     write: EngineKind,
+    pub(super) feed: ChangeFeed,
+    pub(super) safe_point: AtomicU64,
+    pub(super) safe_point_path: PathBuf,
 }
 
 impl MultiStore {
@@ -28,7 +39,22 @@ impl MultiStore {
             "sled" => EngineKind::sled(KvSled::open(path.join("write")).unwrap()),
             _unknown => unreachable!(),
         };
-        MultiStore { data, lock, write }
+        let safe_point_path = path.join("gc_safe_point");
+        let safe_point = std::fs::read_to_string(&safe_point_path)
+            .ok()
+            .and_then(|safe_point| safe_point.trim().parse().ok())
+            .unwrap_or(0);
+        let store = MultiStore {
+            data,
+            lock,
+            lock_index: Mutex::new(BTreeSet::new()),
+            write,
+            feed: ChangeFeed::default(),
+            safe_point: AtomicU64::new(safe_point),
+            safe_point_path,
+        };
+        store.reindex_locks();
+        store
     }
     /// Reads the latest key-value record from a Data column
     /// in MemoryStorage with a given key and a timestamp range.
@@ -81,6 +107,39 @@ impl MultiStore {
             )
         })
     }
+    /// Reads the latest committed record from the Write column, skipping
+    /// rollback records of aborted transactions and lock records of reads.
+    pub fn read_committed_write(
+        &self,
+        key: String,
+        ts_start: Option<u64>,
+        ts_end: Option<u64>,
+    ) -> Option<(Key, WriteValue)> {
+        let mut ts_end = ts_end;
+        loop {
+            let (write_key, write_value) = self.read_write(key.clone(), ts_start, ts_end)?;
+            match write_value.op() {
+                WriteOp::Rollback | WriteOp::Lock => {}
+                _ => return Some((write_key, write_value)),
+            }
+            ts_end = Some(write_key.ts().checked_sub(1)?);
+        }
+    }
+    /// Finds the record in the Write column left by the transaction
+    /// started at `start_ts`, either a commit or a rollback.
+    pub fn read_write_of_txn(&self, key: String, start_ts: u64) -> Option<(Key, WriteValue)> {
+        let mut ts_end = None;
+        loop {
+            let (write_key, write_value) = self.read_write(key.clone(), Some(start_ts), ts_end)?;
+            if write_value.ts() == start_ts {
+                return Some((write_key, write_value));
+            }
+            if write_key.ts() <= start_ts {
+                return None;
+            }
+            ts_end = Some(write_key.ts() - 1);
+        }
+    }
 
     /// Writes a record to a specified column in MemoryStorage.
     #[inline]
@@ -91,23 +150,32 @@ impl MultiStore {
     }
     /// Writes a record to a specified column in MemoryStorage.
     #[inline]
-    pub fn write_lock(&self, key: String, ts: u64, primary: String, op: WriteOp) {
+    pub fn write_lock(&self, key: String, ts: u64, primary: String, op: WriteOp, ttl: u64) {
         let key = Key::new(key, ts);
-        let value = LockValue::new(primary, op);
+        let value = LockValue::new(primary, op, ttl);
         self.lock.set(key.to_string(), value.to_string()).unwrap();
+        self.lock_index
+            .lock()
+            .unwrap()
+            .insert((key.ts(), key.key().to_string()));
     }
-    /// Writes a record to a specified column in MemoryStorage.
+    /// Extends the ttl of the primary lock to at least `ttl`.
+    #[inline]
+    pub fn update_lock(&self, primary: String, ts: u64, ttl: u64) {
+        self.heart_beat_lock(primary, ts, ttl);
+    }
+    /// Extends the ttl of the primary lock, returns the new ttl or `None`
+    /// if the lock is gone.
     #[inline]
-    pub fn update_lock(&self, primary: String, ts: u64) {
-        match self.read_lock(primary, Some(ts), Some(ts)) {
-            Some((lock_key, lock_value)) => {
-                let new_value = LockValue::new(lock_value.primary(), lock_value.op());
+    pub fn heart_beat_lock(&self, primary: String, ts: u64, ttl: u64) -> Option<u64> {
+        self.read_lock(primary, Some(ts), Some(ts))
+            .map(|(lock_key, mut lock_value)| {
+                lock_value.extend_ttl(ttl);
                 self.lock
-                    .set(lock_key.to_string(), new_value.to_string())
+                    .set(lock_key.to_string(), lock_value.to_string())
                     .unwrap();
-            }
-            None => {}
-        }
+                lock_value.ttl()
+            })
     }
     /// Writes a record to a specified column in MemoryStorage.
     #[inline]
@@ -115,8 +183,72 @@ impl MultiStore {
         let key = Key::new(key, ts);
         let value = WriteValue::new(value, op);
         self.write.set(key.to_string(), value.to_string()).unwrap();
+        self.record_change(key.key().to_string(), key.ts(), value.ts(), op);
+    }
+
+    /// Removes the lock and data of the transaction started at `start_ts`
+    /// and leaves a rollback record, so late prewrites of it are rejected.
+    pub fn rollback(&self, key: String, start_ts: u64) {
+        let range = generate_range(key.clone(), Some(start_ts), Some(start_ts));
+        self.data.range_erase(range).unwrap();
+        self.erase_lock_at(key.clone(), start_ts);
+        self.write_write(key, start_ts, start_ts, WriteOp::Rollback);
     }
 
+    /// Finds the smallest key from `start` on with a record in the Write or
+    /// Lock column, seeking both columns instead of loading them.
+    pub fn next_key(&self, start: Bound<String>) -> Option<String> {
+        let from = match start {
+            Included(key) => Included(generate_key(&key, u64::MIN)),
+            Excluded(key) => Excluded(generate_key(&key, u64::MAX)),
+            Unbounded => Unbounded,
+        };
+        let range = (from, Unbounded);
+        let write = self.write.range_first(range.clone()).unwrap();
+        let lock = self.lock.range_first(range).unwrap();
+        write
+            .into_iter()
+            .chain(lock)
+            .map(|(key, _)| Key::from_str(&key).unwrap().key().to_string())
+            .min()
+    }
+
+    /// Lists every lock left by the transaction started at `start_ts`.
+    pub fn scan_locks(&self, start_ts: u64) -> Vec<(Key, LockValue)> {
+        let keys: Vec<String> = self
+            .lock_index
+            .lock()
+            .unwrap()
+            .range((start_ts, String::new())..)
+            .take_while(|(ts, _)| *ts == start_ts)
+            .map(|(_, key)| key.clone())
+            .collect();
+        keys.into_iter()
+            .filter_map(|key| self.read_lock(key, Some(start_ts), Some(start_ts)))
+            .collect()
+    }
+    /// The start ts of the oldest lock left.
+    pub fn min_lock_ts(&self) -> Option<u64> {
+        self.lock_index
+            .lock()
+            .unwrap()
+            .iter()
+            .next()
+            .map(|(ts, _)| *ts)
+    }
+    /// Lists every record in the Write column.
+    pub fn scan_writes(&self) -> Vec<(Key, WriteValue)> {
+        let (keys, values) = self.write.export().unwrap();
+        keys.into_iter()
+            .zip(values)
+            .map(|(key, value)| {
+                (
+                    Key::from_str(&key).unwrap(),
+                    WriteValue::from_str(&value).unwrap(),
+                )
+            })
+            .collect()
+    }
     #[inline]
     /// Erases a record from a specified column in MemoryStorage.
     pub fn erase_data(&self, key: String, commit_ts: u64) {
@@ -126,8 +258,33 @@ impl MultiStore {
     #[inline]
     /// Erases a record from a specified column in MemoryStorage.
     pub fn erase_lock(&self, key: String, commit_ts: u64) {
-        let range = generate_range(key, None, Some(commit_ts));
+        let mut ts_end = Some(commit_ts);
+        while let Some((lock_key, _)) = self.read_lock(key.clone(), None, ts_end) {
+            self.erase_lock_at(key.clone(), lock_key.ts());
+            ts_end = match lock_key.ts().checked_sub(1) {
+                Some(ts) => Some(ts),
+                None => break,
+            };
+        }
+    }
+    #[inline]
+    /// Erases the lock taken at exactly `ts` from the Lock column.
+    pub fn erase_lock_at(&self, key: String, ts: u64) {
+        let range = generate_range(key.clone(), Some(ts), Some(ts));
         self.lock.range_erase(range).unwrap();
+        self.lock_index.lock().unwrap().remove(&(ts, key));
+    }
+    #[inline]
+    /// Erases the record written at exactly `ts` from the Data column.
+    pub fn erase_data_at(&self, key: String, ts: u64) {
+        let range = generate_range(key, Some(ts), Some(ts));
+        self.data.range_erase(range).unwrap();
+    }
+    #[inline]
+    /// Erases the record written at exactly `ts` from the Write column.
+    pub fn erase_write_at(&self, key: String, ts: u64) {
+        let range = generate_range(key, Some(ts), Some(ts));
+        self.write.range_erase(range).unwrap();
     }
     #[inline]
     /// Erases a record from a specified column in MemoryStorage.
@@ -158,11 +315,48 @@ impl MultiStore {
         let value = data.pop().unwrap();
         let key = data.pop().unwrap();
         self.lock.import((key, value))?;
+        self.reindex_locks();
         let value = data.pop().unwrap();
         let key = data.pop().unwrap();
         self.data.import((key, value))?;
         Ok(())
     }
+    /// Adds exported records on top of the store, restoring an export a
+    /// part at a time after `import` took the first
+    pub fn extend(&self, data: Vec<Vec<String>>) {
+        let mut data = data.into_iter();
+        let columns = [
+            (&self.data, false),
+            (&self.lock, true),
+            (&self.write, false),
+        ];
+        for (column, is_lock) in columns {
+            let keys = data.next().unwrap();
+            let values = data.next().unwrap();
+            if is_lock {
+                let mut lock_index = self.lock_index.lock().unwrap();
+                for key in keys.iter() {
+                    let key = Key::from_str(key).unwrap();
+                    lock_index.insert((key.ts(), key.key().to_string()));
+                }
+            }
+            for (key, value) in keys.into_iter().zip(values) {
+                column.set(key, value).unwrap();
+            }
+        }
+    }
+    /// Rebuilds the index of the locks from the Lock column, after it was
+    /// opened or restored
+    fn reindex_locks(&self) {
+        let (keys, _values) = self.lock.export().unwrap();
+        *self.lock_index.lock().unwrap() = keys
+            .iter()
+            .map(|key| {
+                let key = Key::from_str(key).unwrap();
+                (key.ts(), key.key().to_string())
+            })
+            .collect();
+    }
 }
 
 fn generate_key(key: &str, ts: u64) -> String {
@@ -0,0 +1,150 @@
+use std::ops::Bound::*;
+
+use super::*;
+use crate::preclude::*;
+
+impl MultiStore {
+    /// Reads the value of `key` visible at `ts`, including a write of the
+    /// reading transaction started at `start_ts` itself.
+    ///
+    /// Returns the lock instead if the key is locked by another
+    /// transaction started before `ts`, which the reader has to resolve.
+    pub fn read_at(
+        &self,
+        key: String,
+        ts: u64,
+        start_ts: u64,
+    ) -> std::result::Result<Option<String>, LockInfo> {
+        let lock = self.read_lock(key.clone(), None, Some(ts));
+        let last_write = match lock {
+            // a lock of the reading txn itself, read its own write
+            Some((lock_key, lock_value)) if lock_key.ts() == start_ts => match lock_value.op() {
+                WriteOp::Put => Some(start_ts),
+                WriteOp::Delete => None,
+                _ => self.read_committed_ts(key.clone(), ts),
+            },
+            Some((lock_key, lock_value)) => {
+                return Err(LockInfo {
+                    key,
+                    primary: lock_value.primary(),
+                    lock_ts: lock_key.ts(),
+                    lock_ttl: lock_value.ttl(),
+                })
+            }
+            None => self.read_committed_ts(key.clone(), ts),
+        };
+        Ok(last_write.map(|start_ts| {
+            self.read_data(key, Some(start_ts), Some(start_ts))
+                .unwrap()
+                .1
+                .value()
+        }))
+    }
+    /// The start ts of the latest put of `key` committed before `ts`
+    fn read_committed_ts(&self, key: String, ts: u64) -> Option<u64> {
+        self.read_committed_write(key, None, Some(ts))
+            .filter(|(_, write_value)| write_value.op() != WriteOp::Delete)
+            .map(|(_, write_value)| write_value.ts())
+    }
+    /// Reads the values of `keys` visible at `ts`, leaving out missing keys
+    /// and returning the locks met instead of the locked keys.
+    pub fn batch_get(
+        &self,
+        keys: Vec<String>,
+        ts: u64,
+        start_ts: u64,
+    ) -> (Vec<KvPair>, Vec<LockInfo>) {
+        let mut pairs = Vec::new();
+        let mut locks = Vec::new();
+        for key in keys {
+            match self.read_at(key.clone(), ts, start_ts) {
+                Ok(Some(value)) => pairs.push(KvPair { key, value }),
+                Ok(None) => {}
+                Err(lock) => locks.push(lock),
+            }
+        }
+        (pairs, locks)
+    }
+    /// Reads up to `limit` pairs visible at `ts` with keys in `[start, end)`
+    /// in key order, an empty `end` meaning no upper bound and a zero
+    /// `limit` no limit.
+    ///
+    /// The scan stops at the first key locked by another transaction and
+    /// returns its lock along with the pairs read before it.
+    pub fn scan(
+        &self,
+        start: String,
+        end: String,
+        limit: usize,
+        ts: u64,
+        start_ts: u64,
+    ) -> (Vec<KvPair>, Option<LockInfo>) {
+        let mut pairs = Vec::new();
+        let mut next = self.next_key(Included(start));
+        while let Some(key) = next {
+            if !end.is_empty() && key >= end {
+                break;
+            }
+            match self.read_at(key.clone(), ts, start_ts) {
+                Ok(Some(value)) => pairs.push(KvPair {
+                    key: key.clone(),
+                    value,
+                }),
+                Ok(None) => {}
+                Err(lock) => return (pairs, Some(lock)),
+            }
+            if limit != 0 && pairs.len() >= limit {
+                break;
+            }
+            next = self.next_key(Excluded(key));
+        }
+        (pairs, None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_scan_and_batch_get() {
+        let temp_dir = TempDir::new().unwrap();
+        let store = MultiStore::new(temp_dir.path(), String::from("kvs"));
+        for (key, start_ts, commit_ts) in vec![("a", 10, 11), ("b", 10, 11), ("c", 10, 11)] {
+            store.write_data(key.to_string(), start_ts, format!("{}{}", key, start_ts));
+            store.write_write(key.to_string(), commit_ts, start_ts, WriteOp::Put);
+        }
+        store.write_write(String::from("b"), 21, 20, WriteOp::Delete);
+        store.write_data(String::from("d"), 30, String::from("d30"));
+        store.write_lock(String::from("d"), 30, String::from("d"), WriteOp::Put, 100);
+
+        let (pairs, lock) = store.scan(String::new(), String::new(), 0, 25, 25);
+        let keys: Vec<&str> = pairs.iter().map(|pair| pair.key.as_ref()).collect();
+        assert_eq!(keys, vec!["a", "c"]);
+        assert!(lock.is_none());
+
+        let (pairs, lock) = store.scan(String::from("b"), String::new(), 0, 35, 35);
+        assert_eq!(pairs.len(), 1);
+        assert_eq!(pairs[0].value, "c10");
+        assert_eq!(lock.unwrap().lock_ts, 30);
+
+        let keys = vec![String::from("a"), String::from("b"), String::from("d")];
+        let (pairs, locks) = store.batch_get(keys.clone(), 25, 25);
+        assert_eq!(pairs.len(), 1);
+        assert!(locks.is_empty());
+        let (pairs, locks) = store.batch_get(keys, 35, 35);
+        assert_eq!(pairs.len(), 1);
+        assert_eq!(locks[0].key, "d");
+
+        // the lock of the scanning txn itself is read through
+        let (pairs, lock) = store.scan(String::from("c"), String::new(), 0, 40, 30);
+        assert_eq!(pairs.len(), 2);
+        assert!(lock.is_none());
+
+        let (pairs, _) = store.scan(String::from("a"), String::from("c"), 0, 15, 15);
+        assert_eq!(pairs.len(), 2);
+        let (pairs, _) = store.scan(String::new(), String::new(), 1, 15, 15);
+        assert_eq!(pairs.len(), 1);
+    }
+}
@@ -0,0 +1,252 @@
+use super::*;
+use crate::preclude::*;
+use crate::KvRpcError;
+
+/// The status of a transaction as seen from its primary key
+pub enum TxnState {
+    /// The primary is still locked, with this ttl in milliseconds
+    Locked(u64),
+    /// The primary is committed at this commit ts
+    Committed(u64),
+    /// The transaction is rolled back and never commits
+    RolledBack,
+}
+
+impl TxnState {
+    /// Build the reply of a CheckTxnStatus request with this status
+    pub fn into_reply(self, ts: u64, seq: u64) -> CheckTxnStatusReply {
+        let (status, lock_ttl, commit_ts) = match self {
+            TxnState::Locked(lock_ttl) => (TxnStatus::Locked, lock_ttl, 0),
+            TxnState::Committed(commit_ts) => (TxnStatus::Committed, 0, commit_ts),
+            TxnState::RolledBack => (TxnStatus::RolledBack, 0, 0),
+        };
+        CheckTxnStatusReply {
+            status: status.into(),
+            lock_ttl,
+            commit_ts,
+            ts,
+            seq,
+        }
+    }
+}
+
+impl MultiStore {
+    /// Determines the status of the transaction started at `lock_ts` from
+    /// its primary, rolling the primary back once it expired at `current_ts`.
+    ///
+    /// `lock_ttl` is the ttl of the lock the caller met, used when the
+    /// primary lock is not written yet.
+    pub fn check_txn_status(
+        &self,
+        primary: String,
+        lock_ts: u64,
+        lock_ttl: u64,
+        current_ts: u64,
+    ) -> TxnState {
+        if let Some((_lock_key, lock_value)) =
+            self.read_lock(primary.clone(), Some(lock_ts), Some(lock_ts))
+        {
+            if lock_value.is_expired(lock_ts, current_ts) {
+                self.rollback(primary, lock_ts);
+                return TxnState::RolledBack;
+            }
+            return TxnState::Locked(lock_value.ttl());
+        }
+        match self.read_write_of_txn(primary.clone(), lock_ts) {
+            Some((_write_key, write_value)) if write_value.op() == WriteOp::Rollback => {
+                TxnState::RolledBack
+            }
+            Some((write_key, _write_value)) => TxnState::Committed(write_key.ts()),
+            None => {
+                // the primary may still be on its way, wait for the caller's lock to expire
+                let lock = LockValue::new(primary.clone(), WriteOp::Lock, lock_ttl);
+                if lock.is_expired(lock_ts, current_ts) {
+                    self.rollback(primary, lock_ts);
+                    TxnState::RolledBack
+                } else {
+                    TxnState::Locked(lock.ttl())
+                }
+            }
+        }
+    }
+    /// Commits at `commit_ts`, or rolls back if `None`, every lock left by
+    /// the transaction started at `start_ts`, returns how many were resolved.
+    pub fn resolve_lock(&self, start_ts: u64, commit_ts: Option<u64>) -> usize {
+        let locks = self.scan_locks(start_ts);
+        for (lock_key, lock_value) in locks.iter() {
+            let key = lock_key.key().to_string();
+            match commit_ts {
+                Some(commit_ts) => {
+                    self.write_write(key.clone(), commit_ts, start_ts, lock_value.op());
+                    self.erase_lock(key, start_ts);
+                }
+                None => self.rollback(key, start_ts),
+            }
+        }
+        locks.len()
+    }
+
+    /// Commits a batch of the transaction started at `start_ts`.
+    ///
+    /// Every key has to be locked by the transaction, as the ones
+    /// `resolve_lock` finds are, or committed by an earlier try of the same
+    /// batch. Nothing is written otherwise.
+    pub fn commit_batch(
+        &self,
+        mutations: &[Mutation],
+        start_ts: u64,
+        commit_ts: u64,
+    ) -> std::result::Result<(), KvRpcError> {
+        let mut locked = Vec::new();
+        for mutation in mutations {
+            let op = mutation_op(mutation)?;
+            if self
+                .read_lock(mutation.key.clone(), Some(start_ts), Some(start_ts))
+                .is_some()
+            {
+                locked.push((mutation.key.clone(), op));
+                continue;
+            }
+            match self.read_write_of_txn(mutation.key.clone(), start_ts) {
+                Some((_write_key, write_value)) if write_value.op() != WriteOp::Rollback => {}
+                _ => return Err(KvRpcError::Abort(String::from("lock missing"))),
+            }
+        }
+        for (key, op) in locked {
+            self.write_write(key.clone(), commit_ts, start_ts, op);
+            self.erase_lock(key, commit_ts);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tso::compose_ts;
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_resolve_lock() {
+        let temp_dir = TempDir::new().unwrap();
+        let store = MultiStore::new(temp_dir.path(), String::from("kvs"));
+        let start_ts = compose_ts(1000, 0);
+        for key in vec!["key1", "key2", "key3"] {
+            store.write_data(key.to_string(), start_ts, String::from("value"));
+            store.write_lock(
+                key.to_string(),
+                start_ts,
+                String::from("key1"),
+                WriteOp::Put,
+                100,
+            );
+        }
+        let primary = String::from("key1");
+        match store.check_txn_status(primary.clone(), start_ts, 100, compose_ts(1050, 0)) {
+            TxnState::Locked(ttl) => assert_eq!(ttl, 100),
+            _ => panic!("primary lock should be alive"),
+        }
+
+        // the primary commits, readers then commit every secondary at once
+        let commit_ts = compose_ts(1060, 0);
+        store.write_write(primary.clone(), commit_ts, start_ts, WriteOp::Put);
+        store.erase_lock(primary.clone(), start_ts);
+        match store.check_txn_status(primary, start_ts, 100, compose_ts(2000, 0)) {
+            TxnState::Committed(ts) => assert_eq!(ts, commit_ts),
+            _ => panic!("primary should be committed"),
+        }
+        assert_eq!(store.resolve_lock(start_ts, Some(commit_ts)), 2);
+        assert!(store.scan_locks(start_ts).is_empty());
+        let (_write_key, write_value) = store
+            .read_committed_write(String::from("key3"), None, None)
+            .unwrap();
+        assert_eq!(write_value.ts(), start_ts);
+
+        // an expired primary is rolled back along with its secondaries
+        let start_ts = compose_ts(3000, 0);
+        for key in vec!["key4", "key5"] {
+            store.write_lock(
+                key.to_string(),
+                start_ts,
+                String::from("key4"),
+                WriteOp::Put,
+                100,
+            );
+        }
+        match store.check_txn_status(String::from("key4"), start_ts, 100, compose_ts(3100, 0)) {
+            TxnState::RolledBack => {}
+            _ => panic!("expired primary should be rolled back"),
+        }
+        assert_eq!(store.resolve_lock(start_ts, None), 1);
+        assert!(store.read_lock(String::from("key5"), None, None).is_none());
+    }
+
+    #[test]
+    fn test_commit_batch() {
+        let temp_dir = TempDir::new().unwrap();
+        let store = MultiStore::new(temp_dir.path(), String::from("kvs"));
+        let start_ts = compose_ts(1000, 0);
+        let commit_ts = compose_ts(1010, 0);
+        let mutations: Vec<Mutation> = vec!["key1", "key2"]
+            .into_iter()
+            .map(|key| Mutation {
+                key: key.to_string(),
+                value: String::from("value"),
+                op: WriteOp::Put.into(),
+            })
+            .collect();
+        for mutation in mutations.iter() {
+            let primary = String::from("key1");
+            store.write_lock(mutation.key.clone(), start_ts, primary, WriteOp::Put, 100);
+        }
+        store.commit_batch(&mutations, start_ts, commit_ts).unwrap();
+        assert!(store.scan_locks(start_ts).is_empty());
+        // a retried batch finds its own commit records
+        store.commit_batch(&mutations, start_ts, commit_ts).unwrap();
+
+        // a lock rolled back by a reader fails the whole batch
+        let start_ts = compose_ts(2000, 0);
+        let commit_ts = compose_ts(2010, 0);
+        for mutation in mutations.iter() {
+            let primary = String::from("key1");
+            store.write_lock(mutation.key.clone(), start_ts, primary, WriteOp::Put, 100);
+        }
+        store.rollback(String::from("key2"), start_ts);
+        match store.commit_batch(&mutations, start_ts, commit_ts) {
+            Err(KvRpcError::Abort(_)) => {}
+            _ => panic!("a batch missing a lock should abort"),
+        }
+        assert_eq!(store.scan_locks(start_ts).len(), 1);
+
+        let mut unknown = mutations[..1].to_vec();
+        unknown[0].op = 42;
+        match store.commit_batch(&unknown, start_ts, commit_ts) {
+            Err(KvRpcError::InvalidArgument(_)) => {}
+            _ => panic!("an unknown op should be rejected"),
+        }
+    }
+
+    #[test]
+    fn test_lock_index() {
+        let temp_dir = TempDir::new().unwrap();
+        let store = MultiStore::new(temp_dir.path(), String::from("kvs"));
+        for (key, start_ts) in vec![("a", 20), ("b", 20), ("c", 10)] {
+            let primary = String::from("a");
+            store.write_lock(key.to_string(), start_ts, primary, WriteOp::Put, 100);
+        }
+        assert_eq!(store.min_lock_ts(), Some(10));
+        assert_eq!(store.scan_locks(20).len(), 2);
+
+        store.erase_lock(String::from("c"), 15);
+        assert_eq!(store.min_lock_ts(), Some(20));
+
+        // the index is rebuilt from the Lock column when reopened
+        drop(store);
+        let store = MultiStore::new(temp_dir.path(), String::from("kvs"));
+        assert_eq!(store.min_lock_ts(), Some(20));
+        store.rollback(String::from("a"), 20);
+        let locks = store.scan_locks(20);
+        assert_eq!(locks.len(), 1);
+        assert_eq!(locks[0].0.key(), "b");
+    }
+}
@@ -1,51 +1,217 @@
-use crate::Result;
+use crate::{KvError, Result};
 use std::{
+    cmp::max,
     fs,
     io::prelude::*,
     path::PathBuf,
-    sync::{
-        atomic::{AtomicU64, Ordering},
-        Arc,
-    },
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
 };
 
 static DEFAULT_FILE_NAME: &'static str = ".tso";
 
-/// A TimestampOracle
+/// Number of low bits in a timestamp used by the logical counter
+pub const LOGICAL_BITS: u32 = 18;
+
+/// How far ahead of the issued timestamps the persisted high-water mark is kept, in milliseconds
+const SAVE_WINDOW_MS: u64 = 3000;
+
+/// Compose a hybrid logical timestamp from physical milliseconds and a logical counter
+pub fn compose_ts(physical: u64, logical: u64) -> u64 {
+    physical << LOGICAL_BITS | logical
+}
+
+/// Extract the physical milliseconds of a hybrid logical timestamp
+pub fn physical_of(ts: u64) -> u64 {
+    ts >> LOGICAL_BITS
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+struct TsoState {
+    // the last timestamp handed out
+    last: u64,
+    // every timestamp handed out is below this persisted mark
+    high_water: u64,
+    // whether this oracle made the latest window and may serve timestamps from it
+    owned: bool,
+}
+
+impl TsoState {
+    fn try_fetch(&mut self, count: u64) -> Option<u64> {
+        if !self.owned {
+            return None;
+        }
+        let first = max(self.last + 1, compose_ts(now_ms(), 0));
+        let last = first + count - 1;
+        if last >= self.high_water {
+            return None;
+        }
+        self.last = last;
+        Some(first)
+    }
+    fn next_window(&self, count: u64) -> u64 {
+        let base = max(max(self.last, self.high_water), compose_ts(now_ms(), 0)) + count;
+        compose_ts(physical_of(base) + SAVE_WINDOW_MS, 0)
+    }
+    fn advance(&mut self, high_water: u64, owned: bool) {
+        // another oracle may have served anything below the old mark
+        if !self.owned {
+            self.last = max(self.last, self.high_water);
+        }
+        self.high_water = max(self.high_water, high_water);
+        self.owned = owned;
+    }
+}
+
+/// A TimestampOracle handing out `physical_ms << 18 | logical` timestamps
+///
+/// Only a high-water mark a few seconds ahead of the issued timestamps is
+/// persisted, and a restarted oracle starts above it, so timestamps stay
+/// monotonic across restarts without a disk write per call.
+///
+/// A replicated oracle persists nothing itself: the owner proposes windows
+/// through raft and applies them with `advance`, and serves only from the
+/// latest window it made.
 #[derive(Clone)]
 pub struct TimestampOracle {
-    inner: Arc<AtomicU64>,
-    path: PathBuf,
+    inner: Arc<Mutex<TsoState>>,
+    path: Option<PathBuf>,
 }
 
 impl TimestampOracle {
     /// Open a new TimestampOracle in the given path
     pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
         let path = path.into().join(DEFAULT_FILE_NAME);
-        let ts = restore(path.clone()).unwrap_or(1);
+        let high_water = restore(path.clone()).unwrap_or(0);
         Ok(Self {
-            inner: Arc::new(AtomicU64::new(ts)),
-            path,
+            inner: Arc::new(Mutex::new(TsoState {
+                last: high_water,
+                high_water,
+                owned: true,
+            })),
+            path: Some(path),
         })
     }
+    /// Create a TimestampOracle whose windows are replicated by the caller
+    pub fn replicated() -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(TsoState {
+                last: 0,
+                high_water: 0,
+                owned: false,
+            })),
+            path: None,
+        }
+    }
     /// fetch a timestamp from oracle
     pub fn fetch_one(&self) -> Result<u64> {
-        let ts = self.inner.fetch_add(1, Ordering::SeqCst);
-        backup(self.path.clone(), ts + 1)?;
-        Ok(ts)
+        self.fetch(1)
+    }
+    /// allocate `count` consecutive timestamps and return the first one,
+    /// persisting a new window if the current one is used up
+    pub fn fetch(&self, count: u32) -> Result<u64> {
+        let count = max(count, 1) as u64;
+        let mut state = self.inner.lock().unwrap();
+        if let Some(first) = state.try_fetch(count) {
+            return Ok(first);
+        }
+        let path = self.path.clone().ok_or_else(|| {
+            KvError::StringError(String::from(
+                "timestamp window of replicated oracle used up",
+            ))
+        })?;
+        let high_water = state.next_window(count);
+        backup(path, high_water)?;
+        state.advance(high_water, true);
+        Ok(state.try_fetch(count).unwrap())
+    }
+    /// allocate `count` consecutive timestamps only from the current window
+    pub fn try_fetch(&self, count: u32) -> Option<u64> {
+        self.inner.lock().unwrap().try_fetch(max(count, 1) as u64)
+    }
+    /// the high-water mark of a new window that fits `count` more timestamps
+    pub fn next_window(&self, count: u32) -> u64 {
+        self.inner.lock().unwrap().next_window(max(count, 1) as u64)
+    }
+    /// apply a window ending at `high_water`, `owned` if this oracle made it
+    pub fn advance(&self, high_water: u64, owned: bool) {
+        self.inner.lock().unwrap().advance(high_water, owned)
+    }
+    /// the high-water mark of the latest window
+    pub fn high_water(&self) -> u64 {
+        self.inner.lock().unwrap().high_water
+    }
+    /// the current hybrid logical time, without handing out a timestamp
+    pub fn current(&self) -> u64 {
+        let state = self.inner.lock().unwrap();
+        max(state.last, compose_ts(now_ms(), 0))
     }
 }
 
 fn backup(path: PathBuf, ts: u64) -> Result<()> {
-    let mut tso_file = fs::OpenOptions::new().create(true).write(true).open(path)?;
-    tso_file.write(ts.to_string().as_bytes())?;
-    tso_file.flush()?;
+    let mut tso_file = fs::OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(true)
+        .open(path)?;
+    tso_file.write_all(ts.to_string().as_bytes())?;
+    tso_file.sync_all()?;
     Ok(())
 }
 fn restore(path: PathBuf) -> Result<u64> {
     let mut tso_file = fs::OpenOptions::new().read(true).open(path)?;
     let mut buf = String::new();
     tso_file.read_to_string(&mut buf)?;
-    let ts: u64 = buf.parse().unwrap_or(1);
+    let ts: u64 = buf.parse().unwrap_or(0);
     Ok(ts)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_tso_batch_and_restart() {
+        let temp_dir = TempDir::new().unwrap();
+        let tso = TimestampOracle::open(temp_dir.path()).unwrap();
+        let first = tso.fetch(100).unwrap();
+        let next = tso.fetch_one().unwrap();
+        assert!(next >= first + 100);
+        assert!(physical_of(next) >= physical_of(first));
+        assert!(tso.current() >= next);
+
+        let restarted = TimestampOracle::open(temp_dir.path()).unwrap();
+        let after = restarted.fetch_one().unwrap();
+        assert!(after > next);
+        assert!(after > tso.high_water());
+    }
+
+    #[test]
+    fn test_tso_replicated_window() {
+        let leader = TimestampOracle::replicated();
+        let follower = TimestampOracle::replicated();
+        assert!(leader.try_fetch(1).is_none());
+
+        let old_window = leader.next_window(10);
+        leader.advance(old_window, true);
+        follower.advance(old_window, false);
+        let first = leader.try_fetch(10).unwrap();
+        assert!(first + 10 <= old_window);
+        assert!(follower.try_fetch(1).is_none());
+
+        // the follower takes over, it must start above the old window
+        let window = follower.next_window(1);
+        leader.advance(window, false);
+        follower.advance(window, true);
+        let next = follower.try_fetch(1).unwrap();
+        assert!(next > old_window);
+        assert!(leader.try_fetch(1).is_none());
+        assert_eq!(leader.high_water(), follower.high_water());
+    }
+}
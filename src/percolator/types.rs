@@ -1,10 +1,7 @@
-use crate::rpc::kvs_service::WriteOp;
-use chrono::prelude::*;
-use std::{
-    fmt::Display,
-    str::FromStr,
-    time::{Duration, SystemTime},
-};
+use super::tso::physical_of;
+use crate::rpc::kvs_service::{Mutation, WriteOp};
+use crate::KvRpcError;
+use std::{fmt::Display, str::FromStr};
 
 pub enum Column {
     Write,
@@ -44,10 +41,11 @@ impl FromStr for Key {
     type Err = ();
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let mut splited = s.rsplit_terminator("-");
-        let ts = splited.next().unwrap().parse().unwrap();
-        let key = splited.collect();
-        Ok(Key { key, ts })
+        let (key, ts) = s.rsplit_once('-').unwrap();
+        Ok(Key {
+            key: key.to_string(),
+            ts: ts.parse().unwrap(),
+        })
     }
 }
 
@@ -90,22 +88,26 @@ enum LockType {
     RollBack,
 }
 
+/// Lock ttl in milliseconds used when a client does not choose one
+pub const DEFAULT_LOCK_TTL: u64 = 3000;
+
 /// A LockValue struct
+///
+/// The ttl is counted in TSO physical time from the start ts of the txn owning
+/// this lock, so every replica makes the same expiry decision for the same
+/// timestamps regardless of its local clock.
 #[derive(Clone)]
 pub struct LockValue {
     primary: String,
-    ttl: DateTime<Utc>,
+    ttl: u64,
     op: WriteOp,
 }
 
 impl LockValue {
-    /// Create a new LockValue struct
-    pub fn new(primary: String, op: WriteOp) -> Self {
-        Self {
-            primary,
-            ttl: SystemTime::now().into(),
-            op,
-        }
+    /// Create a new LockValue struct, which expires `ttl` milliseconds after its start ts
+    pub fn new(primary: String, op: WriteOp, ttl: u64) -> Self {
+        let ttl = if ttl == 0 { DEFAULT_LOCK_TTL } else { ttl };
+        Self { primary, ttl, op }
     }
     /// Get the string value of primary
     pub fn primary(&self) -> String {
@@ -115,15 +117,17 @@ impl LockValue {
     pub fn op(&self) -> WriteOp {
         self.op
     }
-    /// Compute how long this key is elapsed
-    pub fn elapsed(&self) -> Duration {
-        let system_now = SystemTime::now();
-        let ttl: SystemTime = self.ttl.into();
-        system_now.duration_since(ttl).expect("Time backward!")
+    /// Get the ttl of this lock in milliseconds
+    pub fn ttl(&self) -> u64 {
+        self.ttl
     }
-    /// reset ttl
-    pub fn reset_ttl(&mut self) {
-        self.ttl = SystemTime::now().into();
+    /// Extend the ttl, a lock never shrinks its ttl
+    pub fn extend_ttl(&mut self, ttl: u64) {
+        self.ttl = self.ttl.max(ttl);
+    }
+    /// Whether a lock written at `start_ts` outlives its ttl at `current_ts`
+    pub fn is_expired(&self, start_ts: u64, current_ts: u64) -> bool {
+        physical_of(current_ts) >= physical_of(start_ts).saturating_add(self.ttl)
     }
 }
 
@@ -137,10 +141,10 @@ impl FromStr for LockValue {
     type Err = ();
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let mut splited = s.rsplit_terminator("~");
+        let mut splited = s.rsplitn(3, "~");
         let op = splited.next().unwrap().parse().unwrap();
         let ttl = splited.next().unwrap().parse().unwrap();
-        let primary = splited.collect();
+        let primary = splited.next().unwrap_or_default().to_string();
         Ok(LockValue { primary, ttl, op })
     }
 }
@@ -151,10 +155,17 @@ impl Display for WriteOp {
             WriteOp::Put => write!(f, "Put"),
             WriteOp::Lock => write!(f, "Lock"),
             WriteOp::Delete => write!(f, "Delete"),
+            WriteOp::Rollback => write!(f, "Rollback"),
         }
     }
 }
 
+/// The op of a mutation sent by a client, which may not be one of ours
+pub fn mutation_op(mutation: &Mutation) -> Result<WriteOp, KvRpcError> {
+    WriteOp::from_i32(mutation.op)
+        .ok_or_else(|| KvRpcError::InvalidArgument(format!("unknown write op {}", mutation.op)))
+}
+
 impl FromStr for WriteOp {
     type Err = ();
 
@@ -163,6 +174,7 @@ impl FromStr for WriteOp {
             "Put" => Ok(WriteOp::Put),
             "Lock" => Ok(WriteOp::Lock),
             "Delete" => Ok(WriteOp::Delete),
+            "Rollback" => Ok(WriteOp::Rollback),
             _ => Err(()),
         }
     }
@@ -211,18 +223,27 @@ impl FromStr for WriteValue {
 
 #[cfg(test)]
 mod tests {
-    use std::{thread::sleep, time::Duration};
-
+    use super::super::tso::compose_ts;
     use super::*;
     #[test]
     fn test_lock_value() {
         assert_eq!(2, 1 + 1);
-        let value = LockValue::new(String::from("some value"), WriteOp::Put);
+        let value = LockValue::new(String::from("some~value"), WriteOp::Put, 500);
         let ss = value.to_string();
         println!("{}", ss);
-        let new_value = LockValue::from_str(&ss).unwrap();
+        let mut new_value = LockValue::from_str(&ss).unwrap();
         println!("{}", new_value);
-        sleep(Duration::from_secs(1));
-        println!("{:?}", new_value.elapsed());
+        assert_eq!(new_value.primary(), "some~value");
+        assert_eq!(new_value.ttl(), 500);
+        let start_ts = compose_ts(10_000, 3);
+        assert!(!new_value.is_expired(start_ts, compose_ts(10_499, 0)));
+        assert!(new_value.is_expired(start_ts, compose_ts(10_500, 0)));
+        // a clock behind the start ts never expires the lock
+        assert!(!new_value.is_expired(start_ts, compose_ts(9_000, 0)));
+        new_value.extend_ttl(100);
+        assert_eq!(new_value.ttl(), 500);
+        new_value.extend_ttl(2000);
+        assert!(!new_value.is_expired(start_ts, compose_ts(10_500, 0)));
+        assert!(new_value.is_expired(start_ts, compose_ts(12_000, 0)));
     }
 }
@@ -0,0 +1,59 @@
+use std::time::{Duration, Instant};
+
+/// When a replica snapshots its state and drops the raft log the snapshot
+/// covers. A snapshot is due once any threshold set is crossed, so with none
+/// set the log is never compacted.
+#[derive(Debug, Clone, Default)]
+pub struct SnapshotPolicy {
+    /// the persisted raft state, log included, reaches this many bytes
+    pub max_log_bytes: Option<u64>,
+    /// this many entries were applied since the last snapshot
+    pub max_log_entries: Option<u64>,
+    /// this long passed since the last snapshot
+    pub interval: Option<Duration>,
+    /// a leader waits for every follower to replicate what the snapshot
+    /// covers, so that none has to be sent it
+    pub followers_caught_up: bool,
+}
+
+impl SnapshotPolicy {
+    /// Whether a snapshot is due with `log_bytes` of raft state persisted and
+    /// `entries` applied since the last snapshot, taken at `last`
+    pub fn due(&self, log_bytes: u64, entries: u64, last: Instant) -> bool {
+        entries > 0
+            && (self.max_log_bytes.is_some_and(|max| log_bytes >= max)
+                || self.max_log_entries.is_some_and(|max| entries >= max)
+                || self
+                    .interval
+                    .is_some_and(|interval| last.elapsed() >= interval))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_due() {
+        let now = Instant::now();
+        assert!(!SnapshotPolicy::default().due(u64::MAX, u64::MAX, now));
+
+        let policy = SnapshotPolicy {
+            max_log_bytes: Some(4096),
+            max_log_entries: Some(100),
+            ..SnapshotPolicy::default()
+        };
+        assert!(!policy.due(1024, 10, now));
+        assert!(policy.due(4096, 10, now));
+        assert!(policy.due(1024, 100, now));
+        // nothing applied since the last snapshot is left to compact
+        assert!(!policy.due(4096, 0, now));
+
+        let policy = SnapshotPolicy {
+            interval: Some(Duration::from_secs(60)),
+            ..SnapshotPolicy::default()
+        };
+        assert!(!policy.due(0, 1, now));
+        assert!(policy.due(0, 1, now - Duration::from_secs(60)));
+    }
+}
@@ -1,20 +1,29 @@
 use std::{
     collections::HashMap,
+    io,
     sync::Arc,
     sync::{
-        atomic::{AtomicU64, Ordering},
+        atomic::{AtomicBool, AtomicU64, Ordering},
         Mutex,
     },
     task::Poll,
     thread,
-    time::Duration,
+    time::{Duration, Instant},
 };
 
-use crate::{percolator::TimestampOracle, rpc::kvs_service::*, KvRpcError, MultiStore};
+use crate::{
+    percolator::{mutation_op, TimestampOracle},
+    rpc::kvs_service::{raft_command::Command, *},
+    rpc::raft_service::Member,
+    KvError, KvRpcError, MultiStore,
+};
 use prost::Message;
 use tonic::{Request, Response, Status};
 
-use super::{persister, raft::ApplyMsg};
+use super::{
+    persister::{self, Staging},
+    raft::ApplyMsg,
+};
 use tokio::{
     runtime::Builder,
     sync::{
@@ -23,22 +32,82 @@ use tokio::{
     },
     time::timeout,
 };
-use tokio_stream::{Stream, StreamExt};
+use tokio_stream::{wrappers::UnboundedReceiverStream, Stream, StreamExt};
 
-use super::raft;
+use super::{raft, ConfChange, SnapshotPolicy};
 
 type RpcResult<T> = std::result::Result<T, KvRpcError>;
 
+/// How many keys of each column one record of a snapshot holds, restored a
+/// record at a time
+const SNAPSHOT_RECORD_KEYS: usize = 1024;
+
+/// Writes `data` exported from a store to the snapshot staged as
+/// `Staging::Generated`, as length delimited `Snapshot` records, the first
+/// of which also carries `head`
+fn write_snapshot(
+    persister: &dyn persister::Persister,
+    data: Vec<Vec<String>>,
+    mut head: Snapshot,
+) -> io::Result<()> {
+    let records = data
+        .iter()
+        .map(|column| column.len().div_ceil(SNAPSHOT_RECORD_KEYS))
+        .max()
+        .unwrap_or(0)
+        .max(1);
+    let mut offset = 0;
+    for record in 0..records {
+        let column = |i: usize| -> Vec<String> {
+            data[i]
+                .iter()
+                .skip(record * SNAPSHOT_RECORD_KEYS)
+                .take(SNAPSHOT_RECORD_KEYS)
+                .cloned()
+                .collect()
+        };
+        let snapshot = Snapshot {
+            d_keys: column(0),
+            d_values: column(1),
+            l_keys: column(2),
+            l_values: column(3),
+            w_keys: column(4),
+            w_values: column(5),
+            ..std::mem::take(&mut head)
+        };
+        let mut buf = Vec::new();
+        snapshot.encode_length_delimited(&mut buf).unwrap();
+        persister.stage_snapshot(Staging::Generated, offset, &buf)?;
+        offset += buf.len() as u64;
+    }
+    Ok(())
+}
+
 pub struct KvRaftInner {
     pub rf: raft::RaftNode,
     me: usize,
-    // snapshot if log grows this big
-    maxraftstate: Option<usize>,
+    // when to snapshot and compact the log
+    policy: SnapshotPolicy,
+    persister: Arc<dyn persister::Persister>,
+    // the last index applied to the store
+    applied_index: u64,
+    snapshot_at: Instant,
+    // set while a snapshot is written out, one at a time
+    snapshotting: Arc<AtomicBool>,
     apply_ch: UnboundedReceiver<ApplyMsg>,
 
     // DB
     store: MultiStore,
+    ts_oracle: TimestampOracle,
+    // identifies the windows this incarnation proposed
+    tso_id: u64,
+    tso_waiters: Vec<(TsRequest, Sender<RpcResult<TsReply>>)>,
+    // timestamp requests waiting for leadership to be confirmed, by id
+    tso_reads: HashMap<u64, (TsRequest, Sender<RpcResult<TsReply>>)>,
+    tso_read_id: u64,
     pending: HashMap<(u64, u64), KvEvent>,
+    // heartbeats by (start_ts, seq), apart from the dedupe of the txn itself
+    heart_beats: HashMap<(u64, u64), Sender<RpcResult<TxnHeartBeatReply>>>,
     last_index: HashMap<u64, Arc<AtomicU64>>,
     // Stream
     receiver: UnboundedReceiver<KvEvent>,
@@ -61,142 +130,286 @@ impl KvRaftInner {
         rf: raft::RaftNode,
         me: usize,
         persister: Arc<dyn persister::Persister>,
-        maxraftstate: Option<usize>,
+        policy: SnapshotPolicy,
         receiver: UnboundedReceiver<KvEvent>,
         apply_ch: UnboundedReceiver<ApplyMsg>,
     ) -> KvRaftInner {
-        let snapshot = persister.snapshot();
-
+        let applied_index = rf.last_included_index();
         let mut server = KvRaftInner {
             me,
             rf,
-            maxraftstate,
+            policy,
+            persister,
+            applied_index,
+            snapshot_at: Instant::now(),
+            snapshotting: Arc::new(AtomicBool::new(false)),
             apply_ch,
             store,
+            ts_oracle: TimestampOracle::replicated(),
+            tso_id: rand::random(),
+            tso_waiters: Vec::new(),
+            tso_reads: HashMap::new(),
+            tso_read_id: 0,
             pending: HashMap::new(),
+            heart_beats: HashMap::new(),
             last_index: HashMap::new(),
             receiver,
         };
-        server.restore_from_snapshot(snapshot);
+        server.restore_from_snapshot();
         server
     }
 
-    fn create_snapshot(&self) -> Vec<u8> {
+    /// Takes a snapshot once the policy finds one due. The state is exported
+    /// here, between two entries applied, while writing it out and compacting
+    /// the log happen on another thread.
+    fn maybe_snapshot(&mut self) {
+        let entries = self
+            .applied_index
+            .saturating_sub(self.rf.last_included_index());
+        if self.snapshotting.load(Ordering::SeqCst)
+            || !self
+                .policy
+                .due(self.rf.raft_state_size(), entries, self.snapshot_at)
+            || self.policy.followers_caught_up
+                && self.rf.is_leader()
+                && self.rf.replicated_index() < self.applied_index
+        {
+            return;
+        }
+        info!("{} take a snapshot at {}", self, self.applied_index);
+        self.snapshotting.store(true, Ordering::SeqCst);
+        self.snapshot_at = Instant::now();
         let data = self.store.export().unwrap();
-        let snapshot = Snapshot {
-            d_keys: data.get(0).unwrap().clone(),
-            d_values: data.get(1).unwrap().clone(),
-            l_keys: data.get(2).unwrap().clone(),
-            l_values: data.get(3).unwrap().clone(),
-            w_keys: data.get(4).unwrap().clone(),
-            w_values: data.get(5).unwrap().clone(),
+        let head = Snapshot {
             timestamps: self.last_index.keys().cloned().collect(),
             seqs: self
                 .last_index
                 .values()
-                .cloned()
                 .map(|v| v.load(Ordering::SeqCst))
                 .collect(),
+            tso_high_water: self.ts_oracle.high_water(),
+            gc_safe_point: self.store.gc_safe_point(),
+            ..Snapshot::default()
         };
-        let mut buf = Vec::new();
-        snapshot.encode(&mut buf).unwrap();
-        buf
-    }
-
-    fn restore_from_snapshot(&mut self, snapshot: Vec<u8>) {
-        if let Ok(Snapshot {
-            d_keys,
-            d_values,
-            l_keys,
-            l_values,
-            w_keys,
-            w_values,
-            timestamps,
-            seqs,
-        }) = Snapshot::decode(&*snapshot)
-        {
+        let applied_index = self.applied_index;
+        let persister = self.persister.clone();
+        let rf = self.rf.clone();
+        let snapshotting = self.snapshotting.clone();
+        thread::Builder::new()
+            .name(format!("KvSnapshot-{}", self.me))
+            .spawn(move || {
+                match write_snapshot(&*persister, data, head) {
+                    // the next snapshot is staged once raft took this one
+                    Ok(()) => {
+                        let _ = rf.start_snapshot(applied_index).blocking_recv();
+                    }
+                    // the log is kept until a later snapshot is written
+                    Err(e) => warn!("Unable to write the snapshot at {}: {}", applied_index, e),
+                }
+                snapshotting.store(false, Ordering::SeqCst);
+            })
+            .unwrap();
+    }
+
+    /// Restores the persisted snapshot a record at a time, so only one
+    /// record of it is in memory.
+    fn restore_from_snapshot(&mut self) {
+        let len = self
+            .persister
+            .snapshot_len()
+            .unwrap_or_else(|e| panic!("{} Unable to read the snapshot: {}", self, e));
+        if len == 0 {
+            // nothing is compacted yet, the whole log is applied to an empty store
+            self.store.import(vec![Vec::new(); 6]).unwrap();
+            self.last_index.clear();
+        }
+        let mut offset = 0;
+        while offset < len {
+            // a record starts with its length, a varint of up to 10 bytes
+            let head = self.read_snapshot(offset, 10);
+            let mut buf = &head[..];
+            let record_len = match prost::encoding::decode_varint(&mut buf) {
+                Ok(record_len) => record_len,
+                Err(_) => break,
+            };
+            let start = offset + (head.len() - buf.len()) as u64;
+            let record = self.read_snapshot(start, record_len as usize);
+            let Snapshot {
+                d_keys,
+                d_values,
+                l_keys,
+                l_values,
+                w_keys,
+                w_values,
+                timestamps,
+                seqs,
+                tso_high_water,
+                gc_safe_point,
+            } = match Snapshot::decode(&*record) {
+                Ok(snapshot) => snapshot,
+                Err(_) => break,
+            };
             let data = vec![d_keys, d_values, l_keys, l_values, w_keys, w_values];
-            self.store.import(data).unwrap();
-            let last_index: HashMap<u64, Arc<AtomicU64>> = timestamps
-                .into_iter()
-                .zip(seqs.into_iter().map(|v| Arc::new(AtomicU64::new(v))))
-                .collect();
-            self.last_index = last_index;
+            if offset > 0 {
+                self.store.extend(data);
+            } else {
+                self.store.import(data).unwrap();
+                let last_index: HashMap<u64, Arc<AtomicU64>> = timestamps
+                    .into_iter()
+                    .zip(seqs.into_iter().map(|v| Arc::new(AtomicU64::new(v))))
+                    .collect();
+                self.last_index = last_index;
+                self.ts_oracle.advance(tso_high_water, false);
+                // the imported versions are compacted already, only the safe point moves
+                if let Err(e) = self.store.set_safe_point(gc_safe_point) {
+                    warn!(
+                        "{} Unable to save the GC safe point {}: {}",
+                        self, gc_safe_point, e
+                    );
+                }
+            }
+            offset = start + record_len;
         }
     }
+
+    /// A store half restored from the snapshot must not serve, so a snapshot
+    /// that cannot be read stops the node.
+    fn read_snapshot(&self, offset: u64, len: usize) -> Vec<u8> {
+        self.persister
+            .read_snapshot(offset, len)
+            .unwrap_or_else(|e| panic!("{} Unable to read the snapshot: {}", self, e))
+    }
+
     fn handle_apply_msg(&mut self, msg: ApplyMsg) {
-        if msg.command.is_empty() {
+        if !msg.command_valid {
+            debug!("{} recv [Snapshot {}]", self, msg.command_index);
+            self.restore_from_snapshot();
+            self.applied_index = msg.command_index;
             return;
         }
-        if !msg.command_valid {
-            debug!("{} recv [Snapshot]", self);
-            self.restore_from_snapshot(msg.command);
+        self.applied_index = self.applied_index.max(msg.command_index);
+        if msg.command.is_empty() {
             return;
         }
         info!(
             "{} recv [ApplyMsg {} {}]",
             self, msg.command_valid, msg.command_index
         );
-        let _index = msg.command_index;
-        if let Ok(req) = GetRequest::decode(&*msg.command) {
-            self.handle_txn_get(req);
-        }
-        if let Ok(req) = PrewriteRequest::decode(&*msg.command) {
-            self.handle_txn_prewrite(req);
-        }
-        if let Ok(req) = CommitRequest::decode(&*msg.command) {
-            self.handle_txn_commit(req);
+        if let Ok(RaftCommand {
+            command: Some(command),
+        }) = RaftCommand::decode(&*msg.command)
+        {
+            match command {
+                Command::Get(req) => self.handle_txn_get(req),
+                Command::Prewrite(req) => self.handle_txn_prewrite(req),
+                Command::Commit(req) => self.handle_txn_commit(req),
+                Command::BatchPrewrite(req) => self.handle_txn_batch_prewrite(req),
+                Command::BatchCommit(req) => self.handle_txn_batch_commit(req),
+                Command::HeartBeat(req) => self.handle_txn_heart_beat(req),
+                Command::Rollback(req) => self.handle_txn_rollback(req),
+                Command::TsoWindow(window) => self.handle_tso_window(window),
+                Command::CheckTxnStatus(req) => self.handle_txn_check_txn_status(req),
+                Command::ResolveLock(req) => self.handle_txn_resolve_lock(req),
+                Command::BatchGet(req) => self.handle_txn_batch_get(req),
+                Command::Scan(req) => self.handle_txn_scan(req),
+                Command::Gc(req) => self.handle_txn_gc(req),
+                Command::TsoConfirm(confirm) => self.handle_tso_confirm(confirm),
+            }
+            // applied entries drive the change feed alike on every replica
+            self.store.publish_changes();
         }
     }
 }
 
 impl KvRaftInner {
     fn handle_txn_get(&mut self, req: GetRequest) {
-        let tx = {
-            if let Some(KvEvent::TxnGet(_args, tx)) = self.pending.remove(&(req.ts, req.seq)) {
-                Some(tx)
-            } else {
-                None
-            }
+        let key = (req.ts, req.seq);
+        let tx = match self.pending.remove(&key) {
+            Some(KvEvent::TxnGet(_args, tx)) => Some(tx),
+            other => self.restore_pending(key, other),
         };
-        loop {
-            if self
-                .store
-                .read_lock(req.key.clone(), None, Some(req.ts))
-                .is_some()
-            {
-                self.lock_back_off_or_clean_up(req.key.clone(), req.ts);
-                continue;
+        if let Err(e) = self.store.check_safe_point(req.ts) {
+            if let Some(tx) = tx {
+                tx.send(Err(e)).unwrap_or(());
             }
-            let last_write = self.store.read_write(req.key.clone(), None, Some(req.ts));
-            if last_write.is_none() || last_write.clone().unwrap().1.op() == WriteOp::Delete {
-                tx.map(|tx| tx.send(Err(KvRpcError::KeyNotFound)).unwrap());
+            return;
+        }
+        // leave the lock to the reader, which resolves it or backs off
+        let (message, lock) = match self.store.read_at(req.key, req.ts, req.ts) {
+            Ok(Some(value)) => (value, None),
+            Ok(None) => {
+                if let Some(tx) = tx {
+                    tx.send(Err(KvRpcError::KeyNotFound)).unwrap_or(());
+                }
                 return;
             }
-            let start_ts = last_write.unwrap().1.ts();
-            let value = self
-                .store
-                .read_data(req.key.clone(), Some(start_ts), Some(start_ts))
-                .unwrap()
-                .1
-                .value();
-            let reply = GetReply {
-                message: value,
-                ts: req.ts,
-                seq: req.seq,
-            };
-            tx.map(|tx| tx.send(Ok(reply)).unwrap());
-
+            Err(lock) => (String::new(), Some(lock)),
+        };
+        let reply = GetReply {
+            message,
+            ts: req.ts,
+            seq: req.seq,
+            lock,
+        };
+        if let Some(tx) = tx {
+            tx.send(Ok(reply)).unwrap_or(());
+        }
+    }
+    fn handle_txn_batch_get(&mut self, req: BatchGetRequest) {
+        let key = (req.ts, req.seq);
+        let tx = match self.pending.remove(&key) {
+            Some(KvEvent::TxnBatchGet(_args, tx)) => Some(tx),
+            other => self.restore_pending(key, other),
+        };
+        if let Err(e) = self.store.check_safe_point(req.ts) {
+            if let Some(tx) = tx {
+                tx.send(Err(e)).unwrap_or(());
+            }
             return;
         }
+        let start_ts = Some(req.start_ts).filter(|ts| *ts != 0).unwrap_or(req.ts);
+        let (pairs, locks) = self.store.batch_get(req.keys, req.ts, start_ts);
+        let reply = BatchGetReply {
+            pairs,
+            locks,
+            ts: req.ts,
+            seq: req.seq,
+        };
+        if let Some(tx) = tx {
+            tx.send(Ok(reply)).unwrap_or(());
+        }
     }
-    fn handle_txn_prewrite(&mut self, req: PrewriteRequest) {
-        let tx = {
-            if let Some(KvEvent::TxnPrewrite(_args, tx)) = self.pending.remove(&(req.ts, req.seq)) {
-                Some(tx)
-            } else {
-                None
+    fn handle_txn_scan(&mut self, req: ScanRequest) {
+        let key = (req.ts, req.seq);
+        let tx = match self.pending.remove(&key) {
+            Some(KvEvent::TxnScan(_args, tx)) => Some(tx),
+            other => self.restore_pending(key, other),
+        };
+        if let Err(e) = self.store.check_safe_point(req.ts) {
+            if let Some(tx) = tx {
+                tx.send(Err(e)).unwrap_or(());
             }
+            return;
+        }
+        let start_ts = Some(req.start_ts).filter(|ts| *ts != 0).unwrap_or(req.ts);
+        let (pairs, lock) =
+            self.store
+                .scan(req.start, req.end, req.limit as usize, req.ts, start_ts);
+        let reply = ScanReply {
+            pairs,
+            lock,
+            ts: req.ts,
+            seq: req.seq,
+        };
+        if let Some(tx) = tx {
+            tx.send(Ok(reply)).unwrap_or(());
+        }
+    }
+    fn handle_txn_prewrite(&mut self, req: PrewriteRequest) {
+        let key = (req.ts, req.seq);
+        let tx = match self.pending.remove(&key) {
+            Some(KvEvent::TxnPrewrite(_args, tx)) => Some(tx),
+            other => self.restore_pending(key, other),
         };
         if self.check_duplicate(req.ts, req.seq).is_err() {
             return;
@@ -228,9 +441,11 @@ impl KvRaftInner {
                 req.ts,
                 req.primary.clone(),
                 WriteOp::from_i32(req.op).unwrap(),
+                req.lock_ttl,
             );
             // for update primary ttl
-            self.store.update_lock(req.primary.clone(), req.ts);
+            self.store
+                .update_lock(req.primary.clone(), req.ts, req.lock_ttl);
             let reply = PrewriteReply {
                 ok: true,
                 ts: req.ts,
@@ -240,14 +455,10 @@ impl KvRaftInner {
         }
     }
     fn handle_txn_commit(&mut self, req: CommitRequest) {
-        let tx = {
-            if let Some(KvEvent::TxnCommit(_args, tx)) =
-                self.pending.remove(&(req.commit_ts, req.seq))
-            {
-                Some(tx)
-            } else {
-                None
-            }
+        let key = (req.commit_ts, req.seq);
+        let tx = match self.pending.remove(&key) {
+            Some(KvEvent::TxnCommit(_args, tx)) => Some(tx),
+            other => self.restore_pending(key, other),
         };
         if self.check_duplicate(req.commit_ts, req.seq).is_err() {
             return;
@@ -282,9 +493,312 @@ impl KvRaftInner {
             tx.map(|tx| tx.send(Ok(reply)).unwrap());
         }
     }
+    // Batches are sent concurrently by the client and may be applied out of
+    // `seq` order, so they rely on being idempotent instead of `last_index`.
+    fn handle_txn_batch_prewrite(&mut self, req: BatchPrewriteRequest) {
+        let key = (req.ts, req.seq);
+        let tx = match self.pending.remove(&key) {
+            Some(KvEvent::TxnBatchPrewrite(_args, tx)) => Some(tx),
+            other => self.restore_pending(key, other),
+        };
+        // index entries are locked under the same primary as the base write
+        let index_mutations = self.store.index_mutations(&req.mutations, req.ts);
+        let mutations: Vec<Mutation> = req
+            .mutations
+            .iter()
+            .chain(index_mutations.iter())
+            .cloned()
+            .collect();
+        let mut ops = Vec::new();
+        for mutation in mutations.iter() {
+            match mutation_op(mutation) {
+                Ok(op) => ops.push(op),
+                Err(e) => {
+                    if let Some(tx) = tx {
+                        tx.send(Err(e)).unwrap_or(());
+                    }
+                    return;
+                }
+            }
+            if self
+                .store
+                .read_write(mutation.key.clone(), Some(req.ts), None)
+                .is_some()
+            {
+                if let Some(tx) = tx {
+                    tx.send(Err(KvRpcError::Abort(String::from("find write after ts"))))
+                        .unwrap_or(());
+                }
+                return;
+            }
+            // a lock left by this txn means the batch was already prewritten
+            if let Some((lock_key, _)) = self.store.read_lock(mutation.key.clone(), None, None) {
+                if lock_key.ts() != req.ts {
+                    if let Some(tx) = tx {
+                        tx.send(Err(KvRpcError::Abort(String::from("find another lock"))))
+                            .unwrap_or(());
+                    }
+                    return;
+                }
+            }
+        }
+        for (mutation, op) in mutations.into_iter().zip(ops) {
+            self.store
+                .write_data(mutation.key.clone(), req.ts, mutation.value);
+            self.store
+                .write_lock(mutation.key, req.ts, req.primary.clone(), op, req.lock_ttl);
+        }
+        // for update primary ttl
+        self.store
+            .update_lock(req.primary.clone(), req.ts, req.lock_ttl);
+        let reply = BatchPrewriteReply {
+            ok: true,
+            ts: req.ts,
+            seq: req.seq,
+            index_mutations,
+        };
+        if let Some(tx) = tx {
+            tx.send(Ok(reply)).unwrap_or(());
+        }
+    }
+    fn handle_txn_batch_commit(&mut self, req: BatchCommitRequest) {
+        let key = (req.commit_ts, req.seq);
+        let tx = match self.pending.remove(&key) {
+            Some(KvEvent::TxnBatchCommit(_args, tx)) => Some(tx),
+            other => self.restore_pending(key, other),
+        };
+        let reply = self
+            .store
+            .commit_batch(&req.mutations, req.start_ts, req.commit_ts)
+            .map(|()| BatchCommitReply {
+                ok: true,
+                ts: req.commit_ts,
+                seq: req.seq,
+            });
+        if let Some(tx) = tx {
+            tx.send(reply).unwrap_or(());
+        }
+    }
+    fn handle_txn_heart_beat(&mut self, req: TxnHeartBeatRequest) {
+        let tx = self.heart_beats.remove(&(req.start_ts, req.seq));
+        let reply =
+            match self
+                .store
+                .heart_beat_lock(req.primary.clone(), req.start_ts, req.advise_lock_ttl)
+            {
+                Some(lock_ttl) => Ok(TxnHeartBeatReply {
+                    lock_ttl,
+                    ts: req.start_ts,
+                    seq: req.seq,
+                }),
+                None => Err(KvRpcError::Abort(String::from("primary lock missing"))),
+            };
+        if let Some(tx) = tx {
+            tx.send(reply).unwrap_or(());
+        }
+    }
+    fn handle_txn_rollback(&mut self, req: RollbackRequest) {
+        let key = (req.start_ts, req.seq);
+        let tx = match self.pending.remove(&key) {
+            Some(KvEvent::TxnRollback(_args, tx)) => Some(tx),
+            other => self.restore_pending(key, other),
+        };
+        for key in req.keys.iter() {
+            if let Some((_write_key, write_value)) =
+                self.store.read_write_of_txn(key.clone(), req.start_ts)
+            {
+                if write_value.op() != WriteOp::Rollback {
+                    if let Some(tx) = tx {
+                        tx.send(Err(KvRpcError::Abort(String::from(
+                            "txn already committed",
+                        ))))
+                        .unwrap_or(());
+                    }
+                    return;
+                }
+            }
+        }
+        for key in req.keys {
+            self.store.rollback(key, req.start_ts);
+        }
+        let reply = RollbackReply {
+            ok: true,
+            ts: req.start_ts,
+            seq: req.seq,
+        };
+        if let Some(tx) = tx {
+            tx.send(Ok(reply)).unwrap_or(());
+        }
+    }
+}
+
+impl KvRaftInner {
+    fn handle_txn_check_txn_status(&mut self, req: CheckTxnStatusRequest) {
+        let key = (req.ts, req.seq);
+        let tx = match self.pending.remove(&key) {
+            Some(KvEvent::TxnCheckTxnStatus(_args, tx)) => Some(tx),
+            other => self.restore_pending(key, other),
+        };
+        // current_ts comes from the log, so every replica judges expiry alike
+        let reply = self
+            .store
+            .check_txn_status(req.primary, req.lock_ts, req.lock_ttl, req.current_ts)
+            .into_reply(req.ts, req.seq);
+        if let Some(tx) = tx {
+            tx.send(Ok(reply)).unwrap_or(());
+        }
+    }
+    fn handle_txn_resolve_lock(&mut self, req: ResolveLockRequest) {
+        let key = (req.ts, req.seq);
+        let tx = match self.pending.remove(&key) {
+            Some(KvEvent::TxnResolveLock(_args, tx)) => Some(tx),
+            other => self.restore_pending(key, other),
+        };
+        let commit_ts = Some(req.commit_ts).filter(|ts| *ts != 0);
+        let resolved = self.store.resolve_lock(req.start_ts, commit_ts);
+        let reply = ResolveLockReply {
+            resolved: resolved as u64,
+            ts: req.ts,
+            seq: req.seq,
+        };
+        if let Some(tx) = tx {
+            tx.send(Ok(reply)).unwrap_or(());
+        }
+    }
+}
+
+impl KvRaftInner {
+    fn handle_txn_gc(&mut self, req: GcRequest) {
+        let key = (req.ts, req.seq);
+        let tx = match self.pending.remove(&key) {
+            Some(KvEvent::TxnGc(_args, tx)) => Some(tx),
+            other => self.restore_pending(key, other),
+        };
+        let reply = match self.store.gc(req.safe_point) {
+            Ok(removed) => Ok(GcReply {
+                removed: removed as u64,
+                safe_point: self.store.gc_safe_point(),
+                ts: req.ts,
+                seq: req.seq,
+            }),
+            Err(e) => {
+                warn!("Unable to save the GC safe point {}: {}", req.safe_point, e);
+                Err(KvRpcError::Unknown(e.to_string()))
+            }
+        };
+        if let Some(tx) = tx {
+            tx.send(reply).unwrap_or(());
+        }
+    }
 }
 
 impl KvRaftInner {
+    fn handle_tso_window(&mut self, window: TsoWindow) {
+        let owned = window.proposer == self.tso_id;
+        self.ts_oracle.advance(window.high_water, owned);
+        for (req, tx) in std::mem::take(&mut self.tso_waiters) {
+            if !owned {
+                // another node serves timestamps now
+                tx.send(Err(KvRpcError::NotLeader)).unwrap_or(());
+            } else if let Some(ts) = self.ts_oracle.try_fetch(req.count) {
+                let reply = TsReply {
+                    name: req.name,
+                    ts,
+                    count: req.count.max(1),
+                };
+                tx.send(Ok(reply)).unwrap_or(());
+            } else {
+                // left for the window proposed on its behalf
+                self.tso_waiters.push((req, tx));
+            }
+        }
+    }
+}
+
+impl KvRaftInner {
+    fn handle_tso_confirm(&mut self, confirm: TsoConfirm) {
+        let (req, tx) = match self.tso_reads.remove(&confirm.id) {
+            Some(read) => read,
+            None => return,
+        };
+        if !self.rf.is_leader() {
+            tx.send(Err(KvRpcError::NotLeader)).unwrap_or(());
+        } else if let Some(ts) = self.ts_oracle.try_fetch(req.count) {
+            let reply = TsReply {
+                name: req.name,
+                ts,
+                count: req.count.max(1),
+            };
+            tx.send(Ok(reply)).unwrap_or(());
+        } else if let Ok((_index, _term)) = self.rf.start(&RaftCommand {
+            command: Some(Command::TsoWindow(TsoWindow {
+                high_water: self.ts_oracle.next_window(req.count),
+                proposer: self.tso_id,
+            })),
+        }) {
+            self.tso_waiters.push((req, tx));
+        } else {
+            tx.send(Err(KvRpcError::NotLeader)).unwrap_or(());
+        }
+    }
+}
+
+impl KvRaftInner {
+    /// Puts back an event taken for the reply of another kind of request
+    /// sharing its (ts, seq), which its own request still waits on
+    fn restore_pending<T>(&mut self, key: (u64, u64), event: Option<KvEvent>) -> Option<T> {
+        if let Some(event) = event {
+            self.pending.insert(key, event);
+        }
+        None
+    }
+    /// Proposes `command` and forwards the reply applied for it to `sender`,
+    /// or a timeout if none is applied in time. `park` files the channel the
+    /// apply loop replies on.
+    fn propose<T: Send + 'static>(
+        &mut self,
+        command: Command,
+        read_only: bool,
+        sender: Sender<RpcResult<T>>,
+        park: impl FnOnce(&mut Self, Sender<RpcResult<T>>),
+    ) {
+        self.propose_then(command, read_only, sender, park, |_| {});
+    }
+    /// Like `propose`, with `on_reply` seeing a successful reply before it
+    /// is forwarded
+    fn propose_then<T: Send + 'static>(
+        &mut self,
+        command: Command,
+        read_only: bool,
+        sender: Sender<RpcResult<T>>,
+        park: impl FnOnce(&mut Self, Sender<RpcResult<T>>),
+        on_reply: impl FnOnce(&T) + Send + 'static,
+    ) {
+        let command = RaftCommand {
+            command: Some(command),
+        };
+        let started = match read_only {
+            true => self.rf.start_read_only(&command),
+            false => self.rf.start(&command),
+        };
+        if started.is_err() {
+            sender.send(Err(KvRpcError::NotLeader)).unwrap_or(());
+            return;
+        }
+        let (tx, rx) = channel();
+        park(self, tx);
+        tokio::spawn(async move {
+            let reply = match timeout(Duration::from_millis(3000), rx).await {
+                Ok(Ok(reply)) => reply,
+                Ok(Err(_e)) => Err(KvRpcError::Recv),
+                Err(_e) => Err(KvRpcError::Timeout),
+            };
+            if let Ok(reply) = &reply {
+                on_reply(reply);
+            }
+            sender.send(reply).unwrap_or(());
+        });
+    }
     fn check_duplicate(&mut self, ts: u64, seq: u64) -> RpcResult<()> {
         if self.last_index.get(&ts).is_none() {
             self.last_index.insert(ts, Arc::new(AtomicU64::new(0)));
@@ -295,44 +809,34 @@ impl KvRaftInner {
             Ok(())
         }
     }
-    fn lock_back_off_or_clean_up(&mut self, key: String, ts: u64) {
-        if let Some((lock_key, lock_value)) = self.store.read_lock(key.clone(), None, Some(ts)) {
-            let primary = lock_value.primary().to_owned();
-            let primary_ts = lock_key.ts();
-            if let Some((_pri_lock_key, pri_lock_value)) =
-                self.store.read_lock(primary.clone(), None, Some(ts))
-            {
-                if pri_lock_value.elapsed() >= Duration::from_secs(3) {
-                    self.store.erase_lock(primary, ts);
-                    self.store.erase_lock(key, ts);
-                }
-            } else {
-                if let Some((write_key, write_value)) =
-                    self.store.read_write(primary, Some(primary_ts), None)
-                {
-                    if write_value.ts() == primary_ts {
-                        let primary_commit_ts = write_key.ts();
-                        self.store.write_write(
-                            key.clone(),
-                            primary_commit_ts,
-                            primary_ts,
-                            lock_value.op(),
-                        );
-                        self.store.erase_lock(key, primary_ts);
-                    }
-                } else {
-                    self.store.erase_lock(key, ts);
-                }
-            }
-        }
-    }
 }
 
 #[derive(Debug)]
 pub enum KvEvent {
+    GetTimestamp(TsRequest, Sender<RpcResult<TsReply>>),
     TxnGet(GetRequest, Sender<RpcResult<GetReply>>),
     TxnPrewrite(PrewriteRequest, Sender<RpcResult<PrewriteReply>>),
     TxnCommit(CommitRequest, Sender<RpcResult<CommitReply>>),
+    TxnBatchPrewrite(BatchPrewriteRequest, Sender<RpcResult<BatchPrewriteReply>>),
+    TxnBatchCommit(BatchCommitRequest, Sender<RpcResult<BatchCommitReply>>),
+    TxnHeartBeat(TxnHeartBeatRequest, Sender<RpcResult<TxnHeartBeatReply>>),
+    TxnRollback(RollbackRequest, Sender<RpcResult<RollbackReply>>),
+    TxnCheckTxnStatus(
+        CheckTxnStatusRequest,
+        Sender<RpcResult<CheckTxnStatusReply>>,
+    ),
+    TxnResolveLock(ResolveLockRequest, Sender<RpcResult<ResolveLockReply>>),
+    TxnBatchGet(BatchGetRequest, Sender<RpcResult<BatchGetReply>>),
+    TxnScan(ScanRequest, Sender<RpcResult<ScanReply>>),
+    TxnGc(GcRequest, Sender<RpcResult<GcReply>>),
+    Subscribe(
+        SubscribeRequest,
+        Sender<RpcResult<UnboundedReceiver<SubscribeReply>>>,
+    ),
+    Watch(
+        WatchRequest,
+        Sender<RpcResult<UnboundedReceiver<SubscribeReply>>>,
+    ),
 }
 
 impl Stream for KvRaftInner {
@@ -346,83 +850,172 @@ impl Stream for KvRaftInner {
             Poll::Ready(Some(event)) => {
                 debug!("{} Executor recv [Event]", self);
                 return match event {
+                    KvEvent::GetTimestamp(args, sender) => {
+                        self.tso_reads.retain(|_, (_, tx)| !tx.is_closed());
+                        // a deposed leader may still hold a window, so timestamps are
+                        // only served once a lease or a heartbeat round confirms it
+                        let id = self.tso_read_id;
+                        if !self.rf.is_leader() {
+                            sender.send(Err(KvRpcError::NotLeader)).unwrap_or(());
+                        } else {
+                            let command = Command::TsoConfirm(TsoConfirm { id });
+                            self.propose(command, true, sender, |kv, tx| {
+                                kv.tso_read_id += 1;
+                                kv.tso_reads.insert(id, (args, tx));
+                            });
+                        }
+                        Poll::Ready(Some(()))
+                    }
                     KvEvent::TxnGet(args, sender) => {
                         if let Err(e) = self.check_duplicate(args.ts, args.seq) {
                             sender.send(Err(e)).unwrap();
-                        } else if let Ok((_index, _term)) = self.rf.start_read_only(&args) {
-                            let (tx, rx) = channel();
-                            self.pending
-                                .insert((args.ts, args.seq), KvEvent::TxnGet(args.clone(), tx));
-                            let last_index = self.last_index.get(&args.ts).unwrap().clone();
-                            tokio::spawn(async move {
-                                let reply = match timeout(Duration::from_millis(3000), rx).await {
-                                    Ok(Ok(Ok(reply))) => {
-                                        last_index.store(reply.seq, Ordering::SeqCst);
-                                        Ok(reply)
-                                    }
-                                    Ok(Ok(Err(status))) => Err(status),
-                                    Ok(Err(_e)) => Err(KvRpcError::Recv),
-                                    Err(_e) => Err(KvRpcError::Timeout),
-                                };
-                                sender.send(reply).unwrap_or(());
-                            });
                         } else {
-                            sender.send(Err(KvRpcError::NotLeader)).unwrap_or(());
+                            let key = (args.ts, args.seq);
+                            let last_index = self.last_index.get(&args.ts).unwrap().clone();
+                            let command = Command::Get(args.clone());
+                            self.propose_then(
+                                command,
+                                true,
+                                sender,
+                                |kv, tx| {
+                                    kv.pending.insert(key, KvEvent::TxnGet(args, tx));
+                                },
+                                move |reply| last_index.store(reply.seq, Ordering::SeqCst),
+                            );
                         }
                         Poll::Ready(Some(()))
                     }
                     KvEvent::TxnPrewrite(args, sender) => {
                         if let Err(e) = self.check_duplicate(args.ts, args.seq) {
                             sender.send(Err(e)).unwrap();
-                        } else if let Ok((_index, _term)) = self.rf.start(&args) {
-                            info!("prewrite 1");
-                            let (tx, rx) = channel();
-                            self.pending.insert(
-                                (args.ts, args.seq),
-                                KvEvent::TxnPrewrite(args.clone(), tx),
-                            );
-                            let last_index = self.last_index.get(&args.ts).unwrap().clone();
-                            tokio::spawn(async move {
-                                let reply = match timeout(Duration::from_millis(3000), rx).await {
-                                    Ok(Ok(Ok(reply))) => {
-                                        last_index.store(reply.seq, Ordering::SeqCst);
-                                        Ok(reply)
-                                    }
-                                    Ok(Ok(Err(status))) => Err(status),
-                                    Ok(Err(_e)) => Err(KvRpcError::Recv),
-                                    Err(_e) => Err(KvRpcError::Timeout),
-                                };
-                                sender.send(reply).unwrap_or(());
-                            });
                         } else {
-                            sender.send(Err(KvRpcError::NotLeader)).unwrap_or(());
+                            let key = (args.ts, args.seq);
+                            let last_index = self.last_index.get(&args.ts).unwrap().clone();
+                            let command = Command::Prewrite(args.clone());
+                            self.propose_then(
+                                command,
+                                false,
+                                sender,
+                                |kv, tx| {
+                                    kv.pending.insert(key, KvEvent::TxnPrewrite(args, tx));
+                                },
+                                move |reply| last_index.store(reply.seq, Ordering::SeqCst),
+                            );
                         }
                         Poll::Ready(Some(()))
                     }
                     KvEvent::TxnCommit(args, sender) => {
                         if let Err(e) = self.check_duplicate(args.commit_ts, args.seq) {
                             sender.send(Err(e)).unwrap();
-                        } else if let Ok((_index, _term)) = self.rf.start(&args) {
-                            let (tx, rx) = channel();
-                            self.pending.insert(
-                                (args.commit_ts, args.seq),
-                                KvEvent::TxnCommit(args.clone(), tx),
-                            );
-                            let last_index = self.last_index.get(&args.commit_ts).unwrap().clone();
-                            tokio::spawn(async move {
-                                let reply = match timeout(Duration::from_millis(3000), rx).await {
-                                    Ok(Ok(Ok(reply))) => {
-                                        last_index.store(reply.seq, Ordering::SeqCst);
-                                        Ok(reply)
-                                    }
-                                    Ok(Ok(Err(status))) => Err(status),
-                                    Ok(Err(_e)) => Err(KvRpcError::Recv),
-                                    Err(_e) => Err(KvRpcError::Timeout),
-                                };
-                                sender.send(reply).unwrap_or(());
-                            });
                         } else {
+                            let key = (args.commit_ts, args.seq);
+                            let last_index = self.last_index.get(&args.commit_ts).unwrap().clone();
+                            let command = Command::Commit(args.clone());
+                            self.propose_then(
+                                command,
+                                false,
+                                sender,
+                                |kv, tx| {
+                                    kv.pending.insert(key, KvEvent::TxnCommit(args, tx));
+                                },
+                                move |reply| last_index.store(reply.seq, Ordering::SeqCst),
+                            );
+                        }
+                        Poll::Ready(Some(()))
+                    }
+                    KvEvent::TxnBatchPrewrite(args, sender) => {
+                        let key = (args.ts, args.seq);
+                        let command = Command::BatchPrewrite(args.clone());
+                        self.propose(command, false, sender, |kv, tx| {
+                            kv.pending.insert(key, KvEvent::TxnBatchPrewrite(args, tx));
+                        });
+                        Poll::Ready(Some(()))
+                    }
+                    KvEvent::TxnBatchCommit(args, sender) => {
+                        let key = (args.commit_ts, args.seq);
+                        let command = Command::BatchCommit(args.clone());
+                        self.propose(command, false, sender, |kv, tx| {
+                            kv.pending.insert(key, KvEvent::TxnBatchCommit(args, tx));
+                        });
+                        Poll::Ready(Some(()))
+                    }
+                    KvEvent::TxnHeartBeat(args, sender) => {
+                        // extending a ttl is idempotent, so heartbeats are
+                        // never checked for duplicates
+                        let key = (args.start_ts, args.seq);
+                        let command = Command::HeartBeat(args);
+                        self.propose(command, false, sender, |kv, tx| {
+                            kv.heart_beats.insert(key, tx);
+                        });
+                        Poll::Ready(Some(()))
+                    }
+                    KvEvent::TxnRollback(args, sender) => {
+                        let key = (args.start_ts, args.seq);
+                        let command = Command::Rollback(args.clone());
+                        self.propose(command, false, sender, |kv, tx| {
+                            kv.pending.insert(key, KvEvent::TxnRollback(args, tx));
+                        });
+                        Poll::Ready(Some(()))
+                    }
+                    KvEvent::TxnCheckTxnStatus(mut args, sender) => {
+                        args.current_ts = args.current_ts.max(self.ts_oracle.current());
+                        let key = (args.ts, args.seq);
+                        let command = Command::CheckTxnStatus(args.clone());
+                        self.propose(command, false, sender, |kv, tx| {
+                            kv.pending.insert(key, KvEvent::TxnCheckTxnStatus(args, tx));
+                        });
+                        Poll::Ready(Some(()))
+                    }
+                    KvEvent::TxnResolveLock(args, sender) => {
+                        let key = (args.ts, args.seq);
+                        let command = Command::ResolveLock(args.clone());
+                        self.propose(command, false, sender, |kv, tx| {
+                            kv.pending.insert(key, KvEvent::TxnResolveLock(args, tx));
+                        });
+                        Poll::Ready(Some(()))
+                    }
+                    KvEvent::TxnGc(args, sender) => {
+                        let key = (args.ts, args.seq);
+                        let command = Command::Gc(args.clone());
+                        self.propose(command, false, sender, |kv, tx| {
+                            kv.pending.insert(key, KvEvent::TxnGc(args, tx));
+                        });
+                        Poll::Ready(Some(()))
+                    }
+                    KvEvent::TxnBatchGet(args, sender) => {
+                        let key = (args.ts, args.seq);
+                        let command = Command::BatchGet(args.clone());
+                        self.propose(command, true, sender, |kv, tx| {
+                            kv.pending.insert(key, KvEvent::TxnBatchGet(args, tx));
+                        });
+                        Poll::Ready(Some(()))
+                    }
+                    KvEvent::TxnScan(args, sender) => {
+                        let key = (args.ts, args.seq);
+                        let command = Command::Scan(args.clone());
+                        self.propose(command, true, sender, |kv, tx| {
+                            kv.pending.insert(key, KvEvent::TxnScan(args, tx));
+                        });
+                        Poll::Ready(Some(()))
+                    }
+                    KvEvent::Subscribe(args, sender) => {
+                        // served from what this replica applied, leader or not
+                        let receiver = self.store.subscribe(args.start_ts, args.key_prefix);
+                        sender.send(Ok(receiver)).unwrap_or(());
+                        Poll::Ready(Some(()))
+                    }
+                    KvEvent::Watch(args, sender) => {
+                        // a replay from a revision is served from what this
+                        // replica applied, as a subscription is. A watch of new
+                        // changes only must skip every change committed so far,
+                        // which only the leader has surely applied, so followers
+                        // turn it down and the client tries the next server
+                        if args.from_revision == 0 && !self.rf.is_leader() {
                             sender.send(Err(KvRpcError::NotLeader)).unwrap_or(());
+                        } else {
+                            let receiver =
+                                self.store.watch(args.key, args.prefix, args.from_revision);
+                            sender.send(Ok(receiver)).unwrap_or(());
                         }
                         Poll::Ready(Some(()))
                     }
@@ -434,6 +1027,7 @@ impl Stream for KvRaftInner {
         match self.apply_ch.poll_recv(cx) {
             Poll::Ready(Some(msg)) => {
                 self.handle_apply_msg(msg);
+                self.maybe_snapshot();
                 Poll::Ready(Some(()))
             }
             Poll::Ready(None) => Poll::Ready(Some(())),
@@ -451,7 +1045,7 @@ impl Stream for KvRaftInner {
 pub struct KvRaftNode {
     handle: Arc<Mutex<thread::JoinHandle<()>>>,
     sender: UnboundedSender<KvEvent>,
-    ts_oracle: TimestampOracle,
+    rf: raft::RaftNode,
 }
 
 impl KvRaftNode {
@@ -461,13 +1055,12 @@ impl KvRaftNode {
         store: MultiStore,
         me: usize,
         persister: Arc<dyn persister::Persister>,
-        maxraftstate: Option<usize>,
+        policy: SnapshotPolicy,
         apply_ch: UnboundedReceiver<ApplyMsg>,
-        ts_oracle: TimestampOracle,
     ) -> KvRaftNode {
         let (sender, receiver) = unbounded_channel();
         let mut kv_raft =
-            KvRaftInner::new(store, rf, me, persister, maxraftstate, receiver, apply_ch);
+            KvRaftInner::new(store, rf.clone(), me, persister, policy, receiver, apply_ch);
 
         let threaded_rt = Builder::new_multi_thread().enable_all().build().unwrap();
         let handle = thread::Builder::new()
@@ -485,9 +1078,32 @@ impl KvRaftNode {
         KvRaftNode {
             handle: Arc::new(Mutex::new(handle)),
             sender,
-            ts_oracle,
+            rf,
         }
     }
+
+    /// Waits for a membership change to commit, replying its voters and learners
+    async fn change_membership(&self, change: ConfChange) -> RpcResult<ConfChangeReply> {
+        let membership = match self.rf.change_membership(change).await {
+            Ok(Ok(membership)) => membership,
+            Ok(Err(KvError::NotLeader)) => return Err(KvRpcError::NotLeader),
+            Ok(Err(e)) => return Err(KvRpcError::ConfChange(e.to_string())),
+            Err(_) => return Err(KvRpcError::Recv),
+        };
+        let node_infos = |members: Vec<Member>| {
+            members
+                .into_iter()
+                .map(|member| NodeInfo {
+                    id: member.id,
+                    addr: member.addr,
+                })
+                .collect()
+        };
+        Ok(ConfChangeReply {
+            nodes: node_infos(membership.voters),
+            learners: node_infos(membership.learners),
+        })
+    }
 }
 
 #[tonic::async_trait]
@@ -496,10 +1112,13 @@ impl KvRpc for KvRaftNode {
         &self,
         request: Request<TsRequest>,
     ) -> std::result::Result<Response<TsReply>, Status> {
-        let name = request.into_inner().name;
-        let ts = self.ts_oracle.fetch_one().unwrap();
-        let reply = TsReply { name, ts };
-        Ok(tonic::Response::new(reply))
+        let req = request.into_inner();
+        let (tx, rx) = channel();
+        self.sender.send(KvEvent::GetTimestamp(req, tx)).unwrap();
+        rx.await
+            .unwrap_or(Err(KvRpcError::Recv))
+            .map(Response::new)
+            .map_err(|e| e.into())
     }
 
     async fn txn_get(
@@ -540,4 +1159,208 @@ impl KvRpc for KvRaftNode {
             .map(|reply| Response::new(reply))
             .map_err(|e| e.into())
     }
+
+    async fn txn_batch_prewrite(
+        &self,
+        request: Request<BatchPrewriteRequest>,
+    ) -> std::result::Result<Response<BatchPrewriteReply>, Status> {
+        let req = request.into_inner();
+        let (tx, rx) = channel();
+        self.sender
+            .send(KvEvent::TxnBatchPrewrite(req, tx))
+            .unwrap();
+        rx.await
+            .unwrap_or(Err(KvRpcError::Recv))
+            .map(Response::new)
+            .map_err(|e| e.into())
+    }
+
+    async fn txn_batch_commit(
+        &self,
+        request: Request<BatchCommitRequest>,
+    ) -> std::result::Result<Response<BatchCommitReply>, Status> {
+        let req = request.into_inner();
+        let (tx, rx) = channel();
+        self.sender.send(KvEvent::TxnBatchCommit(req, tx)).unwrap();
+        rx.await
+            .unwrap_or(Err(KvRpcError::Recv))
+            .map(Response::new)
+            .map_err(|e| e.into())
+    }
+
+    async fn txn_heart_beat(
+        &self,
+        request: Request<TxnHeartBeatRequest>,
+    ) -> std::result::Result<Response<TxnHeartBeatReply>, Status> {
+        let req = request.into_inner();
+        let (tx, rx) = channel();
+        self.sender.send(KvEvent::TxnHeartBeat(req, tx)).unwrap();
+        rx.await
+            .unwrap_or(Err(KvRpcError::Recv))
+            .map(Response::new)
+            .map_err(|e| e.into())
+    }
+
+    async fn txn_rollback(
+        &self,
+        request: Request<RollbackRequest>,
+    ) -> std::result::Result<Response<RollbackReply>, Status> {
+        let req = request.into_inner();
+        let (tx, rx) = channel();
+        self.sender.send(KvEvent::TxnRollback(req, tx)).unwrap();
+        rx.await
+            .unwrap_or(Err(KvRpcError::Recv))
+            .map(Response::new)
+            .map_err(|e| e.into())
+    }
+
+    async fn txn_check_txn_status(
+        &self,
+        request: Request<CheckTxnStatusRequest>,
+    ) -> std::result::Result<Response<CheckTxnStatusReply>, Status> {
+        let req = request.into_inner();
+        let (tx, rx) = channel();
+        self.sender
+            .send(KvEvent::TxnCheckTxnStatus(req, tx))
+            .unwrap();
+        rx.await
+            .unwrap_or(Err(KvRpcError::Recv))
+            .map(Response::new)
+            .map_err(|e| e.into())
+    }
+
+    async fn txn_resolve_lock(
+        &self,
+        request: Request<ResolveLockRequest>,
+    ) -> std::result::Result<Response<ResolveLockReply>, Status> {
+        let req = request.into_inner();
+        let (tx, rx) = channel();
+        self.sender.send(KvEvent::TxnResolveLock(req, tx)).unwrap();
+        rx.await
+            .unwrap_or(Err(KvRpcError::Recv))
+            .map(Response::new)
+            .map_err(|e| e.into())
+    }
+    async fn txn_gc(
+        &self,
+        request: Request<GcRequest>,
+    ) -> std::result::Result<Response<GcReply>, Status> {
+        let req = request.into_inner();
+        let (tx, rx) = channel();
+        self.sender.send(KvEvent::TxnGc(req, tx)).unwrap();
+        rx.await
+            .unwrap_or(Err(KvRpcError::Recv))
+            .map(Response::new)
+            .map_err(|e| e.into())
+    }
+    async fn txn_batch_get(
+        &self,
+        request: Request<BatchGetRequest>,
+    ) -> std::result::Result<Response<BatchGetReply>, Status> {
+        let req = request.into_inner();
+        let (tx, rx) = channel();
+        self.sender.send(KvEvent::TxnBatchGet(req, tx)).unwrap();
+        rx.await
+            .unwrap_or(Err(KvRpcError::Recv))
+            .map(Response::new)
+            .map_err(|e| e.into())
+    }
+
+    async fn txn_scan(
+        &self,
+        request: Request<ScanRequest>,
+    ) -> std::result::Result<Response<ScanReply>, Status> {
+        let req = request.into_inner();
+        let (tx, rx) = channel();
+        self.sender.send(KvEvent::TxnScan(req, tx)).unwrap();
+        rx.await
+            .unwrap_or(Err(KvRpcError::Recv))
+            .map(Response::new)
+            .map_err(|e| e.into())
+    }
+
+    type SubscribeStream = ChangeStream;
+
+    async fn subscribe(
+        &self,
+        request: Request<SubscribeRequest>,
+    ) -> std::result::Result<Response<Self::SubscribeStream>, Status> {
+        let req = request.into_inner();
+        let (tx, rx) = channel();
+        self.sender.send(KvEvent::Subscribe(req, tx)).unwrap();
+        rx.await
+            .unwrap_or(Err(KvRpcError::Recv))
+            .map(|receiver| {
+                let stream = UnboundedReceiverStream::new(receiver).map(Ok);
+                Response::new(Box::pin(stream) as ChangeStream)
+            })
+            .map_err(|e| e.into())
+    }
+
+    type WatchStream = WatchEventStream;
+
+    async fn watch(
+        &self,
+        request: Request<WatchRequest>,
+    ) -> std::result::Result<Response<Self::WatchStream>, Status> {
+        let req = request.into_inner();
+        let (tx, rx) = channel();
+        self.sender.send(KvEvent::Watch(req, tx)).unwrap();
+        rx.await
+            .unwrap_or(Err(KvRpcError::Recv))
+            .map(|receiver| {
+                // watchers only care about the changes, not the bare watermarks
+                let stream = UnboundedReceiverStream::new(receiver)
+                    .filter(|reply| !reply.events.is_empty())
+                    .map(|reply| Ok(reply.into()));
+                Response::new(Box::pin(stream) as WatchEventStream)
+            })
+            .map_err(|e| e.into())
+    }
+
+    async fn add_node(
+        &self,
+        request: Request<AddNodeRequest>,
+    ) -> std::result::Result<Response<ConfChangeReply>, Status> {
+        let req = request.into_inner();
+        self.change_membership(ConfChange::AddNode(req.id as usize, req.addr))
+            .await
+            .map(Response::new)
+            .map_err(|e| e.into())
+    }
+
+    async fn remove_node(
+        &self,
+        request: Request<RemoveNodeRequest>,
+    ) -> std::result::Result<Response<ConfChangeReply>, Status> {
+        let req = request.into_inner();
+        self.change_membership(ConfChange::RemoveNode(req.id as usize))
+            .await
+            .map(Response::new)
+            .map_err(|e| e.into())
+    }
+
+    async fn add_learner(
+        &self,
+        request: Request<AddNodeRequest>,
+    ) -> std::result::Result<Response<ConfChangeReply>, Status> {
+        let req = request.into_inner();
+        self.change_membership(ConfChange::AddLearner(req.id as usize, req.addr))
+            .await
+            .map(Response::new)
+            .map_err(|e| e.into())
+    }
+
+    async fn transfer_leader(
+        &self,
+        request: Request<TransferLeaderRequest>,
+    ) -> std::result::Result<Response<TransferLeaderReply>, Status> {
+        let req = request.into_inner();
+        match self.rf.transfer_leader(Some(req.id as usize)).await {
+            Ok(Ok(())) => Ok(Response::new(TransferLeaderReply {})),
+            Ok(Err(KvError::NotLeader)) => Err(KvRpcError::NotLeader.into()),
+            Ok(Err(e)) => Err(KvRpcError::LeaderTransfer(e.to_string()).into()),
+            Err(_) => Err(KvRpcError::Recv.into()),
+        }
+    }
 }
@@ -0,0 +1,216 @@
+use std::collections::HashSet;
+
+use crate::preclude::*;
+
+/// A change of the members of a raft group
+#[derive(Debug, Clone)]
+pub enum ConfChange {
+    /// Add the node with the id at the addr as a voter, promoting it if it
+    /// is a learner
+    AddNode(usize, String),
+    /// Remove the node with the id, voter or learner
+    RemoveNode(usize),
+    /// Add the node with the id at the addr as a learner
+    AddLearner(usize, String),
+}
+
+impl Membership {
+    /// Whether the group is moving from the outgoing voters to the voters
+    pub fn is_joint(&self) -> bool {
+        !self.outgoing.is_empty()
+    }
+    /// Whether `id` votes in either configuration
+    pub fn is_voter(&self, id: usize) -> bool {
+        self.members().any(|member| member.id as usize == id)
+    }
+    /// Whether `id` is a learner
+    pub fn is_learner(&self, id: usize) -> bool {
+        self.learners.iter().any(|member| member.id as usize == id)
+    }
+    /// The ids of the nodes voting in either configuration
+    pub fn voter_ids(&self) -> Vec<usize> {
+        let mut ids: Vec<usize> = self.members().map(|member| member.id as usize).collect();
+        ids.sort_unstable();
+        ids.dedup();
+        ids
+    }
+    /// The ids of every node entries are replicated to, learners included
+    pub fn ids(&self) -> Vec<usize> {
+        let mut ids = self.voter_ids();
+        ids.extend(self.learners.iter().map(|member| member.id as usize));
+        ids.sort_unstable();
+        ids.dedup();
+        ids
+    }
+    /// Whether `acks` holds a majority of the voters, and of the outgoing
+    /// voters while joint
+    pub fn has_quorum(&self, acks: &HashSet<usize>) -> bool {
+        let majority = |voters: &[Member]| {
+            let acked = voters
+                .iter()
+                .filter(|member| acks.contains(&(member.id as usize)))
+                .count();
+            acked > voters.len() / 2
+        };
+        !self.voters.is_empty()
+            && majority(&self.voters)
+            && (!self.is_joint() || majority(&self.outgoing))
+    }
+    /// The highest index a quorum has matched, given the match index of each node
+    pub fn quorum_index(&self, match_index: impl Fn(usize) -> u64) -> u64 {
+        let quorum_index = |voters: &[Member]| {
+            if voters.is_empty() {
+                return u64::MAX;
+            }
+            let mut matched: Vec<u64> = voters
+                .iter()
+                .map(|member| match_index(member.id as usize))
+                .collect();
+            matched.sort_unstable_by(|a, b| b.cmp(a));
+            matched[voters.len() / 2]
+        };
+        if self.voters.is_empty() {
+            return 0;
+        }
+        quorum_index(&self.voters).min(quorum_index(&self.outgoing))
+    }
+    /// Applies `change` to the voters and learners, `None` if it changes
+    /// nothing. A voter is never demoted to a learner.
+    pub fn apply(&self, change: &ConfChange) -> Option<Membership> {
+        let mut voters = self.voters.clone();
+        let mut learners = self.learners.clone();
+        let contains =
+            |members: &[Member], id: usize| members.iter().any(|member| member.id as usize == id);
+        match change {
+            ConfChange::AddNode(id, addr) => {
+                if contains(&voters, *id) {
+                    return None;
+                }
+                learners.retain(|member| member.id as usize != *id);
+                voters.push(Member {
+                    id: *id as u64,
+                    addr: addr.clone(),
+                });
+            }
+            ConfChange::RemoveNode(id) => {
+                if !contains(&voters, *id) && !contains(&learners, *id) {
+                    return None;
+                }
+                voters.retain(|member| member.id as usize != *id);
+                learners.retain(|member| member.id as usize != *id);
+            }
+            ConfChange::AddLearner(id, addr) => {
+                if contains(&voters, *id) || contains(&learners, *id) {
+                    return None;
+                }
+                learners.push(Member {
+                    id: *id as u64,
+                    addr: addr.clone(),
+                });
+            }
+        }
+        Some(Membership {
+            voters,
+            outgoing: Vec::new(),
+            learners,
+        })
+    }
+    fn members(&self) -> impl Iterator<Item = &Member> {
+        self.voters.iter().chain(self.outgoing.iter())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn members(ids: &[u64]) -> Vec<Member> {
+        ids.iter()
+            .map(|id| Member {
+                id: *id,
+                addr: format!("127.0.0.1:{}", 5000 + id),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_joint_quorum() {
+        let joint = Membership {
+            voters: members(&[2, 3, 4]),
+            outgoing: members(&[1, 2, 3]),
+            learners: members(&[0]),
+        };
+        assert!(joint.is_joint());
+        assert_eq!(joint.ids(), vec![0, 1, 2, 3, 4]);
+        assert_eq!(joint.voter_ids(), vec![1, 2, 3, 4]);
+        // a majority of the new voters alone is not enough
+        assert!(!joint.has_quorum(&[3, 4].iter().cloned().collect()));
+        assert!(!joint.has_quorum(&[1, 2].iter().cloned().collect()));
+        assert!(joint.has_quorum(&[2, 3].iter().cloned().collect()));
+        // nor do learners count
+        assert!(!joint.has_quorum(&[0, 2, 4].iter().cloned().collect()));
+
+        let matched = [0, 10, 9, 5, 7];
+        assert_eq!(joint.quorum_index(|id| matched[id]), 7);
+        let single = Membership {
+            voters: members(&[2]),
+            outgoing: vec![],
+            learners: members(&[1]),
+        };
+        assert_eq!(single.quorum_index(|id| matched[id]), 9);
+        assert!(single.has_quorum(&[2].iter().cloned().collect()));
+    }
+
+    #[test]
+    fn test_apply() {
+        let membership = Membership {
+            voters: members(&[0, 1, 2]),
+            outgoing: vec![],
+            learners: vec![],
+        };
+        let added = membership
+            .apply(&ConfChange::AddNode(3, String::from("127.0.0.1:5003")))
+            .unwrap();
+        assert_eq!(added.voters.len(), 4);
+        assert!(membership
+            .apply(&ConfChange::AddNode(1, String::new()))
+            .is_none());
+        assert_eq!(
+            membership
+                .apply(&ConfChange::RemoveNode(0))
+                .unwrap()
+                .voters
+                .len(),
+            2
+        );
+        assert!(membership.apply(&ConfChange::RemoveNode(7)).is_none());
+    }
+
+    #[test]
+    fn test_apply_learner() {
+        let membership = Membership {
+            voters: members(&[0, 1, 2]),
+            outgoing: vec![],
+            learners: vec![],
+        };
+        let learner = membership
+            .apply(&ConfChange::AddLearner(3, String::from("127.0.0.1:5003")))
+            .unwrap();
+        assert_eq!(learner.voters, membership.voters);
+        assert!(learner.is_learner(3) && !learner.is_voter(3));
+        assert_eq!(learner.ids(), vec![0, 1, 2, 3]);
+        // a voter is not demoted
+        assert!(membership
+            .apply(&ConfChange::AddLearner(1, String::new()))
+            .is_none());
+
+        let promoted = learner
+            .apply(&ConfChange::AddNode(3, String::from("127.0.0.1:5003")))
+            .unwrap();
+        assert!(promoted.is_voter(3) && !promoted.is_learner(3));
+        assert!(promoted.learners.is_empty());
+
+        let removed = learner.apply(&ConfChange::RemoveNode(3)).unwrap();
+        assert_eq!(removed, membership);
+    }
+}
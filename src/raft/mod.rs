@@ -1,8 +1,13 @@
+mod compaction;
 mod kvraft;
+mod membership;
 mod persister;
 mod raft;
 mod read_only;
+mod wal;
 
+pub use compaction::SnapshotPolicy;
 pub use kvraft::KvRaftNode;
-pub use persister::{FilePersister, Persister};
+pub use membership::ConfChange;
+pub use persister::{FilePersister, Persister, Staging};
 pub use raft::RaftNode;
@@ -1,184 +1,480 @@
 //! Support for Raft and kvraft to save persistent
-//! Raft state (log &c) and k/v server snapshots.
+//! Raft state (term, vote &c), the Raft log and k/v server snapshots.
 //!
 //! we will use the original persister.rs to test your code for grading.
 //! so, while you can modify this code to help you debug, please
 //! test with the original before submitting.
 
 use std::{
-    fs::OpenOptions,
-    io::{BufReader, BufWriter, Read, Write},
-    path::PathBuf,
+    cmp::min,
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
     sync::{Arc, Mutex},
 };
 
+use serde::{Deserialize, Serialize};
+
+use super::wal::{sync_dir, Wal};
+
+/// Where a snapshot is written, in chunks, before it replaces the persisted one
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Staging {
+    /// generated from the local state machine
+    Generated,
+    /// received from the leader
+    Received,
+}
+
 /// Persister defined how raft state and snapshot can persist to disk
 pub trait Persister: Send + Sync + 'static {
     /// get the persisted state
-    fn raft_state(&self) -> Vec<u8>;
+    fn raft_state(&self) -> io::Result<Vec<u8>>;
     /// save the state to disk
-    fn save_raft_state(&self, state: Vec<u8>);
+    fn save_raft_state(&self, state: Vec<u8>) -> io::Result<()>;
     /// save the state and snapshot to disk
-    fn save_state_and_snapshot(&self, state: Vec<u8>, snapshot: Vec<u8>);
+    fn save_state_and_snapshot(&self, state: Vec<u8>, snapshot: Vec<u8>) -> io::Result<()>;
     /// get the persisted snapshot
-    fn snapshot(&self) -> Vec<u8>;
+    fn snapshot(&self) -> io::Result<Vec<u8>>;
+    /// get the length of the persisted snapshot
+    fn snapshot_len(&self) -> io::Result<u64> {
+        Ok(self.snapshot()?.len() as u64)
+    }
+    /// get up to `len` bytes of the persisted snapshot from `offset`
+    fn read_snapshot(&self, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+        let snapshot = self.snapshot()?;
+        let start = min(offset as usize, snapshot.len());
+        let end = min(start + len, snapshot.len());
+        Ok(snapshot[start..end].to_vec())
+    }
+    /// get the length of the snapshot staged so far
+    fn staged_len(&self, staging: Staging) -> io::Result<u64>;
+    /// write `data` at `offset` of the staged snapshot, dropping what follows,
+    /// and sync it to disk
+    fn stage_snapshot(&self, staging: Staging, offset: u64, data: &[u8]) -> io::Result<()>;
+    /// save the state and replace the snapshot with the staged one
+    fn save_state_and_staged_snapshot(&self, state: Vec<u8>, staging: Staging) -> io::Result<()>;
+    /// get the persisted log entries, in order
+    fn log_entries(&self) -> io::Result<Vec<Vec<u8>>>;
+    /// append `entries` to the log from `index`, dropping any persisted at
+    /// `index` and after
+    fn append_log(&self, index: u64, entries: Vec<Vec<u8>>) -> io::Result<()>;
+    /// drop the log entries up to `index`, which a snapshot covers
+    fn compact_log(&self, index: u64) -> io::Result<()>;
 }
 
 impl<T: ?Sized + Persister> Persister for Box<T> {
-    fn raft_state(&self) -> Vec<u8> {
+    fn raft_state(&self) -> io::Result<Vec<u8>> {
         (**self).raft_state()
     }
-    fn save_raft_state(&self, state: Vec<u8>) {
+    fn save_raft_state(&self, state: Vec<u8>) -> io::Result<()> {
         (**self).save_raft_state(state)
     }
-    fn save_state_and_snapshot(&self, state: Vec<u8>, snapshot: Vec<u8>) {
+    fn save_state_and_snapshot(&self, state: Vec<u8>, snapshot: Vec<u8>) -> io::Result<()> {
         (**self).save_state_and_snapshot(state, snapshot)
     }
-    fn snapshot(&self) -> Vec<u8> {
+    fn snapshot(&self) -> io::Result<Vec<u8>> {
         (**self).snapshot()
     }
+    fn snapshot_len(&self) -> io::Result<u64> {
+        (**self).snapshot_len()
+    }
+    fn read_snapshot(&self, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+        (**self).read_snapshot(offset, len)
+    }
+    fn staged_len(&self, staging: Staging) -> io::Result<u64> {
+        (**self).staged_len(staging)
+    }
+    fn stage_snapshot(&self, staging: Staging, offset: u64, data: &[u8]) -> io::Result<()> {
+        (**self).stage_snapshot(staging, offset, data)
+    }
+    fn save_state_and_staged_snapshot(&self, state: Vec<u8>, staging: Staging) -> io::Result<()> {
+        (**self).save_state_and_staged_snapshot(state, staging)
+    }
+    fn log_entries(&self) -> io::Result<Vec<Vec<u8>>> {
+        (**self).log_entries()
+    }
+    fn append_log(&self, index: u64, entries: Vec<Vec<u8>>) -> io::Result<()> {
+        (**self).append_log(index, entries)
+    }
+    fn compact_log(&self, index: u64) -> io::Result<()> {
+        (**self).compact_log(index)
+    }
 }
 
 impl<T: ?Sized + Sync + Persister> Persister for Arc<T> {
-    fn raft_state(&self) -> Vec<u8> {
+    fn raft_state(&self) -> io::Result<Vec<u8>> {
         (**self).raft_state()
     }
-    fn save_raft_state(&self, state: Vec<u8>) {
+    fn save_raft_state(&self, state: Vec<u8>) -> io::Result<()> {
         (**self).save_raft_state(state)
     }
-    fn save_state_and_snapshot(&self, state: Vec<u8>, snapshot: Vec<u8>) {
+    fn save_state_and_snapshot(&self, state: Vec<u8>, snapshot: Vec<u8>) -> io::Result<()> {
         (**self).save_state_and_snapshot(state, snapshot)
     }
-    fn snapshot(&self) -> Vec<u8> {
+    fn snapshot(&self) -> io::Result<Vec<u8>> {
         (**self).snapshot()
     }
+    fn snapshot_len(&self) -> io::Result<u64> {
+        (**self).snapshot_len()
+    }
+    fn read_snapshot(&self, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+        (**self).read_snapshot(offset, len)
+    }
+    fn staged_len(&self, staging: Staging) -> io::Result<u64> {
+        (**self).staged_len(staging)
+    }
+    fn stage_snapshot(&self, staging: Staging, offset: u64, data: &[u8]) -> io::Result<()> {
+        (**self).stage_snapshot(staging, offset, data)
+    }
+    fn save_state_and_staged_snapshot(&self, state: Vec<u8>, staging: Staging) -> io::Result<()> {
+        (**self).save_state_and_staged_snapshot(state, staging)
+    }
+    fn log_entries(&self) -> io::Result<Vec<Vec<u8>>> {
+        (**self).log_entries()
+    }
+    fn append_log(&self, index: u64, entries: Vec<Vec<u8>>) -> io::Result<()> {
+        (**self).append_log(index, entries)
+    }
+    fn compact_log(&self, index: u64) -> io::Result<()> {
+        (**self).compact_log(index)
+    }
 }
 
-/// FilePersister is a raft persister that save all data to files
-pub struct FilePersister {
-    raft_state: PathBuf,
-    snapshot: PathBuf,
+const MANIFEST: &str = "MANIFEST";
+
+/// The files holding the raft state and snapshot saved last, which a save
+/// replaces together by writing new files and then the manifest naming them
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Manifest {
+    generation: u64,
+    state: String,
+    snapshot: String,
 }
 
-impl Default for FilePersister {
-    fn default() -> Self {
-        FilePersister {
-            raft_state: PathBuf::from("raft_state.bin"),
-            snapshot: PathBuf::from("snapshot.bin"),
+impl Manifest {
+    /// The files written before there was a manifest
+    fn legacy() -> Manifest {
+        Manifest {
+            generation: 0,
+            state: String::from("raft_state.bin"),
+            snapshot: String::from("snapshot.bin"),
+        }
+    }
+
+    fn next_state(&self) -> Manifest {
+        Manifest {
+            generation: self.generation + 1,
+            state: format!("raft_state.{}.bin", self.generation + 1),
+            snapshot: self.snapshot.clone(),
+        }
+    }
+
+    fn next_state_and_snapshot(&self) -> Manifest {
+        Manifest {
+            snapshot: format!("snapshot.{}.bin", self.generation + 1),
+            ..self.next_state()
         }
     }
 }
 
+/// FilePersister is a raft persister that save all data to files,
+/// the log in segments appended to and the rest of the raft state,
+/// term and vote, in a small file rewritten on change.
+///
+/// The state and snapshot are never written in place, so a crash leaves
+/// either those saved before or those saved after.
+pub struct FilePersister {
+    dir: PathBuf,
+    manifest: Mutex<Manifest>,
+    log: Mutex<Wal>,
+}
+
 impl FilePersister {
-    /// Create a new FilePersister
-    pub fn new() -> Self {
-        Self::default()
+    /// Create a new FilePersister in the current directory
+    pub fn new() -> io::Result<Self> {
+        Self::with_path(PathBuf::from("."))
     }
     /// Create a new FilePersister with given path
-    pub fn with_path(path: PathBuf) -> Self {
-        std::fs::create_dir(path.clone()).unwrap_or(());
-        let mut per = Self::default();
-        per.raft_state = path.clone().join(per.raft_state);
-        per.snapshot = path.clone().join(per.snapshot);
-        per
+    pub fn with_path(path: PathBuf) -> io::Result<Self> {
+        fs::create_dir(path.clone()).unwrap_or(());
+        let manifest = match fs::read(path.join(MANIFEST)) {
+            Ok(data) => serde_json::from_slice(&data)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Manifest::legacy(),
+            Err(e) => return Err(e),
+        };
+        let per = FilePersister {
+            log: Mutex::new(Wal::open(path.join("raft_log"))?),
+            dir: path,
+            manifest: Mutex::new(manifest),
+        };
+        per.remove_uncommitted()?;
+        Ok(per)
+    }
+
+    fn staged(&self, staging: Staging) -> PathBuf {
+        let name = match staging {
+            Staging::Generated => "snapshot.generated.bin",
+            Staging::Received => "snapshot.received.bin",
+        };
+        self.dir.join(name)
+    }
+
+    /// Makes `next` the files saved, removing those it replaces
+    fn commit(&self, manifest: &mut Manifest, next: Manifest) -> io::Result<()> {
+        // the files named must be found before the manifest naming them
+        sync_dir(&self.dir)?;
+        let tmp = self.dir.join(format!("{}.tmp", MANIFEST));
+        write_synced(&tmp, &serde_json::to_vec(&next)?)?;
+        fs::rename(&tmp, self.dir.join(MANIFEST))?;
+        sync_dir(&self.dir)?;
+        let prev = std::mem::replace(manifest, next);
+        for (prev, next) in [
+            (&prev.state, &manifest.state),
+            (&prev.snapshot, &manifest.snapshot),
+        ] {
+            if prev != next {
+                remove_file(&self.dir.join(prev))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Removes the files written by a save a crash cut short, the manifest
+    /// not yet renamed in place among them
+    fn remove_uncommitted(&self) -> io::Result<()> {
+        let manifest = self.manifest.lock().unwrap();
+        for entry in fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let generation = name
+                .strip_prefix("raft_state.")
+                .or_else(|| name.strip_prefix("snapshot."))
+                .and_then(|name| name.strip_suffix(".bin"))
+                .is_some_and(|generation| generation.parse::<u64>().is_ok());
+            let uncommitted = generation && name != manifest.state && name != manifest.snapshot;
+            if uncommitted || name == format!("{}.tmp", MANIFEST) {
+                remove_file(&entry.path())?;
+            }
+        }
+        Ok(())
     }
 }
 
 impl Persister for FilePersister {
-    fn raft_state(&self) -> Vec<u8> {
-        let mut reader = BufReader::new(
-            OpenOptions::new()
-                .create(true)
-                .write(true)
-                .read(true)
-                .open(self.raft_state.clone())
-                .unwrap(),
-        );
-        let mut buf = Vec::new();
-        reader.read_to_end(&mut buf).unwrap();
-        buf
-    }
-
-    fn snapshot(&self) -> Vec<u8> {
-        let mut reader = BufReader::new(
-            OpenOptions::new()
-                .create(true)
-                .write(true)
-                .read(true)
-                .open(self.snapshot.clone())
-                .unwrap(),
-        );
+    fn raft_state(&self) -> io::Result<Vec<u8>> {
+        let manifest = self.manifest.lock().unwrap();
+        read_file(&self.dir.join(&manifest.state))
+    }
+
+    fn snapshot(&self) -> io::Result<Vec<u8>> {
+        let manifest = self.manifest.lock().unwrap();
+        read_file(&self.dir.join(&manifest.snapshot))
+    }
+
+    fn save_raft_state(&self, state: Vec<u8>) -> io::Result<()> {
+        let mut manifest = self.manifest.lock().unwrap();
+        let next = manifest.next_state();
+        write_synced(&self.dir.join(&next.state), &state)?;
+        self.commit(&mut manifest, next)
+    }
+
+    fn save_state_and_snapshot(&self, state: Vec<u8>, snapshot: Vec<u8>) -> io::Result<()> {
+        let mut manifest = self.manifest.lock().unwrap();
+        let next = manifest.next_state_and_snapshot();
+        write_synced(&self.dir.join(&next.state), &state)?;
+        write_synced(&self.dir.join(&next.snapshot), &snapshot)?;
+        self.commit(&mut manifest, next)
+    }
+
+    fn snapshot_len(&self) -> io::Result<u64> {
+        let manifest = self.manifest.lock().unwrap();
+        match fs::metadata(self.dir.join(&manifest.snapshot)) {
+            Ok(meta) => Ok(meta.len()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(0),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn read_snapshot(&self, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+        let manifest = self.manifest.lock().unwrap();
         let mut buf = Vec::new();
-        reader.read_to_end(&mut buf).unwrap();
-        buf
+        match File::open(self.dir.join(&manifest.snapshot)) {
+            Ok(mut file) => {
+                file.seek(SeekFrom::Start(offset))?;
+                file.take(len as u64).read_to_end(&mut buf)?;
+            }
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            Err(_) => {}
+        }
+        Ok(buf)
     }
 
-    fn save_raft_state(&self, state: Vec<u8>) {
-        let mut writer = BufWriter::new(
-            OpenOptions::new()
-                .create(true)
-                .write(true)
-                .open(self.raft_state.clone())
-                .unwrap(),
-        );
-        writer.write_all(&state).unwrap();
-        writer.flush().unwrap();
+    fn staged_len(&self, staging: Staging) -> io::Result<u64> {
+        match fs::metadata(self.staged(staging)) {
+            Ok(meta) => Ok(meta.len()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(0),
+            Err(e) => Err(e),
+        }
     }
 
-    fn save_state_and_snapshot(&self, state: Vec<u8>, snapshot: Vec<u8>) {
-        let mut writer = BufWriter::new(
-            OpenOptions::new()
-                .create(true)
-                .write(true)
-                .open(self.raft_state.clone())
-                .unwrap(),
-        );
-        writer.write_all(&state).unwrap();
-        writer.flush().unwrap();
-        let mut writer = BufWriter::new(
-            OpenOptions::new()
-                .create(true)
-                .write(true)
-                .open(self.snapshot.clone())
-                .unwrap(),
-        );
-        writer.write_all(&snapshot).unwrap();
-        writer.flush().unwrap();
+    fn stage_snapshot(&self, staging: Staging, offset: u64, data: &[u8]) -> io::Result<()> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(offset == 0)
+            .open(self.staged(staging))?;
+        file.set_len(offset)?;
+        file.seek(SeekFrom::Start(offset))?;
+        file.write_all(data)?;
+        // the staged length is where a transfer resumes after a restart, so
+        // it only covers chunks that reached the disk
+        file.sync_data()
+    }
+
+    fn save_state_and_staged_snapshot(&self, state: Vec<u8>, staging: Staging) -> io::Result<()> {
+        let mut manifest = self.manifest.lock().unwrap();
+        let next = manifest.next_state_and_snapshot();
+        write_synced(&self.dir.join(&next.state), &state)?;
+        // the log the snapshot covers is dropped once it is saved
+        OpenOptions::new()
+            .write(true)
+            .open(self.staged(staging))?
+            .sync_all()?;
+        fs::rename(self.staged(staging), self.dir.join(&next.snapshot))?;
+        self.commit(&mut manifest, next)
+    }
+
+    fn log_entries(&self) -> io::Result<Vec<Vec<u8>>> {
+        self.log.lock().unwrap().entries()
+    }
+
+    fn append_log(&self, index: u64, entries: Vec<Vec<u8>>) -> io::Result<()> {
+        self.log.lock().unwrap().append(index, &entries)
+    }
+
+    fn compact_log(&self, index: u64) -> io::Result<()> {
+        self.log.lock().unwrap().compact(index)
     }
 }
 
+/// Reads a file, which is empty until first written
+fn read_file(path: &Path) -> io::Result<Vec<u8>> {
+    match fs::read(path) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+        res => res,
+    }
+}
+
+fn remove_file(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        res => res,
+    }
+}
+
+fn write_synced(path: &Path, data: &[u8]) -> io::Result<()> {
+    let mut file = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(true)
+        .open(path)?;
+    file.write_all(data)?;
+    file.sync_all()
+}
+
 #[derive(Default)]
 pub struct SimplePersister {
     states: Mutex<(
         Vec<u8>, // raft state
         Vec<u8>, // snapshot
     )>,
+    staged: Mutex<HashMap<Staging, Vec<u8>>>,
+    log: Mutex<(
+        u64,          // index of the first entry
+        Vec<Vec<u8>>, // entries
+    )>,
 }
 
 impl SimplePersister {
     pub fn new() -> SimplePersister {
         SimplePersister {
             states: Mutex::default(),
+            staged: Mutex::default(),
+            log: Mutex::default(),
         }
     }
 }
 
 impl Persister for SimplePersister {
-    fn raft_state(&self) -> Vec<u8> {
-        self.states.lock().unwrap().0.clone()
+    fn raft_state(&self) -> io::Result<Vec<u8>> {
+        Ok(self.states.lock().unwrap().0.clone())
     }
 
-    fn save_raft_state(&self, state: Vec<u8>) {
+    fn save_raft_state(&self, state: Vec<u8>) -> io::Result<()> {
         self.states.lock().unwrap().0 = state;
+        Ok(())
     }
 
-    fn save_state_and_snapshot(&self, state: Vec<u8>, snapshot: Vec<u8>) {
-        self.states.lock().unwrap().0 = state;
-        self.states.lock().unwrap().1 = snapshot;
+    fn save_state_and_snapshot(&self, state: Vec<u8>, snapshot: Vec<u8>) -> io::Result<()> {
+        *self.states.lock().unwrap() = (state, snapshot);
+        Ok(())
+    }
+
+    fn snapshot(&self) -> io::Result<Vec<u8>> {
+        Ok(self.states.lock().unwrap().1.clone())
+    }
+
+    fn staged_len(&self, staging: Staging) -> io::Result<u64> {
+        Ok(self
+            .staged
+            .lock()
+            .unwrap()
+            .get(&staging)
+            .map_or(0, |staged| staged.len() as u64))
     }
 
-    fn snapshot(&self) -> Vec<u8> {
-        self.states.lock().unwrap().1.clone()
+    fn stage_snapshot(&self, staging: Staging, offset: u64, data: &[u8]) -> io::Result<()> {
+        let mut staged = self.staged.lock().unwrap();
+        let staged = staged.entry(staging).or_default();
+        staged.resize(offset as usize, 0);
+        staged.extend_from_slice(data);
+        Ok(())
+    }
+
+    fn save_state_and_staged_snapshot(&self, state: Vec<u8>, staging: Staging) -> io::Result<()> {
+        let snapshot = self
+            .staged
+            .lock()
+            .unwrap()
+            .remove(&staging)
+            .unwrap_or_default();
+        self.save_state_and_snapshot(state, snapshot)
+    }
+
+    fn log_entries(&self) -> io::Result<Vec<Vec<u8>>> {
+        Ok(self.log.lock().unwrap().1.clone())
+    }
+
+    fn append_log(&self, index: u64, entries: Vec<Vec<u8>>) -> io::Result<()> {
+        let mut log = self.log.lock().unwrap();
+        if log.1.is_empty() || index < log.0 {
+            log.0 = index;
+            log.1.clear();
+        }
+        let len = (index - log.0) as usize;
+        log.1.truncate(len);
+        log.1.extend(entries);
+        Ok(())
+    }
+
+    fn compact_log(&self, index: u64) -> io::Result<()> {
+        let mut log = self.log.lock().unwrap();
+        let len = min(log.1.len(), (index + 1).saturating_sub(log.0) as usize);
+        log.1.drain(..len);
+        log.0 += len as u64;
+        Ok(())
     }
 }
 
@@ -189,24 +485,129 @@ mod tests {
     #[test]
     fn test_object_safety() {
         let sp = SimplePersister::new();
-        sp.save_raft_state(vec![111]);
+        sp.save_raft_state(vec![111]).unwrap();
         let obj: Box<dyn Persister + Sync> = Box::new(sp);
-        assert_eq!(obj.raft_state(), vec![111]);
-        obj.save_state_and_snapshot(vec![222], vec![123]);
-        assert_eq!(obj.raft_state(), vec![222]);
-        assert_eq!(obj.snapshot(), vec![123]);
+        assert_eq!(obj.raft_state().unwrap(), vec![111]);
+        obj.save_state_and_snapshot(vec![222], vec![123]).unwrap();
+        assert_eq!(obj.raft_state().unwrap(), vec![222]);
+        assert_eq!(obj.snapshot().unwrap(), vec![123]);
 
         let cloneable_obj: Arc<dyn Persister> = Arc::new(obj);
-        assert_eq!(cloneable_obj.raft_state(), vec![222]);
-        assert_eq!(cloneable_obj.snapshot(), vec![123]);
+        assert_eq!(cloneable_obj.raft_state().unwrap(), vec![222]);
+        assert_eq!(cloneable_obj.snapshot().unwrap(), vec![123]);
 
         let cloneable_obj_ = cloneable_obj.clone();
-        cloneable_obj.save_raft_state(vec![233]);
-        assert_eq!(cloneable_obj_.raft_state(), vec![233]);
-        assert_eq!(cloneable_obj_.snapshot(), vec![123]);
+        cloneable_obj.save_raft_state(vec![233]).unwrap();
+        assert_eq!(cloneable_obj_.raft_state().unwrap(), vec![233]);
+        assert_eq!(cloneable_obj_.snapshot().unwrap(), vec![123]);
 
         let sp = SimplePersister::new();
         let obj: Arc<dyn Persister + Sync> = Arc::new(sp);
         let _box_obj: Box<dyn Persister> = Box::new(obj);
     }
+
+    #[test]
+    fn test_staged_snapshot() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let persisters: Vec<Box<dyn Persister>> = vec![
+            Box::new(SimplePersister::new()),
+            Box::new(FilePersister::with_path(dir.path().join("raft")).unwrap()),
+        ];
+        for per in persisters {
+            per.save_state_and_snapshot(vec![1], vec![9; 10]).unwrap();
+            per.stage_snapshot(Staging::Received, 0, &[1, 2, 3])
+                .unwrap();
+            per.stage_snapshot(Staging::Received, 3, &[4, 5]).unwrap();
+            // a chunk sent again after a lost reply overwrites the tail
+            per.stage_snapshot(Staging::Received, 3, &[4, 5, 6])
+                .unwrap();
+            assert_eq!(per.staged_len(Staging::Received).unwrap(), 6);
+            assert_eq!(per.staged_len(Staging::Generated).unwrap(), 0);
+            assert_eq!(per.snapshot_len().unwrap(), 10);
+
+            per.save_state_and_staged_snapshot(vec![2], Staging::Received)
+                .unwrap();
+            assert_eq!(per.raft_state().unwrap(), vec![2]);
+            assert_eq!(per.snapshot().unwrap(), vec![1, 2, 3, 4, 5, 6]);
+            assert_eq!(per.read_snapshot(2, 3).unwrap(), vec![3, 4, 5]);
+            assert_eq!(per.read_snapshot(5, 3).unwrap(), vec![6]);
+            assert_eq!(per.staged_len(Staging::Received).unwrap(), 0);
+        }
+    }
+
+    #[test]
+    fn test_log() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let persisters: Vec<Box<dyn Persister>> = vec![
+            Box::new(SimplePersister::new()),
+            Box::new(FilePersister::with_path(dir.path().join("raft")).unwrap()),
+        ];
+        for per in persisters {
+            per.append_log(1, vec![vec![1], vec![2], vec![3]]).unwrap();
+            per.append_log(4, vec![vec![4]]).unwrap();
+            // a conflicting entry replaces those from it on
+            per.append_log(3, vec![vec![33]]).unwrap();
+            assert_eq!(per.log_entries().unwrap(), vec![vec![1], vec![2], vec![33]]);
+            per.save_raft_state(vec![7]).unwrap();
+            assert_eq!(per.raft_state().unwrap(), vec![7]);
+            assert_eq!(per.log_entries().unwrap().len(), 3);
+
+            per.compact_log(3).unwrap();
+            per.append_log(4, vec![vec![4]]).unwrap();
+            assert_eq!(per.log_entries().unwrap().last(), Some(&vec![4]));
+        }
+        let per = FilePersister::with_path(dir.path().join("raft")).unwrap();
+        assert_eq!(per.raft_state().unwrap(), vec![7]);
+        assert_eq!(per.log_entries().unwrap().last(), Some(&vec![4]));
+    }
+
+    #[test]
+    fn test_manifest() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("raft");
+        let files = || {
+            let mut files: Vec<String> = fs::read_dir(&path)
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+                .filter(|name| name != "raft_log")
+                .collect();
+            files.sort();
+            files
+        };
+
+        // the files written before there was a manifest are read, and
+        // replaced by the first save
+        fs::create_dir(&path).unwrap();
+        fs::write(path.join("raft_state.bin"), [1]).unwrap();
+        fs::write(path.join("snapshot.bin"), [2]).unwrap();
+        let per = FilePersister::with_path(path.clone()).unwrap();
+        assert_eq!(per.raft_state().unwrap(), vec![1]);
+        assert_eq!(per.snapshot().unwrap(), vec![2]);
+        per.save_raft_state(vec![3]).unwrap();
+        assert_eq!(
+            files(),
+            vec!["MANIFEST", "raft_state.1.bin", "snapshot.bin"]
+        );
+        per.save_state_and_snapshot(vec![4], vec![5]).unwrap();
+        assert_eq!(
+            files(),
+            vec!["MANIFEST", "raft_state.2.bin", "snapshot.2.bin"]
+        );
+
+        // a save cut short by a crash is dropped whole
+        fs::write(path.join("raft_state.3.bin"), [6]).unwrap();
+        fs::write(path.join("snapshot.3.bin"), [7]).unwrap();
+        fs::write(path.join("MANIFEST.tmp"), "{").unwrap();
+        let per = FilePersister::with_path(path.clone()).unwrap();
+        assert_eq!(per.raft_state().unwrap(), vec![4]);
+        assert_eq!(per.snapshot().unwrap(), vec![5]);
+        assert_eq!(
+            files(),
+            vec!["MANIFEST", "raft_state.2.bin", "snapshot.2.bin"]
+        );
+        per.save_raft_state(vec![8]).unwrap();
+        let per = FilePersister::with_path(path.clone()).unwrap();
+        assert_eq!(per.raft_state().unwrap(), vec![8]);
+        assert_eq!(per.snapshot().unwrap(), vec![5]);
+    }
 }
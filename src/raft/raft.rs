@@ -1,14 +1,15 @@
 use std::{
     cmp::{max, min},
+    collections::{BTreeMap, HashMap, HashSet},
     fmt::Display,
     sync::Arc,
     sync::{
-        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        atomic::{AtomicBool, AtomicU64, Ordering},
         Mutex,
     },
     task::Poll,
     thread,
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 use futures::FutureExt;
@@ -25,17 +26,37 @@ use tokio::{
 use tokio_stream::{Stream, StreamExt};
 use tonic::{transport::Channel, Code, Request, Response, Status};
 
+const MIN_ELECTION_TIMEOUT_MS: u64 = 600;
+
 fn election_timeout() -> Duration {
-    let variant = rand::thread_rng().gen_range(600, 800);
+    let variant = rand::thread_rng().gen_range(MIN_ELECTION_TIMEOUT_MS, 800);
     Duration::from_millis(variant)
 }
 
+/// How much faster a follower's clock may run than the leader's over an
+/// election timeout
+const MAX_CLOCK_DRIFT_MS: u64 = 100;
+
+/// How long a quorum of acks lets the leader serve reads on its own. No
+/// other leader is elected meanwhile, since the voters refuse to vote within
+/// an election timeout of hearing from this one.
+const LEASE_MS: u64 = MIN_ELECTION_TIMEOUT_MS - MAX_CLOCK_DRIFT_MS;
+
+/// How long a leader waits for its leadership to be taken over before it
+/// takes proposals again
+const LEADER_TRANSFER_TIMEOUT_MS: u64 = 2000;
+
+/// How many bytes of a snapshot one InstallSnapshot request carries, well
+/// under the gRPC message limit
+const SNAPSHOT_CHUNK_BYTES: usize = 256 * 1024;
+
 fn heartbeat_timeout() -> Duration {
     // let variant = rand::thread_rng().gen_range(100, 104);
     let variant = 50;
     Duration::from_millis(variant)
 }
 
+use super::membership::ConfChange;
 use super::persister::*;
 use crate::preclude::*;
 // use crate::rpc::raft_service::*;
@@ -62,6 +83,41 @@ pub struct Persistent {
     pub last_included_index: u64,
     #[prost(uint64, tag = "5")]
     pub last_included_term: u64,
+    #[prost(message, optional, tag = "6")]
+    pub membership: Option<Membership>,
+}
+
+/// A leadership transfer in progress
+struct LeaderTransfer {
+    target: usize,
+    deadline: Instant,
+    // replied once the transferee leads, or the transfer fails
+    tx: Sender<Result<()>>,
+}
+
+/// How far the snapshot streamed to a follower got
+#[derive(Default)]
+struct SnapshotProgress {
+    // the last index the snapshot includes
+    index: u64,
+    // where the follower expects the next chunk
+    offset: u64,
+    streaming: bool,
+}
+
+/// Records that `server` acked a request sent at `sent` in `term`, the
+/// latest of which counts toward the lease
+fn renew_lease(
+    acks: &Mutex<HashMap<usize, (u64, Instant)>>,
+    server: usize,
+    term: u64,
+    sent: Instant,
+) {
+    let mut acks = acks.lock().unwrap();
+    let ack = acks.entry(server).or_insert((term, sent));
+    if *ack < (term, sent) {
+        *ack = (term, sent);
+    }
 }
 
 #[derive(PartialEq, Clone)]
@@ -73,11 +129,11 @@ enum RaftRole {
 
 // A single RaftInner peer.
 struct RaftInner {
-    // RPC end points of all peers
-    peers: Vec<RaftRpcClient<Channel>>,
+    // RPC end points of all peers, by node id
+    peers: BTreeMap<usize, RaftRpcClient<Channel>>,
     // Object to hold this peer's persisted state
     persister: Arc<dyn Persister>,
-    // this peer's index into peers[]
+    // this peer's node id
     me: usize,
 
     // Persistent state on all servers
@@ -89,6 +145,17 @@ struct RaftInner {
     // auxilary state
     role: RaftRole,
     is_leader: Arc<AtomicBool>,
+    // when a current leader was last heard from
+    leader_contact: Option<Instant>,
+    // the leader of the current term, once heard from
+    leader: Option<usize>,
+    // the peers that replied to the leader since it last checked for a quorum
+    recent_active: Arc<Mutex<HashSet<usize>>>,
+    quorum_checked: Instant,
+    // whether the leader serves reads on its own while it holds a lease
+    lease_read: bool,
+    // the term and send time of the latest request each peer acked
+    lease_acks: Arc<Mutex<HashMap<usize, (u64, Instant)>>>,
 
     // Volatile state on all servers
     commit_index: Arc<AtomicU64>,
@@ -96,17 +163,34 @@ struct RaftInner {
 
     // Volatile state on leader
     // Reinitialized after election
-    next_index: Vec<Arc<AtomicU64>>,
-    match_index: Vec<Arc<AtomicU64>>,
+    next_index: BTreeMap<usize, Arc<AtomicU64>>,
+    match_index: BTreeMap<usize, Arc<AtomicU64>>,
 
     // Persistent state on all servers
     // Add when using Snapshot
     last_included_index: Arc<AtomicU64>,
     last_included_term: Arc<AtomicU64>,
-
-    // current save size
+    // the membership as of last_included_index
+    snapshot_membership: Membership,
+    // the snapshots streamed to followers, resumed where they stopped
+    snapshot_progress: Arc<Mutex<HashMap<usize, SnapshotProgress>>>,
+    // the term and last index of the snapshot being received
+    receiving: Option<(u64, u64)>,
+
+    // the membership of the latest entry changing it, in effect once appended
+    membership: Membership,
+    membership_index: u64,
+    // replied once the membership change proposed is done
+    conf_change_tx: Option<Sender<Result<Membership>>>,
+    // no proposal is taken while the leadership is handed over
+    transfer: Option<LeaderTransfer>,
+
+    // current save size, of the state and the log
     // update when persist
     raft_state_size: Arc<AtomicU64>,
+    state_size: u64,
+    // on the leader, the last index every replica has
+    replicated_index: Arc<AtomicU64>,
 
     // ApplyMsg channel
     sender: UnboundedSender<RaftEvent>,
@@ -114,6 +198,10 @@ struct RaftInner {
 
     // Read Only
     read_only: super::read_only::ReadOnly,
+    // the read indexes followers asked for, replied once confirmed
+    read_index: super::read_only::ReadOnly<Sender<ReadIndexReply>>,
+    // the reads served by this follower, applied after their read index
+    follower_reads: super::read_only::ReadOnly,
 
     // for stream
     receiver: UnboundedReceiver<RaftEvent>,
@@ -145,27 +233,28 @@ impl Display for RaftInner {
 }
 
 impl RaftInner {
-    /// the service or tester wants to create a RaftInner server. the ports
-    /// of all the RaftInner servers (including this one) are in peers. this
-    /// server's port is peers[me]. all the servers' peers arrays
-    /// have the same order. persister is a place for this server to
+    /// the service or tester wants to create a RaftInner server. peers
+    /// are the voters the group starts with, including this server with id
+    /// me, or empty for a server waiting to be added to a running group.
+    /// persister is a place for this server to
     /// save its persistent state, and also initially holds the most
     /// recent saved state, if any. apply_ch is a channel on which the
     /// tester or service expects RaftInner to send ApplyMsg messages.
     /// This method must return quickly.
     pub fn new(
-        peers: Vec<RaftRpcClient<Channel>>,
+        peers: Vec<Member>,
         me: usize,
         persister: Arc<dyn Persister>,
         apply_ch: UnboundedSender<ApplyMsg>,
     ) -> (RaftInner, UnboundedSender<RaftEvent>) {
-        let raft_state = persister.raft_state();
-        let peers_num = peers.len();
+        let raft_state = persister
+            .raft_state()
+            .unwrap_or_else(|e| panic!("Unable to read the raft state: {}", e));
 
         let (sender, receiver) = unbounded_channel();
 
         let mut rf = RaftInner {
-            peers,
+            peers: BTreeMap::new(),
             persister,
             me,
             // state: Arc::default(),
@@ -175,21 +264,42 @@ impl RaftInner {
 
             is_leader: Arc::new(AtomicBool::new(false)),
             role: RaftRole::Follower,
+            leader_contact: None,
+            leader: None,
+            recent_active: Arc::new(Mutex::new(HashSet::new())),
+            quorum_checked: Instant::now(),
+            lease_read: false,
+            lease_acks: Arc::new(Mutex::new(HashMap::new())),
 
             commit_index: Arc::new(AtomicU64::new(0)),
             last_applied: Arc::new(AtomicU64::new(0)),
 
             last_included_index: Arc::new(AtomicU64::new(0)),
             last_included_term: Arc::new(AtomicU64::new(0)),
+            snapshot_membership: Membership {
+                voters: peers,
+                ..Membership::default()
+            },
+            snapshot_progress: Arc::new(Mutex::new(HashMap::new())),
+            receiving: None,
             raft_state_size: Arc::new(AtomicU64::new(0)),
+            state_size: 0,
+            replicated_index: Arc::new(AtomicU64::new(0)),
 
-            next_index: Vec::new(),
-            match_index: Vec::new(),
+            membership: Membership::default(),
+            membership_index: 0,
+            conf_change_tx: None,
+            transfer: None,
+
+            next_index: BTreeMap::new(),
+            match_index: BTreeMap::new(),
 
             sender: sender.clone(),
             apply_ch,
 
             read_only: super::read_only::ReadOnly::new(),
+            read_index: super::read_only::ReadOnly::new(),
+            follower_reads: super::read_only::ReadOnly::new(),
 
             // for stream
             receiver,
@@ -197,13 +307,9 @@ impl RaftInner {
             apply_msg_delay: Delay::new(heartbeat_timeout()),
         };
 
-        for _i in 0..peers_num {
-            rf.next_index.push(Arc::new(AtomicU64::new(0)));
-            rf.match_index.push(Arc::new(AtomicU64::new(0)));
-        }
-
         // initialize from state persisted before a crash
         rf.restore(&raft_state);
+        rf.reload_membership();
 
         debug!("{} Started!", rf);
 
@@ -213,35 +319,66 @@ impl RaftInner {
     /// save RaftInner's persistent state to stable storage,
     /// where it can later be retrieved after a crash and restart.
     /// see paper's Figure 2 for a description of what should be persistent.
+    /// The log is persisted on its own, by `persist_log`.
+    ///
+    /// A node that cannot save its state may not answer for it, so it stops.
     fn persist(&mut self) {
-        let mut data = Vec::new();
-        let per = Persistent {
-            current_term: self.current_term.load(Ordering::SeqCst),
-            voted_for: self.voted_for.map_or(-1, |v| v as i32),
-            log: self.log.clone(),
-            last_included_index: self.last_included_index.load(Ordering::SeqCst),
-            last_included_term: self.last_included_term.load(Ordering::SeqCst),
-        };
-        per.encode(&mut data).unwrap();
-        self.raft_state_size
-            .store(data.len() as u64, Ordering::SeqCst);
-        self.persister.save_raft_state(data);
+        let data = self.encode_state();
+        self.persister
+            .save_raft_state(data)
+            .unwrap_or_else(|e| panic!("{} Unable to persist: {}", self, e));
     }
 
-    /// save RaftInner's persistent state to stable storage,
-    /// where it can later be retrieved after a crash and restart.
-    /// see paper's Figure 2 for a description of what should be persistent.
-    fn persist_with_snapshot(&mut self, snapshot: Vec<u8>) {
+    /// save the log entries from `index` on, replacing those persisted
+    /// there and after
+    fn persist_log(&mut self, index: u64) {
+        let last_included_index = self.last_included_index.load(Ordering::SeqCst);
+        let entries = self.log[(index - last_included_index - 1) as usize..]
+            .iter()
+            .map(|entry| {
+                let mut data = Vec::new();
+                entry.encode(&mut data).unwrap();
+                data
+            })
+            .collect();
+        self.persister
+            .append_log(index, entries)
+            .unwrap_or_else(|e| panic!("{} Unable to persist the log: {}", self, e));
+        self.update_state_size();
+    }
+
+    /// save RaftInner's persistent state together with a snapshot, and drop
+    /// the log the snapshot covers
+    fn persist_with_snapshot(&mut self, staging: Staging) {
+        let data = self.encode_state();
+        let last_included_index = self.last_included_index.load(Ordering::SeqCst);
+        self.persister
+            .save_state_and_staged_snapshot(data, staging)
+            .and_then(|_| self.persister.compact_log(last_included_index))
+            .unwrap_or_else(|e| panic!("{} Unable to persist the snapshot: {}", self, e));
+        self.update_state_size();
+    }
+
+    fn encode_state(&mut self) -> Vec<u8> {
         let mut data = Vec::new();
         let per = Persistent {
             current_term: self.current_term.load(Ordering::SeqCst),
             voted_for: self.voted_for.map_or(-1, |v| v as i32),
-            log: self.log.clone(),
+            log: Vec::new(),
             last_included_index: self.last_included_index.load(Ordering::SeqCst),
             last_included_term: self.last_included_term.load(Ordering::SeqCst),
+            membership: Some(self.snapshot_membership.clone()),
         };
         per.encode(&mut data).unwrap();
-        self.persister.save_state_and_snapshot(data, snapshot);
+        self.state_size = data.len() as u64;
+        self.update_state_size();
+        data
+    }
+
+    fn update_state_size(&self) {
+        let log_size: usize = self.log.iter().map(|entry| entry.encoded_len()).sum();
+        self.raft_state_size
+            .store(self.state_size + log_size as u64, Ordering::SeqCst);
     }
 
     /// restore previously persisted state.
@@ -256,15 +393,28 @@ impl RaftInner {
                 self.current_term = Arc::new(AtomicU64::new(o.current_term));
                 self.voted_for = {
                     match o.voted_for < 0 {
-                        true => Some(o.voted_for as usize),
-                        false => None,
+                        true => None,
+                        false => Some(o.voted_for as usize),
                     }
                 };
-                self.log = o.log;
                 self.last_included_index = Arc::new(AtomicU64::new(o.last_included_index));
                 self.last_included_term = Arc::new(AtomicU64::new(o.last_included_term));
                 self.commit_index = Arc::new(AtomicU64::new(o.last_included_index));
                 self.last_applied = Arc::new(AtomicU64::new(o.last_included_index));
+                // state persisted before membership changes keeps the peers given
+                if let Some(membership) = o.membership {
+                    self.snapshot_membership = membership;
+                }
+                self.state_size = data.len() as u64;
+                if o.log.is_empty() {
+                    self.log = self.restore_log(o.last_included_index);
+                    self.update_state_size();
+                } else {
+                    // state persisted before the log was kept apart moves it
+                    self.log = o.log;
+                    self.persist_log(o.last_included_index + 1);
+                    self.persist();
+                }
             }
             Err(e) => {
                 panic!("{:?}", e);
@@ -272,6 +422,27 @@ impl RaftInner {
         }
     }
 
+    /// read the log persisted after the snapshot, up to the first gap
+    fn restore_log(&self, last_included_index: u64) -> Vec<LogEntry> {
+        let mut log: Vec<LogEntry> = Vec::new();
+        let entries = self
+            .persister
+            .log_entries()
+            .unwrap_or_else(|e| panic!("{} Unable to read the log: {}", self, e));
+        for data in entries {
+            let entry = LogEntry::decode(&data[..]).unwrap();
+            let next = log
+                .last()
+                .map_or(last_included_index + 1, |last| last.index + 1);
+            if entry.index == next {
+                log.push(entry);
+            } else if entry.index > next {
+                break;
+            }
+        }
+        log
+    }
+
     fn send_apply_msg(&mut self) {
         while !self.apply_ch.is_closed()
             && self.last_applied.load(Ordering::SeqCst) < self.commit_index.load(Ordering::SeqCst)
@@ -302,18 +473,20 @@ impl RaftInner {
         let term = self.current_term.load(Ordering::SeqCst);
         let is_leader = self.is_leader.load(Ordering::SeqCst);
 
-        if is_leader {
+        if is_leader && self.transfer.is_none() {
             self.log.push(LogEntry {
                 command: command.to_owned(),
                 index,
                 term,
+                membership: None,
             });
             info!(
                 "{} Receive a Command! Append to [log {} {}]",
                 self, index, term
             );
-            self.match_index[self.me] = Arc::new(AtomicU64::new(index as u64));
-            self.persist();
+            self.match_index
+                .insert(self.me, Arc::new(AtomicU64::new(index as u64)));
+            self.persist_log(index);
             Ok((index, term))
         } else {
             Err(KvError::NotLeader)
@@ -323,20 +496,82 @@ impl RaftInner {
         let commit_index = self.commit_index.load(Ordering::SeqCst);
         if self.is_leader.load(Ordering::SeqCst) {
             if self.term(commit_index).unwrap() == self.current_term.load(Ordering::SeqCst) {
-                self.read_only.add_request(commit_index, command.to_owned());
-                self.send_heart_beat_all();
+                if self.holds_lease() {
+                    self.apply_read_only(commit_index, vec![command.to_owned()]);
+                } else {
+                    self.read_only.add_request(commit_index, command.to_owned());
+                    self.send_heart_beat_all();
+                }
                 Ok((commit_index, self.last_term()))
             } else {
                 Err(KvError::StringError(
                     "New Leader without commit".to_string(),
                 ))
             }
+        } else if self.forward_read_only(command) {
+            Ok((commit_index, self.last_term()))
         } else {
             Err(KvError::NotLeader)
         }
     }
 
+    /// Serves a read at this follower: the leader is asked for a read index,
+    /// and the read is applied once this node has applied up to it. Returns
+    /// whether a live leader was known to ask.
+    fn forward_read_only(&mut self, command: &[u8]) -> bool {
+        let leader = match self.leader {
+            Some(leader) if self.leader_alive() && self.peers.contains_key(&leader) => leader,
+            _ => return false,
+        };
+        let args = ReadIndexArgs {
+            follower_id: self.me as i32,
+        };
+        debug!("{} Ask {} for a read index", self, leader);
+        let mut peer = self.peers[&leader].clone();
+        let tx = self.sender.clone();
+        let command = command.to_owned();
+        tokio::spawn(async move {
+            if let Ok(reply) = peer.read_index(Request::new(args)).await {
+                let reply = reply.into_inner();
+                if reply.success {
+                    let _ = tx.send(RaftEvent::ReadIndexReady(reply.read_index, command));
+                }
+            }
+        });
+        true
+    }
+
+    /// Replies the commit index to a follower once a quorum confirms this
+    /// node still leads, right away while it holds a lease
+    fn start_read_index(&mut self, tx: Sender<ReadIndexReply>) {
+        let commit_index = self.commit_index.load(Ordering::SeqCst);
+        let term = self.current_term.load(Ordering::SeqCst);
+        if !self.is_leader.load(Ordering::SeqCst) || self.term(commit_index) != Some(term) {
+            let _ = tx.send(ReadIndexReply::default());
+        } else if self.holds_lease() {
+            let _ = tx.send(ReadIndexReply {
+                success: true,
+                read_index: commit_index,
+            });
+        } else {
+            self.read_index.add_request(commit_index, tx);
+            self.send_heart_beat_all();
+        }
+    }
+
+    /// Applies the reads at this follower whose read index it has applied
+    fn apply_follower_reads(&mut self) {
+        self.send_apply_msg();
+        let last_applied = self.last_applied.load(Ordering::SeqCst);
+        let msgs = self.follower_reads.pop_requests(last_applied);
+        if !msgs.is_empty() {
+            self.apply_read_only(last_applied, msgs);
+        }
+    }
+
     fn apply_read_only(&mut self, index: u64, msgs: Vec<Vec<u8>>) {
+        // the entries committed before the reads are applied ahead of them
+        self.send_apply_msg();
         if !self.apply_ch.is_closed() {
             for msg in msgs {
                 let msg = ApplyMsg {
@@ -364,11 +599,15 @@ impl RaftInner {
         self.role = RaftRole::Leader;
         self.is_leader.store(true, Ordering::SeqCst);
         let (index, _term) = self.get_last_log_info();
-        for i in 0..self.peers.len() {
-            self.next_index[i] = Arc::new(AtomicU64::new(index + 1));
-            self.match_index[i] = Arc::new(AtomicU64::new(0));
+        for id in self.peers.keys().cloned().collect::<Vec<_>>() {
+            self.next_index
+                .insert(id, Arc::new(AtomicU64::new(index + 1)));
+            self.match_index.insert(id, Arc::new(AtomicU64::new(0)));
         }
-        self.match_index[self.me] = Arc::new(AtomicU64::new(index));
+        self.match_index
+            .insert(self.me, Arc::new(AtomicU64::new(index)));
+        self.recent_active.lock().unwrap().clear();
+        self.quorum_checked = Instant::now();
         self.persist();
 
         let last_included_index = self.last_included_index.load(Ordering::SeqCst);
@@ -376,11 +615,10 @@ impl RaftInner {
             .store(last_included_index, Ordering::SeqCst);
         self.last_applied
             .store(last_included_index, Ordering::SeqCst);
-        let snapshot = self.persister.snapshot();
         let msg = ApplyMsg {
             command_valid: false,
-            command: snapshot,
-            command_index: 0,
+            command: Vec::new(),
+            command_index: last_included_index,
         };
         self.apply_ch.send(msg).expect("Unable send ApplyMsg");
 
@@ -388,39 +626,96 @@ impl RaftInner {
     }
 
     fn become_follower(&mut self, term: u64) {
+        // a higher term is the transferee running, kept until it leads
+        if self.role == RaftRole::Leader && term <= self.current_term.load(Ordering::SeqCst) {
+            if let Some(transfer) = self.transfer.take() {
+                let _ = transfer.tx.send(Err(KvError::StringError(String::from(
+                    "stepped down before the transfer",
+                ))));
+            }
+        }
+        if term > self.current_term.load(Ordering::SeqCst) {
+            self.leader = None;
+        }
         self.current_term.store(term, Ordering::SeqCst);
         // self.voted_for = None;
         self.role = RaftRole::Follower;
         self.is_leader.store(false, Ordering::SeqCst);
+        // a change proposed as leader is left to the next one
+        self.conf_change_tx = None;
         self.persist();
         debug!("{} Become Follower", self);
     }
 
-    fn become_candidate(&mut self) {
+    /// Asks the voters whether they would vote for this node before running
+    /// for leader, so that a node cut off from the group does not raise its
+    /// term and depose the leader once it is back.
+    fn become_pre_candidate(&mut self) {
+        // nodes not added yet or removed already do not run for leader
+        if !self.membership.is_voter(self.me) {
+            return;
+        }
+        if let Some(transfer) = self.transfer.take() {
+            let _ = transfer.tx.send(Err(KvError::StringError(String::from(
+                "no leader elected after the transfer",
+            ))));
+        }
+        self.send_pre_vote_all();
+    }
+
+    /// Steps down unless a quorum replied since the last check, once per
+    /// election timeout, so that a leader cut off from the group stops
+    /// serving. Returns whether this node still leads.
+    fn check_quorum(&mut self) -> bool {
+        if self.quorum_checked.elapsed() < Duration::from_millis(MIN_ELECTION_TIMEOUT_MS) {
+            return true;
+        }
+        self.quorum_checked = Instant::now();
+        let mut active = std::mem::take(&mut *self.recent_active.lock().unwrap());
+        active.insert(self.me);
+        if self.membership.has_quorum(&active) {
+            return true;
+        }
+        warn!("{} Step down without hearing from a quorum", self);
+        let term = self.current_term.load(Ordering::SeqCst);
+        self.become_follower(term);
+        false
+    }
+
+    /// Runs for leader at the next term, `leader_transfer` if asked to by
+    /// the leader handing its leadership over.
+    fn become_candidate(&mut self, leader_transfer: bool) {
+        if !self.membership.is_voter(self.me) {
+            return;
+        }
         self.current_term.fetch_add(1, Ordering::SeqCst);
         self.role = RaftRole::Candidate;
+        self.leader = None;
         self.is_leader.store(false, Ordering::SeqCst);
         self.voted_for = Some(self.me);
         self.persist();
         debug!("{} Become Candidate", self);
 
-        self.send_request_vote_all();
+        self.send_request_vote_all(leader_transfer);
     }
 
     fn update_commit_index(&mut self) {
         let last_included_index = self.last_included_index.load(Ordering::SeqCst);
         let last_included_term = self.last_included_term.load(Ordering::SeqCst);
-        self.match_index[self.me].store(
+        self.match_index.entry(self.me).or_default().store(
             self.log.len() as u64 + last_included_index,
             Ordering::SeqCst,
         );
-        let mut match_index_all: Vec<u64> = self
-            .match_index
-            .iter()
-            .map(|v| v.load(Ordering::SeqCst))
-            .collect();
-        match_index_all.sort_unstable();
-        let match_n = match_index_all[self.peers.len() / 2];
+        let match_index = &self.match_index;
+        let match_of = |id| {
+            match_index
+                .get(&id)
+                .map_or(0, |index| index.load(Ordering::SeqCst))
+        };
+        let match_n = self.membership.quorum_index(match_of);
+        let replicated = self.replication_ids().into_iter().map(match_of).min();
+        self.replicated_index
+            .store(replicated.unwrap_or(0), Ordering::SeqCst);
         if match_n > self.commit_index.load(Ordering::SeqCst)
             && (match_n == last_included_index
                 || self
@@ -431,11 +726,289 @@ impl RaftInner {
         {
             debug!("{} Update commit index: {}", self, match_n);
             self.commit_index.store(match_n, Ordering::SeqCst);
+            self.advance_membership();
         }
         self.send_apply_msg();
     }
 }
 
+impl RaftInner {
+    /// Takes the membership of the latest entry changing it, or of the
+    /// snapshot if none is left in the log, and connects to new peers.
+    fn reload_membership(&mut self) {
+        let (index, membership) = self
+            .log
+            .iter()
+            .rev()
+            .find_map(|entry| entry.membership.clone().map(|m| (entry.index, m)))
+            .unwrap_or_else(|| {
+                (
+                    self.last_included_index.load(Ordering::SeqCst),
+                    self.snapshot_membership.clone(),
+                )
+            });
+        let last_index = self.last_index();
+        for member in membership
+            .voters
+            .iter()
+            .chain(membership.outgoing.iter())
+            .chain(membership.learners.iter())
+        {
+            let id = member.id as usize;
+            if id != self.me && !self.peers.contains_key(&id) {
+                let channel = Channel::from_shared(format!("http://{}", member.addr))
+                    .unwrap()
+                    .connect_lazy()
+                    .unwrap();
+                self.peers.insert(id, RaftRpcClient::new(channel));
+            }
+            self.next_index
+                .entry(id)
+                .or_insert_with(|| Arc::new(AtomicU64::new(last_index + 1)));
+            self.match_index.entry(id).or_default();
+        }
+        if membership != self.membership {
+            info!("{} Membership changed at {}: {:?}", self, index, membership);
+        }
+        self.membership_index = index;
+        self.membership = membership;
+    }
+
+    /// Starts moving to the membership `change` leads to, through the joint
+    /// membership of both the current and the new voters if the voters
+    /// change, or directly if only the learners do.
+    fn propose_membership(&mut self, change: ConfChange, tx: Sender<Result<Membership>>) {
+        if !self.is_leader.load(Ordering::SeqCst) {
+            let _ = tx.send(Err(KvError::NotLeader));
+            return;
+        }
+        if self.membership.is_joint()
+            || self.membership_index > self.commit_index.load(Ordering::SeqCst)
+        {
+            let _ = tx.send(Err(KvError::StringError(String::from(
+                "another membership change is in progress",
+            ))));
+            return;
+        }
+        let membership = match self.membership.apply(&change) {
+            Some(membership) if membership.voters.is_empty() => {
+                let _ = tx.send(Err(KvError::StringError(String::from(
+                    "cannot remove the last voter",
+                ))));
+                return;
+            }
+            Some(membership) => membership,
+            // nothing to change
+            None => {
+                let _ = tx.send(Ok(self.membership.clone()));
+                return;
+            }
+        };
+        info!("{} Propose {:?}", self, change);
+        self.conf_change_tx = Some(tx);
+        if membership.voters == self.membership.voters {
+            self.append_membership(membership);
+        } else {
+            self.append_membership(Membership {
+                outgoing: self.membership.voters.clone(),
+                ..membership
+            });
+        }
+        self.send_append_entries_all();
+    }
+
+    /// Leaves the joint membership once it is committed, and replies the
+    /// change once the new membership is committed too.
+    fn advance_membership(&mut self) {
+        if !self.is_leader.load(Ordering::SeqCst)
+            || self.membership_index > self.commit_index.load(Ordering::SeqCst)
+        {
+            return;
+        }
+        if self.membership.is_joint() {
+            self.append_membership(Membership {
+                outgoing: Vec::new(),
+                ..self.membership.clone()
+            });
+            return;
+        }
+        if let Some(tx) = self.conf_change_tx.take() {
+            let _ = tx.send(Ok(self.membership.clone()));
+        }
+        // a leader removed steps down once its removal is committed
+        if !self.membership.is_voter(self.me) {
+            let term = self.current_term.load(Ordering::SeqCst);
+            self.become_follower(term);
+        }
+    }
+
+    /// The nodes entries go to, the nodes removed included until their
+    /// removal is committed, so that they learn of it.
+    fn replication_ids(&self) -> Vec<usize> {
+        let mut ids = self.membership.ids();
+        if self.membership_index > self.commit_index.load(Ordering::SeqCst) {
+            let previous = self
+                .log
+                .iter()
+                .rev()
+                .filter(|entry| entry.index < self.membership_index)
+                .find_map(|entry| entry.membership.clone())
+                .unwrap_or_else(|| self.snapshot_membership.clone());
+            ids.extend(previous.ids());
+            ids.sort_unstable();
+            ids.dedup();
+        }
+        ids
+    }
+
+    fn append_membership(&mut self, membership: Membership) {
+        let index = self.last_index() + 1;
+        let term = self.current_term.load(Ordering::SeqCst);
+        self.log.push(LogEntry {
+            command: Vec::new(),
+            index,
+            term,
+            membership: Some(membership),
+        });
+        self.reload_membership();
+        self.update_commit_index();
+        self.persist_log(index);
+    }
+}
+
+impl RaftInner {
+    /// Starts handing the leadership over to `target`, or to the voter most
+    /// caught up, by catching it up and telling it to run for leader.
+    fn transfer_leader(&mut self, target: Option<usize>, tx: Sender<Result<()>>) {
+        if !self.is_leader.load(Ordering::SeqCst) {
+            let _ = tx.send(Err(KvError::NotLeader));
+            return;
+        }
+        if self.transfer.is_some() {
+            let _ = tx.send(Err(KvError::StringError(String::from(
+                "another leader transfer is in progress",
+            ))));
+            return;
+        }
+        let target = target.or_else(|| {
+            self.membership
+                .voter_ids()
+                .into_iter()
+                .filter(|id| *id != self.me)
+                .max_by_key(|id| {
+                    self.match_index
+                        .get(id)
+                        .map_or(0, |index| index.load(Ordering::SeqCst))
+                })
+        });
+        let target = match target {
+            Some(target) if target == self.me => {
+                let _ = tx.send(Ok(()));
+                return;
+            }
+            Some(target) if self.membership.is_voter(target) => target,
+            Some(target) => {
+                let _ = tx.send(Err(KvError::StringError(format!(
+                    "node {} is not a voter",
+                    target
+                ))));
+                return;
+            }
+            None => {
+                let _ = tx.send(Err(KvError::StringError(String::from(
+                    "no other voter to take over",
+                ))));
+                return;
+            }
+        };
+        info!("{} Transfer leadership to {}", self, target);
+        self.transfer = Some(LeaderTransfer {
+            target,
+            deadline: Instant::now() + Duration::from_millis(LEADER_TRANSFER_TIMEOUT_MS),
+            tx,
+        });
+        self.send_append_entries_all();
+        self.advance_transfer();
+    }
+
+    /// Tells the transferee to run for leader once it holds the whole log,
+    /// or gives the transfer up past its deadline.
+    fn advance_transfer(&mut self) {
+        let target = match &self.transfer {
+            Some(transfer) if transfer.deadline <= Instant::now() => {
+                warn!("{} Give up transferring leadership", self);
+                let transfer = self.transfer.take().unwrap();
+                let _ = transfer.tx.send(Err(KvError::StringError(String::from(
+                    "timed out waiting for the transferee",
+                ))));
+                return;
+            }
+            Some(transfer) => transfer.target,
+            None => return,
+        };
+        let matched = self
+            .match_index
+            .get(&target)
+            .map_or(0, |index| index.load(Ordering::SeqCst));
+        if matched < self.last_index() {
+            return;
+        }
+        let term = self.current_term.load(Ordering::SeqCst);
+        let args = TimeoutNowArgs {
+            term,
+            leader_id: self.me as i32,
+        };
+        debug!("{} Send TimeoutNow to {}", self, target);
+        let mut peer = self.peers[&target].clone();
+        let tx = self.sender.clone();
+        tokio::spawn(async move {
+            if let Ok(reply) = peer.timeout_now(Request::new(args)).await {
+                let reply = reply.into_inner();
+                if reply.term > term {
+                    let _ = tx.send(RaftEvent::BecomeFollower(reply.term));
+                }
+            }
+        });
+    }
+
+    /// Replies to the transfer handed over before stepping down, once
+    /// `leader_id` is heard from as the leader of the current term and has
+    /// committed an entry of it, so that it serves reads right away.
+    fn finish_transfer(&mut self, leader_id: usize) {
+        let target = match &self.transfer {
+            Some(transfer) if self.role != RaftRole::Leader => transfer.target,
+            _ => return,
+        };
+        let term = self.current_term.load(Ordering::SeqCst);
+        let commit_index = self.commit_index.load(Ordering::SeqCst);
+        let reply = if leader_id != target {
+            Err(KvError::StringError(format!(
+                "node {} took over instead",
+                leader_id
+            )))
+        } else if self.term(commit_index) == Some(term) {
+            Ok(())
+        } else {
+            return;
+        };
+        let _ = self.transfer.take().unwrap().tx.send(reply);
+    }
+
+    /// Runs for leader at once if told to by the leader of the current term
+    fn handle_timeout_now(&mut self, args: TimeoutNowArgs) -> TimeoutNowReply {
+        let current_term = self.current_term.load(Ordering::SeqCst);
+        // a node that lost the election of this term is left a candidate
+        if args.term == current_term && self.role != RaftRole::Leader {
+            info!(
+                "{} Run for leader on TimeoutNow from {}",
+                self, args.leader_id
+            );
+            self.become_candidate(true);
+        }
+        TimeoutNowReply { term: current_term }
+    }
+}
+
 impl RaftInner {
     fn last_index(&self) -> u64 {
         self.log
@@ -473,7 +1046,7 @@ impl RaftInner {
         server: usize,
         args: RequestVoteArgs,
     ) -> Receiver<Result<RequestVoteReply>> {
-        let peer = &self.peers[server];
+        let peer = &self.peers[&server];
         let mut peer_clone = peer.clone();
         let (tx, rx) = channel::<Result<RequestVoteReply>>();
         tokio::spawn(async move {
@@ -487,8 +1060,53 @@ impl RaftInner {
         rx
     }
 
+    /// Whether this node leads, or heard from a leader within the shortest
+    /// election timeout
+    fn leader_alive(&self) -> bool {
+        self.role == RaftRole::Leader
+            || self.leader_contact.is_some_and(|contact| {
+                contact.elapsed() < Duration::from_millis(MIN_ELECTION_TIMEOUT_MS)
+            })
+    }
+
+    /// Whether a quorum acked this leader within the lease, so that it may
+    /// serve reads without confirming its leadership first
+    fn holds_lease(&self) -> bool {
+        if !self.lease_read || self.role != RaftRole::Leader || self.transfer.is_some() {
+            return false;
+        }
+        let term = self.current_term.load(Ordering::SeqCst);
+        let lease = Duration::from_millis(LEASE_MS);
+        let mut fresh: HashSet<usize> = self
+            .lease_acks
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, (acked_term, sent))| *acked_term == term && sent.elapsed() < lease)
+            .map(|(id, _)| *id)
+            .collect();
+        fresh.insert(self.me);
+        self.membership.has_quorum(&fresh)
+    }
+
+    /// Whether this node knows it no longer votes, learners included
+    fn is_removed(&self) -> bool {
+        !self.membership.voters.is_empty() && !self.membership.is_voter(self.me)
+    }
+
     fn handle_request_vote(&mut self, args: RequestVoteArgs) -> RequestVoteReply {
         let current_term = self.current_term.load(Ordering::SeqCst);
+        // a node removed from the group no longer hears from the leader and
+        // keeps running for it, which must not depose a leader still alive,
+        // nor may the nodes removed elect one among themselves
+        let leader_alive = self.leader_alive() && !args.leader_transfer;
+        if (leader_alive || self.is_removed()) && current_term < args.term {
+            debug!("{} Handle {}, Vote false due to a live leader", self, args);
+            return RequestVoteReply {
+                term: current_term,
+                vote_granted: false,
+            };
+        }
         if current_term < args.term {
             self.voted_for = None;
             self.become_follower(args.term);
@@ -520,6 +1138,7 @@ impl RaftInner {
         } else {
             info!("{} Handle {}, Vote true", self, args);
             self.voted_for = Some(args.candidate_id as usize);
+            self.persist();
             RequestVoteReply {
                 term: args.term,
                 vote_granted: true,
@@ -527,43 +1146,129 @@ impl RaftInner {
         }
     }
 
-    fn send_request_vote_all(&mut self) {
-        let vote_count = Arc::new(AtomicUsize::new(1));
+    fn send_pre_vote(
+        &self,
+        server: usize,
+        args: RequestVoteArgs,
+    ) -> Receiver<Result<RequestVoteReply>> {
+        let peer = &self.peers[&server];
+        let mut peer_clone = peer.clone();
+        let (tx, rx) = channel::<Result<RequestVoteReply>>();
+        tokio::spawn(async move {
+            let res = peer_clone
+                .pre_vote(Request::new(args))
+                .await
+                .map(|resp| resp.into_inner())
+                .map_err(KvError::Rpc);
+            let _ = tx.send(res);
+        });
+        rx
+    }
+
+    /// Grants a pre-vote to a candidate that would win the vote at args.term,
+    /// without adopting the term or recording the vote.
+    fn handle_pre_vote(&self, args: RequestVoteArgs) -> RequestVoteReply {
+        let current_term = self.current_term.load(Ordering::SeqCst);
+        let vote_granted = args.term > current_term
+            && !self.leader_alive()
+            && !self.is_removed()
+            && self.is_up_to_date(args.last_log_index, args.last_log_term);
+        debug!("{} Handle {}, PreVote {}", self, args, vote_granted);
+        RequestVoteReply {
+            term: current_term,
+            vote_granted,
+        }
+    }
+
+    fn send_pre_vote_all(&mut self) {
+        let votes = Arc::new(Mutex::new(HashSet::new()));
+        votes.lock().unwrap().insert(self.me);
+        let term = self.current_term.load(Ordering::SeqCst) + 1;
+        // a group of one needs no one's vote
+        if self.membership.has_quorum(&votes.lock().unwrap()) {
+            self.become_candidate(false);
+            return;
+        }
+        let (last_log_index, last_log_term) = self.get_last_log_info();
+        let args = RequestVoteArgs {
+            term,
+            candidate_id: self.me as i32,
+            last_log_index,
+            last_log_term,
+            leader_transfer: false,
+        };
+        debug!("{} Send PreVote {} to ALL RaftNode", self, args);
+        let is_pre_candidate = Arc::new(AtomicBool::new(true));
+        for server in self.membership.voter_ids() {
+            if server != self.me {
+                let tx = self.sender.clone();
+                let membership = self.membership.clone();
+                let is_pre_candidate = is_pre_candidate.clone();
+                let votes = votes.clone();
+                let rx = self.send_pre_vote(server, args.clone());
+                tokio::spawn(async move {
+                    if let Ok(Ok(reply)) = rx.await {
+                        if !is_pre_candidate.load(Ordering::SeqCst) {
+                            return;
+                        }
+                        if reply.vote_granted {
+                            let mut votes = votes.lock().unwrap();
+                            votes.insert(server);
+                            if membership.has_quorum(&votes) {
+                                is_pre_candidate.store(false, Ordering::SeqCst);
+                                tx.send(RaftEvent::BecomeCandidate(term)).unwrap();
+                            }
+                        } else if reply.term >= term {
+                            is_pre_candidate.store(false, Ordering::SeqCst);
+                            tx.send(RaftEvent::BecomeFollower(reply.term)).unwrap();
+                        }
+                    }
+                });
+            }
+        }
+    }
+
+    fn send_request_vote_all(&mut self, leader_transfer: bool) {
+        let votes = Arc::new(Mutex::new(HashSet::new()));
+        votes.lock().unwrap().insert(self.me);
+        // a group of one elects itself
+        if self.membership.has_quorum(&votes.lock().unwrap()) {
+            let term = self.current_term.load(Ordering::SeqCst);
+            self.sender.send(RaftEvent::BecomeLeader(term)).unwrap();
+            return;
+        }
         let (last_log_index, last_log_term) = self.get_last_log_info();
         let args = RequestVoteArgs {
             term: self.current_term.load(Ordering::SeqCst),
             candidate_id: self.me as i32,
             last_log_index,
             last_log_term,
+            leader_transfer,
         };
         // let mut rx_vec = FuturesUnordered::new();
         info!("{} Send {} to ALL RaftNode", self, args);
         let is_candidate = Arc::new(AtomicBool::new(true));
-        for server in 0..self.peers.len() {
+        for server in self.membership.voter_ids() {
             if server != self.me {
                 let args = args.clone();
                 let tx = self.sender.clone();
-                let peers_num = self.peers.len();
+                let membership = self.membership.clone();
                 let is_candidate = is_candidate.clone();
                 let term = self.current_term.load(Ordering::SeqCst);
-                let vote_count = vote_count.clone();
+                let votes = votes.clone();
                 // rx_vec.push(self.send_request_vote(server, args));
                 let rx = self.send_request_vote(server, args);
                 tokio::spawn(async move {
                     if let Ok(reply) = rx.await {
                         if let Ok(reply) = reply {
                             if is_candidate.load(Ordering::SeqCst) {
-                                debug!(
-                                    "Get one {}, current {}, total {}",
-                                    reply,
-                                    vote_count.load(Ordering::SeqCst),
-                                    peers_num
-                                );
+                                debug!("Get one {} from {}", reply, server);
                                 if reply.term > term {
                                     tx.send(RaftEvent::BecomeFollower(reply.term)).unwrap();
                                 } else if reply.vote_granted {
-                                    vote_count.fetch_add(1, Ordering::Relaxed);
-                                    if vote_count.load(Ordering::SeqCst) > peers_num / 2 {
+                                    let mut votes = votes.lock().unwrap();
+                                    votes.insert(server);
+                                    if membership.has_quorum(&votes) {
                                         is_candidate.store(false, Ordering::SeqCst);
                                         tx.send(RaftEvent::BecomeLeader(reply.term)).unwrap();
                                     }
@@ -583,7 +1288,7 @@ impl RaftInner {
         server: usize,
         args: AppendEntriesArgs,
     ) -> Receiver<Result<AppendEntriesReply>> {
-        let peer = &self.peers[server];
+        let peer = &self.peers[&server];
         let mut peer_clone = peer.clone();
         let (tx, rx) = channel::<Result<AppendEntriesReply>>();
         tokio::spawn(async move {
@@ -656,13 +1361,24 @@ impl RaftInner {
             }
         } else {
             debug!("{} Handle {}, Success true", self, args);
-            self.log
-                .truncate((args.prev_log_index - last_included_index) as usize);
-            self.log.extend(args.entries);
-            self.persist();
+            // the log is cut only where it conflicts with the leader's, so
+            // a stale or repeated append keeps the entries that follow
+            let last_new_index = args.prev_log_index + args.entries.len() as u64;
+            let new = args
+                .entries
+                .iter()
+                .position(|entry| !self.match_term(entry.index, entry.term));
+            if let Some(new) = new {
+                let index = args.entries[new].index;
+                self.log
+                    .truncate((index - last_included_index - 1) as usize);
+                self.log.extend(args.entries.into_iter().skip(new));
+                self.reload_membership();
+                self.persist_log(index);
+            }
             if args.leader_commit > self.commit_index.load(Ordering::SeqCst) {
                 self.commit_index
-                    .store(min(args.leader_commit, self.last_index()), Ordering::SeqCst);
+                    .fetch_max(min(args.leader_commit, last_new_index), Ordering::SeqCst);
             }
             AppendEntriesReply {
                 term: self.current_term.load(Ordering::SeqCst),
@@ -678,38 +1394,18 @@ impl RaftInner {
         debug!("{} Send append entries to ALL RaftNode", self);
         let term = self.current_term.load(Ordering::SeqCst);
         // let peers_num = self.peers.len();
-        for server in 0..self.peers.len() {
+        for server in self.replication_ids() {
             if server != self.me {
-                let match_index = self.match_index[server].clone();
-                let next_index = self.next_index[server].clone();
+                let match_index = self.match_index[&server].clone();
+                let next_index = self.next_index[&server].clone();
                 let tx = self.sender.clone();
                 let is_leader = self.is_leader.clone();
                 let last_included_index = self.last_included_index.load(Ordering::SeqCst);
                 let last_included_term = self.last_included_term.load(Ordering::SeqCst);
 
-                let prev_log_index = max(1, self.next_index[server].load(Ordering::SeqCst)) - 1;
+                let prev_log_index = max(1, next_index.load(Ordering::SeqCst)) - 1;
                 if prev_log_index < last_included_index {
-                    let args = InstallSnapshotArgs {
-                        term: self.current_term.load(Ordering::SeqCst),
-                        leader_id: self.me as i32,
-                        last_included_index,
-                        last_included_term,
-                        offset: 0,
-                        data: self.persister.snapshot(),
-                        done: true,
-                    };
-                    debug!("{} Send RaftNode {} {} ", self, server, args);
-                    let rx = self.send_install_snapshot(server, args);
-                    tokio::spawn(async move {
-                        if let Ok(Ok(reply)) = rx.await {
-                            if reply.term > term {
-                                tx.send(RaftEvent::BecomeFollower(reply.term)).unwrap();
-                            } else {
-                                match_index.store(last_included_index, Ordering::SeqCst);
-                                next_index.store(last_included_index + 1, Ordering::SeqCst);
-                            }
-                        }
-                    });
+                    self.send_snapshot(server);
                 } else {
                     let prev_log_term = {
                         if prev_log_index == last_included_index {
@@ -740,14 +1436,23 @@ impl RaftInner {
                     };
                     debug!("{} Send RaftNode {} {} ", self, server, args);
                     // rx_vec.push(self.send_append_entries(server, args));
+                    let sent = Instant::now();
                     let rx = self.send_append_entries(server, args);
+                    let recent_active = self.recent_active.clone();
+                    let lease_acks = self.lease_acks.clone();
                     tokio::spawn(async move {
                         if let Ok(Ok(reply)) = rx.await {
                             if is_leader.load(Ordering::SeqCst) {
                                 if !reply.success && reply.term > term {
                                     is_leader.store(false, Ordering::SeqCst);
                                     tx.send(RaftEvent::BecomeFollower(reply.term)).unwrap();
-                                } else if reply.success {
+                                    return;
+                                }
+                                recent_active.lock().unwrap().insert(server);
+                                if reply.term == term {
+                                    renew_lease(&lease_acks, server, term, sent);
+                                }
+                                if reply.success {
                                     // info!("recv {}, upper: {}", reply, upper_log_index);
                                     match_index.store(upper_log_index, Ordering::SeqCst);
                                     next_index.store(upper_log_index + 1, Ordering::SeqCst);
@@ -762,6 +1467,8 @@ impl RaftInner {
                 self.update_commit_index();
             }
         }
+        // a group of one commits without any peer
+        self.update_commit_index();
     }
 }
 
@@ -771,7 +1478,7 @@ impl RaftInner {
         server: usize,
         args: HeartBeatArgs,
     ) -> Receiver<Result<HeartBeatReply>> {
-        let peer = &self.peers[server];
+        let peer = &self.peers[&server];
         let mut peer_clone = peer.clone();
         let (tx, rx) = channel::<Result<HeartBeatReply>>();
         tokio::spawn(async move {
@@ -813,30 +1520,48 @@ impl RaftInner {
 
     fn send_heart_beat_all(&mut self) {
         debug!("{} Send append entries to ALL RaftNode", self);
-        let beat_count = Arc::new(AtomicUsize::new(1));
+        let acks = Arc::new(Mutex::new(HashSet::new()));
+        acks.lock().unwrap().insert(self.me);
         let term = self.current_term.load(Ordering::SeqCst);
+        // a group of one confirms its reads alone
+        if self.membership.has_quorum(&acks.lock().unwrap()) {
+            let commit_index = self.commit_index.load(Ordering::SeqCst);
+            self.sender
+                .send(RaftEvent::ReadOnlyCommit(commit_index))
+                .unwrap();
+        }
         let args = HeartBeatArgs {
             term,
             leader_id: self.me as i32,
             leader_commit: self.commit_index.load(Ordering::SeqCst),
         };
-        for server in 0..self.peers.len() {
+        let leader_commit = args.leader_commit;
+        for server in self.replication_ids() {
             if server != self.me {
                 let args = args.clone();
                 let tx = self.sender.clone();
-                let peers_num = self.peers.len();
-                let beat_count = beat_count.clone();
+                let membership = self.membership.clone();
+                let acks = acks.clone();
+                let recent_active = self.recent_active.clone();
+                let lease_acks = self.lease_acks.clone();
+                let sent = Instant::now();
                 let rx = self.send_heart_beat(server, args);
                 tokio::spawn(async move {
                     if let Ok(reply) = rx.await {
                         if let Ok(reply) = reply {
                             if reply.term > term {
                                 tx.send(RaftEvent::BecomeFollower(reply.term)).unwrap();
-                            } else if reply.success {
-                                beat_count.fetch_add(1, Ordering::Relaxed);
-                                if beat_count.load(Ordering::SeqCst) > peers_num / 2 {
-                                    tx.send(RaftEvent::ReadOnlyCommit(reply.commit_index))
-                                        .unwrap();
+                                return;
+                            }
+                            recent_active.lock().unwrap().insert(server);
+                            if reply.term == term {
+                                renew_lease(&lease_acks, server, term, sent);
+                                // a reply at this term confirms the leadership,
+                                // even from a follower yet to learn the commit
+                                let mut acks = acks.lock().unwrap();
+                                acks.insert(server);
+                                if membership.has_quorum(&acks) {
+                                    tx.send(RaftEvent::ReadOnlyCommit(leader_commit)).unwrap();
                                 }
                             }
                         }
@@ -848,28 +1573,105 @@ impl RaftInner {
 }
 
 impl RaftInner {
-    fn send_install_snapshot(
-        &self,
-        server: usize,
-        args: InstallSnapshotArgs,
-    ) -> Receiver<Result<InstallSnapshotReply>> {
-        let peer = &self.peers[server];
-        let mut peer_clone = peer.clone();
-        let (tx, rx) = channel::<Result<InstallSnapshotReply>>();
+    /// Streams the snapshot to `server` a chunk at a time, from where an
+    /// earlier stream of the same snapshot stopped. One stream runs per
+    /// follower, given up once the snapshot is replaced.
+    fn send_snapshot(&self, server: usize) {
+        let last_included_index = self.last_included_index.load(Ordering::SeqCst);
+        let mut offset = {
+            let mut progress = self.snapshot_progress.lock().unwrap();
+            let progress = progress.entry(server).or_default();
+            if progress.streaming {
+                return;
+            }
+            if progress.index != last_included_index {
+                *progress = SnapshotProgress {
+                    index: last_included_index,
+                    ..SnapshotProgress::default()
+                };
+            }
+            progress.streaming = true;
+            progress.offset
+        };
+        debug!(
+            "{} Send RaftNode {} the snapshot from {}",
+            self, server, offset
+        );
+        let term = self.current_term.load(Ordering::SeqCst);
+        let leader_id = self.me as i32;
+        let last_included_term = self.last_included_term.load(Ordering::SeqCst);
+        let membership = self.snapshot_membership.clone();
+        let mut peer = self.peers[&server].clone();
+        let persister = self.persister.clone();
+        let included_index = self.last_included_index.clone();
+        let snapshot_progress = self.snapshot_progress.clone();
+        let recent_active = self.recent_active.clone();
+        let match_index = self.match_index[&server].clone();
+        let next_index = self.next_index[&server].clone();
+        let tx = self.sender.clone();
         tokio::spawn(async move {
-            let res = peer_clone
-                .install_snapshot(Request::new(args))
-                .await
-                .map(|resp| resp.into_inner())
-                .map_err(KvError::Rpc);
-            let _ = tx.send(res);
+            loop {
+                let (len, data) = match persister.snapshot_len().and_then(|len| {
+                    Ok((len, persister.read_snapshot(offset, SNAPSHOT_CHUNK_BYTES)?))
+                }) {
+                    Ok(chunk) => chunk,
+                    Err(e) => {
+                        warn!("Unable to read the snapshot for {}: {}", server, e);
+                        break;
+                    }
+                };
+                // the index moves before the snapshot is replaced, so a chunk
+                // of a later snapshot is never sent under this one
+                if included_index.load(Ordering::SeqCst) != last_included_index {
+                    break;
+                }
+                let done = offset + data.len() as u64 >= len;
+                let args = InstallSnapshotArgs {
+                    term,
+                    leader_id,
+                    last_included_index,
+                    last_included_term,
+                    offset,
+                    data,
+                    done,
+                    membership: Some(membership.clone()),
+                };
+                let reply = match peer.install_snapshot(Request::new(args)).await {
+                    Ok(reply) => reply.into_inner(),
+                    Err(_) => break,
+                };
+                if reply.term > term {
+                    let _ = tx.send(RaftEvent::BecomeFollower(reply.term));
+                    break;
+                }
+                recent_active.lock().unwrap().insert(server);
+                if done && reply.offset == len {
+                    match_index.store(last_included_index, Ordering::SeqCst);
+                    next_index.store(last_included_index + 1, Ordering::SeqCst);
+                    break;
+                }
+                offset = reply.offset;
+                snapshot_progress
+                    .lock()
+                    .unwrap()
+                    .entry(server)
+                    .or_default()
+                    .offset = offset;
+            }
+            snapshot_progress
+                .lock()
+                .unwrap()
+                .entry(server)
+                .or_default()
+                .streaming = false;
         });
-        rx
     }
 
+    /// Stages a chunk of the leader's snapshot, and installs the snapshot
+    /// with its last chunk. A chunk past what was staged of the same
+    /// snapshot is refused with the offset to resume from.
     fn handle_install_snapshot(&mut self, args: InstallSnapshotArgs) -> InstallSnapshotReply {
-        let current_term = self.current_term.load(Ordering::SeqCst);
-        if current_term < args.term {
+        if self.current_term.load(Ordering::SeqCst) < args.term {
             self.voted_for = Some(args.leader_id as usize);
             self.become_follower(args.term);
             debug!(
@@ -877,8 +1679,55 @@ impl RaftInner {
                 self, args.leader_id
             );
         }
+        let current_term = self.current_term.load(Ordering::SeqCst);
+        let chunk_end = args.offset + args.data.len() as u64;
+        if args.term < current_term {
+            return InstallSnapshotReply {
+                term: current_term,
+                offset: 0,
+            };
+        }
         let last_included_index = self.last_included_index.load(Ordering::SeqCst);
-        if args.term == current_term && args.last_included_index > last_included_index {
+        if args.last_included_index <= last_included_index {
+            // covered by a snapshot taken here already
+            return InstallSnapshotReply {
+                term: current_term,
+                offset: chunk_end,
+            };
+        }
+        let snapshot = Some((args.term, args.last_included_index));
+        let staged = self
+            .persister
+            .staged_len(Staging::Received)
+            .unwrap_or_default();
+        if args.offset > 0 && (self.receiving != snapshot || args.offset > staged) {
+            let offset = if self.receiving == snapshot {
+                staged
+            } else {
+                0
+            };
+            debug!("{} Handle {}, resume from {}", self, args, offset);
+            return InstallSnapshotReply {
+                term: current_term,
+                offset,
+            };
+        }
+        self.receiving = snapshot;
+        if let Err(e) = self
+            .persister
+            .stage_snapshot(Staging::Received, args.offset, &args.data)
+        {
+            // staged again from the start
+            warn!("{} Handle {}, unable to stage: {}", self, args, e);
+            self.receiving = None;
+            return InstallSnapshotReply {
+                term: current_term,
+                offset: 0,
+            };
+        }
+        if args.done {
+            debug!("{} Handle {}, install the snapshot", self, args);
+            self.receiving = None;
             let range = min(
                 self.log.len(),
                 (args.last_included_index - last_included_index) as usize,
@@ -888,7 +1737,11 @@ impl RaftInner {
                 .store(args.last_included_index, Ordering::SeqCst);
             self.last_included_term
                 .store(args.last_included_term, Ordering::SeqCst);
-            self.persist_with_snapshot(args.data.clone());
+            if let Some(membership) = args.membership {
+                self.snapshot_membership = membership;
+            }
+            self.reload_membership();
+            self.persist_with_snapshot(Staging::Received);
             self.commit_index
                 .fetch_max(args.last_included_index, Ordering::SeqCst);
             self.last_applied
@@ -896,28 +1749,39 @@ impl RaftInner {
 
             let msg = ApplyMsg {
                 command_valid: false,
-                command: args.data,
-                command_index: 0,
+                command: Vec::new(),
+                command_index: args.last_included_index,
             };
             self.apply_ch.send(msg).expect("Unable send ApplyMsg");
         }
 
-        InstallSnapshotReply { term: current_term }
+        InstallSnapshotReply {
+            term: current_term,
+            offset: chunk_end,
+        }
     }
 }
 
 #[derive(Debug)]
 enum RaftEvent {
     RequestVote(RequestVoteArgs, Sender<RequestVoteReply>),
+    PreVote(RequestVoteArgs, Sender<RequestVoteReply>),
     AppendEntries(AppendEntriesArgs, Sender<AppendEntriesReply>),
     HeartBeat(HeartBeatArgs, Sender<HeartBeatReply>),
     InstallSnapshot(InstallSnapshotArgs, Sender<InstallSnapshotReply>),
     BecomeLeader(u64),
     BecomeFollower(u64),
+    BecomeCandidate(u64),
     ReadOnlyCommit(u64),
     StartCommand(Vec<u8>, Sender<Result<(u64, u64)>>),
     StartReadOnly(Vec<u8>, Sender<Result<(u64, u64)>>),
-    StartSnapshot(Vec<u8>, u64),
+    StartSnapshot(u64, Sender<()>),
+    ChangeMembership(ConfChange, Sender<Result<Membership>>),
+    TransferLeader(Option<usize>, Sender<Result<()>>),
+    TimeoutNow(TimeoutNowArgs, Sender<TimeoutNowReply>),
+    SetLeaseRead(bool),
+    ReadIndex(ReadIndexArgs, Sender<ReadIndexReply>),
+    ReadIndexReady(u64, Vec<u8>),
     Shutdown,
 }
 
@@ -934,13 +1798,18 @@ impl Stream for RaftInner {
                 return {
                     trace!("{} poll timeout ready!", self);
                     if self.is_leader.load(Ordering::SeqCst) {
-                        self.timeout.reset(heartbeat_timeout());
-                        self.send_append_entries_all();
+                        if self.check_quorum() {
+                            self.timeout.reset(heartbeat_timeout());
+                            self.send_append_entries_all();
+                            self.advance_transfer();
+                        } else {
+                            self.timeout.reset(election_timeout());
+                        }
                         Poll::Ready(Some(()))
                     } else {
                         trace!("{} loss Leader connection", self);
                         self.timeout.reset(election_timeout());
-                        self.become_candidate();
+                        self.become_pre_candidate();
                         Poll::Ready(Some(()))
                     }
                 };
@@ -951,7 +1820,7 @@ impl Stream for RaftInner {
             Poll::Ready(()) => {
                 trace!("{} poll Apply Msg ready!", self);
                 self.apply_msg_delay.reset(heartbeat_timeout());
-                self.send_apply_msg();
+                self.apply_follower_reads();
                 return Poll::Ready(Some(()));
             }
             Poll::Pending => {}
@@ -966,26 +1835,49 @@ impl Stream for RaftInner {
                     let _ = tx.send(reply);
                     Poll::Ready(Some(()))
                 }
+                RaftEvent::PreVote(args, tx) => {
+                    let _ = tx.send(self.handle_pre_vote(args));
+                    Poll::Ready(Some(()))
+                }
                 RaftEvent::AppendEntries(args, tx) => {
                     let current_term = args.term;
+                    let leader_id = args.leader_id as usize;
                     let reply = self.handle_append_entries(args);
                     if reply.success || reply.term == current_term {
                         self.timeout.reset(election_timeout());
+                        self.leader_contact = Some(Instant::now());
+                        self.leader = Some(leader_id);
+                    }
+                    if current_term == self.current_term.load(Ordering::SeqCst) {
+                        self.finish_transfer(leader_id);
                     }
                     let _ = tx.send(reply);
                     Poll::Ready(Some(()))
                 }
                 RaftEvent::HeartBeat(args, tx) => {
                     let current_term = args.term;
+                    let leader_id = args.leader_id as usize;
                     let reply = self.handle_heart_beat(args);
                     if reply.success || reply.term == current_term {
                         self.timeout.reset(election_timeout());
+                        self.leader_contact = Some(Instant::now());
+                        self.leader = Some(leader_id);
+                    }
+                    if current_term == self.current_term.load(Ordering::SeqCst) {
+                        self.finish_transfer(leader_id);
                     }
                     let _ = tx.send(reply);
                     Poll::Ready(Some(()))
                 }
                 RaftEvent::InstallSnapshot(args, tx) => {
+                    let current_term = args.term;
+                    let leader_id = args.leader_id as usize;
                     let reply = self.handle_install_snapshot(args);
+                    if reply.term == current_term {
+                        self.timeout.reset(election_timeout());
+                        self.leader_contact = Some(Instant::now());
+                        self.leader = Some(leader_id);
+                    }
                     let _ = tx.send(reply);
                     Poll::Ready(Some(()))
                 }
@@ -997,13 +1889,41 @@ impl Stream for RaftInner {
                     Poll::Ready(Some(()))
                 }
                 RaftEvent::BecomeFollower(term) => {
+                    if term > self.current_term.load(Ordering::SeqCst) {
+                        self.voted_for = None;
+                    }
                     self.become_follower(term);
                     self.timeout.reset(election_timeout());
                     Poll::Ready(Some(()))
                 }
+                RaftEvent::BecomeCandidate(term) => {
+                    // the pre-vote is stale once the term moved or a leader
+                    // was heard from
+                    if term == self.current_term.load(Ordering::SeqCst) + 1 && !self.leader_alive()
+                    {
+                        self.timeout.reset(election_timeout());
+                        self.become_candidate(false);
+                    }
+                    Poll::Ready(Some(()))
+                }
                 RaftEvent::ReadOnlyCommit(commit) => {
                     let msgs = self.read_only.pop_requests(commit);
                     self.apply_read_only(commit, msgs);
+                    for tx in self.read_index.pop_requests(commit) {
+                        let _ = tx.send(ReadIndexReply {
+                            success: true,
+                            read_index: commit,
+                        });
+                    }
+                    Poll::Ready(Some(()))
+                }
+                RaftEvent::ReadIndex(_args, tx) => {
+                    self.start_read_index(tx);
+                    Poll::Ready(Some(()))
+                }
+                RaftEvent::ReadIndexReady(index, command) => {
+                    self.follower_reads.add_request(index, command);
+                    self.apply_follower_reads();
                     Poll::Ready(Some(()))
                 }
                 RaftEvent::StartCommand(command, tx) => {
@@ -1016,19 +1936,49 @@ impl Stream for RaftInner {
                     let _ = tx.send(self.start_read_only(&command));
                     Poll::Ready(Some(()))
                 }
-                RaftEvent::StartSnapshot(snapshot, last_applied) => {
-                    let snapshot_len =
-                        (last_applied - self.last_included_index.load(Ordering::SeqCst)) as usize;
+                RaftEvent::StartSnapshot(last_applied, tx) => {
+                    // a snapshot received meanwhile may cover more already
+                    let snapshot_len = last_applied
+                        .saturating_sub(self.last_included_index.load(Ordering::SeqCst))
+                        as usize;
                     if snapshot_len > 0 {
                         info!("{} Exexutor -- Receive Snapshot!", self);
                         self.last_included_index
                             .store(last_applied, Ordering::SeqCst);
                         self.last_included_term
                             .store(self.log[snapshot_len - 1].term, Ordering::SeqCst);
+                        if let Some(membership) = self.log[..snapshot_len]
+                            .iter()
+                            .rev()
+                            .find_map(|entry| entry.membership.clone())
+                        {
+                            self.snapshot_membership = membership;
+                        }
                         self.log.drain(..snapshot_len);
-                        self.persist_with_snapshot(snapshot);
+                        self.persist_with_snapshot(Staging::Generated);
                         info!("{} Exexutor -- Finish Snapshot!", self);
                     }
+                    let _ = tx.send(());
+                    Poll::Ready(Some(()))
+                }
+                RaftEvent::ChangeMembership(change, tx) => {
+                    self.propose_membership(change, tx);
+                    Poll::Ready(Some(()))
+                }
+                RaftEvent::TransferLeader(target, tx) => {
+                    self.transfer_leader(target, tx);
+                    Poll::Ready(Some(()))
+                }
+                RaftEvent::SetLeaseRead(enabled) => {
+                    self.lease_read = enabled;
+                    Poll::Ready(Some(()))
+                }
+                RaftEvent::TimeoutNow(args, tx) => {
+                    let reply = self.handle_timeout_now(args);
+                    if self.role == RaftRole::Candidate {
+                        self.timeout.reset(election_timeout());
+                    }
+                    let _ = tx.send(reply);
                     Poll::Ready(Some(()))
                 }
                 RaftEvent::Shutdown => Poll::Ready(None),
@@ -1055,12 +2005,14 @@ pub struct RaftNode {
     raft_state_size: Arc<AtomicU64>,
     commit_index: Arc<AtomicU64>,
     last_applied: Arc<AtomicU64>,
+    last_included_index: Arc<AtomicU64>,
+    replicated_index: Arc<AtomicU64>,
 }
 
 impl RaftNode {
     /// Create a new raft service.
     pub fn new(
-        peers: Vec<RaftRpcClient<Channel>>,
+        peers: Vec<Member>,
         me: usize,
         persister: Arc<dyn Persister>,
         apply_ch: UnboundedSender<ApplyMsg>,
@@ -1071,6 +2023,8 @@ impl RaftNode {
         let raft_state_size = raft.raft_state_size.clone();
         let commit_index = raft.commit_index.clone();
         let last_applied = raft.last_applied.clone();
+        let last_included_index = raft.last_included_index.clone();
+        let replicated_index = raft.replicated_index.clone();
 
         let threaded_rt = Builder::new_multi_thread().enable_all().build().unwrap();
         let handle = thread::Builder::new()
@@ -1094,6 +2048,8 @@ impl RaftNode {
             raft_state_size,
             commit_index,
             last_applied,
+            last_included_index,
+            replicated_index,
         }
     }
 
@@ -1131,47 +2087,76 @@ impl RaftNode {
         }
     }
 
-    /// start a command that can encode to Bytes
+    /// start a read-only command that can encode to Bytes, on the leader
+    /// or on a follower that asks it for a read index
     pub fn start_read_only<M>(&self, command: &M) -> Result<(u64, u64)>
     where
         M: Message,
     {
-        if self.is_leader() {
-            let mut buf = vec![];
-            // labcodec::encode(command, &mut buf).unwrap();
-            command.encode(&mut buf).unwrap();
-
-            let threaded_rt = Builder::new_multi_thread().build().unwrap();
+        let mut buf = vec![];
+        // labcodec::encode(command, &mut buf).unwrap();
+        command.encode(&mut buf).unwrap();
 
-            let (tx, rx) = channel();
-            let sender = self.sender.clone();
-            let handle = thread::spawn(move || {
-                sender
-                    .send(RaftEvent::StartReadOnly(buf, tx))
-                    .expect("Unable to send start ReadOnly to RaftExecutor");
+        let threaded_rt = Builder::new_multi_thread().build().unwrap();
 
-                let fut_values = async { rx.await };
-                threaded_rt.block_on(fut_values).unwrap()
-            });
-            let response = handle.join().unwrap();
-            debug!(
-                "RaftNode {} -- Start a ReadOnly, response with: {:?}",
-                self.me, response
-            );
-            response
-        } else {
-            debug!("RaftNode {} -- Start a ReadOnly but in Not Leader", self.me);
-            Err(KvError::NotLeader)
-        }
+        let (tx, rx) = channel();
+        let sender = self.sender.clone();
+        let handle = thread::spawn(move || {
+            sender
+                .send(RaftEvent::StartReadOnly(buf, tx))
+                .expect("Unable to send start ReadOnly to RaftExecutor");
+
+            let fut_values = async { rx.await };
+            threaded_rt.block_on(fut_values).unwrap()
+        });
+        let response = handle.join().unwrap();
+        debug!(
+            "RaftNode {} -- Start a ReadOnly, response with: {:?}",
+            self.me, response
+        );
+        response
     }
 
-    /// receive a snapshot and save it.
-    pub fn start_snapshot(&self, snapshot: Vec<u8>, last_applied: u64) {
+    /// install the snapshot staged as `Staging::Generated`, taken once
+    /// `last_applied` was applied, and drop the log it covers. Replied once
+    /// the staged snapshot is no longer read.
+    pub fn start_snapshot(&self, last_applied: u64) -> Receiver<()> {
+        let (tx, rx) = channel();
         self.sender
-            .send(RaftEvent::StartSnapshot(snapshot, last_applied))
+            .send(RaftEvent::StartSnapshot(last_applied, tx))
             .expect("Unable to send start Snapshot to RaftExecutor");
 
         debug!("RaftNode {} -- Start a Snapshot", self.me,);
+        rx
+    }
+
+    /// Change the voters of the group through joint consensus, replied with
+    /// the new membership once it is committed.
+    pub fn change_membership(&self, change: ConfChange) -> Receiver<Result<Membership>> {
+        let (tx, rx) = channel();
+        self.sender
+            .send(RaftEvent::ChangeMembership(change, tx))
+            .expect("Unable to send membership change to RaftExecutor");
+        rx
+    }
+
+    /// Hand the leadership over to the voter `target`, or to the one most
+    /// caught up if `None`. Proposals are refused meanwhile, and the reply
+    /// comes once the target leads.
+    pub fn transfer_leader(&self, target: Option<usize>) -> Receiver<Result<()>> {
+        let (tx, rx) = channel();
+        self.sender
+            .send(RaftEvent::TransferLeader(target, tx))
+            .expect("Unable to send leader transfer to RaftExecutor");
+        rx
+    }
+
+    /// Serve reads on the leader alone while a quorum acked it within the
+    /// lease, instead of confirming the leadership for every read.
+    pub fn set_lease_read(&self, enabled: bool) {
+        self.sender
+            .send(RaftEvent::SetLeaseRead(enabled))
+            .expect("Unable to send lease read to RaftExecutor");
     }
 
     /// The current term of this peer.
@@ -1184,6 +2169,21 @@ impl RaftNode {
         self.is_leader.load(Ordering::SeqCst)
     }
 
+    /// The bytes of raft state, log included, last persisted.
+    pub fn raft_state_size(&self) -> u64 {
+        self.raft_state_size.load(Ordering::SeqCst)
+    }
+
+    /// The last index the snapshot covers.
+    pub fn last_included_index(&self) -> u64 {
+        self.last_included_index.load(Ordering::SeqCst)
+    }
+
+    /// On the leader, the last index every replica has.
+    pub fn replicated_index(&self) -> u64 {
+        self.replicated_index.load(Ordering::SeqCst)
+    }
+
     /// Shutdown this node
     pub fn kill(&self) {
         let _ = self.sender.send(RaftEvent::Shutdown);
@@ -1205,6 +2205,19 @@ impl RaftRpc for RaftNode {
             .map_err(|e| Status::new(Code::Cancelled, e.to_string()))
     }
 
+    async fn pre_vote(
+        &self,
+        args: Request<RequestVoteArgs>,
+    ) -> std::result::Result<Response<RequestVoteReply>, Status> {
+        let (tx, rx) = channel();
+        let event = RaftEvent::PreVote(args.into_inner(), tx);
+        let _ = self.sender.clone().send(event);
+        let reply = rx.await;
+        reply
+            .map(Response::new)
+            .map_err(|e| Status::new(Code::Cancelled, e.to_string()))
+    }
+
     async fn append_entries(
         &self,
         args: Request<AppendEntriesArgs>,
@@ -1231,6 +2244,32 @@ impl RaftRpc for RaftNode {
             .map_err(|e| Status::new(Code::Cancelled, e.to_string()))
     }
 
+    async fn timeout_now(
+        &self,
+        args: Request<TimeoutNowArgs>,
+    ) -> std::result::Result<Response<TimeoutNowReply>, Status> {
+        let (tx, rx) = channel();
+        let event = RaftEvent::TimeoutNow(args.into_inner(), tx);
+        let _ = self.sender.clone().send(event);
+        let reply = rx.await;
+        reply
+            .map(Response::new)
+            .map_err(|e| Status::new(Code::Cancelled, e.to_string()))
+    }
+
+    async fn read_index(
+        &self,
+        args: Request<ReadIndexArgs>,
+    ) -> std::result::Result<Response<ReadIndexReply>, Status> {
+        let (tx, rx) = channel();
+        let event = RaftEvent::ReadIndex(args.into_inner(), tx);
+        let _ = self.sender.clone().send(event);
+        let reply = rx.await;
+        reply
+            .map(Response::new)
+            .map_err(|e| Status::new(Code::Cancelled, e.to_string()))
+    }
+
     async fn install_snapshot(
         &self,
         args: Request<InstallSnapshotArgs>,
@@ -1244,3 +2283,396 @@ impl RaftRpc for RaftNode {
             .map_err(|e| Status::new(Code::Cancelled, e.to_string()))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Serves the RaftRpc of a node, dropping the messages to and from the
+    /// nodes isolated, and hiding the leader's commit from the nodes lagging
+    struct Network {
+        node: RaftNode,
+        me: usize,
+        isolated: Arc<Mutex<HashSet<usize>>>,
+        lagging: Arc<Mutex<HashSet<usize>>>,
+    }
+
+    impl Network {
+        fn check(&self, from: i32) -> std::result::Result<(), Status> {
+            let isolated = self.isolated.lock().unwrap();
+            if isolated.contains(&self.me) || isolated.contains(&(from as usize)) {
+                Err(Status::unavailable("partitioned"))
+            } else {
+                Ok(())
+            }
+        }
+    }
+
+    #[tonic::async_trait]
+    impl RaftRpc for Network {
+        async fn request_vote(
+            &self,
+            args: Request<RequestVoteArgs>,
+        ) -> std::result::Result<Response<RequestVoteReply>, Status> {
+            self.check(args.get_ref().candidate_id)?;
+            self.node.request_vote(args).await
+        }
+
+        async fn pre_vote(
+            &self,
+            args: Request<RequestVoteArgs>,
+        ) -> std::result::Result<Response<RequestVoteReply>, Status> {
+            self.check(args.get_ref().candidate_id)?;
+            self.node.pre_vote(args).await
+        }
+
+        async fn append_entries(
+            &self,
+            args: Request<AppendEntriesArgs>,
+        ) -> std::result::Result<Response<AppendEntriesReply>, Status> {
+            self.check(args.get_ref().leader_id)?;
+            let mut args = args;
+            if self.lagging.lock().unwrap().contains(&self.me) {
+                args.get_mut().leader_commit = 0;
+            }
+            self.node.append_entries(args).await
+        }
+
+        async fn heart_beat(
+            &self,
+            args: Request<HeartBeatArgs>,
+        ) -> std::result::Result<Response<HeartBeatReply>, Status> {
+            self.check(args.get_ref().leader_id)?;
+            self.node.heart_beat(args).await
+        }
+
+        async fn install_snapshot(
+            &self,
+            args: Request<InstallSnapshotArgs>,
+        ) -> std::result::Result<Response<InstallSnapshotReply>, Status> {
+            self.check(args.get_ref().leader_id)?;
+            self.node.install_snapshot(args).await
+        }
+
+        async fn timeout_now(
+            &self,
+            args: Request<TimeoutNowArgs>,
+        ) -> std::result::Result<Response<TimeoutNowReply>, Status> {
+            self.check(args.get_ref().leader_id)?;
+            self.node.timeout_now(args).await
+        }
+
+        async fn read_index(
+            &self,
+            args: Request<ReadIndexArgs>,
+        ) -> std::result::Result<Response<ReadIndexReply>, Status> {
+            self.check(args.get_ref().follower_id)?;
+            self.node.read_index(args).await
+        }
+    }
+
+    struct Group {
+        nodes: Vec<RaftNode>,
+        isolated: Arc<Mutex<HashSet<usize>>>,
+        lagging: Arc<Mutex<HashSet<usize>>>,
+        apply_chs: Vec<UnboundedReceiver<ApplyMsg>>,
+        persisters: Vec<Arc<SimplePersister>>,
+    }
+
+    impl Group {
+        fn start(ports: &[u16]) -> Group {
+            let peers: Vec<Member> = ports
+                .iter()
+                .enumerate()
+                .map(|(id, port)| Member {
+                    id: id as u64,
+                    addr: format!("127.0.0.1:{}", port),
+                })
+                .collect();
+            let isolated = Arc::new(Mutex::new(HashSet::new()));
+            let lagging = Arc::new(Mutex::new(HashSet::new()));
+            let mut nodes = Vec::new();
+            let mut apply_chs = Vec::new();
+            let mut persisters = Vec::new();
+            for member in peers.iter() {
+                let (tx, rx) = unbounded_channel();
+                let me = member.id as usize;
+                let persister = Arc::new(SimplePersister::new());
+                let node = RaftNode::new(peers.clone(), me, persister.clone(), tx);
+                let network = Network {
+                    node: node.clone(),
+                    me,
+                    isolated: isolated.clone(),
+                    lagging: lagging.clone(),
+                };
+                let addr = member.addr.parse().unwrap();
+                tokio::spawn(
+                    tonic::transport::Server::builder()
+                        .add_service(RaftRpcServer::new(network))
+                        .serve(addr),
+                );
+                nodes.push(node);
+                apply_chs.push(rx);
+                persisters.push(persister);
+            }
+            Group {
+                nodes,
+                isolated,
+                lagging,
+                apply_chs,
+                persisters,
+            }
+        }
+
+        fn leaders(&self) -> Vec<usize> {
+            (0..self.nodes.len())
+                .filter(|id| self.nodes[*id].is_leader())
+                .collect()
+        }
+
+        fn isolate(&self, id: usize) {
+            self.isolated.lock().unwrap().insert(id);
+        }
+
+        fn heal(&self) {
+            self.isolated.lock().unwrap().clear();
+        }
+
+        /// Stops node `id` learning that later entries committed
+        fn lag(&self, id: usize) {
+            self.lagging.lock().unwrap().insert(id);
+        }
+
+        /// Whether node `id` applies the read-only `command` within `wait`
+        async fn applies_read(&mut self, id: usize, command: &[u8], wait: Duration) -> bool {
+            let apply_ch = &mut self.apply_chs[id];
+            let applied = async {
+                while let Some(msg) = apply_ch.recv().await {
+                    if msg.command_valid && msg.command == command {
+                        return true;
+                    }
+                }
+                false
+            };
+            tokio::time::timeout(wait, applied).await.unwrap_or(false)
+        }
+    }
+
+    impl Drop for Group {
+        fn drop(&mut self) {
+            self.nodes.iter().for_each(|node| node.kill());
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_pre_vote_partitioned_follower() {
+        let group = Group::start(&[7101, 7102, 7103]);
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        let leaders = group.leaders();
+        assert_eq!(leaders.len(), 1);
+        let leader = leaders[0];
+        let term = group.nodes[leader].term();
+
+        // a follower cut off keeps timing out without raising its term
+        let follower = (leader + 1) % 3;
+        group.isolate(follower);
+        tokio::time::sleep(Duration::from_secs(3)).await;
+        assert_eq!(group.nodes[follower].term(), term);
+
+        // so it rejoins without deposing the leader
+        group.heal();
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert_eq!(group.leaders(), vec![leader]);
+        assert_eq!(group.nodes[leader].term(), term);
+        assert_eq!(group.nodes[follower].term(), term);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_check_quorum_partitioned_leader() {
+        let group = Group::start(&[7111, 7112, 7113]);
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        let leaders = group.leaders();
+        assert_eq!(leaders.len(), 1);
+        let old_leader = leaders[0];
+
+        // a leader cut off steps down while the others elect a new one
+        group.isolate(old_leader);
+        tokio::time::sleep(Duration::from_secs(3)).await;
+        assert!(!group.nodes[old_leader].is_leader());
+        let leaders = group.leaders();
+        assert_eq!(leaders.len(), 1);
+        let new_leader = leaders[0];
+        assert_ne!(new_leader, old_leader);
+
+        group.heal();
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert_eq!(group.leaders(), vec![new_leader]);
+        assert_eq!(
+            group.nodes[old_leader].term(),
+            group.nodes[new_leader].term()
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_transfer_leader() {
+        let group = Group::start(&[7121, 7122, 7123]);
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        let leaders = group.leaders();
+        assert_eq!(leaders.len(), 1);
+        let leader = leaders[0];
+        let term = group.nodes[leader].term();
+
+        let target = (leader + 1) % 3;
+        let transferred = group.nodes[leader].transfer_leader(Some(target)).await;
+        assert!(transferred.unwrap().is_ok());
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        // the target ran at once, without waiting out an election timeout
+        assert_eq!(group.leaders(), vec![target]);
+        assert_eq!(group.nodes[target].term(), term + 1);
+
+        // only voters take over
+        let refused = group.nodes[target].transfer_leader(Some(7)).await;
+        assert!(refused.unwrap().is_err());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_lease_read() {
+        let mut group = Group::start(&[7131, 7132, 7133]);
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        let leaders = group.leaders();
+        assert_eq!(leaders.len(), 1);
+        let leader = leaders[0];
+        let read = |id| TimeoutNowArgs {
+            term: 0,
+            leader_id: id,
+        };
+        let encoded = |id| {
+            let mut buf = vec![];
+            read(id).encode(&mut buf).unwrap();
+            buf
+        };
+
+        // without a lease a read waits for a quorum to confirm the leader
+        group.isolate((leader + 1) % 3);
+        group.isolate((leader + 2) % 3);
+        assert!(group.nodes[leader].start_read_only(&read(1)).is_ok());
+        let wait = Duration::from_millis(200);
+        assert!(!group.applies_read(leader, &encoded(1), wait).await);
+
+        group.heal();
+        group.nodes[leader].set_lease_read(true);
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert_eq!(group.leaders(), vec![leader]);
+
+        // while the lease holds, the leader reads on its own
+        group.isolate((leader + 1) % 3);
+        group.isolate((leader + 2) % 3);
+        assert!(group.nodes[leader].start_read_only(&read(2)).is_ok());
+        assert!(group.applies_read(leader, &encoded(2), wait).await);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_read_with_lagging_follower() {
+        let mut group = Group::start(&[7161, 7162, 7163]);
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        let leaders = group.leaders();
+        assert_eq!(leaders.len(), 1);
+        let leader = leaders[0];
+        let command = |id| TimeoutNowArgs {
+            term: 0,
+            leader_id: id,
+        };
+        let encoded = |id| {
+            let mut buf = vec![];
+            command(id).encode(&mut buf).unwrap();
+            buf
+        };
+
+        // the only follower left to confirm the leader never learns the
+        // leader committed the write
+        group.lag((leader + 1) % 3);
+        group.isolate((leader + 2) % 3);
+        assert!(group.nodes[leader].start(&command(1)).is_ok());
+        let wait = Duration::from_millis(500);
+        assert!(group.applies_read(leader, &encoded(1), wait).await);
+
+        // a reply at the leader's term still confirms it for the read
+        assert!(group.nodes[leader].start_read_only(&command(2)).is_ok());
+        assert!(group.applies_read(leader, &encoded(2), wait).await);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_follower_read() {
+        let mut group = Group::start(&[7141, 7142, 7143]);
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        let leaders = group.leaders();
+        assert_eq!(leaders.len(), 1);
+        let follower = (leaders[0] + 1) % 3;
+        let read = TimeoutNowArgs {
+            term: 0,
+            leader_id: 1,
+        };
+        let mut encoded = vec![];
+        read.encode(&mut encoded).unwrap();
+
+        // a follower serves a read once it applied the leader's read index
+        assert!(group.nodes[follower].start_read_only(&read).is_ok());
+        let wait = Duration::from_millis(500);
+        assert!(group.applies_read(follower, &encoded, wait).await);
+
+        // and refuses it once cut off from the leader
+        group.isolate(follower);
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert!(group.nodes[follower].start_read_only(&read).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_chunked_snapshot() {
+        let mut group = Group::start(&[7151, 7152, 7153]);
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        let leaders = group.leaders();
+        assert_eq!(leaders.len(), 1);
+        let leader = leaders[0];
+        let follower = (leader + 1) % 3;
+        group.isolate(follower);
+
+        let command = TimeoutNowArgs {
+            term: 0,
+            leader_id: 1,
+        };
+        let (index, _) = group.nodes[leader].start(&command).unwrap();
+        tokio::time::sleep(Duration::from_millis(500)).await;
+
+        // a snapshot of several chunks compacts the log the follower misses
+        let snapshot: Vec<u8> = (0..SNAPSHOT_CHUNK_BYTES * 4 + 100)
+            .map(|i| (i % 251) as u8)
+            .collect();
+        group.persisters[leader]
+            .stage_snapshot(Staging::Generated, 0, &snapshot)
+            .unwrap();
+        group.nodes[leader].start_snapshot(index).await.unwrap();
+        assert_eq!(group.persisters[leader].snapshot().unwrap(), snapshot);
+        // appends sent before the snapshot are dropped meanwhile
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        group.heal();
+        let apply_ch = &mut group.apply_chs[follower];
+        let installed = async {
+            while let Some(msg) = apply_ch.recv().await {
+                if !msg.command_valid && msg.command_index == index {
+                    return true;
+                }
+            }
+            false
+        };
+        let wait = Duration::from_secs(3);
+        assert!(tokio::time::timeout(wait, installed).await.unwrap_or(false));
+        assert_eq!(group.persisters[follower].snapshot().unwrap(), snapshot);
+        assert_eq!(
+            group.persisters[follower]
+                .staged_len(Staging::Received)
+                .unwrap(),
+            0
+        );
+    }
+}
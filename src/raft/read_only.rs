@@ -1,14 +1,14 @@
-use std::collections::HashMap;
+use std::collections::BTreeMap;
 
-#[derive(Default, Debug, Clone)]
-pub struct ReadOnly {
-    req_status: HashMap<u64, Vec<Vec<u8>>>,
+#[derive(Debug)]
+pub struct ReadOnly<T = Vec<u8>> {
+    req_status: BTreeMap<u64, Vec<T>>,
 }
 
-impl ReadOnly {
-    pub fn new() -> ReadOnly {
+impl<T> ReadOnly<T> {
+    pub fn new() -> ReadOnly<T> {
         ReadOnly {
-            req_status: HashMap::default(),
+            req_status: BTreeMap::default(),
         }
     }
 
@@ -16,14 +16,17 @@ impl ReadOnly {
         self.req_status.clear();
     }
 
-    pub fn add_request(&mut self, index: u64, req: Vec<u8>) {
-        if !self.req_status.contains_key(&index) {
-            self.req_status.insert(index, Vec::default());
-        }
-        self.req_status.get_mut(&index).unwrap().push(req);
+    pub fn add_request(&mut self, index: u64, req: T) {
+        self.req_status.entry(index).or_default().push(req);
     }
 
-    pub fn pop_requests(&mut self, index: u64) -> Vec<Vec<u8>> {
-        self.req_status.remove(&index).unwrap_or_default()
+    /// Pops the requests waiting on `index` or an earlier one, which a
+    /// quorum confirmed the leadership for, or this node applied
+    pub fn pop_requests(&mut self, index: u64) -> Vec<T> {
+        let later = self.req_status.split_off(&(index + 1));
+        std::mem::replace(&mut self.req_status, later)
+            .into_values()
+            .flatten()
+            .collect()
     }
 }
@@ -0,0 +1,371 @@
+//! A segmented, append-only log of raft entries.
+//!
+//! Each segment is named after the index of its first entry and holds
+//! records of `[len: u32][crc: u32][index: u64][entry]`, the CRC covering the
+//! index and the entry. A torn or corrupt record ends the log, and is cut off
+//! with everything after it when the log is opened. The directory is synced
+//! whenever a segment is created or removed, so neither is undone by a crash.
+
+use std::{
+    collections::BTreeMap,
+    convert::TryInto,
+    fs::{self, File, OpenOptions},
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+};
+
+/// How large a segment grows before the next entry starts a new one
+const SEGMENT_BYTES: u64 = 4 * 1024 * 1024;
+
+const HEADER_BYTES: u64 = 16;
+
+/// A raft log kept as segment files in a directory
+pub struct Wal {
+    dir: PathBuf,
+    // segments by the index of their first entry, with their length
+    segments: BTreeMap<u64, u64>,
+    // the index of the last entry, 0 for none
+    last_index: u64,
+}
+
+/// A record read back from a segment
+struct Record {
+    index: u64,
+    entry: Vec<u8>,
+    // where the record ends in its segment
+    end: u64,
+}
+
+impl Wal {
+    /// Opens the log in `dir`, cutting off a torn or corrupt tail
+    pub fn open(dir: PathBuf) -> io::Result<Wal> {
+        let mut wal = Wal {
+            dir,
+            segments: BTreeMap::new(),
+            last_index: 0,
+        };
+        if let Ok(read_dir) = fs::read_dir(&wal.dir) {
+            for entry in read_dir.flatten() {
+                let name = entry.file_name();
+                let first = name
+                    .to_str()
+                    .and_then(|name| name.strip_suffix(".log"))
+                    .and_then(|index| index.parse().ok());
+                if let Some(first) = first {
+                    wal.segments.insert(first, 0);
+                }
+            }
+        }
+        let firsts: Vec<u64> = wal.segments.keys().cloned().collect();
+        for (i, first) in firsts.iter().enumerate() {
+            let records = wal.read_segment(*first)?;
+            let end = records.last().map_or(0, |record| record.end);
+            let len = fs::metadata(wal.segment(*first)).map_or(0, |meta| meta.len());
+            if let Some(record) = records.last() {
+                wal.last_index = record.index;
+            }
+            wal.segments.insert(*first, end);
+            if end < len {
+                // nothing after a bad record is trusted
+                cut(&wal.segment(*first), end)?;
+                for later in &firsts[i + 1..] {
+                    fs::remove_file(wal.segment(*later))?;
+                    wal.segments.remove(later);
+                }
+                sync_dir(&wal.dir)?;
+                break;
+            }
+        }
+        Ok(wal)
+    }
+
+    /// Reads the entries logged, in order
+    pub fn entries(&self) -> io::Result<Vec<Vec<u8>>> {
+        let mut entries = Vec::new();
+        for first in self.segments.keys() {
+            entries.extend(
+                self.read_segment(*first)?
+                    .into_iter()
+                    .map(|record| record.entry),
+            );
+        }
+        Ok(entries)
+    }
+
+    /// Logs `entries` from `index` on, dropping any logged there and after,
+    /// and syncs them to disk
+    pub fn append(&mut self, index: u64, entries: &[Vec<u8>]) -> io::Result<()> {
+        self.truncate(index)?;
+        if entries.is_empty() {
+            return Ok(());
+        }
+        if !self.dir.exists() {
+            fs::create_dir_all(&self.dir)?;
+            if let Some(parent) = self.dir.parent().filter(|p| !p.as_os_str().is_empty()) {
+                sync_dir(parent)?;
+            }
+        }
+        let mut writer: Option<(u64, BufWriter<File>)> = None;
+        let mut created = false;
+        for (index, entry) in (index..).zip(entries) {
+            let full = self
+                .segments
+                .iter()
+                .next_back()
+                .is_none_or(|(_, len)| *len >= SEGMENT_BYTES);
+            if full {
+                if let Some((_, writer)) = writer.take() {
+                    writer.into_inner()?.sync_data()?;
+                }
+                self.segments.insert(index, 0);
+                created = true;
+            }
+            let (first, len) = self
+                .segments
+                .iter_mut()
+                .next_back()
+                .map(|(first, len)| (*first, len))
+                .unwrap();
+            if writer.as_ref().is_none_or(|(open, _)| *open != first) {
+                let file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(self.dir.join(segment_name(first)))?;
+                writer = Some((first, BufWriter::new(file)));
+            }
+            let (_, out) = writer.as_mut().unwrap();
+            let mut hasher = crc32fast::Hasher::new();
+            hasher.update(&index.to_le_bytes());
+            hasher.update(entry);
+            out.write_all(&(entry.len() as u32).to_le_bytes())?;
+            out.write_all(&hasher.finalize().to_le_bytes())?;
+            out.write_all(&index.to_le_bytes())?;
+            out.write_all(entry)?;
+            *len += HEADER_BYTES + entry.len() as u64;
+            self.last_index = index;
+        }
+        if let Some((_, writer)) = writer {
+            writer.into_inner()?.sync_data()?;
+        }
+        if created {
+            sync_dir(&self.dir)?;
+        }
+        Ok(())
+    }
+
+    /// Deletes the segments holding only entries up to `index`
+    pub fn compact(&mut self, index: u64) -> io::Result<()> {
+        let firsts: Vec<u64> = self.segments.keys().cloned().collect();
+        let mut removed = false;
+        for (i, first) in firsts.iter().enumerate() {
+            let last = firsts.get(i + 1).map_or(self.last_index, |next| next - 1);
+            if last > index {
+                break;
+            }
+            fs::remove_file(self.segment(*first))?;
+            self.segments.remove(first);
+            removed = true;
+        }
+        if removed {
+            sync_dir(&self.dir)?;
+        }
+        Ok(())
+    }
+
+    /// Drops the entries from `index` on
+    fn truncate(&mut self, index: u64) -> io::Result<()> {
+        if index > self.last_index {
+            return Ok(());
+        }
+        let later: Vec<u64> = self
+            .segments
+            .range(index..)
+            .map(|(first, _)| *first)
+            .collect();
+        for first in &later {
+            fs::remove_file(self.segment(*first))?;
+            self.segments.remove(first);
+        }
+        if !later.is_empty() {
+            sync_dir(&self.dir)?;
+        }
+        self.last_index = index.saturating_sub(1);
+        if let Some((first, len)) = self.segments.iter_mut().next_back() {
+            let records = read_records(&self.dir.join(segment_name(*first)))?;
+            let end = records
+                .iter()
+                .take_while(|record| record.index < index)
+                .last()
+                .map_or(0, |record| record.end);
+            cut(&self.dir.join(segment_name(*first)), end)?;
+            *len = end;
+        }
+        Ok(())
+    }
+
+    fn segment(&self, first: u64) -> PathBuf {
+        self.dir.join(segment_name(first))
+    }
+
+    fn read_segment(&self, first: u64) -> io::Result<Vec<Record>> {
+        read_records(&self.segment(first))
+    }
+}
+
+fn segment_name(first: u64) -> String {
+    format!("{:020}.log", first)
+}
+
+/// Cuts a segment off at `end`, durably
+fn cut(path: &Path, end: u64) -> io::Result<()> {
+    let file = OpenOptions::new().write(true).open(path)?;
+    file.set_len(end)?;
+    file.sync_all()
+}
+
+/// Makes the files created, renamed and removed in `dir` durable
+pub(super) fn sync_dir(dir: &Path) -> io::Result<()> {
+    File::open(dir)?.sync_all()
+}
+
+/// Reads the records of a segment up to the first torn or corrupt one
+fn read_records(path: &Path) -> io::Result<Vec<Record>> {
+    let mut records = Vec::new();
+    let mut reader = match File::open(path) {
+        Ok(file) => BufReader::new(file),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(records),
+        Err(e) => return Err(e),
+    };
+    let mut end = 0;
+    loop {
+        let mut header = [0; HEADER_BYTES as usize];
+        if reader.read_exact(&mut header).is_err() {
+            break;
+        }
+        let len = u32::from_le_bytes(header[0..4].try_into().unwrap());
+        let crc = u32::from_le_bytes(header[4..8].try_into().unwrap());
+        let index = u64::from_le_bytes(header[8..16].try_into().unwrap());
+        let mut entry = vec![0; len as usize];
+        if reader.read_exact(&mut entry).is_err() {
+            break;
+        }
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&header[8..16]);
+        hasher.update(&entry);
+        if hasher.finalize() != crc {
+            break;
+        }
+        end += HEADER_BYTES + len as u64;
+        records.push(Record { index, entry, end });
+    }
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(index: u64) -> Vec<u8> {
+        vec![index as u8; 1 + index as usize % 7]
+    }
+
+    #[test]
+    fn test_wal() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("raft_log");
+        let mut wal = Wal::open(path.clone()).unwrap();
+        let entries: Vec<Vec<u8>> = (1..=10).map(entry).collect();
+        wal.append(1, &entries).unwrap();
+        assert_eq!(Wal::open(path.clone()).unwrap().entries().unwrap(), entries);
+
+        // a conflicting entry replaces those from it on
+        wal.append(6, &[vec![66]]).unwrap();
+        let mut expected = entries[..5].to_vec();
+        expected.push(vec![66]);
+        assert_eq!(
+            Wal::open(path.clone()).unwrap().entries().unwrap(),
+            expected
+        );
+
+        // a torn write is cut off on open
+        let segment = path.join(segment_name(1));
+        let len = fs::metadata(&segment).unwrap().len();
+        let file = OpenOptions::new().write(true).open(&segment).unwrap();
+        file.set_len(len - 1).unwrap();
+        let mut wal = Wal::open(path.clone()).unwrap();
+        assert_eq!(wal.entries().unwrap(), expected[..5].to_vec());
+        wal.append(6, &[vec![6]]).unwrap();
+        assert_eq!(Wal::open(path.clone()).unwrap().entries().unwrap().len(), 6);
+
+        // so is a record failing its CRC, with those after it
+        let mut data = fs::read(&segment).unwrap();
+        let third = 2 * HEADER_BYTES as usize + entries[0].len() + entries[1].len();
+        data[third + HEADER_BYTES as usize] ^= 0xff;
+        fs::write(&segment, data).unwrap();
+        let mut wal = Wal::open(path.clone()).unwrap();
+        assert_eq!(wal.entries().unwrap(), entries[..2].to_vec());
+        wal.append(3, &entries[2..]).unwrap();
+        assert_eq!(Wal::open(path).unwrap().entries().unwrap(), entries);
+    }
+
+    #[test]
+    fn test_wal_segments() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("raft_log");
+        let mut wal = Wal::open(path.clone()).unwrap();
+        let big = vec![7; SEGMENT_BYTES as usize];
+        for index in 1..=4 {
+            wal.append(index, &[big.clone()]).unwrap();
+        }
+        assert_eq!(wal.segments.len(), 4);
+
+        // only segments wholly covered by a snapshot are deleted
+        wal.compact(2).unwrap();
+        assert_eq!(wal.segments.keys().cloned().collect::<Vec<_>>(), vec![3, 4]);
+        wal.append(5, &[vec![5]]).unwrap();
+        let mut wal = Wal::open(path.clone()).unwrap();
+        assert_eq!(wal.entries().unwrap().len(), 3);
+
+        // truncation removes the later segments
+        wal.append(4, &[vec![4]]).unwrap();
+        assert_eq!(wal.segments.keys().cloned().collect::<Vec<_>>(), vec![3, 4]);
+        assert_eq!(wal.entries().unwrap(), vec![big, vec![4]]);
+
+        wal.compact(4).unwrap();
+        assert!(wal.segments.is_empty());
+        assert!(Wal::open(path).unwrap().entries().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_wal_rollover() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("raft_log");
+        let mut wal = Wal::open(path.clone()).unwrap();
+        // a segment is full after four of these, so one append rolls over twice
+        let entries: Vec<Vec<u8>> = (1..=10u8)
+            .map(|i| vec![i; SEGMENT_BYTES as usize / 4])
+            .collect();
+        wal.append(1, &entries).unwrap();
+        let mut files: Vec<String> = fs::read_dir(&path)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        files.sort();
+        assert_eq!(
+            files,
+            vec![segment_name(1), segment_name(5), segment_name(9)]
+        );
+
+        let mut wal = Wal::open(path.clone()).unwrap();
+        assert_eq!(wal.last_index, 10);
+        assert_eq!(wal.entries().unwrap(), entries);
+        // the last segment is appended to until full
+        wal.append(11, &entries[..2]).unwrap();
+        wal.append(13, &entries[..1]).unwrap();
+        assert_eq!(
+            wal.segments.keys().cloned().collect::<Vec<_>>(),
+            vec![1, 5, 9, 13]
+        );
+        assert_eq!(Wal::open(path).unwrap().entries().unwrap().len(), 13);
+    }
+}
@@ -5,9 +5,43 @@ pub mod kvs_service {
     pub use include::kv_rpc_client::KvRpcClient;
     pub use include::kv_rpc_server::{KvRpc, KvRpcServer};
     pub use include::{
-        CommitReply, CommitRequest, GetReply, GetRequest, PrewriteReply, PrewriteRequest, Snapshot,
-        TsReply, TsRequest, WriteOp,
+        raft_command, AddNodeRequest, BatchCommitReply, BatchCommitRequest, BatchGetReply,
+        BatchGetRequest, BatchPrewriteReply, BatchPrewriteRequest, ChangeEvent,
+        CheckTxnStatusReply, CheckTxnStatusRequest, CommitReply, CommitRequest, ConfChangeReply,
+        GcReply, GcRequest, GetReply, GetRequest, KvPair, LockInfo, Mutation, NodeInfo,
+        PrewriteReply, PrewriteRequest, RaftCommand, RemoveNodeRequest, ResolveLockReply,
+        ResolveLockRequest, RollbackReply, RollbackRequest, ScanReply, ScanRequest, Snapshot,
+        SubscribeReply, SubscribeRequest, TransferLeaderReply, TransferLeaderRequest, TsReply,
+        TsRequest, TsoConfirm, TsoWindow, TxnHeartBeatReply, TxnHeartBeatRequest, TxnStatus,
+        WatchReply, WatchRequest, WriteOp,
     };
+
+    /// The stream of changes a subscriber receives
+    pub type ChangeStream = std::pin::Pin<
+        Box<
+            dyn futures_core::Stream<Item = std::result::Result<SubscribeReply, tonic::Status>>
+                + Send
+                + Sync,
+        >,
+    >;
+
+    /// The stream of events a watcher receives
+    pub type WatchEventStream = std::pin::Pin<
+        Box<
+            dyn futures_core::Stream<Item = std::result::Result<WatchReply, tonic::Status>>
+                + Send
+                + Sync,
+        >,
+    >;
+
+    impl From<SubscribeReply> for WatchReply {
+        fn from(reply: SubscribeReply) -> Self {
+            WatchReply {
+                events: reply.events,
+                revision: reply.resolved_ts,
+            }
+        }
+    }
 }
 
 pub mod raft_service {
@@ -18,7 +52,8 @@ pub mod raft_service {
     pub use include::raft_rpc_server::{RaftRpc, RaftRpcServer};
     pub use include::{
         AppendEntriesArgs, AppendEntriesReply, HeartBeatArgs, HeartBeatReply, InstallSnapshotArgs,
-        InstallSnapshotReply, LogEntry, RequestVoteArgs, RequestVoteReply,
+        InstallSnapshotReply, LogEntry, Member, Membership, ReadIndexArgs, ReadIndexReply,
+        RequestVoteArgs, RequestVoteReply, TimeoutNowArgs, TimeoutNowReply,
     };
 
     impl std::fmt::Display for RequestVoteArgs {
@@ -84,7 +119,11 @@ pub mod raft_service {
 
     impl std::fmt::Display for InstallSnapshotReply {
         fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-            write!(f, "[InstallSnapshotReply Term: {}]", self.term)
+            write!(
+                f,
+                "[InstallSnapshotReply Term: {} Offset: {}]",
+                self.term, self.offset
+            )
         }
     }
 }
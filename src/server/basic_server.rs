@@ -1,6 +1,10 @@
 use crate::*;
-use crate::{percolator::TimestampOracle, rpc::kvs_service::*};
-use std::{net::SocketAddr, time::Duration};
+use crate::{
+    percolator::{mutation_op, TimestampOracle},
+    rpc::kvs_service::*,
+};
+use std::net::SocketAddr;
+use tokio_stream::{wrappers::UnboundedReceiverStream, StreamExt};
 use tonic::{Request, Response, Status};
 
 /// Kvs Server
@@ -38,37 +42,6 @@ impl KvsBasicServer {
         });
         handle.join().unwrap()
     }
-    fn lock_back_off_or_clean_up(&self, key: String, ts: u64) {
-        if let Some((lock_key, lock_value)) = self.store.read_lock(key.clone(), None, Some(ts)) {
-            let primary = lock_value.primary().to_owned();
-            let primary_ts = lock_key.ts();
-            if let Some((_pri_lock_key, pri_lock_value)) =
-                self.store.read_lock(primary.clone(), None, Some(ts))
-            {
-                if pri_lock_value.elapsed() >= Duration::from_secs(3) {
-                    self.store.erase_lock(primary, ts);
-                    self.store.erase_lock(key, ts);
-                }
-            } else {
-                if let Some((write_key, write_value)) =
-                    self.store.read_write(primary, Some(primary_ts), None)
-                {
-                    if write_value.ts() == primary_ts {
-                        let primary_commit_ts = write_key.ts();
-                        self.store.write_write(
-                            key.clone(),
-                            primary_commit_ts,
-                            primary_ts,
-                            lock_value.op(),
-                        );
-                        self.store.erase_lock(key, primary_ts);
-                    }
-                } else {
-                    self.store.erase_lock(key, ts);
-                }
-            }
-        }
-    }
 }
 
 #[tonic::async_trait]
@@ -77,9 +50,14 @@ impl KvRpc for KvsBasicServer {
         &self,
         request: Request<TsRequest>,
     ) -> std::result::Result<Response<TsReply>, Status> {
-        let name = request.into_inner().name;
-        let ts = self.ts_oracle.fetch_one().unwrap();
-        let reply = TsReply { name, ts };
+        let req = request.into_inner();
+        let count = req.count.max(1);
+        let ts = self.ts_oracle.fetch(count)?;
+        let reply = TsReply {
+            name: req.name,
+            ts,
+            count,
+        };
         Ok(tonic::Response::new(reply))
     }
 
@@ -88,33 +66,20 @@ impl KvRpc for KvsBasicServer {
         req: Request<GetRequest>,
     ) -> std::result::Result<Response<GetReply>, Status> {
         let req = req.into_inner();
-        loop {
-            if self
-                .store
-                .read_lock(req.key.clone(), None, Some(req.ts))
-                .is_some()
-            {
-                self.lock_back_off_or_clean_up(req.key.clone(), req.ts);
-                continue;
-            }
-            let last_write = self.store.read_write(req.key.clone(), None, Some(req.ts));
-            if last_write.is_none() || last_write.clone().unwrap().1.op() == WriteOp::Delete {
-                return Err(KvRpcError::KeyNotFound)?;
-            }
-            let start_ts = last_write.unwrap().1.ts();
-            let value = self
-                .store
-                .read_data(req.key.clone(), Some(start_ts), Some(start_ts))
-                .unwrap()
-                .1
-                .value();
-            let reply = GetReply {
-                message: value,
-                ts: req.ts,
-                seq: req.seq,
-            };
-            return Ok(Response::new(reply));
-        }
+        self.store.check_safe_point(req.ts)?;
+        // leave the lock to the reader, which resolves it or backs off
+        let (message, lock) = match self.store.read_at(req.key, req.ts, req.ts) {
+            Ok(Some(value)) => (value, None),
+            Ok(None) => return Err(KvRpcError::KeyNotFound)?,
+            Err(lock) => (String::new(), Some(lock)),
+        };
+        let reply = GetReply {
+            message,
+            ts: req.ts,
+            seq: req.seq,
+            lock,
+        };
+        Ok(Response::new(reply))
     }
 
     async fn txn_prewrite(
@@ -139,9 +104,11 @@ impl KvRpc for KvsBasicServer {
             req.ts,
             req.primary.clone(),
             WriteOp::from_i32(req.op).unwrap(),
+            req.lock_ttl,
         );
         // for update primary ttl
-        self.store.update_lock(req.primary.clone(), req.ts);
+        self.store
+            .update_lock(req.primary.clone(), req.ts, req.lock_ttl);
         let reply = PrewriteReply {
             ok: true,
             ts: req.ts,
@@ -171,6 +138,7 @@ impl KvRpc for KvsBasicServer {
             WriteOp::from_i32(req.op).unwrap(),
         );
         self.store.erase_lock(req.key, req.commit_ts);
+        self.store.publish_changes();
         let reply = CommitReply {
             ok: true,
             ts: req.commit_ts,
@@ -178,4 +146,253 @@ impl KvRpc for KvsBasicServer {
         };
         Ok(Response::new(reply))
     }
+
+    async fn txn_batch_prewrite(
+        &self,
+        req: Request<BatchPrewriteRequest>,
+    ) -> std::result::Result<Response<BatchPrewriteReply>, Status> {
+        let req = req.into_inner();
+        // index entries are locked under the same primary as the base write
+        let index_mutations = self.store.index_mutations(&req.mutations, req.ts);
+        let mutations: Vec<Mutation> = req
+            .mutations
+            .iter()
+            .chain(index_mutations.iter())
+            .cloned()
+            .collect();
+        let mut ops = Vec::new();
+        for mutation in mutations.iter() {
+            ops.push(mutation_op(mutation)?);
+            if self
+                .store
+                .read_write(mutation.key.clone(), Some(req.ts), None)
+                .is_some()
+            {
+                return Err(KvRpcError::Abort(String::from("find write after ts")))?;
+            }
+            // a lock left by this txn means the batch was already prewritten
+            if let Some((lock_key, _)) = self.store.read_lock(mutation.key.clone(), None, None) {
+                if lock_key.ts() != req.ts {
+                    return Err(KvRpcError::Abort(String::from("find another lock")))?;
+                }
+            }
+        }
+        for (mutation, op) in mutations.into_iter().zip(ops) {
+            self.store
+                .write_data(mutation.key.clone(), req.ts, mutation.value);
+            self.store
+                .write_lock(mutation.key, req.ts, req.primary.clone(), op, req.lock_ttl);
+        }
+        // for update primary ttl
+        self.store
+            .update_lock(req.primary.clone(), req.ts, req.lock_ttl);
+        let reply = BatchPrewriteReply {
+            ok: true,
+            ts: req.ts,
+            seq: req.seq,
+            index_mutations,
+        };
+        Ok(Response::new(reply))
+    }
+
+    async fn txn_batch_commit(
+        &self,
+        request: Request<BatchCommitRequest>,
+    ) -> std::result::Result<Response<BatchCommitReply>, Status> {
+        let req = request.into_inner();
+        self.store
+            .commit_batch(&req.mutations, req.start_ts, req.commit_ts)?;
+        self.store.publish_changes();
+        let reply = BatchCommitReply {
+            ok: true,
+            ts: req.commit_ts,
+            seq: req.seq,
+        };
+        Ok(Response::new(reply))
+    }
+
+    async fn txn_heart_beat(
+        &self,
+        request: Request<TxnHeartBeatRequest>,
+    ) -> std::result::Result<Response<TxnHeartBeatReply>, Status> {
+        let req = request.into_inner();
+        match self
+            .store
+            .heart_beat_lock(req.primary, req.start_ts, req.advise_lock_ttl)
+        {
+            Some(lock_ttl) => {
+                let reply = TxnHeartBeatReply {
+                    lock_ttl,
+                    ts: req.start_ts,
+                    seq: req.seq,
+                };
+                Ok(Response::new(reply))
+            }
+            None => Err(KvRpcError::Abort(String::from("primary lock missing")))?,
+        }
+    }
+
+    async fn txn_rollback(
+        &self,
+        request: Request<RollbackRequest>,
+    ) -> std::result::Result<Response<RollbackReply>, Status> {
+        let req = request.into_inner();
+        for key in req.keys.iter() {
+            if let Some((_write_key, write_value)) =
+                self.store.read_write_of_txn(key.clone(), req.start_ts)
+            {
+                if write_value.op() != WriteOp::Rollback {
+                    return Err(KvRpcError::Abort(String::from("txn already committed")))?;
+                }
+            }
+        }
+        for key in req.keys {
+            self.store.rollback(key, req.start_ts);
+        }
+        self.store.publish_changes();
+        let reply = RollbackReply {
+            ok: true,
+            ts: req.start_ts,
+            seq: req.seq,
+        };
+        Ok(Response::new(reply))
+    }
+
+    async fn txn_check_txn_status(
+        &self,
+        request: Request<CheckTxnStatusRequest>,
+    ) -> std::result::Result<Response<CheckTxnStatusReply>, Status> {
+        let req = request.into_inner();
+        let current_ts = req.current_ts.max(self.ts_oracle.current());
+        let reply = self
+            .store
+            .check_txn_status(req.primary, req.lock_ts, req.lock_ttl, current_ts)
+            .into_reply(req.ts, req.seq);
+        self.store.publish_changes();
+        Ok(Response::new(reply))
+    }
+
+    async fn txn_resolve_lock(
+        &self,
+        request: Request<ResolveLockRequest>,
+    ) -> std::result::Result<Response<ResolveLockReply>, Status> {
+        let req = request.into_inner();
+        let commit_ts = Some(req.commit_ts).filter(|ts| *ts != 0);
+        let resolved = self.store.resolve_lock(req.start_ts, commit_ts);
+        self.store.publish_changes();
+        let reply = ResolveLockReply {
+            resolved: resolved as u64,
+            ts: req.ts,
+            seq: req.seq,
+        };
+        Ok(Response::new(reply))
+    }
+    async fn txn_batch_get(
+        &self,
+        request: Request<BatchGetRequest>,
+    ) -> std::result::Result<Response<BatchGetReply>, Status> {
+        let req = request.into_inner();
+        self.store.check_safe_point(req.ts)?;
+        let start_ts = Some(req.start_ts).filter(|ts| *ts != 0).unwrap_or(req.ts);
+        let (pairs, locks) = self.store.batch_get(req.keys, req.ts, start_ts);
+        let reply = BatchGetReply {
+            pairs,
+            locks,
+            ts: req.ts,
+            seq: req.seq,
+        };
+        Ok(Response::new(reply))
+    }
+
+    async fn txn_scan(
+        &self,
+        request: Request<ScanRequest>,
+    ) -> std::result::Result<Response<ScanReply>, Status> {
+        let req = request.into_inner();
+        self.store.check_safe_point(req.ts)?;
+        let start_ts = Some(req.start_ts).filter(|ts| *ts != 0).unwrap_or(req.ts);
+        let (pairs, lock) =
+            self.store
+                .scan(req.start, req.end, req.limit as usize, req.ts, start_ts);
+        let reply = ScanReply {
+            pairs,
+            lock,
+            ts: req.ts,
+            seq: req.seq,
+        };
+        Ok(Response::new(reply))
+    }
+
+    async fn txn_gc(
+        &self,
+        request: Request<GcRequest>,
+    ) -> std::result::Result<Response<GcReply>, Status> {
+        let req = request.into_inner();
+        let removed = self
+            .store
+            .gc(req.safe_point)
+            .map_err(|e| Status::internal(e.to_string()))?;
+        let reply = GcReply {
+            removed: removed as u64,
+            safe_point: self.store.gc_safe_point(),
+            ts: req.ts,
+            seq: req.seq,
+        };
+        Ok(Response::new(reply))
+    }
+
+    type SubscribeStream = ChangeStream;
+
+    async fn subscribe(
+        &self,
+        request: Request<SubscribeRequest>,
+    ) -> std::result::Result<Response<Self::SubscribeStream>, Status> {
+        let req = request.into_inner();
+        let receiver = self.store.subscribe(req.start_ts, req.key_prefix);
+        let stream = UnboundedReceiverStream::new(receiver).map(Ok);
+        Ok(Response::new(Box::pin(stream)))
+    }
+
+    type WatchStream = WatchEventStream;
+
+    async fn watch(
+        &self,
+        request: Request<WatchRequest>,
+    ) -> std::result::Result<Response<Self::WatchStream>, Status> {
+        let req = request.into_inner();
+        let receiver = self.store.watch(req.key, req.prefix, req.from_revision);
+        // watchers only care about the changes, not the bare watermarks
+        let stream = UnboundedReceiverStream::new(receiver)
+            .filter(|reply| !reply.events.is_empty())
+            .map(|reply| Ok(reply.into()));
+        Ok(Response::new(Box::pin(stream)))
+    }
+
+    async fn add_node(
+        &self,
+        _request: Request<AddNodeRequest>,
+    ) -> std::result::Result<Response<ConfChangeReply>, Status> {
+        Err(Status::unimplemented("a basic server has no raft group"))
+    }
+
+    async fn remove_node(
+        &self,
+        _request: Request<RemoveNodeRequest>,
+    ) -> std::result::Result<Response<ConfChangeReply>, Status> {
+        Err(Status::unimplemented("a basic server has no raft group"))
+    }
+
+    async fn add_learner(
+        &self,
+        _request: Request<AddNodeRequest>,
+    ) -> std::result::Result<Response<ConfChangeReply>, Status> {
+        Err(Status::unimplemented("a basic server has no raft group"))
+    }
+
+    async fn transfer_leader(
+        &self,
+        _request: Request<TransferLeaderRequest>,
+    ) -> std::result::Result<Response<TransferLeaderReply>, Status> {
+        Err(Status::unimplemented("a basic server has no raft group"))
+    }
 }
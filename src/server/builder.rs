@@ -1,7 +1,6 @@
-use std::{net::SocketAddr, path::PathBuf, sync::Arc};
+use std::{io, net::SocketAddr, path::PathBuf, sync::Arc};
 
 use tokio::sync::mpsc::unbounded_channel;
-use tonic::transport::Channel;
 
 use super::*;
 use crate::preclude::*;
@@ -18,11 +17,18 @@ struct ServerNodeInfo {
 ///   - server kind, option: ["basic", "raft"]
 ///   - root path, which can simplify configuration
 ///   - server info: which included SocketAddr and running path
+///   - node ids and whether raft nodes join a running group
+///   - whether raft leaders serve reads under a lease
+///   - when raft nodes snapshot and compact their log
 pub struct KvsServerBuilder {
     info: Vec<ServerNodeInfo>,
     store_kind: String,
     server_kind: String,
     root_path: PathBuf,
+    first_id: usize,
+    join: bool,
+    lease_read: bool,
+    snapshot_policy: SnapshotPolicy,
 }
 
 impl Default for KvsServerBuilder {
@@ -32,6 +38,10 @@ impl Default for KvsServerBuilder {
             store_kind: String::from("kvs"),
             server_kind: String::from("basic"),
             root_path: std::env::current_dir().unwrap(),
+            first_id: 0,
+            join: false,
+            lease_read: false,
+            snapshot_policy: SnapshotPolicy::default(),
         }
     }
 }
@@ -52,10 +62,32 @@ impl KvsServerBuilder {
         self.root_path = path;
         self
     }
+    /// set the id of the first node, the nodes added are numbered from it
+    pub fn set_first_id(mut self, id: usize) -> Self {
+        self.first_id = id;
+        self
+    }
+    /// start raft nodes outside of any group, waiting to be added to a
+    /// running one instead of forming a new group
+    pub fn set_join(mut self, join: bool) -> Self {
+        self.join = join;
+        self
+    }
+    /// let raft leaders serve reads locally while they hold a lease, instead
+    /// of confirming the leadership with a quorum for every read
+    pub fn set_lease_read(mut self, lease_read: bool) -> Self {
+        self.lease_read = lease_read;
+        self
+    }
+    /// set when raft nodes snapshot their state and compact the log
+    pub fn set_snapshot_policy(mut self, policy: SnapshotPolicy) -> Self {
+        self.snapshot_policy = policy;
+        self
+    }
     /// add one node and its addr and path
     pub fn add_node(mut self, addr: SocketAddr, path: PathBuf) -> Self {
         let node = ServerNodeInfo {
-            id: self.info.len(),
+            id: self.first_id + self.info.len(),
             addr,
             path,
         };
@@ -67,10 +99,11 @@ impl KvsServerBuilder {
         addrs
             .into_iter()
             .map(|addr| {
+                let id = self.first_id + self.info.len();
                 let node = ServerNodeInfo {
-                    id: self.info.len(),
+                    id,
                     addr,
-                    path: self.root_path.join(format!("server-{}", self.info.len())),
+                    path: self.root_path.join(format!("server-{}", id)),
                 };
                 self.info.push(node);
             })
@@ -78,47 +111,45 @@ impl KvsServerBuilder {
         self
     }
 
-    /// consume this builder, create a new KvsServer
-    pub fn build(self) -> KvsServer {
+    /// consume this builder, create a new KvsServer, failing when the
+    /// raft state kept on disk cannot be opened
+    pub fn build(self) -> io::Result<KvsServer> {
         match self.server_kind.as_ref() {
-            "basic" => self.build_basic_server(),
+            "basic" => Ok(self.build_basic_server()),
             "raft" => self.build_raft_server(),
             _unknown => unreachable!(),
         }
     }
 
-    fn build_raft_server(self) -> KvsServer {
-        assert!(self.info.len() > 1 && self.info.len() % 2 != 0);
-        let peers: Vec<RaftRpcClient<Channel>> = self
-            .info
-            .iter()
-            .map(|node| format!("http://{}", node.addr))
-            .map(|node| Channel::from_shared(node).unwrap().connect_lazy().unwrap())
-            .map(|res| RaftRpcClient::new(res))
-            .collect();
-        let ts_oracle = TimestampOracle::open(self.root_path.clone()).unwrap();
+    fn build_raft_server(self) -> io::Result<KvsServer> {
+        assert!(!self.info.is_empty());
+        let peers: Vec<Member> = match self.join {
+            true => Vec::new(),
+            false => self
+                .info
+                .iter()
+                .map(|node| Member {
+                    id: node.id as u64,
+                    addr: node.addr.to_string(),
+                })
+                .collect(),
+        };
         let nodes: Vec<(RaftNode, KvRaftNode, SocketAddr)> = self
             .info
             .iter()
             .map(|info| {
-                let per = Arc::new(FilePersister::with_path(info.path.clone()));
+                let per = Arc::new(FilePersister::with_path(info.path.clone())?);
                 let (tx, rx) = unbounded_channel();
                 let raft = RaftNode::new(peers.clone(), info.id, per.clone(), tx);
+                raft.set_lease_read(self.lease_read);
                 let store = MultiStore::new(info.path.clone(), self.store_kind.clone());
-                let kv_raft = KvRaftNode::new(
-                    raft.clone(),
-                    store,
-                    info.id,
-                    per,
-                    Some(1024),
-                    rx,
-                    ts_oracle.clone(),
-                );
-                (raft, kv_raft, info.addr)
+                let policy = self.snapshot_policy.clone();
+                let kv_raft = KvRaftNode::new(raft.clone(), store, info.id, per, policy, rx);
+                Ok((raft, kv_raft, info.addr))
             })
-            .collect();
+            .collect::<io::Result<_>>()?;
         let server = KvRaftServer::new(nodes);
-        KvsServer::new(ServerKind::Raft(server))
+        Ok(KvsServer::new(ServerKind::Raft(server)))
     }
     fn build_basic_server(self) -> KvsServer {
         assert!(self.info.len() == 1);
@@ -126,6 +157,6 @@ impl KvsServerBuilder {
         let store = MultiStore::new(info.path.clone(), self.store_kind.clone());
         let ts_oracle = TimestampOracle::open(info.path.clone()).unwrap();
         let server = KvsBasicServer::new(store, info.addr, ts_oracle).unwrap();
-        KvsServer::new(ServerKind::Basic(server))
+        KvsServer::new(ServerKind::Basic(Box::new(server)))
     }
 }
@@ -25,9 +25,9 @@ impl KvRaftServer {
                 std::thread::spawn(move || {
                     threaded_rt.block_on(async move {
                         tonic::transport::Server::builder()
-                            .add_service(RaftRpcServer::new(rf))
+                            .add_service(RaftRpcServer::new(rf.clone()))
                             .add_service(KvRpcServer::new(kvrf))
-                            .serve(addr)
+                            .serve_with_shutdown(addr, shutdown_signal(rf))
                             .await
                             .map_err(|e| KvError::StringError(e.to_string()))
                     })
@@ -40,3 +40,17 @@ impl KvRaftServer {
         Ok(())
     }
 }
+
+/// Waits for ctrl-c, handing the leadership of a leader over before the
+/// node stops so that the group need not wait out an election timeout.
+async fn shutdown_signal(rf: RaftNode) {
+    let _ = tokio::signal::ctrl_c().await;
+    if rf.is_leader() {
+        match rf.transfer_leader(None).await {
+            Ok(Ok(())) => info!("Leadership transferred before shutting down"),
+            Ok(Err(e)) => warn!("Shutting down without transferring leadership: {}", e),
+            Err(_) => warn!("Shutting down without transferring leadership"),
+        }
+    }
+    rf.kill();
+}
@@ -2,7 +2,7 @@ use super::*;
 use crate::Result;
 
 pub(crate) enum ServerKind {
-    Basic(KvsBasicServer),
+    Basic(Box<KvsBasicServer>),
     Raft(KvRaftServer),
 }
 
@@ -11,6 +11,7 @@ use std::{
 };
 use tempfile::TempDir;
 use thread::JoinHandle;
+use tokio_stream::{wrappers::UnboundedReceiverStream, StreamExt};
 use tonic::transport::Channel;
 
 fn open_server(engine: &str, addr: &str, temp_dir: &TempDir) -> (SyncSender<()>, JoinHandle<()>) {
@@ -37,9 +38,16 @@ struct ClientWrapper {
 }
 impl ClientWrapper {
     fn new(addr: &str) -> ClientWrapper {
+        ClientWrapper::with_isolation(addr, "snapshot")
+    }
+    fn with_isolation(addr: &str, isolation: &str) -> ClientWrapper {
+        ClientWrapper::with_args(addr, isolation, &[])
+    }
+    fn with_args(addr: &str, isolation: &str, args: &[&str]) -> ClientWrapper {
         let mut child = Command::cargo_bin("kvs-client")
             .unwrap()
             .args(&["txn", "--addr", addr])
+            .args(args)
             .env("RUST_LOG", "warn")
             .stdout(Stdio::piped())
             .stdin(Stdio::piped())
@@ -58,11 +66,14 @@ impl ClientWrapper {
             reader,
             writer,
         };
-        client.begin();
+        client.begin_with(isolation);
         client
     }
     fn begin(&mut self) {
-        let buf = format!("begin\n");
+        self.begin_with("");
+    }
+    fn begin_with(&mut self, isolation: &str) {
+        let buf = format!("begin {}\n", isolation);
         self.writer.write(buf.as_bytes()).expect("Writer error");
         self.writer.flush().expect("Writer error");
 
@@ -75,6 +86,29 @@ impl ClientWrapper {
         self.writer.write(buf.as_bytes()).expect("Writer error");
         self.writer.flush().expect("Writer error");
     }
+    fn remove(&mut self, key: &str) {
+        let buf = format!("remove {}\n", key);
+        self.writer.write(buf.as_bytes()).expect("Writer error");
+        self.writer.flush().expect("Writer error");
+    }
+    fn set_fail(&mut self, key: &str, value: &str, expected: &str) {
+        self.set(key, value);
+
+        let mut reader_buf = String::new();
+        self.reader.read_line(&mut reader_buf).unwrap();
+        assert!(reader_buf.trim().contains(expected.trim()));
+    }
+    fn scan(&mut self, args: &str, expected: &[&str]) {
+        let buf = format!("scan {}\n", args);
+        self.writer.write(buf.as_bytes()).expect("Writer error");
+        self.writer.flush().expect("Writer error");
+
+        for expected in expected {
+            let mut reader_buf = String::new();
+            self.reader.read_line(&mut reader_buf).unwrap();
+            assert_eq!(expected.trim(), reader_buf.trim());
+        }
+    }
     fn get(&mut self, key: &str, expected: &str) {
         let buf = format!("get {}\n", key);
         self.writer.write(buf.as_bytes()).expect("Writer error");
@@ -95,6 +129,15 @@ impl ClientWrapper {
 
         self.exit();
     }
+    fn abort(&mut self, expected: &str) {
+        let buf = format!("abort\n");
+        self.writer.write(buf.as_bytes()).expect("Writer error");
+        self.writer.flush().expect("Writer error");
+
+        let mut reader_buf = String::new();
+        self.reader.read_line(&mut reader_buf).unwrap();
+        assert!(reader_buf.trim().contains(expected.trim()));
+    }
     fn exit(&mut self) {
         let buf = format!("exit\n");
         self.writer.write(buf.as_bytes()).expect("Writer error");
@@ -136,7 +179,7 @@ fn client_cli_txn_single_access() {
         let mut client0 = ClientWrapper::new(addr);
         client0.set("key1", "100");
         client0.set("key2", "200");
-        client0.get("key1", "Key not found");
+        client0.get("key1", "100");
         client0.get("key3", "Key not found");
         client0.commit("Transaction Success");
 
@@ -188,7 +231,7 @@ fn client_cli_txn_pmp_write_predicates() {
 
         client1.set("key1", "200");
         client1.set("key2", "300");
-        client1.get("key2", "200");
+        client1.get("key2", "300");
 
         client2.set("key2", "400");
 
@@ -378,12 +421,490 @@ fn client_cli_txn_anti_dependency_cycles() {
     }
 }
 
+#[test]
+fn client_cli_txn_multi_batch_commit() {
+    let addr = "127.0.0.1:4022";
+    for engine in vec!["kvs", "sled"] {
+        let temp_dir = TempDir::new().unwrap();
+        let (sender, handle) = open_server(engine, addr, &temp_dir);
+
+        // more keys than fit in one prewrite or commit batch
+        let mut client0 = ClientWrapper::new(addr);
+        for i in 0..150 {
+            client0.set(&format!("key{}", i), &format!("{}", i));
+        }
+        client0.commit("Transaction Success");
+
+        let mut client1 = ClientWrapper::new(addr);
+        for i in (0..150).step_by(7) {
+            client1.get(&format!("key{}", i), &format!("{}", i));
+        }
+
+        sender.send(()).unwrap();
+        handle.join().unwrap();
+    }
+}
+
+#[test]
+fn client_cli_txn_abort() {
+    let addr = "127.0.0.1:4023";
+    for engine in vec!["kvs", "sled"] {
+        let temp_dir = TempDir::new().unwrap();
+        let (sender, handle) = open_server(engine, addr, &temp_dir);
+
+        let mut client0 = ClientWrapper::new(addr);
+        client0.set("key1", "100");
+        client0.abort("Transaction Aborted");
+        client0.begin();
+        client0.set("key2", "200");
+        client0.commit("Transaction Success");
+
+        let mut client1 = ClientWrapper::new(addr);
+        client1.get("key1", "Key not found");
+        client1.get("key2", "200");
+
+        // beginning again drops what the unfinished transaction buffered
+        let mut client2 = ClientWrapper::new(addr);
+        client2.set("key3", "300");
+        client2.begin();
+        client2.set("key4", "400");
+        client2.commit("Transaction Success");
+
+        let mut client3 = ClientWrapper::new(addr);
+        client3.get("key3", "Key not found");
+        client3.get("key4", "400");
+
+        sender.send(()).unwrap();
+        handle.join().unwrap();
+    }
+}
+
+#[test]
+fn client_cli_txn_write_skew_serializable() {
+    let addr = "127.0.0.1:4024";
+    for engine in vec!["kvs", "sled"] {
+        let temp_dir = TempDir::new().unwrap();
+        let (sender, handle) = open_server(engine, addr, &temp_dir);
+
+        let mut client0 = ClientWrapper::new(addr);
+        client0.set("key1", "100");
+        client0.set("key2", "200");
+        client0.commit("Transaction Success");
+
+        let mut client1 = ClientWrapper::with_isolation(addr, "serializable");
+        let mut client2 = ClientWrapper::with_isolation(addr, "serializable");
+
+        client1.get("key1", "100");
+        client1.get("key2", "200");
+        client2.get("key1", "100");
+        client2.get("key2", "200");
+
+        client1.set("key1", "101");
+        client2.set("key2", "201");
+        client1.commit("Transaction Success");
+        client2.commit("Transaction Failed");
+
+        let mut client3 = ClientWrapper::new(addr);
+        client3.get("key1", "101");
+        client3.get("key2", "200");
+
+        // a txn only reading still conflicts with a write of what it read
+        let mut client4 = ClientWrapper::with_isolation(addr, "serializable");
+        client4.get("key1", "101");
+        let mut client5 = ClientWrapper::new(addr);
+        client5.set("key1", "102");
+        client5.commit("Transaction Success");
+        client4.commit("Transaction Failed");
+
+        sender.send(()).unwrap();
+        handle.join().unwrap();
+    }
+}
+
+#[test]
+fn client_cli_txn_read_skew_read_committed() {
+    let addr = "127.0.0.1:4025";
+    for engine in vec!["kvs", "sled"] {
+        let temp_dir = TempDir::new().unwrap();
+        let (sender, handle) = open_server(engine, addr, &temp_dir);
+
+        let mut client0 = ClientWrapper::new(addr);
+        client0.set("key1", "100");
+        client0.set("key2", "200");
+        client0.commit("Transaction Success");
+
+        let mut client1 = ClientWrapper::with_isolation(addr, "read-committed");
+        let mut client2 = ClientWrapper::new(addr);
+
+        client1.get("key1", "100");
+        client2.get("key1", "100");
+        client2.get("key2", "200");
+
+        client2.set("key1", "101");
+        client2.set("key2", "201");
+        client2.commit("Transaction Success");
+
+        // each read sees the latest commit, so read skew is allowed
+        client1.get("key1", "101");
+        client1.get("key2", "201");
+
+        sender.send(()).unwrap();
+        handle.join().unwrap();
+    }
+}
+
+#[test]
+fn client_cli_txn_size_limit() {
+    let addr = "127.0.0.1:4026";
+    for engine in vec!["kvs", "sled"] {
+        let temp_dir = TempDir::new().unwrap();
+        let (sender, handle) = open_server(engine, addr, &temp_dir);
+
+        let mut client0 = ClientWrapper::with_args(addr, "snapshot", &["--txn-size-limit", "64"]);
+        client0.set("key1", "100");
+        client0.set_fail("key2", &"x".repeat(64), "Transaction too large");
+        client0.commit("Transaction Success");
+
+        let mut client1 = ClientWrapper::new(addr);
+        client1.get("key1", "100");
+        client1.get("key2", "Key not found");
+        client1.commit("Transaction Success");
+
+        sender.send(()).unwrap();
+        handle.join().unwrap();
+    }
+}
+
+#[test]
+fn client_cli_txn_read_own_writes() {
+    let addr = "127.0.0.1:4027";
+    for engine in vec!["kvs", "sled"] {
+        let temp_dir = TempDir::new().unwrap();
+        let (sender, handle) = open_server(engine, addr, &temp_dir);
+
+        let mut client0 = ClientWrapper::new(addr);
+        client0.set("key1", "100");
+        client0.set("key2", "200");
+        client0.commit("Transaction Success");
+
+        let mut client1 = ClientWrapper::new(addr);
+        client1.set("key1", "101");
+        client1.get("key1", "101");
+        client1.set("key1", "102");
+        client1.get("key1", "102");
+        client1.remove("key2");
+        client1.get("key2", "Key not found");
+        // enough writes to flush a batch to servers before commit
+        for i in 0..100 {
+            client1.set(&format!("batch{}", i), &format!("{}", i));
+        }
+        client1.get("batch3", "3");
+        client1.get("key1", "102");
+
+        client1.commit("Transaction Success");
+
+        let mut client2 = ClientWrapper::new(addr);
+        client2.get("key1", "102");
+        client2.get("key2", "Key not found");
+        client2.get("batch99", "99");
+        client2.commit("Transaction Success");
+
+        sender.send(()).unwrap();
+        handle.join().unwrap();
+    }
+}
+
+#[test]
+fn client_cli_txn_scan() {
+    let addr = "127.0.0.1:4028";
+    for engine in vec!["kvs", "sled"] {
+        let temp_dir = TempDir::new().unwrap();
+        let (sender, handle) = open_server(engine, addr, &temp_dir);
+
+        let mut client0 = ClientWrapper::new(addr);
+        for i in 1..6 {
+            client0.set(&format!("key{}", i), &format!("{}00", i));
+        }
+        client0.commit("Transaction Success");
+
+        let mut client1 = ClientWrapper::new(addr);
+        client1.set("key6", "600");
+        client1.remove("key2");
+        client1.scan(
+            "key",
+            &["key1 100", "key3 300", "key4 400", "key5 500", "key6 600"],
+        );
+        client1.scan("key1 key4 2", &["key1 100", "key3 300"]);
+
+        // a txn committed after the start ts is not seen
+        let mut client2 = ClientWrapper::new(addr);
+        client2.set("key7", "700");
+        client2.commit("Transaction Success");
+        client1.scan("key6", &["key6 600"]);
+        client1.get("key7", "Key not found");
+        client1.commit("Transaction Success");
+
+        let mut client3 = ClientWrapper::new(addr);
+        client3.scan("key5", &["key5 500", "key6 600", "key7 700"]);
+        client3.get("key2", "Key not found");
+        client3.commit("Transaction Success");
+
+        sender.send(()).unwrap();
+        handle.join().unwrap();
+    }
+}
+
+fn subscribe(addr: &str, prefix: &str) -> (Child, BufReader<ChildStdout>) {
+    let mut child = Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["subscribe", prefix])
+        .args(&["--addr", addr])
+        .env("RUST_LOG", "warn")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    let stdout = child.stdout.take().expect("Unable get stdout");
+    (child, BufReader::new(stdout))
+}
+
+#[test]
+fn client_cli_txn_subscribe() {
+    let addr = "127.0.0.1:4029";
+    for engine in vec!["kvs", "sled"] {
+        let temp_dir = TempDir::new().unwrap();
+        let (sender, handle) = open_server(engine, addr, &temp_dir);
+
+        let mut client0 = ClientWrapper::new(addr);
+        client0.set("key1", "100");
+        client0.set("key2", "200");
+        client0.commit("Transaction Success");
+
+        // committed changes are replayed before new ones are followed
+        let (mut child, mut reader) = subscribe(addr, "key");
+        let mut client1 = ClientWrapper::new(addr);
+        client1.remove("key1");
+        client1.set("key3", "300");
+        client1.set("other", "400");
+        client1.commit("Transaction Success");
+
+        let mut commit_ts = Vec::new();
+        for expected in vec![
+            "put key1 100",
+            "put key2 200",
+            "delete key1",
+            "put key3 300",
+        ] {
+            let mut reader_buf = String::new();
+            reader.read_line(&mut reader_buf).unwrap();
+            let (ts, event) = reader_buf.trim().split_once(' ').unwrap();
+            assert_eq!(expected, event);
+            commit_ts.push(ts.parse::<u64>().unwrap());
+        }
+        assert_eq!(commit_ts[0], commit_ts[1]);
+        assert!(commit_ts[1] < commit_ts[2]);
+        assert_eq!(commit_ts[2], commit_ts[3]);
+        child.kill().unwrap();
+
+        sender.send(()).unwrap();
+        handle.join().unwrap();
+    }
+}
+
+fn watch(addr: &str, prefix: &str) -> (Child, BufReader<ChildStdout>) {
+    let mut child = Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["watch", prefix])
+        .args(&["--addr", addr])
+        .env("RUST_LOG", "warn")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    let stdout = child.stdout.take().expect("Unable get stdout");
+    (child, BufReader::new(stdout))
+}
+
+#[test]
+fn client_cli_txn_watch() {
+    let addr = "127.0.0.1:4030";
+    for engine in vec!["kvs", "sled"] {
+        let temp_dir = TempDir::new().unwrap();
+        let (sender, handle) = open_server(engine, addr, &temp_dir);
+
+        let mut client0 = ClientWrapper::new(addr);
+        client0.set("conf1", "100");
+        client0.commit("Transaction Success");
+
+        // only the changes committed after the watch starts are printed
+        let (mut child, mut reader) = watch(addr, "conf");
+        let mut client1 = ClientWrapper::new(addr);
+        client1.set("conf2", "200");
+        client1.set("other", "300");
+        client1.commit("Transaction Success");
+        let mut client2 = ClientWrapper::new(addr);
+        client2.remove("conf1");
+        client2.commit("Transaction Success");
+
+        for expected in vec!["put conf2 200", "delete conf1"] {
+            let mut reader_buf = String::new();
+            reader.read_line(&mut reader_buf).unwrap();
+            let (_revision, event) = reader_buf.trim().split_once(' ').unwrap();
+            assert_eq!(expected, event);
+        }
+        child.kill().unwrap();
+
+        sender.send(()).unwrap();
+        handle.join().unwrap();
+    }
+}
+
+#[test]
+fn client_cli_txn_get_at_ts() {
+    let addr = "127.0.0.1:4031";
+    for engine in vec!["kvs", "sled"] {
+        let temp_dir = TempDir::new().unwrap();
+        let (sender, handle) = open_server(engine, addr, &temp_dir);
+
+        for value in vec!["100", "200"] {
+            let mut client = ClientWrapper::new(addr);
+            client.set("key1", value);
+            client.commit("Transaction Success");
+        }
+        // the commit ts of both versions, as the change feed reports them
+        let (mut child, mut reader) = subscribe(addr, "key1");
+        let mut commit_ts = Vec::new();
+        for _ in 0..2 {
+            let mut reader_buf = String::new();
+            reader.read_line(&mut reader_buf).unwrap();
+            let (ts, _event) = reader_buf.trim().split_once(' ').unwrap();
+            commit_ts.push(ts.to_string());
+        }
+        child.kill().unwrap();
+        let before = (commit_ts[0].parse::<u64>().unwrap() - 1).to_string();
+
+        let get_at = |ts: &str| {
+            let mut cmd = Command::cargo_bin("kvs-client").unwrap();
+            cmd.args(&["get", "key1", "--at-ts", ts])
+                .args(&["--addr", addr]);
+            cmd
+        };
+        get_at(&before).assert().success().stdout("Key not found\n");
+        get_at(&commit_ts[0]).assert().success().stdout("100\n");
+        get_at(&commit_ts[1]).assert().success().stdout("200\n");
+        // a later version may still be committed below a ts the TSO did not reach
+        get_at(&u64::MAX.to_string())
+            .assert()
+            .failure()
+            .stderr(predicates::str::contains("ahead of the TSO"));
+
+        // versions overwritten before the safe point are gone
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(&["gc", &commit_ts[1]])
+            .args(&["--addr", addr])
+            .assert()
+            .success();
+        get_at(&commit_ts[0])
+            .assert()
+            .failure()
+            .stderr(predicates::str::contains("Version compacted"));
+        get_at(&commit_ts[1]).assert().success().stdout("200\n");
+
+        sender.send(()).unwrap();
+        handle.join().unwrap();
+    }
+}
+
+#[test]
+fn client_cli_txn_index_lookup() {
+    let addr = "127.0.0.1:4032";
+    for engine in vec!["kvs", "sled"] {
+        let temp_dir = TempDir::new().unwrap();
+        let (sender, handle) = open_server(engine, addr, &temp_dir);
+
+        let mut client = ClientWrapper::new(addr);
+        client.set("user-1", r#"{"email":"a@x"}"#);
+        client.commit("Transaction Success");
+        // keys written before the index are indexed when it is created
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(&["create-index", "email", "/email"])
+            .args(&["--addr", addr])
+            .assert()
+            .success();
+        let mut client = ClientWrapper::new(addr);
+        client.set("user-2", r#"{"email":"a@x"}"#);
+        client.set("user-3", r#"{"email":"b@x"}"#);
+        client.commit("Transaction Success");
+
+        let lookup = |value: &str| {
+            let mut cmd = Command::cargo_bin("kvs-client").unwrap();
+            cmd.args(&["lookup", "email", value])
+                .args(&["--addr", addr]);
+            cmd
+        };
+        lookup("a@x").assert().success().stdout("user-1\nuser-2\n");
+        lookup("b@x").assert().success().stdout("user-3\n");
+
+        // entries move with the value and go away with the key
+        let mut client = ClientWrapper::new(addr);
+        client.set("user-1", r#"{"email":"b@x"}"#);
+        client.remove("user-3");
+        client.commit("Transaction Success");
+        lookup("a@x").assert().success().stdout("user-2\n");
+        lookup("b@x").assert().success().stdout("user-1\n");
+
+        sender.send(()).unwrap();
+        handle.join().unwrap();
+    }
+}
+
+#[test]
+fn client_cli_txn_index_streamed() {
+    let addr = "127.0.0.1:4033";
+    for engine in vec!["kvs", "sled"] {
+        let temp_dir = TempDir::new().unwrap();
+        let (sender, handle) = open_server(engine, addr, &temp_dir);
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(&["create-index", "email", "/email"])
+            .args(&["--addr", addr])
+            .assert()
+            .success();
+
+        // the first value is flushed with a batch before it is overwritten
+        let mut client = ClientWrapper::new(addr);
+        client.set("user-1", r#"{"email":"a@x"}"#);
+        for i in 0..100 {
+            client.set(&format!("batch{}", i), &format!("{}", i));
+        }
+        client.set("user-1", r#"{"email":"b@x"}"#);
+        client.commit("Transaction Success");
+
+        let lookup = |value: &str| {
+            let mut cmd = Command::cargo_bin("kvs-client").unwrap();
+            cmd.args(&["lookup", "email", value])
+                .args(&["--addr", addr]);
+            cmd
+        };
+        lookup("a@x").assert().success().stdout("");
+        lookup("b@x").assert().success().stdout("user-1\n");
+
+        sender.send(()).unwrap();
+        handle.join().unwrap();
+    }
+}
+
 struct Proxy {
     addr: String,
     server_addr: String,
     drop_req: bool,
     drop_resp: bool,
     fail_primary: bool,
+    // roll the primary back before committing it, as a reader would on expiry
+    rollback_primary: bool,
 }
 
 impl Proxy {
@@ -425,6 +946,16 @@ impl KvRpc for Proxy {
         request: tonic::Request<CommitRequest>,
     ) -> std::result::Result<tonic::Response<CommitReply>, tonic::Status> {
         let request = request.into_inner();
+        if request.is_primary && self.rollback_primary {
+            let rollback = RollbackRequest {
+                keys: vec![request.key.clone()],
+                start_ts: request.start_ts,
+                seq: request.seq,
+            };
+            self.build_client()
+                .txn_rollback(tonic::Request::new(rollback))
+                .await?;
+        }
         let req = if self.drop_req {
             if request.is_primary && !self.fail_primary {
                 self.build_client()
@@ -444,6 +975,147 @@ impl KvRpc for Proxy {
             req
         }
     }
+
+    async fn txn_batch_prewrite(
+        &self,
+        request: tonic::Request<BatchPrewriteRequest>,
+    ) -> std::result::Result<tonic::Response<BatchPrewriteReply>, tonic::Status> {
+        self.build_client().txn_batch_prewrite(request).await
+    }
+
+    async fn txn_heart_beat(
+        &self,
+        request: tonic::Request<TxnHeartBeatRequest>,
+    ) -> std::result::Result<tonic::Response<TxnHeartBeatReply>, tonic::Status> {
+        self.build_client().txn_heart_beat(request).await
+    }
+
+    async fn txn_rollback(
+        &self,
+        request: tonic::Request<RollbackRequest>,
+    ) -> std::result::Result<tonic::Response<RollbackReply>, tonic::Status> {
+        self.build_client().txn_rollback(request).await
+    }
+
+    async fn txn_check_txn_status(
+        &self,
+        request: tonic::Request<CheckTxnStatusRequest>,
+    ) -> std::result::Result<tonic::Response<CheckTxnStatusReply>, tonic::Status> {
+        self.build_client().txn_check_txn_status(request).await
+    }
+
+    async fn txn_resolve_lock(
+        &self,
+        request: tonic::Request<ResolveLockRequest>,
+    ) -> std::result::Result<tonic::Response<ResolveLockReply>, tonic::Status> {
+        self.build_client().txn_resolve_lock(request).await
+    }
+
+    async fn txn_batch_get(
+        &self,
+        request: tonic::Request<BatchGetRequest>,
+    ) -> std::result::Result<tonic::Response<BatchGetReply>, tonic::Status> {
+        self.build_client().txn_batch_get(request).await
+    }
+
+    async fn txn_scan(
+        &self,
+        request: tonic::Request<ScanRequest>,
+    ) -> std::result::Result<tonic::Response<ScanReply>, tonic::Status> {
+        self.build_client().txn_scan(request).await
+    }
+
+    async fn txn_gc(
+        &self,
+        request: tonic::Request<GcRequest>,
+    ) -> std::result::Result<tonic::Response<GcReply>, tonic::Status> {
+        self.build_client().txn_gc(request).await
+    }
+
+    async fn add_node(
+        &self,
+        request: tonic::Request<AddNodeRequest>,
+    ) -> std::result::Result<tonic::Response<ConfChangeReply>, tonic::Status> {
+        self.build_client().add_node(request).await
+    }
+
+    async fn remove_node(
+        &self,
+        request: tonic::Request<RemoveNodeRequest>,
+    ) -> std::result::Result<tonic::Response<ConfChangeReply>, tonic::Status> {
+        self.build_client().remove_node(request).await
+    }
+
+    async fn add_learner(
+        &self,
+        request: tonic::Request<AddNodeRequest>,
+    ) -> std::result::Result<tonic::Response<ConfChangeReply>, tonic::Status> {
+        self.build_client().add_learner(request).await
+    }
+
+    async fn transfer_leader(
+        &self,
+        request: tonic::Request<TransferLeaderRequest>,
+    ) -> std::result::Result<tonic::Response<TransferLeaderReply>, tonic::Status> {
+        self.build_client().transfer_leader(request).await
+    }
+
+    type SubscribeStream = ChangeStream;
+
+    async fn subscribe(
+        &self,
+        request: tonic::Request<SubscribeRequest>,
+    ) -> std::result::Result<tonic::Response<ChangeStream>, tonic::Status> {
+        let mut stream = self.build_client().subscribe(request).await?.into_inner();
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Some(reply) = stream.next().await {
+                if tx.send(reply).is_err() {
+                    break;
+                }
+            }
+        });
+        Ok(tonic::Response::new(Box::pin(
+            UnboundedReceiverStream::new(rx),
+        )))
+    }
+
+    type WatchStream = WatchEventStream;
+
+    async fn watch(
+        &self,
+        request: tonic::Request<WatchRequest>,
+    ) -> std::result::Result<tonic::Response<WatchEventStream>, tonic::Status> {
+        let mut stream = self.build_client().watch(request).await?.into_inner();
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Some(reply) = stream.next().await {
+                if tx.send(reply).is_err() {
+                    break;
+                }
+            }
+        });
+        Ok(tonic::Response::new(Box::pin(
+            UnboundedReceiverStream::new(rx),
+        )))
+    }
+
+    async fn txn_batch_commit(
+        &self,
+        request: tonic::Request<BatchCommitRequest>,
+    ) -> std::result::Result<tonic::Response<BatchCommitReply>, tonic::Status> {
+        // batch commits only carry secondaries
+        let req = if self.drop_req {
+            Err(tonic::Status::data_loss("Drop request"))
+        } else {
+            self.build_client().txn_batch_commit(request).await
+        };
+        if self.drop_resp {
+            Err(tonic::Status::data_loss("Drop response"))
+        } else {
+            req
+        }
+    }
 }
 
 fn proxy_hook(proxy: Proxy) -> JoinHandle<()> {
@@ -475,6 +1147,7 @@ fn client_cli_txn_test_proxy_with_nothing_drop() {
         drop_req: false,
         drop_resp: false,
         fail_primary: false,
+        rollback_primary: false,
     };
     let _proxy_handle = proxy_hook(proxy);
     thread::sleep(Duration::from_secs(1));
@@ -516,6 +1189,7 @@ fn client_cli_txn_commit_primary_drop_secondary_requests() {
         drop_req: true,
         drop_resp: false,
         fail_primary: false,
+        rollback_primary: false,
     };
     let _proxy_handle = proxy_hook(proxy);
     thread::sleep(Duration::from_secs(1));
@@ -551,6 +1225,7 @@ fn client_cli_txn_commit_primary_success() {
         drop_req: true,
         drop_resp: false,
         fail_primary: false,
+        rollback_primary: false,
     };
     let _proxy_handle = proxy_hook(proxy);
     thread::sleep(Duration::from_secs(1));
@@ -586,6 +1261,7 @@ fn client_cli_txn_commit_primary_success_without_response() {
         drop_req: false,
         drop_resp: true,
         fail_primary: false,
+        rollback_primary: false,
     };
     let _proxy_handle = proxy_hook(proxy);
     thread::sleep(Duration::from_secs(1));
@@ -621,6 +1297,7 @@ fn client_cli_txn_commit_primary_fail() {
         drop_req: true,
         drop_resp: false,
         fail_primary: true,
+        rollback_primary: false,
     };
     let _proxy_handle = proxy_hook(proxy);
     thread::sleep(Duration::from_secs(1));
@@ -646,6 +1323,63 @@ fn client_cli_txn_commit_primary_fail() {
     }
 }
 
+#[test]
+fn client_cli_txn_commit_primary_abort_index() {
+    let server_addr = "127.0.0.1:4034";
+    let addr = "127.0.0.1:4035";
+    let proxy = Proxy {
+        addr: addr.to_string(),
+        server_addr: server_addr.to_string(),
+        drop_req: false,
+        drop_resp: false,
+        fail_primary: false,
+        rollback_primary: true,
+    };
+    let _proxy_handle = proxy_hook(proxy);
+    thread::sleep(Duration::from_secs(1));
+
+    for engine in vec!["kvs", "sled"] {
+        let temp_dir = TempDir::new().unwrap();
+        let (sender, handle) = open_server(engine, server_addr, &temp_dir);
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(&["create-index", "email", "/email"])
+            .args(&["--addr", server_addr])
+            .assert()
+            .success();
+
+        let mut client0 = ClientWrapper::new(addr);
+        client0.set("user-1", r#"{"email":"a@x"}"#);
+        client0.commit("Transaction Failed");
+
+        // a lock left on the index entry would hold the resolved ts back
+        let (mut child, mut reader) = subscribe(server_addr, "key");
+        let mut client1 = ClientWrapper::new(server_addr);
+        client1.set("key1", "100");
+        client1.commit("Transaction Success");
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            let mut reader_buf = String::new();
+            reader.read_line(&mut reader_buf).unwrap();
+            tx.send(reader_buf).unwrap();
+        });
+        let line = rx.recv_timeout(Duration::from_secs(10)).unwrap();
+        assert_eq!("put key1 100", line.trim().split_once(' ').unwrap().1);
+        child.kill().unwrap();
+
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(&["lookup", "email", "a@x"])
+            .args(&["--addr", server_addr])
+            .assert()
+            .success()
+            .stdout("");
+
+        sender.send(()).unwrap();
+        handle.join().unwrap();
+    }
+}
+
 #[test]
 fn client_cli_txn_server_crash() {
     let server_addr = "127.0.0.1:4020";
@@ -656,6 +1390,7 @@ fn client_cli_txn_server_crash() {
         drop_req: true,
         drop_resp: false,
         fail_primary: true,
+        rollback_primary: false,
     };
     let _proxy_handle = proxy_hook(proxy);
     thread::sleep(Duration::from_secs(1));
@@ -3,6 +3,7 @@ use kvs::preclude::*;
 use mpsc::SyncSender;
 use std::{
     io::{prelude::*, BufReader, BufWriter},
+    path::Path,
     process::Command,
     process::{Child, ChildStdin, ChildStdout, Stdio},
     sync::mpsc,
@@ -11,12 +12,22 @@ use std::{
 };
 use tempfile::TempDir;
 use thread::JoinHandle;
+use tokio_stream::{wrappers::UnboundedReceiverStream, StreamExt};
 use tonic::transport::Channel;
 
 fn open_server(
     engine: &str,
     addrs: Vec<&str>,
     temp_dir: &TempDir,
+) -> (SyncSender<()>, JoinHandle<()>) {
+    open_server_with(engine, addrs, temp_dir, &[])
+}
+
+fn open_server_with(
+    engine: &str,
+    addrs: Vec<&str>,
+    temp_dir: &TempDir,
+    flags: &[&str],
 ) -> (SyncSender<()>, JoinHandle<()>) {
     let (sender, receiver) = mpsc::sync_channel::<()>(0);
     let mut addr = vec![];
@@ -27,6 +38,30 @@ fn open_server(
     let mut child = Command::cargo_bin("kvs-server")
         .unwrap()
         .args(&[["--engine", engine, "--server", "raft"].to_vec(), addr].concat())
+        .args(flags)
+        .env("RUST_LOG", "warn")
+        .current_dir(temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+    });
+    thread::sleep(Duration::from_secs(1));
+    (sender, handle)
+}
+
+fn open_joining_server(
+    engine: &str,
+    addr: &str,
+    id: usize,
+    temp_dir: &TempDir,
+) -> (SyncSender<()>, JoinHandle<()>) {
+    let (sender, receiver) = mpsc::sync_channel::<()>(0);
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--engine", engine, "--server", "raft", "--addr", addr])
+        .args(&["--first-id", &id.to_string(), "--join"])
         .env("RUST_LOG", "warn")
         .current_dir(temp_dir)
         .spawn()
@@ -39,6 +74,15 @@ fn open_server(
     (sender, handle)
 }
 
+/// The length of the snapshot a raft node in `path` saved last, as named by
+/// its manifest
+fn snapshot_len(path: &Path) -> u64 {
+    let manifest: serde_json::Value =
+        serde_json::from_slice(&std::fs::read(path.join("MANIFEST")).unwrap()).unwrap();
+    let snapshot = manifest["snapshot"].as_str().unwrap();
+    std::fs::metadata(path.join(snapshot)).unwrap().len()
+}
+
 struct ClientWrapper {
     child: Child,
     reader: BufReader<ChildStdout>,
@@ -46,6 +90,9 @@ struct ClientWrapper {
 }
 impl ClientWrapper {
     fn new(addrs: Vec<&str>) -> ClientWrapper {
+        ClientWrapper::with_isolation(addrs, "snapshot")
+    }
+    fn with_isolation(addrs: Vec<&str>, isolation: &str) -> ClientWrapper {
         let mut addr = vec![];
         for add in addrs {
             addr.push("--addr");
@@ -72,11 +119,14 @@ impl ClientWrapper {
             reader,
             writer,
         };
-        client.begin();
+        client.begin_with(isolation);
         client
     }
     fn begin(&mut self) {
-        let buf = format!("begin\n");
+        self.begin_with("");
+    }
+    fn begin_with(&mut self, isolation: &str) {
+        let buf = format!("begin {}\n", isolation);
         self.writer.write(buf.as_bytes()).expect("Writer error");
         self.writer.flush().expect("Writer error");
 
@@ -89,6 +139,22 @@ impl ClientWrapper {
         self.writer.write(buf.as_bytes()).expect("Writer error");
         self.writer.flush().expect("Writer error");
     }
+    fn remove(&mut self, key: &str) {
+        let buf = format!("remove {}\n", key);
+        self.writer.write(buf.as_bytes()).expect("Writer error");
+        self.writer.flush().expect("Writer error");
+    }
+    fn scan(&mut self, args: &str, expected: &[&str]) {
+        let buf = format!("scan {}\n", args);
+        self.writer.write(buf.as_bytes()).expect("Writer error");
+        self.writer.flush().expect("Writer error");
+
+        for expected in expected {
+            let mut reader_buf = String::new();
+            self.reader.read_line(&mut reader_buf).unwrap();
+            assert_eq!(expected.trim(), reader_buf.trim());
+        }
+    }
     fn get(&mut self, key: &str, expected: &str) {
         let buf = format!("get {}\n", key);
         self.writer.write(buf.as_bytes()).expect("Writer error");
@@ -110,6 +176,15 @@ impl ClientWrapper {
 
         self.exit();
     }
+    fn abort(&mut self, expected: &str) {
+        let buf = format!("abort\n");
+        self.writer.write(buf.as_bytes()).expect("Writer error");
+        self.writer.flush().expect("Writer error");
+
+        let mut reader_buf = String::new();
+        self.reader.read_line(&mut reader_buf).unwrap();
+        assert!(reader_buf.trim().contains(expected.trim()));
+    }
     fn exit(&mut self) {
         let buf = format!("exit\n");
         self.writer.write(buf.as_bytes()).expect("Writer error");
@@ -151,7 +226,7 @@ fn client_cli_txn_single_access() {
         let mut client0 = ClientWrapper::new(addr.clone());
         client0.set("key1", "100");
         client0.set("key2", "200");
-        client0.get("key1", "Key not found");
+        client0.get("key1", "100");
         client0.get("key3", "Key not found");
         client0.commit("Transaction Success");
 
@@ -203,7 +278,7 @@ fn client_cli_txn_pmp_write_predicates() {
 
         client1.set("key1", "200");
         client1.set("key2", "300");
-        client1.get("key2", "200");
+        client1.get("key2", "300");
 
         client2.set("key2", "400");
 
@@ -393,6 +468,788 @@ fn client_cli_txn_anti_dependency_cycles() {
     }
 }
 
+#[test]
+fn client_cli_txn_multi_batch_commit() {
+    let addr = vec!["127.0.0.1:6211", "127.0.0.1:6212", "127.0.0.1:6213"];
+    for engine in vec!["kvs", "sled"] {
+        let temp_dir = TempDir::new().unwrap();
+        let (sender, handle) = open_server(engine, addr.clone(), &temp_dir);
+
+        // more keys than fit in one prewrite or commit batch
+        let mut client0 = ClientWrapper::new(addr.clone());
+        for i in 0..150 {
+            client0.set(&format!("key{}", i), &format!("{}", i));
+        }
+        client0.commit("Transaction Success");
+
+        let mut client1 = ClientWrapper::new(addr.clone());
+        for i in (0..150).step_by(7) {
+            client1.get(&format!("key{}", i), &format!("{}", i));
+        }
+
+        sender.send(()).unwrap();
+        handle.join().unwrap();
+    }
+}
+
+#[test]
+fn client_cli_txn_abort() {
+    let addr = vec!["127.0.0.1:6221", "127.0.0.1:6222", "127.0.0.1:6223"];
+    for engine in vec!["kvs", "sled"] {
+        let temp_dir = TempDir::new().unwrap();
+        let (sender, handle) = open_server(engine, addr.clone(), &temp_dir);
+
+        let mut client0 = ClientWrapper::new(addr.clone());
+        client0.set("key1", "100");
+        client0.abort("Transaction Aborted");
+        client0.begin();
+        client0.set("key2", "200");
+        client0.commit("Transaction Success");
+
+        let mut client1 = ClientWrapper::new(addr.clone());
+        client1.get("key1", "Key not found");
+        client1.get("key2", "200");
+
+        sender.send(()).unwrap();
+        handle.join().unwrap();
+    }
+}
+
+#[test]
+fn client_cli_txn_timestamp_after_restart() {
+    let addr = vec!["127.0.0.1:6231", "127.0.0.1:6232", "127.0.0.1:6233"];
+    for engine in vec!["kvs", "sled"] {
+        let temp_dir = TempDir::new().unwrap();
+        let (sender, handle) = open_server(engine, addr.clone(), &temp_dir);
+
+        let mut client0 = ClientWrapper::new(addr.clone());
+        client0.set("key1", "100");
+        client0.commit("Transaction Success");
+
+        sender.send(()).unwrap();
+        handle.join().unwrap();
+        let (sender, handle) = open_server(engine, addr.clone(), &temp_dir);
+
+        // timestamps after restart start above the replicated high-water mark
+        let mut client1 = ClientWrapper::new(addr.clone());
+        client1.get("key1", "100");
+        client1.set("key1", "200");
+        client1.commit("Transaction Success");
+
+        let mut client2 = ClientWrapper::new(addr.clone());
+        client2.get("key1", "200");
+
+        sender.send(()).unwrap();
+        handle.join().unwrap();
+    }
+}
+
+#[test]
+fn client_cli_txn_write_skew_serializable() {
+    let addr = vec!["127.0.0.1:6241", "127.0.0.1:6242", "127.0.0.1:6243"];
+    for engine in vec!["kvs", "sled"] {
+        let temp_dir = TempDir::new().unwrap();
+        let (sender, handle) = open_server(engine, addr.clone(), &temp_dir);
+
+        let mut client0 = ClientWrapper::new(addr.clone());
+        client0.set("key1", "100");
+        client0.set("key2", "200");
+        client0.commit("Transaction Success");
+
+        let mut client1 = ClientWrapper::with_isolation(addr.clone(), "serializable");
+        let mut client2 = ClientWrapper::with_isolation(addr.clone(), "serializable");
+
+        client1.get("key1", "100");
+        client1.get("key2", "200");
+        client2.get("key1", "100");
+        client2.get("key2", "200");
+
+        client1.set("key1", "101");
+        client2.set("key2", "201");
+        client1.commit("Transaction Success");
+        client2.commit("Transaction Failed");
+
+        sender.send(()).unwrap();
+        handle.join().unwrap();
+    }
+}
+
+#[test]
+fn client_cli_txn_read_own_writes() {
+    let addr = vec!["127.0.0.1:6251", "127.0.0.1:6252", "127.0.0.1:6253"];
+    for engine in vec!["kvs", "sled"] {
+        let temp_dir = TempDir::new().unwrap();
+        let (sender, handle) = open_server(engine, addr.clone(), &temp_dir);
+
+        let mut client0 = ClientWrapper::new(addr.clone());
+        client0.set("key1", "100");
+        client0.set("key2", "200");
+        client0.commit("Transaction Success");
+
+        let mut client1 = ClientWrapper::new(addr.clone());
+        client1.set("key1", "101");
+        client1.set("key1", "102");
+        client1.get("key1", "102");
+        client1.remove("key2");
+        client1.get("key2", "Key not found");
+        // enough writes to flush a batch to servers before commit
+        for i in 0..100 {
+            client1.set(&format!("batch{}", i), &format!("{}", i));
+        }
+        client1.get("batch3", "3");
+        client1.commit("Transaction Success");
+
+        let mut client2 = ClientWrapper::new(addr.clone());
+        client2.get("key1", "102");
+        client2.get("key2", "Key not found");
+        client2.get("batch99", "99");
+        client2.commit("Transaction Success");
+
+        sender.send(()).unwrap();
+        handle.join().unwrap();
+    }
+}
+
+#[test]
+fn client_cli_txn_scan() {
+    let addr = vec!["127.0.0.1:6261", "127.0.0.1:6262", "127.0.0.1:6263"];
+    for engine in vec!["kvs", "sled"] {
+        let temp_dir = TempDir::new().unwrap();
+        let (sender, handle) = open_server(engine, addr.clone(), &temp_dir);
+
+        let mut client0 = ClientWrapper::new(addr.clone());
+        for i in 1..6 {
+            client0.set(&format!("key{}", i), &format!("{}00", i));
+        }
+        client0.commit("Transaction Success");
+
+        let mut client1 = ClientWrapper::new(addr.clone());
+        client1.set("key6", "600");
+        client1.remove("key2");
+        client1.scan(
+            "key",
+            &["key1 100", "key3 300", "key4 400", "key5 500", "key6 600"],
+        );
+        client1.scan("key1 key4 2", &["key1 100", "key3 300"]);
+
+        // a txn committed after the start ts is not seen
+        let mut client2 = ClientWrapper::new(addr.clone());
+        client2.set("key7", "700");
+        client2.commit("Transaction Success");
+        client1.scan("key6", &["key6 600"]);
+        client1.get("key7", "Key not found");
+        client1.commit("Transaction Success");
+
+        let mut client3 = ClientWrapper::new(addr.clone());
+        client3.scan("key5", &["key5 500", "key6 600", "key7 700"]);
+        client3.get("key2", "Key not found");
+        client3.commit("Transaction Success");
+
+        sender.send(()).unwrap();
+        handle.join().unwrap();
+    }
+}
+
+fn subscribe(addrs: Vec<&str>, prefix: &str) -> (Child, BufReader<ChildStdout>) {
+    let mut child = Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["subscribe", prefix])
+        .args(addrs.iter().flat_map(|addr| vec!["--addr", addr]))
+        .env("RUST_LOG", "warn")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    let stdout = child.stdout.take().expect("Unable get stdout");
+    (child, BufReader::new(stdout))
+}
+
+#[test]
+fn client_cli_txn_subscribe() {
+    let addr = vec!["127.0.0.1:6271", "127.0.0.1:6272", "127.0.0.1:6273"];
+    for engine in vec!["kvs", "sled"] {
+        let temp_dir = TempDir::new().unwrap();
+        let (sender, handle) = open_server(engine, addr.clone(), &temp_dir);
+
+        let mut client0 = ClientWrapper::new(addr.clone());
+        client0.set("key1", "100");
+        client0.set("key2", "200");
+        client0.commit("Transaction Success");
+
+        // committed changes are replayed before new ones are followed
+        let (mut child, mut reader) = subscribe(addr.clone(), "key");
+        let mut client1 = ClientWrapper::new(addr.clone());
+        client1.remove("key1");
+        client1.set("key3", "300");
+        client1.set("other", "400");
+        client1.commit("Transaction Success");
+
+        let mut commit_ts = Vec::new();
+        for expected in vec![
+            "put key1 100",
+            "put key2 200",
+            "delete key1",
+            "put key3 300",
+        ] {
+            let mut reader_buf = String::new();
+            reader.read_line(&mut reader_buf).unwrap();
+            let (ts, event) = reader_buf.trim().split_once(' ').unwrap();
+            assert_eq!(expected, event);
+            commit_ts.push(ts.parse::<u64>().unwrap());
+        }
+        assert_eq!(commit_ts[0], commit_ts[1]);
+        assert!(commit_ts[1] < commit_ts[2]);
+        assert_eq!(commit_ts[2], commit_ts[3]);
+        child.kill().unwrap();
+
+        sender.send(()).unwrap();
+        handle.join().unwrap();
+    }
+}
+
+fn watch(addrs: Vec<&str>, prefix: &str) -> (Child, BufReader<ChildStdout>) {
+    let mut child = Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["watch", prefix])
+        .args(addrs.iter().flat_map(|addr| vec!["--addr", addr]))
+        .env("RUST_LOG", "warn")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    let stdout = child.stdout.take().expect("Unable get stdout");
+    (child, BufReader::new(stdout))
+}
+
+#[test]
+fn client_cli_txn_watch() {
+    let addr = vec!["127.0.0.1:6281", "127.0.0.1:6282", "127.0.0.1:6283"];
+    for engine in vec!["kvs", "sled"] {
+        let temp_dir = TempDir::new().unwrap();
+        let (sender, handle) = open_server(engine, addr.clone(), &temp_dir);
+
+        let mut client0 = ClientWrapper::new(addr.clone());
+        client0.set("conf1", "100");
+        client0.commit("Transaction Success");
+
+        // only the changes committed after the watch starts are printed
+        let (mut child, mut reader) = watch(addr.clone(), "conf");
+        let mut client1 = ClientWrapper::new(addr.clone());
+        client1.set("conf2", "200");
+        client1.set("other", "300");
+        client1.commit("Transaction Success");
+        let mut client2 = ClientWrapper::new(addr.clone());
+        client2.remove("conf1");
+        client2.commit("Transaction Success");
+
+        for expected in vec!["put conf2 200", "delete conf1"] {
+            let mut reader_buf = String::new();
+            reader.read_line(&mut reader_buf).unwrap();
+            let (_revision, event) = reader_buf.trim().split_once(' ').unwrap();
+            assert_eq!(expected, event);
+        }
+        child.kill().unwrap();
+
+        sender.send(()).unwrap();
+        handle.join().unwrap();
+    }
+}
+
+#[test]
+fn client_cli_txn_watch_follower() {
+    let addr = vec!["127.0.0.1:6381", "127.0.0.1:6382", "127.0.0.1:6383"];
+    for engine in vec!["kvs", "sled"] {
+        let temp_dir = TempDir::new().unwrap();
+        let (sender, handle) = open_server(engine, addr.clone(), &temp_dir);
+
+        let mut client0 = ClientWrapper::new(addr.clone());
+        client0.set("conf1", "100");
+        client0.commit("Transaction Success");
+
+        // a watch of new changes only is turned down by followers
+        let mut children: Vec<Child> = addr
+            .iter()
+            .map(|addr| watch(vec![addr], "conf").0)
+            .collect();
+        thread::sleep(Duration::from_secs(2));
+        let refused = children
+            .iter_mut()
+            .filter_map(|child| child.try_wait().unwrap())
+            .filter(|status| !status.success())
+            .count();
+        assert_eq!(refused, 2);
+        for child in children.iter_mut() {
+            child.kill().unwrap_or(());
+        }
+
+        // while every server replays from a revision
+        for addr in addr.iter() {
+            let mut child = Command::cargo_bin("kvs-client")
+                .unwrap()
+                .args(&["watch", "conf", "--from-revision", "1"])
+                .args(&["--addr", addr])
+                .env("RUST_LOG", "warn")
+                .stdout(Stdio::piped())
+                .stderr(Stdio::null())
+                .spawn()
+                .unwrap();
+            let mut reader = BufReader::new(child.stdout.take().unwrap());
+            let mut reader_buf = String::new();
+            reader.read_line(&mut reader_buf).unwrap();
+            let (_revision, event) = reader_buf.trim().split_once(' ').unwrap();
+            assert_eq!("put conf1 100", event);
+            child.kill().unwrap();
+        }
+
+        sender.send(()).unwrap();
+        handle.join().unwrap();
+    }
+}
+
+#[test]
+fn client_cli_txn_get_at_ts() {
+    let addr = vec!["127.0.0.1:6291", "127.0.0.1:6292", "127.0.0.1:6293"];
+    for engine in vec!["kvs", "sled"] {
+        let temp_dir = TempDir::new().unwrap();
+        let (sender, handle) = open_server(engine, addr.clone(), &temp_dir);
+
+        for value in vec!["100", "200"] {
+            let mut client = ClientWrapper::new(addr.clone());
+            client.set("key1", value);
+            client.commit("Transaction Success");
+        }
+        // the commit ts of both versions, as the change feed reports them
+        let (mut child, mut reader) = subscribe(addr.clone(), "key1");
+        let mut commit_ts = Vec::new();
+        for _ in 0..2 {
+            let mut reader_buf = String::new();
+            reader.read_line(&mut reader_buf).unwrap();
+            let (ts, _event) = reader_buf.trim().split_once(' ').unwrap();
+            commit_ts.push(ts.to_string());
+        }
+        child.kill().unwrap();
+        let before = (commit_ts[0].parse::<u64>().unwrap() - 1).to_string();
+
+        let get_at = |ts: &str| {
+            let mut cmd = Command::cargo_bin("kvs-client").unwrap();
+            cmd.args(&["get", "key1", "--at-ts", ts])
+                .args(addr.iter().flat_map(|addr| vec!["--addr", addr]));
+            cmd
+        };
+        get_at(&before).assert().success().stdout("Key not found\n");
+        get_at(&commit_ts[0]).assert().success().stdout("100\n");
+        get_at(&commit_ts[1]).assert().success().stdout("200\n");
+
+        // versions overwritten before the safe point are gone
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(&["gc", &commit_ts[1]])
+            .args(addr.iter().flat_map(|addr| vec!["--addr", addr]))
+            .assert()
+            .success();
+        get_at(&commit_ts[0])
+            .assert()
+            .failure()
+            .stderr(predicates::str::contains("Version compacted"));
+        get_at(&commit_ts[1]).assert().success().stdout("200\n");
+
+        sender.send(()).unwrap();
+        handle.join().unwrap();
+    }
+}
+
+#[test]
+fn client_cli_txn_membership_change() {
+    let addr = vec!["127.0.0.1:6311", "127.0.0.1:6312", "127.0.0.1:6313"];
+    let new_addr = "127.0.0.1:6314";
+    for engine in vec!["kvs", "sled"] {
+        let temp_dir = TempDir::new().unwrap();
+        let (sender, handle) = open_server(engine, addr.clone(), &temp_dir);
+        let mut client = ClientWrapper::new(addr.clone());
+        client.set("key1", "100");
+        client.commit("Transaction Success");
+
+        let new_dir = TempDir::new().unwrap();
+        let (new_sender, new_handle) = open_joining_server(engine, new_addr, 3, &new_dir);
+        // the new node may lead the group once the others are removed
+        let admin = |args: &[&str]| {
+            let mut cmd = Command::cargo_bin("kvs-client").unwrap();
+            cmd.args(args)
+                .args(addr.iter().flat_map(|addr| vec!["--addr", addr]))
+                .args(&["--addr", new_addr]);
+            cmd
+        };
+        admin(&["add-node", "3", new_addr])
+            .assert()
+            .success()
+            .stdout(predicates::str::contains("3 127.0.0.1:6314"));
+        // hand the group over to the new node alone, leader included
+        for id in vec!["0", "1", "2"] {
+            admin(&["remove-node", id]).assert().success();
+            thread::sleep(Duration::from_secs(2));
+        }
+        admin(&["remove-node", "3"])
+            .assert()
+            .failure()
+            .stderr(predicates::str::contains("last voter"));
+
+        // the new node caught up with what was written before it joined
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(&["get", "key1", "--addr", new_addr])
+            .assert()
+            .success()
+            .stdout("100\n");
+
+        new_sender.send(()).unwrap();
+        new_handle.join().unwrap();
+        sender.send(()).unwrap();
+        handle.join().unwrap();
+    }
+}
+
+#[test]
+fn client_cli_txn_learner() {
+    let addr = vec!["127.0.0.1:6321", "127.0.0.1:6322", "127.0.0.1:6323"];
+    let new_addr = "127.0.0.1:6324";
+    for engine in vec!["kvs", "sled"] {
+        let temp_dir = TempDir::new().unwrap();
+        let (sender, handle) = open_server(engine, addr.clone(), &temp_dir);
+        let mut client = ClientWrapper::new(addr.clone());
+        client.set("key1", "100");
+        client.commit("Transaction Success");
+
+        let new_dir = TempDir::new().unwrap();
+        let (new_sender, new_handle) = open_joining_server(engine, new_addr, 3, &new_dir);
+        let admin = |args: &[&str]| {
+            let mut cmd = Command::cargo_bin("kvs-client").unwrap();
+            cmd.args(args)
+                .args(addr.iter().flat_map(|addr| vec!["--addr", addr]))
+                .args(&["--addr", new_addr]);
+            cmd
+        };
+        admin(&["add-learner", "3", new_addr])
+            .assert()
+            .success()
+            .stdout("3 127.0.0.1:6324\n");
+        // a voter is never demoted
+        admin(&["add-learner", "0", addr[0]])
+            .assert()
+            .success()
+            .stdout("3 127.0.0.1:6324\n");
+
+        // the learner counts toward no quorum
+        let mut client = ClientWrapper::new(addr.clone());
+        client.set("key2", "200");
+        client.commit("Transaction Success");
+
+        admin(&["add-node", "3", new_addr])
+            .assert()
+            .success()
+            .stdout(predicates::str::contains("3 127.0.0.1:6324"));
+        admin(&["add-learner", "3", new_addr])
+            .assert()
+            .success()
+            .stdout("");
+
+        // the promoted voter holds everything committed while it learned
+        for id in vec!["0", "1", "2"] {
+            admin(&["remove-node", id]).assert().success();
+            thread::sleep(Duration::from_secs(2));
+        }
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(&["get", "key2", "--addr", new_addr])
+            .assert()
+            .success()
+            .stdout("200\n");
+
+        new_sender.send(()).unwrap();
+        new_handle.join().unwrap();
+        sender.send(()).unwrap();
+        handle.join().unwrap();
+    }
+}
+
+#[test]
+fn client_cli_txn_lease_read() {
+    let addr = vec!["127.0.0.1:6351", "127.0.0.1:6352", "127.0.0.1:6353"];
+    for engine in vec!["kvs", "sled"] {
+        let temp_dir = TempDir::new().unwrap();
+        let (sender, handle) = open_server_with(engine, addr.clone(), &temp_dir, &["--lease-read"]);
+        let mut client = ClientWrapper::new(addr.clone());
+        client.set("key1", "100");
+        client.commit("Transaction Success");
+
+        let get = |expected: &str| {
+            Command::cargo_bin("kvs-client")
+                .unwrap()
+                .args(&["get", "key1"])
+                .args(addr.iter().flat_map(|addr| vec!["--addr", addr]))
+                .assert()
+                .success()
+                .stdout(format!("{}\n", expected));
+        };
+        get("100");
+        // a read under the lease sees the writes committed before it
+        let mut client = ClientWrapper::new(addr.clone());
+        client.set("key1", "200");
+        client.commit("Transaction Success");
+        get("200");
+
+        // the new leader reads under a lease of its own term
+        for id in 0..addr.len() {
+            Command::cargo_bin("kvs-client")
+                .unwrap()
+                .args(&["transfer-leader", &id.to_string()])
+                .args(addr.iter().flat_map(|addr| vec!["--addr", addr]))
+                .assert()
+                .success();
+            get("200");
+        }
+
+        sender.send(()).unwrap();
+        handle.join().unwrap();
+    }
+}
+
+#[test]
+fn client_cli_txn_follower_read() {
+    let addr = vec!["127.0.0.1:6361", "127.0.0.1:6362", "127.0.0.1:6363"];
+    for engine in vec!["kvs", "sled"] {
+        let temp_dir = TempDir::new().unwrap();
+        let (sender, handle) = open_server(engine, addr.clone(), &temp_dir);
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(&["transfer-leader", "0"])
+            .args(addr.iter().flat_map(|addr| vec!["--addr", addr]))
+            .assert()
+            .success();
+
+        for (value, follower) in ["100", "200"].iter().zip(&addr[1..]) {
+            let mut client = ClientWrapper::new(addr.clone());
+            client.set("key1", value);
+            client.commit("Transaction Success");
+            // the follower listed first serves the read, timestamps still
+            // come from the leader
+            Command::cargo_bin("kvs-client")
+                .unwrap()
+                .args(&["get", "key1", "--addr", follower, "--addr", addr[0]])
+                .assert()
+                .success()
+                .stdout(format!("{}\n", value));
+        }
+
+        sender.send(()).unwrap();
+        handle.join().unwrap();
+    }
+}
+
+#[test]
+fn client_cli_txn_log_compaction() {
+    let addr = vec!["127.0.0.1:6371", "127.0.0.1:6372", "127.0.0.1:6373"];
+    let new_addr = "127.0.0.1:6374";
+    let flags = ["--snapshot-log-entries", "4"];
+    for engine in vec!["kvs", "sled"] {
+        let temp_dir = TempDir::new().unwrap();
+        let (sender, handle) = open_server_with(engine, addr.clone(), &temp_dir, &flags);
+        for value in ["100", "200", "300"] {
+            let mut client = ClientWrapper::new(addr.clone());
+            client.set("key1", value);
+            client.set("key2", value);
+            client.commit("Transaction Success");
+        }
+        thread::sleep(Duration::from_secs(1));
+        for id in 0..addr.len() {
+            let path = temp_dir.path().join(format!("server-{}", id));
+            assert!(snapshot_len(&path) > 0);
+        }
+
+        // a node added once the log is compacted is sent the snapshot
+        let new_dir = TempDir::new().unwrap();
+        let (new_sender, new_handle) = open_joining_server(engine, new_addr, 3, &new_dir);
+        let admin = |args: &[&str]| {
+            let mut cmd = Command::cargo_bin("kvs-client").unwrap();
+            cmd.args(args)
+                .args(addr.iter().flat_map(|addr| vec!["--addr", addr]))
+                .args(&["--addr", new_addr]);
+            cmd
+        };
+        admin(&["add-node", "3", new_addr]).assert().success();
+        admin(&["transfer-leader", "3"]).assert().success();
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(&["get", "key2", "--addr", new_addr])
+            .assert()
+            .success()
+            .stdout("300\n");
+        assert!(snapshot_len(&new_dir.path().join("server-3")) > 0);
+        new_sender.send(()).unwrap();
+        new_handle.join().unwrap();
+
+        // restarted, the nodes restore their snapshots
+        sender.send(()).unwrap();
+        handle.join().unwrap();
+        let (sender, handle) = open_server_with(engine, addr.clone(), &temp_dir, &flags);
+        thread::sleep(Duration::from_secs(1));
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(&["get", "key1"])
+            .args(addr.iter().flat_map(|addr| vec!["--addr", addr]))
+            .assert()
+            .success()
+            .stdout("300\n");
+
+        sender.send(()).unwrap();
+        handle.join().unwrap();
+    }
+}
+
+#[test]
+fn client_cli_txn_transfer_leader() {
+    let addr = vec!["127.0.0.1:6331", "127.0.0.1:6332", "127.0.0.1:6333"];
+    for engine in vec!["kvs", "sled"] {
+        let temp_dir = TempDir::new().unwrap();
+        let (sender, handle) = open_server(engine, addr.clone(), &temp_dir);
+        let mut client = ClientWrapper::new(addr.clone());
+        client.set("key1", "100");
+        client.commit("Transaction Success");
+
+        for (id, node_addr) in addr.iter().enumerate().rev() {
+            Command::cargo_bin("kvs-client")
+                .unwrap()
+                .args(&["transfer-leader", &id.to_string()])
+                .args(addr.iter().flat_map(|addr| vec!["--addr", addr]))
+                .assert()
+                .success();
+            // only the leader serves reads
+            Command::cargo_bin("kvs-client")
+                .unwrap()
+                .args(&["get", "key1", "--addr", node_addr])
+                .assert()
+                .success()
+                .stdout("100\n");
+        }
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(&["transfer-leader", "7"])
+            .args(addr.iter().flat_map(|addr| vec!["--addr", addr]))
+            .assert()
+            .failure()
+            .stderr(predicates::str::contains("not a voter"));
+
+        sender.send(()).unwrap();
+        handle.join().unwrap();
+    }
+}
+
+#[test]
+fn client_cli_txn_graceful_shutdown() {
+    let addr = vec!["127.0.0.1:6341", "127.0.0.1:6342", "127.0.0.1:6343"];
+    let new_addr = "127.0.0.1:6344";
+    for engine in vec!["kvs", "sled"] {
+        let temp_dir = TempDir::new().unwrap();
+        let (sender, handle) = open_server(engine, addr.clone(), &temp_dir);
+        let mut client = ClientWrapper::new(addr.clone());
+        client.set("key1", "100");
+        client.commit("Transaction Success");
+
+        // a leader of its own process, to be shut down alone
+        let new_dir = TempDir::new().unwrap();
+        let log = std::fs::File::create(new_dir.path().join("log")).unwrap();
+        let mut child = Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(&["--engine", engine, "--server", "raft", "--addr", new_addr])
+            .args(&["--first-id", "3", "--join"])
+            .env("RUST_LOG", "info")
+            .current_dir(&new_dir)
+            .stderr(log)
+            .spawn()
+            .unwrap();
+        thread::sleep(Duration::from_secs(1));
+        let admin = |args: &[&str]| {
+            let mut cmd = Command::cargo_bin("kvs-client").unwrap();
+            cmd.args(args)
+                .args(addr.iter().flat_map(|addr| vec!["--addr", addr]))
+                .args(&["--addr", new_addr]);
+            cmd
+        };
+        admin(&["add-node", "3", new_addr]).assert().success();
+        admin(&["transfer-leader", "3"]).assert().success();
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(&["get", "key1", "--addr", new_addr])
+            .assert()
+            .success()
+            .stdout("100\n");
+
+        // ctrl-c hands the leadership over before the server exits
+        Command::new("kill")
+            .args(&["-INT", &child.id().to_string()])
+            .assert()
+            .success();
+        assert!(child.wait().unwrap().success());
+        let log = std::fs::read_to_string(new_dir.path().join("log")).unwrap();
+        assert!(
+            log.contains("Leadership transferred before shutting down"),
+            "{}",
+            log
+        );
+        // the voters left take writes at once
+        let mut client = ClientWrapper::new(addr.clone());
+        client.set("key2", "200");
+        client.commit("Transaction Success");
+
+        sender.send(()).unwrap();
+        handle.join().unwrap();
+    }
+}
+
+#[test]
+fn client_cli_txn_index_lookup() {
+    let addr = vec!["127.0.0.1:6301", "127.0.0.1:6302", "127.0.0.1:6303"];
+    for engine in vec!["kvs", "sled"] {
+        let temp_dir = TempDir::new().unwrap();
+        let (sender, handle) = open_server(engine, addr.clone(), &temp_dir);
+
+        let mut client = ClientWrapper::new(addr.clone());
+        client.set("user-1", r#"{"email":"a@x"}"#);
+        client.commit("Transaction Success");
+        // keys written before the index are indexed when it is created
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(&["create-index", "email", "/email"])
+            .args(addr.iter().flat_map(|addr| vec!["--addr", addr]))
+            .assert()
+            .success();
+        let mut client = ClientWrapper::new(addr.clone());
+        client.set("user-2", r#"{"email":"a@x"}"#);
+        client.set("user-3", r#"{"email":"b@x"}"#);
+        client.commit("Transaction Success");
+
+        let lookup = |value: &str| {
+            let mut cmd = Command::cargo_bin("kvs-client").unwrap();
+            cmd.args(&["lookup", "email", value])
+                .args(addr.iter().flat_map(|addr| vec!["--addr", addr]));
+            cmd
+        };
+        lookup("a@x").assert().success().stdout("user-1\nuser-2\n");
+        lookup("b@x").assert().success().stdout("user-3\n");
+
+        // entries move with the value and go away with the key
+        let mut client = ClientWrapper::new(addr.clone());
+        client.set("user-1", r#"{"email":"b@x"}"#);
+        client.remove("user-3");
+        client.commit("Transaction Success");
+        lookup("a@x").assert().success().stdout("user-2\n");
+        lookup("b@x").assert().success().stdout("user-1\n");
+
+        sender.send(()).unwrap();
+        handle.join().unwrap();
+    }
+}
+
 struct Proxy {
     addr: String,
     server_addr: String,
@@ -459,6 +1316,147 @@ impl KvRpc for Proxy {
             req
         }
     }
+
+    async fn txn_batch_prewrite(
+        &self,
+        request: tonic::Request<BatchPrewriteRequest>,
+    ) -> std::result::Result<tonic::Response<BatchPrewriteReply>, tonic::Status> {
+        self.build_client().txn_batch_prewrite(request).await
+    }
+
+    async fn txn_heart_beat(
+        &self,
+        request: tonic::Request<TxnHeartBeatRequest>,
+    ) -> std::result::Result<tonic::Response<TxnHeartBeatReply>, tonic::Status> {
+        self.build_client().txn_heart_beat(request).await
+    }
+
+    async fn txn_rollback(
+        &self,
+        request: tonic::Request<RollbackRequest>,
+    ) -> std::result::Result<tonic::Response<RollbackReply>, tonic::Status> {
+        self.build_client().txn_rollback(request).await
+    }
+
+    async fn txn_check_txn_status(
+        &self,
+        request: tonic::Request<CheckTxnStatusRequest>,
+    ) -> std::result::Result<tonic::Response<CheckTxnStatusReply>, tonic::Status> {
+        self.build_client().txn_check_txn_status(request).await
+    }
+
+    async fn txn_resolve_lock(
+        &self,
+        request: tonic::Request<ResolveLockRequest>,
+    ) -> std::result::Result<tonic::Response<ResolveLockReply>, tonic::Status> {
+        self.build_client().txn_resolve_lock(request).await
+    }
+
+    async fn txn_batch_get(
+        &self,
+        request: tonic::Request<BatchGetRequest>,
+    ) -> std::result::Result<tonic::Response<BatchGetReply>, tonic::Status> {
+        self.build_client().txn_batch_get(request).await
+    }
+
+    async fn txn_scan(
+        &self,
+        request: tonic::Request<ScanRequest>,
+    ) -> std::result::Result<tonic::Response<ScanReply>, tonic::Status> {
+        self.build_client().txn_scan(request).await
+    }
+
+    async fn txn_gc(
+        &self,
+        request: tonic::Request<GcRequest>,
+    ) -> std::result::Result<tonic::Response<GcReply>, tonic::Status> {
+        self.build_client().txn_gc(request).await
+    }
+
+    async fn add_node(
+        &self,
+        request: tonic::Request<AddNodeRequest>,
+    ) -> std::result::Result<tonic::Response<ConfChangeReply>, tonic::Status> {
+        self.build_client().add_node(request).await
+    }
+
+    async fn remove_node(
+        &self,
+        request: tonic::Request<RemoveNodeRequest>,
+    ) -> std::result::Result<tonic::Response<ConfChangeReply>, tonic::Status> {
+        self.build_client().remove_node(request).await
+    }
+
+    async fn add_learner(
+        &self,
+        request: tonic::Request<AddNodeRequest>,
+    ) -> std::result::Result<tonic::Response<ConfChangeReply>, tonic::Status> {
+        self.build_client().add_learner(request).await
+    }
+
+    async fn transfer_leader(
+        &self,
+        request: tonic::Request<TransferLeaderRequest>,
+    ) -> std::result::Result<tonic::Response<TransferLeaderReply>, tonic::Status> {
+        self.build_client().transfer_leader(request).await
+    }
+
+    type SubscribeStream = ChangeStream;
+
+    async fn subscribe(
+        &self,
+        request: tonic::Request<SubscribeRequest>,
+    ) -> std::result::Result<tonic::Response<ChangeStream>, tonic::Status> {
+        let mut stream = self.build_client().subscribe(request).await?.into_inner();
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Some(reply) = stream.next().await {
+                if tx.send(reply).is_err() {
+                    break;
+                }
+            }
+        });
+        Ok(tonic::Response::new(Box::pin(
+            UnboundedReceiverStream::new(rx),
+        )))
+    }
+
+    type WatchStream = WatchEventStream;
+
+    async fn watch(
+        &self,
+        request: tonic::Request<WatchRequest>,
+    ) -> std::result::Result<tonic::Response<WatchEventStream>, tonic::Status> {
+        let mut stream = self.build_client().watch(request).await?.into_inner();
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Some(reply) = stream.next().await {
+                if tx.send(reply).is_err() {
+                    break;
+                }
+            }
+        });
+        Ok(tonic::Response::new(Box::pin(
+            UnboundedReceiverStream::new(rx),
+        )))
+    }
+
+    async fn txn_batch_commit(
+        &self,
+        request: tonic::Request<BatchCommitRequest>,
+    ) -> std::result::Result<tonic::Response<BatchCommitReply>, tonic::Status> {
+        // batch commits only carry secondaries
+        let req = if self.drop_req {
+            Err(tonic::Status::data_loss("Drop request"))
+        } else {
+            self.build_client().txn_batch_commit(request).await
+        };
+        if self.drop_resp {
+            Err(tonic::Status::data_loss("Drop response"))
+        } else {
+            req
+        }
+    }
 }
 
 struct MultiProxy {